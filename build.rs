@@ -4,6 +4,22 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             "message.Transaction",
             "#[derive(serde::Serialize, serde::Deserialize)]",
         )
+        .type_attribute(
+            "message.Block",
+            "#[derive(serde::Serialize, serde::Deserialize)]",
+        )
+        .type_attribute(
+            "message.Header",
+            "#[derive(serde::Serialize, serde::Deserialize)]",
+        )
+        .type_attribute(
+            "message.Commit",
+            "#[derive(serde::Serialize, serde::Deserialize)]",
+        )
+        .type_attribute(
+            "message.CommitSignature",
+            "#[derive(serde::Serialize, serde::Deserialize)]",
+        )
         .out_dir("src/network/messages")
         .compile_protos(
             &["src/network/messages/message.proto"],
@@ -0,0 +1,335 @@
+/*
+An adversary turns a node byzantine without touching its engine: it wraps the
+engine the node would run honestly, and stands between it and the network so every
+message the engine sends, its blocks included, goes through the strategy of the
+adversary. The same strategies run against every engine.
+
+- equivocate: two instances of the engine run with the identity of the node, each
+  talking to one half of the peers, so each half sees validly signed but
+  conflicting blocks and votes. The twin only sees every other transaction, so the
+  blocks it proposes differ from the ones of the first instance.
+- silent: the node follows the network but never sends anything.
+- crash: the node runs honestly until `crash_at_secs`, then stops for good.
+- delay: every message the node sends is held back for `delay_ms`.
+- invalid-signatures: the signatures of the blocks and votes of the node are
+  forged, and it floods the network with forged transactions and blocks.
+- selective-forwarding: the messages of the node only reach one half of its peers.
+*/
+
+use crate::byzantine::transport::{forged_signature, AdversaryTransport, Outgoing};
+use crate::consensus::chain::Chain;
+use crate::consensus::engine::{BlockVerdict, Context, Engine};
+use crate::network::messages::message::message::Payload;
+use crate::network::messages::message::{Block, Message, Transaction};
+use crate::network::messages::signing::SigningKey;
+use crate::network::peer::new_transaction;
+use crate::network::transport::Network;
+use crate::CunnerError;
+use clap::ValueEnum;
+use futures::future::{self, Either};
+use libp2p::PeerId;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Period the messages held back by a delaying adversary are checked at.
+const FLUSH_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Strategy is how a byzantine node misbehaves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Strategy {
+    Equivocate,
+    Silent,
+    Crash,
+    Delay,
+    InvalidSignatures,
+    SelectiveForwarding,
+}
+
+/// Byzantine nodes of a run, set in the `byzantine` section of the experiment. Every
+/// node is honest unless a strategy is set.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Params {
+    pub strategy: Option<Strategy>,
+    /// indexes of the byzantine nodes, as the faults name them
+    pub nodes: Vec<usize>,
+    /// how long a delaying node holds its messages back
+    pub delay_ms: u64,
+    /// seconds after the start a crashing node stops
+    pub crash_at_secs: u64,
+    /// period between two forged transactions and blocks of an invalid-signatures node
+    pub spam_interval_ms: u64,
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Self {
+            strategy: None,
+            nodes: vec![0],
+            delay_ms: 2_000,
+            crash_at_secs: 30,
+            spam_interval_ms: 200,
+        }
+    }
+}
+
+impl Params {
+    /// Checks the byzantine nodes of a network of `nodes` nodes.
+    pub fn validate(&self, nodes: usize) -> Result<(), CunnerError> {
+        let invalid = |message: &str| Err(CunnerError::Config(format!("byzantine: {message}")));
+        if self.nodes.iter().any(|node| *node >= nodes) {
+            return invalid("nodes must only name nodes of the network");
+        }
+        if self.delay_ms == 0 {
+            return invalid("delay_ms must be at least 1");
+        }
+        if self.spam_interval_ms == 0 {
+            return invalid("spam_interval_ms must be at least 1");
+        }
+        if self.crash_at_secs == 0 {
+            return invalid("crash_at_secs must be at least 1");
+        }
+        Ok(())
+    }
+
+    /// Returns the strategy of the node with the index, None if it is honest.
+    pub fn strategy_of(&self, node: usize) -> Option<Strategy> {
+        self.strategy.filter(|_| self.nodes.contains(&node))
+    }
+}
+
+/// Adversary is the engine of a byzantine node, it wraps the engine the node would
+/// run honestly.
+#[derive(Clone)]
+pub struct Adversary {
+    strategy: Strategy,
+    params: Params,
+    // context of the node, whose network is not wrapped
+    context: Context,
+    transport: AdversaryTransport,
+    engine: Box<dyn Engine>,
+    // second instance of an equivocating node, with its own chain
+    twin: Option<(Box<dyn Engine>, Chain)>,
+    crashed: Arc<AtomicBool>,
+}
+
+/// Returns the engine built by `new_engine` wrapped in an adversary with the strategy.
+pub fn new_adversary(
+    strategy: Strategy,
+    params: &Params,
+    context: Context,
+    new_engine: impl Fn(Context) -> Box<dyn Engine>,
+) -> Box<dyn Engine> {
+    info!("Node is byzantine, strategy {strategy:?}");
+    let wrap = |outgoing: Outgoing, chain: Chain| {
+        let transport =
+            AdversaryTransport::new(context.network.transport(), outgoing, context.clock.clone());
+        let engine = new_engine(Context {
+            network: Network::new(transport.clone()),
+            chain,
+            ..context.clone()
+        });
+        (engine, transport)
+    };
+
+    let outgoing = match strategy {
+        Strategy::Equivocate | Strategy::SelectiveForwarding => Outgoing::Half(0),
+        Strategy::Silent => Outgoing::Drop,
+        // a crashing node is honest until it crashes
+        Strategy::Crash => Outgoing::Forward,
+        Strategy::Delay => Outgoing::Delay(Duration::from_millis(params.delay_ms)),
+        Strategy::InvalidSignatures => Outgoing::CorruptSignatures,
+    };
+    let (engine, transport) = wrap(outgoing, context.chain.clone());
+    let twin = (strategy == Strategy::Equivocate).then(|| {
+        let chain = Chain::in_memory(context.network.local_peer_id());
+        let (twin, _) = wrap(Outgoing::Half(1), chain.clone());
+        (twin, chain)
+    });
+
+    Box::new(Adversary {
+        strategy,
+        params: params.clone(),
+        context,
+        transport,
+        engine,
+        twin,
+        crashed: Arc::new(AtomicBool::new(false)),
+    })
+}
+
+impl Adversary {
+    fn is_crashed(&self) -> bool {
+        self.crashed.load(Ordering::SeqCst)
+    }
+
+    // sends the messages held back as they come due
+    async fn flush(&self) {
+        loop {
+            self.context.clock.sleep(FLUSH_INTERVAL).await;
+            self.transport.flush();
+        }
+    }
+
+    // the crash time counts from the start of the node, not from the last restart of run
+    async fn crash(&self) {
+        let crash_at = Duration::from_secs(self.params.crash_at_secs);
+        self.context
+            .clock
+            .sleep(crash_at.saturating_sub(self.context.clock.now()))
+            .await;
+        info!("Byzantine node crashes");
+        self.crashed.store(true, Ordering::SeqCst);
+    }
+
+    // floods the network with forged transactions and blocks, straight through the
+    // network of the node so they bypass the engine
+    async fn spam(&self) {
+        let key = SigningKey::generate();
+        let transport = self.context.network.transport();
+        loop {
+            self.context
+                .clock
+                .sleep(Duration::from_millis(self.params.spam_interval_ms))
+                .await;
+            let mut transaction = new_transaction(&key);
+            transaction.signature = forged_signature();
+            let mut block = match self
+                .context
+                .chain
+                .next_block(vec![transaction.clone()], self.context.clock.timestamp())
+            {
+                Ok(block) => block,
+                Err(e) => {
+                    error!("Failed to read the chain head: {:?}", e);
+                    continue;
+                }
+            };
+            block.signature = forged_signature();
+            for payload in [Payload::Transaction(transaction), Payload::Block(block)] {
+                transport.publish(Message {
+                    payload: Some(payload),
+                    to: Vec::new(),
+                });
+            }
+        }
+    }
+}
+
+impl Engine for Adversary {
+    fn add_transaction(&self, transaction: Transaction) {
+        if self.is_crashed() {
+            return;
+        }
+        if let Some((twin, _)) = &self.twin {
+            if transaction.nonce.is_multiple_of(2) {
+                twin.add_transaction(transaction.clone());
+            }
+        }
+        self.engine.add_transaction(transaction);
+    }
+
+    fn run<'a>(&'a self) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> {
+        Box::pin(async move {
+            match self.strategy {
+                Strategy::Equivocate => {
+                    let (twin, _) = self.twin.as_ref().expect("equivocating nodes have a twin");
+                    future::join(self.engine.run(), twin.run()).await;
+                }
+                Strategy::Silent | Strategy::SelectiveForwarding => self.engine.run().await,
+                Strategy::Crash => {
+                    // the engine stops where it is once the node crashed, and stays
+                    // stopped when the node runs its engine again
+                    if self.is_crashed() {
+                        return future::pending::<()>().await;
+                    }
+                    if let Either::Left(_) =
+                        future::select(self.engine.run(), Box::pin(self.crash())).await
+                    {
+                        return;
+                    }
+                    future::pending::<()>().await;
+                }
+                Strategy::Delay => {
+                    future::join(self.engine.run(), self.flush()).await;
+                }
+                Strategy::InvalidSignatures => {
+                    future::join(self.engine.run(), self.spam()).await;
+                }
+            }
+        })
+    }
+
+    fn handle_message(&self, from: PeerId, bytes: Vec<u8>) {
+        if self.is_crashed() {
+            return;
+        }
+        if let Some((twin, _)) = &self.twin {
+            twin.handle_message(from, bytes.clone());
+        }
+        self.engine.handle_message(from, bytes);
+    }
+
+    fn on_block(&self, block: &Block, from: PeerId) -> BlockVerdict {
+        if self.is_crashed() {
+            return BlockVerdict::Hold;
+        }
+        if let Some((twin, chain)) = &self.twin {
+            if twin.on_block(block, from) == BlockVerdict::Accept {
+                chain.append(block);
+            }
+        }
+        self.engine.on_block(block, from)
+    }
+
+    fn mempool_size(&self) -> usize {
+        self.engine.mempool_size()
+    }
+
+    // the state of the honest engine, with what the adversary does to it
+    fn state(&self) -> Value {
+        let strategy = self
+            .strategy
+            .to_possible_value()
+            .expect("strategies are never skipped");
+        json!({
+            "byzantine": strategy.get_name(),
+            "crashed": self.is_crashed(),
+            "engine": self.engine.state(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consensus::pbft::{engine as pbft, pbft::Params as PbftParams};
+    use crate::simulation::rng;
+    use crate::testing::{assert_agree, simulate};
+
+    fn new_pbft(context: Context) -> Box<dyn Engine> {
+        pbft::Engine::new_engine(Duration::from_secs(5), PbftParams::default(), 4, context)
+    }
+
+    #[test]
+    fn a_crashed_node_stops_while_the_others_go_on() {
+        let _rng = rng::exclusive();
+        let chains = simulate(4, 7, 120, |node, context| {
+            if node == 0 {
+                new_adversary(Strategy::Crash, &Params::default(), context, new_pbft)
+            } else {
+                new_pbft(context)
+            }
+        });
+        assert_agree(&chains[1..], 10);
+        let crashed = chains[0].len().unwrap();
+        assert!(crashed > 0 && crashed < chains[1].len().unwrap() / 2);
+    }
+}
@@ -0,0 +1,338 @@
+use crate::network::messages::message::message::Payload;
+use crate::network::messages::message::Message;
+use crate::network::transport::Transport;
+use crate::simulation::clock::Clock;
+use crate::simulation::rng::with_rng;
+use libp2p::PeerId;
+use log::debug;
+use rand::Rng;
+use serde_json::Value;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Size of the signatures an adversary forges, the size of an ed25519 signature.
+const SIGNATURE_SIZE: usize = 64;
+
+// a message held back, with when it is due and the peer it is for if any
+type Delayed = (Duration, Option<PeerId>, Message);
+
+/// Outgoing is what an adversary does to the messages its engine sends.
+#[derive(Debug, Clone, Copy)]
+pub enum Outgoing {
+    /// every message is sent as is
+    Forward,
+    /// every message is dropped
+    Drop,
+    /// messages only reach one half of the peers, 0 or 1
+    Half(usize),
+    /// messages are held back for the duration before they are sent
+    Delay(Duration),
+    /// the signatures of the blocks and votes are replaced by random bytes
+    CorruptSignatures,
+}
+
+/// AdversaryTransport stands between an engine and the transport of its node, and
+/// applies what the adversary does to every message the engine sends. It is cheap
+/// to clone, clones share the messages held back.
+#[derive(Clone)]
+pub struct AdversaryTransport {
+    inner: Arc<dyn Transport>,
+    outgoing: Outgoing,
+    clock: Clock,
+    delayed: Arc<Mutex<Vec<Delayed>>>,
+}
+
+impl AdversaryTransport {
+    pub fn new(inner: Arc<dyn Transport>, outgoing: Outgoing, clock: Clock) -> Self {
+        Self {
+            inner,
+            outgoing,
+            clock,
+            delayed: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Sends the messages held back that are due.
+    pub fn flush(&self) {
+        let now = self.clock.now();
+        let due: Vec<_> = {
+            let mut delayed = self.delayed.lock().unwrap();
+            let (due, held) = std::mem::take(&mut *delayed)
+                .into_iter()
+                .partition(|(at, _, _)| *at <= now);
+            *delayed = held;
+            due
+        };
+        for (_, peer, message) in due {
+            match peer {
+                Some(peer) => self.inner.send_to(peer, message),
+                None => self.inner.publish(message),
+            }
+        }
+    }
+
+    // the peers of one half, every other peer in peer id order so both halves stay
+    // about the same size
+    fn half(&self, half: usize) -> Vec<PeerId> {
+        let mut peers = self.inner.connected_peers();
+        peers.sort();
+        peers
+            .into_iter()
+            .enumerate()
+            .filter(|(index, _)| index % 2 == half)
+            .map(|(_, peer)| peer)
+            .collect()
+    }
+
+    fn hold(&self, delay: Duration, peer: Option<PeerId>, message: Message) {
+        self.delayed
+            .lock()
+            .unwrap()
+            .push((self.clock.now() + delay, peer, message));
+    }
+}
+
+impl Transport for AdversaryTransport {
+    fn local_peer_id(&self) -> PeerId {
+        self.inner.local_peer_id()
+    }
+
+    fn connected_peers(&self) -> Vec<PeerId> {
+        self.inner.connected_peers()
+    }
+
+    fn nodes(&self) -> Vec<PeerId> {
+        self.inner.nodes()
+    }
+
+    fn publish(&self, message: Message) {
+        match self.outgoing {
+            Outgoing::Forward => self.inner.publish(message),
+            Outgoing::Drop => debug!("Adversary dropped a message"),
+            Outgoing::Half(half) => {
+                for peer in self.half(half) {
+                    self.inner.send_to(peer, message.clone());
+                }
+            }
+            Outgoing::Delay(delay) => self.hold(delay, None, message),
+            Outgoing::CorruptSignatures => self.inner.publish(corrupt(message)),
+        }
+    }
+
+    fn send_to(&self, peer: PeerId, message: Message) {
+        match self.outgoing {
+            Outgoing::Forward => self.inner.send_to(peer, message),
+            Outgoing::Drop => debug!("Adversary dropped a message to {peer}"),
+            Outgoing::Half(half) => {
+                if self.half(half).contains(&peer) {
+                    self.inner.send_to(peer, message);
+                }
+            }
+            Outgoing::Delay(delay) => self.hold(delay, Some(peer), message),
+            Outgoing::CorruptSignatures => self.inner.send_to(peer, corrupt(message)),
+        }
+    }
+}
+
+/// Returns random bytes the size of a signature, which no key signed.
+pub fn forged_signature() -> Vec<u8> {
+    with_rng(|rng| (0..SIGNATURE_SIZE).map(|_| rng.gen()).collect())
+}
+
+// engine messages are opaque to the network, but every engine encodes them as json, so
+// every `signature` field in them is forged, the ones of the blocks they carry included
+fn corrupt(mut message: Message) -> Message {
+    match &mut message.payload {
+        Some(Payload::Block(block)) => block.signature = forged_signature(),
+        Some(Payload::ConsensusMessage(consensus_message)) => {
+            if let Ok(mut value) = serde_json::from_slice::<Value>(&consensus_message.data) {
+                forge_signatures(&mut value);
+                consensus_message.data =
+                    serde_json::to_vec(&value).expect("Failed to serialize json message");
+            }
+        }
+        Some(Payload::Transaction(_)) | None => {}
+    }
+    message
+}
+
+fn forge_signatures(value: &mut Value) {
+    match value {
+        Value::Object(fields) => {
+            for (name, field) in fields.iter_mut() {
+                if name == "signature" && field.is_array() {
+                    *field = Value::from(forged_signature());
+                } else {
+                    forge_signatures(field);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(forge_signatures),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::messages::message::{Block, ConsensusMessage};
+    use crate::simulation::rng;
+    use crate::simulation::scheduler::{Event, Scheduler};
+    use serde_json::json;
+
+    // a transport that keeps what it is asked to send, with the peer it is for if any
+    struct Recording {
+        peer_id: PeerId,
+        peers: Vec<PeerId>,
+        sent: Mutex<Vec<(Option<PeerId>, Message)>>,
+    }
+
+    impl Recording {
+        fn new(peers: usize) -> Arc<Self> {
+            Arc::new(Self {
+                peer_id: PeerId::random(),
+                peers: (0..peers).map(|_| PeerId::random()).collect(),
+                sent: Mutex::new(Vec::new()),
+            })
+        }
+
+        fn take(&self) -> Vec<(Option<PeerId>, Message)> {
+            std::mem::take(&mut *self.sent.lock().unwrap())
+        }
+    }
+
+    impl Transport for Recording {
+        fn local_peer_id(&self) -> PeerId {
+            self.peer_id
+        }
+
+        fn connected_peers(&self) -> Vec<PeerId> {
+            self.peers.clone()
+        }
+
+        fn nodes(&self) -> Vec<PeerId> {
+            Vec::new()
+        }
+
+        fn publish(&self, message: Message) {
+            self.sent.lock().unwrap().push((None, message));
+        }
+
+        fn send_to(&self, peer: PeerId, message: Message) {
+            self.sent.lock().unwrap().push((Some(peer), message));
+        }
+    }
+
+    fn adversary(inner: &Arc<Recording>, outgoing: Outgoing) -> AdversaryTransport {
+        let clock = Clock::Virtual(Arc::new(Mutex::new(Scheduler::new())));
+        AdversaryTransport::new(inner.clone(), outgoing, clock)
+    }
+
+    fn consensus_message(data: Vec<u8>) -> Message {
+        Message {
+            payload: Some(Payload::ConsensusMessage(ConsensusMessage { data })),
+            ..Message::default()
+        }
+    }
+
+    #[test]
+    fn half_splits_the_peers_in_two() {
+        let inner = Recording::new(5);
+        let mut sorted = inner.peers.clone();
+        sorted.sort();
+
+        let mut halves = Vec::new();
+        for half in 0..2 {
+            let transport = adversary(&inner, Outgoing::Half(half));
+            transport.publish(consensus_message(b"vote".to_vec()));
+            let reached: Vec<PeerId> = inner.take().into_iter().filter_map(|(to, _)| to).collect();
+            for peer in &sorted {
+                transport.send_to(*peer, consensus_message(b"vote".to_vec()));
+            }
+            let sent: Vec<PeerId> = inner.take().into_iter().filter_map(|(to, _)| to).collect();
+            assert_eq!(reached, sent);
+            halves.push(reached);
+        }
+        assert_eq!(halves[0], [sorted[0], sorted[2], sorted[4]]);
+        assert_eq!(halves[1], [sorted[1], sorted[3]]);
+    }
+
+    #[test]
+    fn corrupt_signatures_forges_every_signature() {
+        let _rng = rng::exclusive();
+        let inner = Recording::new(2);
+        let transport = adversary(&inner, Outgoing::CorruptSignatures);
+
+        let block = Block::new_block(None, inner.peer_id, 1_000, Vec::new());
+        transport.publish(Message {
+            payload: Some(Payload::Block(block.clone())),
+            ..Message::default()
+        });
+        let vote = json!({
+            "Vote": {"height": 3, "signature": [1, 2, 3], "block": {"signature": [4, 5]}},
+            "votes": [{"signature": [6]}],
+        });
+        transport.send_to(
+            inner.peers[1],
+            consensus_message(serde_json::to_vec(&vote).unwrap()),
+        );
+
+        let sent = inner.take();
+        let Some(Payload::Block(corrupted)) = &sent[0].1.payload else {
+            panic!("expected a block");
+        };
+        assert_eq!(corrupted.signature.len(), SIGNATURE_SIZE);
+        assert_ne!(corrupted.signature, block.signature);
+        assert_eq!(corrupted.hash(), block.hash());
+
+        assert_eq!(sent[1].0, Some(inner.peers[1]));
+        let Some(Payload::ConsensusMessage(message)) = &sent[1].1.payload else {
+            panic!("expected a consensus message");
+        };
+        let corrupted: Value = serde_json::from_slice(&message.data).unwrap();
+        assert_eq!(corrupted["Vote"]["height"], 3);
+        let signatures = [
+            &corrupted["Vote"]["signature"],
+            &corrupted["Vote"]["block"]["signature"],
+            &corrupted["votes"][0]["signature"],
+        ];
+        for signature in signatures {
+            assert_eq!(signature.as_array().unwrap().len(), SIGNATURE_SIZE);
+        }
+    }
+
+    #[test]
+    fn delay_holds_messages_until_they_are_due() {
+        let inner = Recording::new(2);
+        let scheduler = Arc::new(Mutex::new(Scheduler::new()));
+        let transport = AdversaryTransport::new(
+            inner.clone(),
+            Outgoing::Delay(Duration::from_secs(2)),
+            Clock::Virtual(scheduler.clone()),
+        );
+        // moves the virtual time to `secs` after the start
+        let at = |secs| {
+            let mut scheduler = scheduler.lock().unwrap();
+            scheduler.schedule(
+                Duration::from_secs(secs),
+                Event::EmitTransaction { node: 0 },
+            );
+            scheduler.next_event(Duration::MAX);
+        };
+        transport.publish(consensus_message(b"first".to_vec()));
+        transport.flush();
+        assert!(inner.take().is_empty());
+
+        at(1);
+        transport.send_to(inner.peers[0], consensus_message(b"second".to_vec()));
+        at(2);
+        transport.flush();
+        let sent = inner.take();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0, None);
+
+        at(3);
+        transport.flush();
+        assert_eq!(inner.take()[0].0, Some(inner.peers[0]));
+    }
+}
@@ -0,0 +1,394 @@
+/*
+The cluster launcher runs a local network of cunner nodes, each one a `cunner node`
+process of its own listening on consecutive ports. The output of every node goes to
+its own log file, where the launcher also watches for the nodes discovering each
+other. Unless the nodes form a full mesh, they are started without mDNS and dial
+only their neighbours in the topology, so messages between the other nodes are
+relayed by gossip over several hops. The identity of every node is kept in the log
+directory and every node is told the peer ids of all of them, so the faults of an
+experiment can name the nodes of a cluster by index, and the byzantine nodes are
+started with their strategy. Nodes are stopped the way Ctrl-C stops a single node,
+so they shut down cleanly and write their metrics report and history, which the
+launcher runs the invariant checker over.
+*/
+
+use crate::byzantine::adversary::Strategy;
+use crate::metrics::invariants::{check, History};
+use crate::network::messages::signing::load_or_generate_identity;
+use crate::workload::generator::Generator;
+use crate::CunnerError;
+use clap::ValueEnum;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashSet};
+use std::env;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::time::Duration;
+use tokio::process::{Child, Command};
+use tokio::time::{interval, sleep, Instant};
+use tokio::{select, signal};
+
+/// How long the launcher waits for every node to discover all the others.
+const MESH_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a stopped node gets to shut down before it is killed.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct ClusterConfig {
+    pub nodes: u16,
+    pub engine: String,
+    pub base_port: u16,
+    /// port of the RPC API of the first node, the others get the next ports, no API if None
+    pub rpc_base_port: Option<u16>,
+    /// port of the Prometheus metrics of the first node, the others get the next ports
+    pub metrics_base_port: Option<u16>,
+    /// the cluster runs until Ctrl-C if None
+    pub duration: Option<Duration>,
+    /// experiment file every node is started with
+    pub experiment: Option<PathBuf>,
+    pub log_dir: PathBuf,
+    /// every node records its inputs to node-i.trace in the log directory
+    pub record: bool,
+    pub topology: Topology,
+    /// generator of the workload of the nodes, and its rate over the whole cluster
+    pub workload: Generator,
+    pub tps: Option<f64>,
+    /// strategy of the byzantine nodes, and their indexes
+    pub byzantine: Option<(Strategy, Vec<usize>)>,
+    /// time a transaction has to become final on every honest node
+    pub liveness_bound: Duration,
+}
+
+/// Topology is how the nodes of a cluster are connected to each other.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Topology {
+    /// every node is connected to every other one
+    #[default]
+    Full,
+    /// every node is connected to the nodes before and after it
+    Line,
+    /// a line whose ends are connected too
+    Ring,
+    /// every node is connected to the first one only
+    Star,
+}
+
+impl Topology {
+    /// Returns the nodes the node at `index` is connected to.
+    pub fn neighbours(self, index: u16, nodes: u16) -> BTreeSet<u16> {
+        let last = nodes.saturating_sub(1);
+        let mut neighbours: BTreeSet<u16> = match self {
+            Topology::Full => (0..nodes).collect(),
+            Topology::Line => [index.checked_sub(1), Some(index + 1)]
+                .into_iter()
+                .flatten()
+                .filter(|neighbour| *neighbour <= last)
+                .collect(),
+            Topology::Ring => [
+                index.checked_sub(1).unwrap_or(last),
+                if index == last { 0 } else { index + 1 },
+            ]
+            .into_iter()
+            .collect(),
+            Topology::Star if index == 0 => (1..nodes).collect(),
+            Topology::Star => [0].into_iter().collect(),
+        };
+        neighbours.remove(&index);
+        neighbours
+    }
+
+    /// Returns the neighbours the node at `index` dials, every connection is dialed by
+    /// the node started last.
+    fn dials(self, index: u16, nodes: u16) -> Vec<u16> {
+        self.neighbours(index, nodes)
+            .into_iter()
+            .filter(|neighbour| *neighbour < index)
+            .collect()
+    }
+}
+
+struct Node {
+    index: u16,
+    port: u16,
+    log: PathBuf,
+    process: Child,
+}
+
+/// Spawns the nodes of the cluster and supervises them until Ctrl-C, the end of the
+/// configured duration or the exit of any node, then stops all of them.
+pub async fn run_cluster(config: ClusterConfig) -> Result<(), CunnerError> {
+    if config.nodes == 0 {
+        return Err(CunnerError::Config(
+            "A cluster needs at least one node".into(),
+        ));
+    }
+    config
+        .base_port
+        .checked_add(config.nodes - 1)
+        .ok_or_else(|| CunnerError::Config("Not enough ports above the base port".into()))?;
+    for (base_port, name) in [
+        (config.rpc_base_port, "RPC"),
+        (config.metrics_base_port, "metrics"),
+    ] {
+        if let Some(base_port) = base_port {
+            base_port.checked_add(config.nodes - 1).ok_or_else(|| {
+                CunnerError::Config(format!("Not enough ports above the {name} base port"))
+            })?;
+        }
+    }
+    fs::create_dir_all(&config.log_dir)?;
+
+    let peers = (0..config.nodes)
+        .map(|index| {
+            load_or_generate_identity(&identity_file(&config, index))
+                .map(|keypair| keypair.public().to_peer_id().to_string())
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut nodes = Vec::new();
+    for index in 0..config.nodes {
+        match spawn_node(&config, index, &peers) {
+            Ok(node) => nodes.push(node),
+            Err(e) => {
+                stop_nodes(&mut nodes).await;
+                return Err(e);
+            }
+        }
+    }
+
+    let started = Instant::now();
+    let deadline = config.duration.map(|duration| started + duration);
+    let mut mesh_formed = config.nodes == 1;
+    let mut ticks = interval(Duration::from_secs(1));
+    let mut shutdown = std::pin::pin!(signal::ctrl_c());
+
+    loop {
+        select! {
+            _ = &mut shutdown => {
+                info!("Stopping the cluster");
+                break;
+            },
+            _ = ticks.tick() => {
+                if let Some((index, status)) = exited_node(&mut nodes) {
+                    // nodes given a duration by the experiment stop on their own
+                    if status.success() {
+                        info!("Node {index} finished its run, stopping the cluster");
+                    } else {
+                        warn!("Node {index} exited with {status}, stopping the cluster");
+                    }
+                    break;
+                }
+                if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                    info!("Cluster ran for its configured duration, stopping it");
+                    break;
+                }
+                if !mesh_formed {
+                    if mesh_is_formed(&nodes, &config) {
+                        mesh_formed = true;
+                        info!("All {} nodes discovered their neighbours in a {:?} topology after {:?}", config.nodes, config.topology, started.elapsed());
+                    } else if started.elapsed() >= MESH_TIMEOUT {
+                        mesh_formed = true;
+                        warn!("Nodes did not all discover their neighbours within {:?}, see their logs in {}", MESH_TIMEOUT, config.log_dir.display());
+                    }
+                }
+            },
+        }
+    }
+
+    stop_nodes(&mut nodes).await;
+    check_invariants(&config);
+    Ok(())
+}
+
+fn identity_file(config: &ClusterConfig, index: u16) -> PathBuf {
+    config.log_dir.join(format!("node-{index}.key"))
+}
+
+fn history_file(config: &ClusterConfig, index: u16) -> PathBuf {
+    config.log_dir.join(format!("node-{index}.history.json"))
+}
+
+// checks the histories the nodes wrote when they stopped, a node that did not write
+// one is left out of the check
+fn check_invariants(config: &ClusterConfig) {
+    let mut histories = Vec::new();
+    for index in 0..config.nodes {
+        let path = history_file(config, index);
+        match History::read(&path) {
+            Ok(history) => histories.push(history),
+            Err(e) => warn!("Leaving node {index} out of the invariant check: {e}"),
+        }
+    }
+    let report = check(&histories, config.liveness_bound);
+    if report.violations.is_empty() {
+        info!("{}", report.summary());
+    } else {
+        warn!("{}", report.summary());
+    }
+    let path = config.log_dir.join("invariants.json");
+    match report.write(&path) {
+        Ok(()) => info!("Wrote invariant report to {}", path.display()),
+        Err(e) => warn!("Failed to write the invariant report: {e}"),
+    }
+}
+
+fn spawn_node(config: &ClusterConfig, index: u16, peers: &[String]) -> Result<Node, CunnerError> {
+    let port = config.base_port + index;
+    let log = config.log_dir.join(format!("node-{index}.log"));
+    let report = config.log_dir.join(format!("node-{index}.json"));
+    let output = File::create(&log)?;
+    // a history left by an earlier run in the directory would be checked with this one
+    let history = history_file(config, index);
+    if let Err(e) = fs::remove_file(&history) {
+        if e.kind() != io::ErrorKind::NotFound {
+            return Err(e.into());
+        }
+    }
+
+    let mut command = Command::new(env::current_exe()?);
+    command
+        .arg("node")
+        .args(["--tcp", &port.to_string()])
+        .args(["--engine", &config.engine])
+        .args(["--nodes", &config.nodes.to_string()])
+        .arg("--report")
+        .arg(&report)
+        .arg("--identity-file")
+        .arg(identity_file(config, index))
+        .arg("--history")
+        .arg(&history)
+        .arg("--peers")
+        .args(peers);
+    if let Some(experiment) = &config.experiment {
+        command.arg("--config").arg(experiment);
+    }
+    if config.record {
+        command
+            .arg("--record")
+            .arg(config.log_dir.join(format!("node-{index}.trace")));
+    }
+    if let Some(rpc_base_port) = config.rpc_base_port {
+        command.args(["--rpc-port", &(rpc_base_port + index).to_string()]);
+    }
+    if let Some(metrics_base_port) = config.metrics_base_port {
+        command.args(["--metrics-port", &(metrics_base_port + index).to_string()]);
+    }
+    // the neighbours a node dials are given on the command line, on top of what the experiment sets
+    for neighbour in config.topology.dials(index, config.nodes) {
+        command.arg("--bootstrap").arg(format!(
+            "/ip4/127.0.0.1/tcp/{}",
+            config.base_port + neighbour
+        ));
+    }
+    if config.topology != Topology::Full {
+        command.arg("--no-mdns");
+    }
+    let workload = config
+        .workload
+        .to_possible_value()
+        .expect("generators are never skipped");
+    command.args(["--workload", workload.get_name()]);
+    if let Some(tps) = config.tps {
+        command.args(["--tps", &tps.to_string()]);
+    }
+    if let Some((strategy, byzantine)) = &config.byzantine {
+        if byzantine.contains(&index.into()) {
+            let strategy = strategy
+                .to_possible_value()
+                .expect("strategies are never skipped");
+            command.args(["--byzantine", strategy.get_name()]);
+        }
+    }
+    let process = command
+        // discoveries are logged at info level, the launcher needs them to follow the mesh
+        .env(
+            "RUST_LOG",
+            env::var("RUST_LOG").unwrap_or_else(|_| "info".into()),
+        )
+        .stdout(output.try_clone()?)
+        .stderr(output)
+        .kill_on_drop(true)
+        .spawn()?;
+
+    info!(
+        "Started node {index} on port {port}, logging to {}",
+        log.display()
+    );
+    Ok(Node {
+        index,
+        port,
+        log,
+        process,
+    })
+}
+
+fn exited_node(nodes: &mut [Node]) -> Option<(u16, ExitStatus)> {
+    nodes.iter_mut().find_map(|node| {
+        node.process
+            .try_wait()
+            .ok()
+            .flatten()
+            .map(|status| (node.index, status))
+    })
+}
+
+// every node logged the discovery of all its neighbours
+fn mesh_is_formed(nodes: &[Node], config: &ClusterConfig) -> bool {
+    nodes.iter().all(|node| {
+        discovered_peers(&node.log) >= config.topology.neighbours(node.index, config.nodes).len()
+    })
+}
+
+fn discovered_peers(log: &Path) -> usize {
+    let Ok(content) = fs::read_to_string(log) else {
+        return 0;
+    };
+    content
+        .lines()
+        .filter_map(|line| line.split("Discovered a new peer: ").nth(1))
+        .map(|peer| peer.trim())
+        .collect::<HashSet<_>>()
+        .len()
+}
+
+// interrupts every node as Ctrl-C would, and kills the ones still running after the timeout
+async fn stop_nodes(nodes: &mut [Node]) {
+    for node in nodes.iter_mut() {
+        interrupt(&mut node.process);
+    }
+
+    let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+    for node in nodes.iter_mut() {
+        select! {
+            status = node.process.wait() => match status {
+                Ok(status) => info!("Node {} on port {} stopped with {status}", node.index, node.port),
+                Err(e) => warn!("Failed to wait for node {}: {e}", node.index),
+            },
+            _ = sleep(deadline.saturating_duration_since(Instant::now())) => {
+                warn!("Node {} did not stop in time, killing it", node.index);
+                if let Err(e) = node.process.kill().await {
+                    warn!("Failed to kill node {}: {e}", node.index);
+                }
+            },
+        }
+    }
+}
+
+#[cfg(unix)]
+fn interrupt(process: &mut Child) {
+    if let Some(pid) = process.id() {
+        // SAFETY: sending a signal to a child process we spawned and have not reaped yet
+        unsafe {
+            libc::kill(pid as libc::pid_t, libc::SIGINT);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn interrupt(process: &mut Child) {
+    let _ = process.start_kill();
+}
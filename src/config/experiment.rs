@@ -0,0 +1,240 @@
+/*
+An experiment file describes a run in JSON: the engine and its parameters, the
+network, the workload and how long it lasts. Every field is optional and falls back
+to the defaults cunner was run with before experiments were configurable, and the
+flags given on the command line override the file. Unknown fields are rejected, so
+a typo in an experiment does not silently run the defaults.
+*/
+
+use crate::byzantine::adversary::Params as ByzantineParams;
+use crate::cluster::launcher::Topology;
+use crate::consensus::avalanche::avalanche::Params as AvalancheParams;
+use crate::consensus::hotstuff::hotstuff::Params as HotStuffParams;
+use crate::consensus::pbft::pbft::Params as PbftParams;
+use crate::consensus::pos::pos::Params as PosParams;
+use crate::consensus::pow::pow::Params as PowParams;
+use crate::consensus::raft::raft::Params as RaftParams;
+use crate::consensus::tendermint::tendermint::Params as TendermintParams;
+use crate::metrics::invariants::InvariantConfig;
+use crate::network::faults::FaultConfig;
+use crate::network::messages::signing::SigningKey;
+use crate::workload::generator::WorkloadConfig;
+use crate::{CunnerError, DefinedEngines};
+use libp2p::identity::Keypair;
+use libp2p::{Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+/// PeerConfig is the configuration of a run, as read from an experiment file.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PeerConfig {
+    pub tcp_listen_address: Option<u16>,
+    /// port the RPC API of a node is served on, on localhost, none if not set
+    pub rpc_port: Option<u16>,
+    /// port the Prometheus metrics of a node are served on, on localhost, none if not set
+    pub metrics_port: Option<u16>,
+    // the identity and keys of a node are never part of an experiment
+    #[serde(skip)]
+    pub keypair: Keypair,
+    /// key the transactions of the node are signed with
+    #[serde(skip)]
+    pub private_key: SigningKey,
+    pub engine: EngineConfig,
+    pub network: NetworkConfig,
+    pub workload: WorkloadConfig,
+    pub byzantine: ByzantineParams,
+    pub invariants: InvariantConfig,
+    /// seed of a simulation
+    pub seed: u64,
+    /// a node runs until Ctrl-C if None, a simulation for two minutes
+    pub duration_secs: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EngineConfig {
+    pub name: Option<DefinedEngines>,
+    /// period between two blocks created by a node
+    pub block_interval_secs: u64,
+    pub avalanche: AvalancheParams,
+    pub pbft: PbftParams,
+    pub raft: RaftParams,
+    pub pow: PowParams,
+    pub pos: PosParams,
+    pub hotstuff: HotStuffParams,
+    pub tendermint: TendermintParams,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    /// nodes of a cluster or a simulation, and of the network a node is part of
+    pub nodes: usize,
+    /// latency between simulated nodes
+    pub latency_ms: u64,
+    pub gossipsub_heartbeat_secs: u64,
+    /// multiaddrs of the peers a node dials on startup, and again whenever it loses them
+    pub bootstrap: Vec<String>,
+    /// a node finds the peers on its local network with mDNS
+    pub mdns: bool,
+    /// a node finds the peers of its peers through a Kademlia DHT
+    pub kademlia: bool,
+    /// how the nodes of a cluster are connected
+    pub topology: Topology,
+    /// peer ids of the nodes of a live network, in the order the faults name them by index
+    pub peers: Vec<String>,
+    pub faults: FaultConfig,
+}
+
+impl Default for PeerConfig {
+    fn default() -> Self {
+        Self {
+            tcp_listen_address: None,
+            rpc_port: None,
+            metrics_port: None,
+            keypair: Keypair::generate_ed25519(),
+            private_key: SigningKey::generate(),
+            engine: EngineConfig::default(),
+            network: NetworkConfig::default(),
+            workload: WorkloadConfig::default(),
+            byzantine: ByzantineParams::default(),
+            invariants: InvariantConfig::default(),
+            seed: 0,
+            duration_secs: None,
+        }
+    }
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        Self {
+            name: None,
+            block_interval_secs: 15,
+            avalanche: AvalancheParams::default(),
+            pbft: PbftParams::default(),
+            raft: RaftParams::default(),
+            pow: PowParams::default(),
+            pos: PosParams::default(),
+            hotstuff: HotStuffParams::default(),
+            tendermint: TendermintParams::default(),
+        }
+    }
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            nodes: 4,
+            latency_ms: 50,
+            gossipsub_heartbeat_secs: 10,
+            bootstrap: Vec::new(),
+            mdns: true,
+            kademlia: false,
+            topology: Topology::Full,
+            peers: Vec::new(),
+            faults: FaultConfig::default(),
+        }
+    }
+}
+
+impl PeerConfig {
+    /// Reads the experiment file at `path`, the defaults if there is none.
+    pub fn load(path: Option<&Path>) -> Result<Self, CunnerError> {
+        let Some(path) = path else {
+            return Ok(Self::default());
+        };
+        let content = fs::read_to_string(path).map_err(|e| {
+            CunnerError::Config(format!("Failed to read {}: {}", path.display(), e))
+        })?;
+        serde_json::from_str(&content).map_err(|e| {
+            CunnerError::Config(format!("Invalid experiment {}: {}", path.display(), e))
+        })
+    }
+
+    /// Returns the experiment as JSON, with the command line overrides applied, as a
+    /// trace records it.
+    pub fn to_json(&self) -> Result<String, CunnerError> {
+        serde_json::to_string(self)
+            .map_err(|e| CunnerError::Config(format!("Failed to encode experiment: {e}")))
+    }
+
+    /// Reads an experiment from JSON, as a trace records it.
+    pub fn from_json(json: &str) -> Result<Self, CunnerError> {
+        serde_json::from_str(json)
+            .map_err(|e| CunnerError::Config(format!("Invalid experiment: {e}")))
+    }
+
+    /// Checks the configuration once the command line overrides are applied.
+    pub fn validate(&self) -> Result<(), CunnerError> {
+        let invalid = |message: &str| Err(CunnerError::Config(message.into()));
+        if self.engine.block_interval_secs == 0 {
+            return invalid("engine.block_interval_secs must be at least 1");
+        }
+        if self.network.nodes == 0 {
+            return invalid("network.nodes must be at least 1");
+        }
+        if self.network.gossipsub_heartbeat_secs == 0 {
+            return invalid("network.gossipsub_heartbeat_secs must be at least 1");
+        }
+        if self.duration_secs == Some(0) {
+            return invalid("duration_secs must be at least 1");
+        }
+        self.bootstrap_addresses()?;
+        self.peer_ids()?;
+        self.network.faults.validate(self.network.nodes)?;
+        self.byzantine.validate(self.network.nodes)?;
+        self.workload.validate()?;
+        self.invariants.validate()?;
+        self.engine.avalanche.validate()?;
+        self.engine.pbft.validate()?;
+        if matches!(self.engine.name, Some(DefinedEngines::Pbft)) {
+            self.engine.pbft.validate_nodes(self.network.nodes)?;
+        }
+        self.engine.raft.validate()?;
+        self.engine.pow.validate()?;
+        self.engine.pos.validate()?;
+        self.engine.hotstuff.validate()?;
+        if matches!(self.engine.name, Some(DefinedEngines::Hotstuff)) {
+            self.engine.hotstuff.validate_nodes(self.network.nodes)?;
+        }
+        self.engine.tendermint.validate()
+    }
+
+    pub fn block_interval(&self) -> Duration {
+        Duration::from_secs(self.engine.block_interval_secs)
+    }
+
+    pub fn gossipsub_heartbeat(&self) -> Duration {
+        Duration::from_secs(self.network.gossipsub_heartbeat_secs)
+    }
+
+    pub fn bootstrap_addresses(&self) -> Result<Vec<Multiaddr>, CunnerError> {
+        self.network
+            .bootstrap
+            .iter()
+            .map(|address| {
+                address.parse().map_err(|e| {
+                    CunnerError::Config(format!("Invalid bootstrap address {address}: {e}"))
+                })
+            })
+            .collect()
+    }
+
+    pub fn peer_ids(&self) -> Result<Vec<PeerId>, CunnerError> {
+        self.network
+            .peers
+            .iter()
+            .map(|peer| {
+                peer.parse()
+                    .map_err(|e| CunnerError::Config(format!("Invalid peer id {peer}: {e}")))
+            })
+            .collect()
+    }
+
+    pub fn duration(&self) -> Option<Duration> {
+        self.duration_secs.map(Duration::from_secs)
+    }
+}
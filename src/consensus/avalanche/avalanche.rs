@@ -1,217 +1,135 @@
 /*
 The consensus mechanism is probabilistic and relies on repeated sub-sampled voting.
 Nodes repeatedly query a random subset of other nodes in the network and move towards consensus based on the majority responses they receive.
-This Rust implementation provides a basic but functional simulation of the Avalanche consensus mechanism, focusing on the core ideas of state management, node interaction, and iterative consensus building through network communication.
+This module holds the Snowball state kept for every transaction in a node's mempool, the network transport and the
+query loop live in the engine that drives it.
 */
 
-extern crate byteorder;
-extern crate hex;
-extern crate rand;
-extern crate ring;
-
-use byteorder::{LittleEndian, WriteBytesExt};
-use rand::{thread_rng, Rng};
-use rand::seq::SliceRandom;
-
-use ring::digest;
-
-use std::collections::HashMap;
-use std::sync::{mpsc::{channel, Receiver, Sender},
-                Arc,
-                Mutex};
-use std::thread;
-use std::time::Duration;
-
-/// Hardcoded tuning parameters for the algorithm.
-pub const SAMPLES: usize = 4;
-pub const MAX_EPOCHS: u32 = 4;
-pub const THRESHOLD: f32 = 0.75;
-pub const CONVICTION_THRESHOLD: f32 = 0.75;
-
-fn main() {
-    let mut net = Network::new(10);
-    net.run();
-
-    loop {
-        // Transactions are generated and sent to random nodes in the network
-        let tx = Transaction::random();
-        println!("sending new transaction into the network {}", &tx.hash());
-
-        // Pick a random node in the network and let the node handle the random transaction.
-        let id = thread_rng().gen_range(0..net.nodes.len()) as u64;
-        // Transactions are verified by nodes independently. If the transaction data is less than 7, it's considered valid; otherwise, it's invalid.
-        let node = net.nodes.get_mut(&id).unwrap();
-        node.lock()
-            .unwrap()
-            .handle_message(0, &Message::Transaction(tx));
-
-        thread::sleep(Duration::from_millis(500)); // cpu ded
-    }
+use crate::network::messages::message::Transaction;
+use crate::CunnerError;
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+/// Tuning parameters for the algorithm, set in the `avalanche` section of the engine
+/// configuration.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Params {
+    /// Number of peers sampled by a query round.
+    pub samples: usize,
+    /// Number of epochs a status must be accepted for before the transaction is final.
+    pub max_epochs: u32,
+    /// Fraction of the sampled peers that must agree for a round to count.
+    pub threshold: f32,
+    /// Fraction of the samples a status must be counted in a row to move to the next epoch.
+    pub conviction_threshold: f32,
+    /// Number of query intervals a round may wait for its k responses before a fresh sample is picked.
+    pub round_timeout: u32,
 }
 
-#[derive(Eq, PartialEq, Clone, Hash)]
-struct Hash(Vec<u8>);
+impl Default for Params {
+    fn default() -> Self {
+        Self {
+            samples: 4,
+            max_epochs: 4,
+            threshold: 0.75,
+            conviction_threshold: 0.75,
+            round_timeout: 3,
+        }
+    }
+}
 
-impl Hash {
-    fn to_string(&self) -> String {
-        hex::encode(&self.0)
+impl Params {
+    pub fn validate(&self) -> Result<(), CunnerError> {
+        let invalid = |message: &str| Err(CunnerError::Config(format!("avalanche: {message}")));
+        if self.samples == 0 {
+            return invalid("samples must be at least 1");
+        }
+        if self.max_epochs == 0 {
+            return invalid("max_epochs must be at least 1");
+        }
+        if !(self.threshold > 0.0 && self.threshold <= 1.0) {
+            return invalid("threshold must be in (0, 1]");
+        }
+        if !(self.conviction_threshold > 0.0 && self.conviction_threshold <= 1.0) {
+            return invalid("conviction_threshold must be in (0, 1]");
+        }
+        if self.round_timeout == 0 {
+            return invalid("round_timeout must be at least 1");
+        }
+        Ok(())
     }
 }
 
+#[derive(Eq, PartialEq, Ord, PartialOrd, Clone, Hash)]
+pub struct Hash(pub Vec<u8>);
+
 impl ::std::fmt::Display for Hash {
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
-        write!(f, "{:?}", self.to_string())
+        write!(f, "{}", hex::encode(&self.0))
     }
 }
 
 impl ::std::fmt::Debug for Hash {
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
-        write!(f, "{:?}", self.to_string())
+        write!(f, "{:?}", hex::encode(&self.0))
     }
 }
 
-#[derive(Debug)]
-enum Message {
-    Query(QueryMessage),
-    QueryResponse((u64, QueryResponse)),
-    Transaction(Transaction),
-}
-
-#[derive(Debug, Clone, PartialEq)]
-enum Status {
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
     Valid,
     Invalid,
 }
 
-/*
-Nodes handle incoming messages (Query, QueryResponse, Transaction) and 
-process them according to the Avalanche protocol rules.
-*/
-
-#[derive(Debug)]
-struct QueryResponse {
-    hash: Hash,
-    status: Status,
-}
-
-#[derive(Debug)]
-struct QueryMessage {
-    tx: Transaction,
-    status: Status,
-}
-
-#[derive(Debug, Clone)]
-struct Transaction {
-    nonce: u64,
-    data: i32,
-}
-
-impl Transaction {
-    fn random() -> Self {
-        let mut rng = thread_rng();
-        Transaction {
-            nonce: rand::random::<u64>(),
-            data: rng.gen_range(0..10),
-        }
-    }
-
-    fn serialize(&self) -> Vec<u8> {
-        let mut buf = vec![];
-        buf.write_u64::<LittleEndian>(self.nonce).unwrap();
-        buf
-    }
-
-    fn hash(&self) -> Hash {
-        let mut ctx = digest::Context::new(&digest::SHA256);
-        ctx.update(&self.serialize());
-        Hash(ctx.finish().as_ref().to_vec())
-    }
-}
-
-// manages nodes and handles message passing between them using channels
-#[derive(Debug)]
-struct Network {
-    nodes: HashMap<u64, Arc<Mutex<Node>>>,
-    receiver: Arc<Mutex<Receiver<(u64, Message)>>>,
-}
-
-impl Network {
-    /// Create a new network with `n` participating nodes.
-    fn new(n: u64) -> Self {
-        let (sender, receiver) = channel();
-        Network {
-            nodes: (0..n)
-                .map(|id| (id, Arc::new(Mutex::new(Node::new(id, sender.clone())))))
-                .collect(),
-            receiver: Arc::new(Mutex::new(receiver)),
+impl Status {
+    pub fn from_valid(valid: bool) -> Self {
+        if valid {
+            Status::Valid
+        } else {
+            Status::Invalid
         }
     }
 
-    fn run(&self) {
-        let receiver = self.receiver.clone();
-        let mut nodes = self.nodes.clone();
-
-        thread::spawn(move || loop {
-            let (origin, msg) = receiver.lock().unwrap().recv().unwrap();
-            match msg {
-                Message::Query(ref _msg) => {
-                    let mut sampled = sample_nodes(&nodes, SAMPLES, origin);
-                    sampled
-                        .iter()
-                        .map(|id| {
-                            nodes
-                                .get_mut(&id)
-                                .unwrap()
-                                .lock()
-                                .unwrap()
-                                .handle_message(origin, &msg)
-                        })
-                        .collect::<Vec<_>>();
-                }
-                Message::QueryResponse((to, ref _msg)) => {
-                    let mut node = nodes.get_mut(&to).unwrap();
-                    node.lock().unwrap().handle_message(origin, &msg);
-                }
-                _ => unreachable!(),
-            }
-        });
+    pub fn is_valid(&self) -> bool {
+        *self == Status::Valid
     }
 }
 
-fn sample_nodes(nodes: &HashMap<u64, Arc<Mutex<Node>>>, n: usize, excl: u64) -> Vec<u64> {
-    let ids: Vec<u64> = nodes
-        .iter()
-        .filter(|(&id, _)| id != excl)
-        .map(|(id, _)| *id)
-        .collect();
-    let mut rng = thread_rng();
-    let sampled_ids = ids.as_slice().choose_multiple(&mut rng, n).cloned().collect();
-    sampled_ids
-}
-
-// Each Node has a mempool to manage transaction states 
+// Each Node has a mempool to manage transaction states
 #[derive(Debug, Clone)]
-struct TxState {
+pub struct TxState {
     epoch: u32,
-    tx: Transaction,
-    status: Status,
+    pub tx: Transaction,
+    pub status: Status,
     responses: Vec<Status>,
-    is_final: bool,
+    pub is_final: bool,
 
     /// 1. Each node maintains a counter cnt
     /// 2. Upon every color change, the node resets cnt to 0
     /// 3. Upon every successful query that yields ≥ αk responses for the same
-    /// color as the node, the node increments cnt.
+    ///    color as the node, the node increments cnt.
     cnt_valid: u32,
     cnt_invalid: u32,
     cnt: u32,
 
     /// Last decided status.
     last_status: Status,
+
+    /// Query round currently collecting responses, the number of peers sampled for it,
+    /// the sampled peers that did not respond yet and the query intervals it has been
+    /// waiting.
+    pub round: u64,
+    sampled: usize,
+    awaited: BTreeSet<PeerId>,
+    ticks: u32,
+
+    params: Params,
 }
 
 impl TxState {
-    fn new(tx: Transaction, status: Status) -> Self {
+    pub fn new(tx: Transaction, status: Status, params: Params) -> Self {
         TxState {
             responses: Vec::new(),
             is_final: false,
@@ -220,8 +138,13 @@ impl TxState {
             cnt_valid: 0,
             cnt_invalid: 0,
             cnt: 0,
+            round: 0,
+            sampled: 0,
+            awaited: BTreeSet::new(),
+            ticks: 0,
             tx,
             status,
+            params,
         }
     }
 
@@ -247,145 +170,123 @@ impl TxState {
 
     fn advance(&mut self) {
         self.epoch += 1;
-        self.responses.clear();
     }
-}
 
-#[derive(Debug, Clone)]
-struct Node {
-    mempool: HashMap<Hash, TxState>,
-    id: u64,
-    sender: Sender<(u64, Message)>,
-}
-
-impl Node {
-    fn new(id: u64, sender: Sender<(u64, Message)>) -> Self {
-        Node {
-            id,
-            sender,
-            mempool: HashMap::new(),
-        }
+    /// Starts a new query round over the sampled peers, dropping whatever the previous
+    /// round collected.
+    pub fn new_round(&mut self, sampled: &[PeerId]) -> u64 {
+        self.round += 1;
+        self.awaited = sampled.iter().copied().collect();
+        self.sampled = self.awaited.len();
+        self.ticks = 0;
+        self.responses.clear();
+        self.round
     }
 
-    fn handle_message(&mut self, origin: u64, msg: &Message) {
-        println!("node {} recv from {} => {:?}", self.id, origin, msg);
-
-        match msg {
-            Message::Query(ref msg) => self.handle_query(origin, msg),
-            Message::QueryResponse((_to, ref msg)) => {
-                if let Some((hash, status)) = self.handle_query_response(msg) {
-                    println!("node {} got decision {:?} for tx {}", self.id, status, hash);
-                };
-            }
-            Message::Transaction(tx) => self.handle_transaction(tx),
-        }
+    /// Returns whether all the responses of the current round are in.
+    pub fn round_complete(&self) -> bool {
+        self.awaited.is_empty()
     }
 
-    /// Upon receiving a query, an uncolored node adopts the color in the query,
-    /// responds with that color, and initiates its own query, whereas a colored
-    /// node simply responds with its current color.
-    fn handle_query(&mut self, origin: u64, msg: &QueryMessage) {
-        // TODO: This can be so much cleaner, just fighting to much with compiler!!
-        let state = if !self.mempool.contains_key(&msg.tx.hash()) {
-            let state = TxState::new(msg.tx.clone(), msg.status.clone());
-            // Nodes receive a query about a transaction. If the transaction is new, it's added to the mempool and queried further. Responses are sent back to the querying node.
-            self.mempool.insert(msg.tx.hash(), state.clone());
-            self.send_query(msg.tx.clone(), msg.status.clone());
-            state
-        } else {
-            let state = self.mempool.get(&msg.tx.hash()).unwrap();
-            state.clone()
-        };
-        self.send_response(origin, state.tx.hash(), state.status.clone());
+    /// Called once per query interval, returns whether a round should be started.
+    /// If k responses are not received within a time bound, the node picks an
+    /// additional sample and queries them again.
+    pub fn tick(&mut self) -> bool {
+        if self.is_final {
+            return false;
+        }
+        self.ticks += 1;
+        self.round == 0 || self.ticks >= self.params.round_timeout
     }
 
-    /// If k responses are not received within a time bound, the node picks an
-    /// additional sample from the remaining nodes uniformly at random and queries
-    /// them until it collects all responses.     
-    /// TODO: timeout + error handling + factor some pieces out of this method!
-    fn handle_query_response(&mut self, msg: &QueryResponse) -> Option<(Hash, Status)> {
-        // Nodes process responses to their queries and update their internal state.
-        {
-            let state = self.mempool.get_mut(&msg.hash).unwrap();
-            // If the state is considered final we dont handle this response anymore.
-            if state.is_final {
-                return None;
-            }
-            state.responses.push(msg.status.clone());
+    /// Records the response of `from` for the current round and returns the decided
+    /// status once the transaction becomes final. Only the peers sampled for the round
+    /// are counted, once each.
+    pub fn record_response(&mut self, from: PeerId, status: Status) -> Option<Status> {
+        // If the state is considered final we dont handle this response anymore.
+        if self.is_final || !self.awaited.remove(&from) {
+            return None;
+        }
+        self.responses.push(status);
+        if !self.round_complete() {
+            return None;
+        }
 
-            let n = state
-                .responses
-                .iter()
-                .filter(|&status| status == &msg.status)
-                .count();
+        for color in [Status::Valid, Status::Invalid] {
+            let n = self.responses.iter().filter(|&s| s == &color).count();
 
             // If responses meet the threshold criteria, the node updates its internal state and may decide on the transaction's status.
-            if n >= (THRESHOLD * SAMPLES as f32) as usize {
+            if n as f32 >= self.params.threshold * self.sampled as f32 {
                 // Increment the confidence of the received status.
-                let cnt = state.incr_status(&msg.status);
+                let cnt = self.incr_status(&color);
                 // Get the confidence of our current status.
-                let our_status_cnt = state.status_count(&state.status);
+                let our_status_cnt = self.status_count(&self.status);
 
                 // If the confidence of the received status is higher then ours we
                 // flip to that status.
                 if cnt > our_status_cnt {
-                    state.status = msg.status.clone();
-                    state.last_status = state.status.clone();
+                    self.status = color;
                 }
 
-                if msg.status != state.last_status {
-                    state.last_status = msg.status.clone();
-                    state.cnt = 0;
+                if color != self.last_status {
+                    self.last_status = color;
+                    self.cnt = 0;
                 } else {
-                    state.cnt += 1;
+                    self.cnt += 1;
                     // We only accept the color (move to the next epoch) if the
                     // counter is higher the the conviction threshold.
-                    if state.cnt > (CONVICTION_THRESHOLD * SAMPLES as f32) as u32 {
-                        state.advance();
-                        if state.epoch == MAX_EPOCHS {
-                            state.is_final = true;
-                            return Some((state.tx.hash(), state.status.clone()));
+                    if self.cnt
+                        > (self.params.conviction_threshold * self.params.samples as f32) as u32
+                    {
+                        self.advance();
+                        self.cnt = 0;
+                        if self.epoch == self.params.max_epochs {
+                            self.is_final = true;
+                            return Some(self.status);
                         }
                     }
                 }
             }
         }
 
-        let state = self.mempool.get(&msg.hash).unwrap();
-        self.send_query(state.tx.clone(), state.status.clone());
         None
     }
+}
 
-    // When a new transaction is received, it's verified and added to the mempool, and the querying process begins.
-    fn handle_transaction(&mut self, tx: &Transaction) {
-        // Verify transaction ourself.
-        let status = self.verify_transaction(tx);
-
-        // Add the tx to our mempool.
-        self.mempool
-            .insert(tx.hash(), TxState::new(tx.clone(), status.clone()));
-        self.send_query(tx.clone(), status.clone());
-    }
-
-    // sending a query to another node
-    fn send_query(&self, tx: Transaction, status: Status) {
-        let msg = Message::Query(QueryMessage {
-            tx: tx,
-            status: status,
-        });
-        self.sender.send((self.id, msg));
-    }
-
-    // sending a response to another node
-    fn send_response(&self, to: u64, hash: Hash, status: Status) {
-        let msg = Message::QueryResponse((to, QueryResponse { hash, status }));
-        self.sender.send((self.id, msg));
-    }
+/// Transactions are verified by nodes independently. If the last digit of the nonce is less than 7,
+/// it's considered valid; otherwise, it's invalid.
+pub fn verify_transaction(tx: &Transaction) -> Status {
+    Status::from_valid(tx.nonce % 10 < 7)
+}
 
-    fn verify_transaction(&self, tx: &Transaction) -> Status {
-        match tx.data < 7 {
-            true => Status::Valid,
-            false => Status::Invalid,
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::rng;
+
+    #[test]
+    fn a_round_counts_each_sampled_peer_once() {
+        let _rng = rng::exclusive();
+        let params = Params {
+            samples: 2,
+            threshold: 1.0,
+            ..Params::default()
+        };
+        let mut state = TxState::new(Transaction::new_transaction(), Status::Valid, params);
+        let sampled = [PeerId::random(), PeerId::random()];
+        state.new_round(&sampled);
+
+        assert_eq!(
+            state.record_response(PeerId::random(), Status::Invalid),
+            None
+        );
+        assert_eq!(state.record_response(sampled[0], Status::Invalid), None);
+        assert_eq!(state.record_response(sampled[0], Status::Invalid), None);
+        assert!(!state.round_complete());
+        assert_eq!(state.record_response(sampled[1], Status::Invalid), None);
+        assert!(state.round_complete());
+        assert_eq!(state.cnt_invalid, 1);
+        assert_eq!(state.record_response(sampled[1], Status::Invalid), None);
+        assert_eq!(state.cnt_invalid, 1);
     }
 }
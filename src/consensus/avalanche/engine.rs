@@ -1,2 +1,304 @@
-// It is a pluggable consensus engine that can be plugged in and out of the
-// Cunner framework.
+use crate::consensus::avalanche::avalanche::{verify_transaction, Hash, Params, Status, TxState};
+use crate::consensus::engine::{BlockVerdict, Context, Engine as EngineTrait};
+use crate::network::messages::message::{Block, Transaction};
+use crate::network::transport::Network;
+use crate::simulation::rng::with_rng;
+use libp2p::PeerId;
+use log::{debug, error, info, warn};
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How often the engine looks for transactions without a pending query round.
+const QUERY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Messages exchanged by the Avalanche engines, sent as json over the network.
+#[derive(Serialize, Deserialize)]
+enum AvalancheMessage {
+    /// asks a sampled peer for its preferred status of the transaction
+    Query {
+        transaction: Transaction,
+        valid: bool,
+        round: u64,
+    },
+    /// answers a query with the preferred status of the peer
+    QueryResponse {
+        transaction_hash: Vec<u8>,
+        valid: bool,
+        round: u64,
+    },
+}
+
+/// Engine runs Snowball over every transaction it sees: each query round samples
+/// a few peers for their preferred status, and transactions decided as valid are
+/// batched into blocks.
+#[derive(Clone)]
+pub struct Engine {
+    block_generation_interval: Duration,
+    params: Params,
+    context: Context,
+    mempool: Arc<Mutex<BTreeMap<Hash, TxState>>>,
+    // transactions decided as valid and waiting to be put in a block
+    accepted: Arc<Mutex<Vec<Transaction>>>,
+}
+
+impl EngineTrait for Engine {
+    // query the peers for the undecided transactions and batch the decided ones
+    fn run<'a>(&'a self) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> {
+        Box::pin(async move {
+            info!("Engine is querying peers");
+            let clock = &self.context.clock;
+            let mut last_block = clock.now();
+            loop {
+                clock.sleep(QUERY_INTERVAL).await;
+                self.query_peers();
+
+                if clock.now() - last_block >= self.block_generation_interval {
+                    last_block = clock.now();
+                    self.create_block();
+                }
+            }
+        })
+    }
+
+    // When a new transaction is received, it's verified and added to the mempool, the next query round picks it up.
+    fn add_transaction(&self, transaction: Transaction) {
+        let mut mempool = self.mempool.lock().unwrap();
+        mempool.entry(Hash(transaction.hash())).or_insert_with(|| {
+            let status = verify_transaction(&transaction);
+            TxState::new(transaction, status, self.params)
+        });
+    }
+
+    /// A block is rejected if it does not extend the head or this node decided any of its transactions
+    /// as invalid, the transactions of an accepted block are not batched again.
+    fn on_block(&self, block: &Block, from: PeerId) -> BlockVerdict {
+        if let Err(e) = self.context.chain.verify_next(block) {
+            warn!("Rejecting block from {}: {}", from, e);
+            return BlockVerdict::Reject;
+        }
+
+        let mempool = self.mempool.lock().unwrap();
+        let decided_invalid = block.transactions.iter().find(|transaction| {
+            mempool
+                .get(&Hash(transaction.hash()))
+                .is_some_and(|state| state.is_final && !state.status.is_valid())
+        });
+        if let Some(transaction) = decided_invalid {
+            warn!(
+                "Rejecting block from {} with invalid transaction {:?}",
+                from, transaction
+            );
+            return BlockVerdict::Reject;
+        }
+
+        self.accepted
+            .lock()
+            .unwrap()
+            .retain(|transaction| !block.transactions.contains(transaction));
+        BlockVerdict::Accept
+    }
+
+    fn handle_message(&self, from: PeerId, bytes: Vec<u8>) {
+        match serde_json::from_slice(&bytes) {
+            Ok(AvalancheMessage::Query {
+                transaction,
+                valid,
+                round,
+            }) => self.handle_query(from, transaction, valid, round),
+            Ok(AvalancheMessage::QueryResponse {
+                transaction_hash,
+                valid,
+                round,
+            }) => self.handle_query_response(from, Hash(transaction_hash), valid, round),
+            Err(e) => warn!("Failed to decode avalanche message from {from}: {e}"),
+        }
+    }
+
+    // undecided transactions and the ones decided as valid that wait for a block
+    fn mempool_size(&self) -> usize {
+        let undecided = self
+            .mempool
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|state| !state.is_final)
+            .count();
+        undecided + self.accepted.lock().unwrap().len()
+    }
+
+    fn state(&self) -> Value {
+        let mempool = self.mempool.lock().unwrap();
+        let undecided = mempool.values().filter(|state| !state.is_final).count();
+        json!({
+            "transactions": mempool.len(),
+            "undecided_transactions": undecided,
+            "accepted_transactions": self.accepted.lock().unwrap().len(),
+        })
+    }
+}
+
+impl Engine {
+    pub fn new_engine(
+        interval: Duration,
+        params: Params,
+        context: Context,
+    ) -> Box<dyn EngineTrait> {
+        Box::new(Self {
+            block_generation_interval: interval,
+            params,
+            context,
+            mempool: Arc::new(Mutex::new(BTreeMap::new())),
+            accepted: Arc::new(Mutex::new(vec![])),
+        })
+    }
+
+    /// Upon receiving a query, an uncolored node adopts the color in the query,
+    /// responds with that color, and initiates its own query, whereas a colored
+    /// node simply responds with its current color.
+    fn handle_query(&self, from: PeerId, transaction: Transaction, valid: bool, round: u64) {
+        let hash = Hash(transaction.hash());
+        let status = self
+            .mempool
+            .lock()
+            .unwrap()
+            .entry(hash.clone())
+            .or_insert_with(|| TxState::new(transaction, Status::from_valid(valid), self.params))
+            .status;
+
+        send(
+            &self.context.network,
+            from,
+            &AvalancheMessage::QueryResponse {
+                transaction_hash: hash.0,
+                valid: status.is_valid(),
+                round,
+            },
+        );
+    }
+
+    // Nodes process responses to their queries and update their internal state.
+    fn handle_query_response(&self, from: PeerId, hash: Hash, valid: bool, round: u64) {
+        let network = &self.context.network;
+        let mut mempool = self.mempool.lock().unwrap();
+        let Some(state) = mempool.get_mut(&hash) else {
+            return;
+        };
+        // responses to an older round arrived too late to be counted
+        if state.round != round {
+            return;
+        }
+
+        if let Some(status) = state.record_response(from, Status::from_valid(valid)) {
+            info!(
+                "Decided {:?} for transaction {} after response from {}",
+                status, hash, from
+            );
+            if status.is_valid() {
+                self.accepted.lock().unwrap().push(state.tx.clone());
+            }
+        } else if state.round_complete() {
+            // the round is over, keep querying without waiting for the next interval
+            query(
+                network,
+                state,
+                &network.connected_peers(),
+                self.params.samples,
+            );
+        }
+    }
+
+    // starts a query round for every undecided transaction that is new or whose round timed out
+    fn query_peers(&self) {
+        let network = &self.context.network;
+        let peers = network.connected_peers();
+        let mut mempool = self.mempool.lock().unwrap();
+        for state in mempool.values_mut() {
+            if state.tick() {
+                query(network, state, &peers, self.params.samples);
+            }
+        }
+    }
+
+    // batches the decided transactions into a block on the turn of the node
+    fn create_block(&self) {
+        if !self.context.proposes_next() {
+            return;
+        }
+        // transactions decided after a peer block holding them was accepted are already in the chain
+        let block_transactions: Vec<Transaction> = self
+            .accepted
+            .lock()
+            .unwrap()
+            .drain(..)
+            .filter(|transaction| !self.context.chain.contains(transaction))
+            .collect();
+        if block_transactions.is_empty() {
+            debug!("No transactions to process");
+            return;
+        }
+
+        // a node resuming a persisted chain builds on its head
+        let block = match self
+            .context
+            .chain
+            .next_block(block_transactions, self.context.clock.timestamp())
+        {
+            Ok(block) => block,
+            Err(e) => {
+                error!("Failed to read the chain head: {:?}", e);
+                return;
+            }
+        };
+
+        info!("Created new block: {:?}", block);
+        self.context.chain.append(&block);
+        self.context.network.publish_block(block);
+    }
+}
+
+// samples the peers for a new round on the transaction
+fn query(network: &Network, state: &mut TxState, peers: &[PeerId], samples: usize) {
+    if peers.is_empty() {
+        return;
+    }
+
+    let sampled: Vec<PeerId> =
+        with_rng(|rng| peers.choose_multiple(rng, samples).copied().collect());
+    let round = state.new_round(&sampled);
+
+    let message = AvalancheMessage::Query {
+        transaction: state.tx.clone(),
+        valid: state.status.is_valid(),
+        round,
+    };
+    for peer in sampled {
+        send(network, peer, &message);
+    }
+}
+
+fn send(network: &Network, peer: PeerId, message: &AvalancheMessage) {
+    let bytes = serde_json::to_vec(message).expect("Failed to serialize avalanche message");
+    network.send_to(peer, bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::rng;
+    use crate::testing::{assert_agree, simulate};
+
+    #[test]
+    fn the_nodes_agree_on_the_accepted_blocks() {
+        let _rng = rng::exclusive();
+        let chains = simulate(4, 7, 120, |_, context| {
+            Engine::new_engine(Duration::from_secs(10), Params::default(), context)
+        });
+        assert_agree(&chains, 3);
+    }
+}
@@ -0,0 +1,125 @@
+use crate::metrics::recorder;
+use crate::network::messages::message::{Block, Transaction};
+use crate::storage::chain_store::ChainStore;
+use crate::storage::store::{MemStore, Store};
+use libp2p::PeerId;
+use log::error;
+use sha2::{Digest, Sha256};
+use std::io;
+use std::sync::Arc;
+
+/// Chain holds the blocks a node has accepted, the ones its engine produced and
+/// the ones its engine accepted from peers, in the order they were appended.
+/// The transactions of a block are final on the node once it is appended.
+/// It is cheap to clone, clones share the same store.
+#[derive(Clone)]
+pub struct Chain {
+    node: PeerId,
+    store: Arc<ChainStore>,
+}
+
+impl Chain {
+    pub fn new(node: PeerId, store: ChainStore) -> Self {
+        Self {
+            node,
+            store: Arc::new(store),
+        }
+    }
+
+    /// Returns a chain kept in memory, for nodes that do not need to resume it.
+    pub fn in_memory(node: PeerId) -> Self {
+        Self::new(node, ChainStore::new(MemStore::new_mem_store()))
+    }
+
+    /// Appends the block if it extends the head, a block that does not is refused
+    /// so the chain never holds a fork.
+    pub fn append(&self, block: &Block) {
+        match self.store.append(block) {
+            Ok(_) => recorder::block_finalized(block, self.node),
+            Err(e) => error!("Failed to append block: {}", e),
+        }
+    }
+
+    /// Returns the store the chain is persisted on.
+    pub fn store(&self) -> Arc<dyn Store> {
+        self.store.store()
+    }
+
+    /// Returns the number of blocks, which is the height of the head.
+    pub fn len(&self) -> io::Result<u64> {
+        Ok(self.store.head()?.map_or(0, |(height, _)| height))
+    }
+
+    /// Returns the last block of the chain, None if it is empty.
+    pub fn head(&self) -> io::Result<Option<Block>> {
+        match self.store.head()? {
+            Some((height, _)) => self.store.block_by_height(height),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the block at `height`, heights start at 1.
+    pub fn block(&self, height: u64) -> io::Result<Option<Block>> {
+        self.store.block_by_height(height)
+    }
+
+    /// Returns every block of the chain, in height order.
+    pub fn blocks(&self) -> io::Result<Vec<Block>> {
+        self.store.blocks()
+    }
+
+    pub fn block_by_hash(&self, hash: &[u8]) -> io::Result<Option<Block>> {
+        self.store.block_by_hash(hash)
+    }
+
+    /// Returns the height of the block the transaction was committed in, None if
+    /// it is not committed.
+    pub fn transaction_height(&self, hash: &[u8]) -> io::Result<Option<u64>> {
+        self.store.transaction_height(hash)
+    }
+
+    /// Checks that a block received from a peer can be appended: it verifies on its
+    /// own, extends the head and holds no transaction already in the chain.
+    pub fn verify_next(&self, block: &Block) -> Result<(), String> {
+        let head = self
+            .head()
+            .map_err(|e| format!("failed to read the chain head: {e}"))?;
+        block.verify(head.as_ref()).map_err(|e| e.to_string())?;
+        for (index, transaction) in block.transactions.iter().enumerate() {
+            if self.contains(transaction) {
+                return Err(format!("transaction {index} is already in the chain"));
+            }
+        }
+        Ok(())
+    }
+
+    /// The transaction is in a block of the chain, a chain that cannot be read
+    /// holds nothing.
+    pub fn contains(&self, transaction: &Transaction) -> bool {
+        self.transaction_height(&transaction.hash())
+            .unwrap_or_else(|e| {
+                error!("Failed to read the chain: {:?}", e);
+                None
+            })
+            .is_some()
+    }
+
+    /// Returns a block of `transactions` created by this node on top of the head.
+    pub fn next_block(&self, transactions: Vec<Transaction>, timestamp: u64) -> io::Result<Block> {
+        Ok(Block::new_block(
+            self.head()?.as_ref(),
+            self.node,
+            timestamp,
+            transactions,
+        ))
+    }
+
+    /// Returns the hash over the block hashes, equal for byte-identical chains.
+    pub fn digest(&self) -> io::Result<String> {
+        let mut hasher = Sha256::new();
+        for block in self.store.blocks()? {
+            hasher.update(block.hash());
+        }
+        Ok(hex::encode(hasher.finalize()))
+    }
+}
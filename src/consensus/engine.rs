@@ -1,5 +1,12 @@
+use crate::consensus::chain::Chain;
 use crate::network::messages::message::{Block, Transaction};
+use crate::network::transport::Network;
+use crate::simulation::clock::Clock;
 use dyn_clone::DynClone;
+use libp2p::identity::Keypair;
+use libp2p::PeerId;
+use log::error;
+use serde_json::Value;
 use std::future::Future;
 use std::pin::Pin;
 // use tokio::time::Duration;
@@ -15,6 +22,68 @@ pub trait Engine: Send + Sync + DynClone {
 
     /// runs the engine on the transactions and relays the generated blocks back to the network
     fn run<'a>(&'a self) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>>;
+
+    /// handle_message will be called each time a peer broadcasts a consensus
+    /// message or sends one to this node, the bytes are the ones the peer's
+    /// engine handed to its Network.
+    /// engines that do not talk to each other can ignore it
+    fn handle_message(&self, _from: PeerId, _bytes: Vec<u8>) {}
+
+    /// on_block will be called each time a peer relays a block, the block is
+    /// appended to the chain of the node only if the engine accepts it.
+    /// blocks produced by the engine itself are appended by the engine
+    fn on_block(&self, block: &Block, from: PeerId) -> BlockVerdict;
+
+    /// mempool_size returns the number of transactions the engine holds that are
+    /// not committed yet
+    fn mempool_size(&self) -> usize {
+        0
+    }
+
+    /// state returns where the engine stands, such as its view or its round, as
+    /// json for the RPC API of the node.
+    /// engines with nothing to tell can leave it out
+    fn state(&self) -> Value {
+        Value::Null
+    }
 }
 
 dyn_clone::clone_trait_object!(Engine);
+
+/// BlockVerdict is the answer of an engine to a block received from a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockVerdict {
+    Accept,
+    Reject,
+    /// the engine keeps the block and appends it to the chain itself once it is final
+    Hold,
+}
+
+/// Context is what a node hands over to its engine: the network to publish on,
+/// the clock to measure time with, the chain of blocks the node accepted and the
+/// identity of the node, which engines sign their blocks with.
+#[derive(Clone)]
+pub struct Context {
+    pub network: Network,
+    pub clock: Clock,
+    pub chain: Chain,
+    pub keypair: Keypair,
+}
+
+impl Context {
+    /// Returns whether the node proposes the next block of the chain, for engines
+    /// without a leader of their own: the node and its connected peers, sorted by
+    /// peer id, take the heights in turn so that two of them never extend the same
+    /// head.
+    pub fn proposes_next(&self) -> bool {
+        let me = self.network.local_peer_id();
+        let mut nodes = self.network.connected_peers();
+        nodes.push(me);
+        nodes.sort();
+        let height = self.chain.len().unwrap_or_else(|e| {
+            error!("Failed to read the chain head: {:?}", e);
+            0
+        });
+        nodes[(height % nodes.len() as u64) as usize] == me
+    }
+}
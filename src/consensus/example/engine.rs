@@ -1,7 +1,9 @@
-use crate::consensus::engine::Engine as EngineTrait;
+use crate::consensus::engine::{BlockVerdict, Context, Engine as EngineTrait};
 use crate::network::messages::message::{Block, Transaction};
-use crate::network::peer::publish_block;
+use libp2p::PeerId;
+use log::{error, warn};
 // use secp256k1::SecretKey;
+use serde_json::{json, Value};
 use std::future::Future;
 use std::pin::Pin;
 use std::sync::{Arc, Mutex};
@@ -9,9 +11,9 @@ use tokio::time::Duration;
 
 pub struct Engine {
     block_generation_interval: Duration,
+    context: Context,
     // private_key: Option<Arc<SecretKey>>,
     transactions: Arc<Mutex<Vec<Transaction>>>,
-    last_block_index: u32, // Added this field to keep track of the last block index
 }
 
 impl EngineTrait for Engine {
@@ -20,8 +22,15 @@ impl EngineTrait for Engine {
         Box::pin(async move {
             println!("Engine is processing transactions");
             loop {
-                tokio::time::sleep(self.block_generation_interval).await;
+                self.context
+                    .clock
+                    .sleep(self.block_generation_interval)
+                    .await;
 
+                // the other nodes propose the blocks of their turns
+                if !self.context.proposes_next() {
+                    continue;
+                }
                 let new_block = {
                     let mut transactions = self.transactions.lock().unwrap();
                     if transactions.is_empty() {
@@ -34,28 +43,67 @@ impl EngineTrait for Engine {
                     }
 
                     let block_transactions = transactions.drain(..).collect();
-                    Block::new_block(self.last_block_index + 1, block_transactions)
+                    match self
+                        .context
+                        .chain
+                        .next_block(block_transactions, self.context.clock.timestamp())
+                    {
+                        Ok(block) => block,
+                        Err(e) => {
+                            error!("Failed to read the chain head: {:?}", e);
+                            continue;
+                        }
+                    }
                 };
 
                 println!("Created new block: {:?}", new_block);
-                publish_block(new_block);
+                self.context.chain.append(&new_block);
+                self.context.network.publish_block(new_block);
             }
         })
     }
 
+    // a transaction already in the chain is not put in a block again
     fn add_transaction(&self, transaction: Transaction) {
+        if self.context.chain.contains(&transaction) {
+            return;
+        }
         let mut transactions = self.transactions.lock().unwrap();
         transactions.push(transaction);
     }
+
+    // any block that extends the head is accepted, its transactions do not need to be put in a block again
+    fn on_block(&self, block: &Block, from: PeerId) -> BlockVerdict {
+        if let Err(e) = self.context.chain.verify_next(block) {
+            warn!("Rejecting block from {}: {}", from, e);
+            return BlockVerdict::Reject;
+        }
+
+        self.transactions
+            .lock()
+            .unwrap()
+            .retain(|transaction| !block.transactions.contains(transaction));
+        BlockVerdict::Accept
+    }
+
+    fn mempool_size(&self) -> usize {
+        self.transactions.lock().unwrap().len()
+    }
+
+    fn state(&self) -> Value {
+        json!({
+            "pending_transactions": self.transactions.lock().unwrap().len(),
+        })
+    }
 }
 
 impl Engine {
-    pub fn new_engine(interval: Duration) -> Box<dyn EngineTrait> {
+    pub fn new_engine(interval: Duration, context: Context) -> Box<dyn EngineTrait> {
         Box::new(Self {
             block_generation_interval: interval,
+            context,
             // private_key: private_key,
             transactions: Arc::new(Mutex::new(vec![])),
-            last_block_index: 0,
         })
     }
 }
@@ -64,9 +112,9 @@ impl Clone for Engine {
     fn clone(&self) -> Self {
         Self {
             block_generation_interval: self.block_generation_interval,
+            context: self.context.clone(),
             // private_key: self.private_key.clone(),
             transactions: self.transactions.clone(),
-            last_block_index: self.last_block_index,
         }
     }
 }
@@ -0,0 +1,701 @@
+use crate::consensus::engine::{BlockVerdict, Context, Engine as EngineTrait};
+use crate::consensus::hotstuff::hotstuff::{
+    vote_digest, HotStuffMessage, LeaderRotation, Node, Params, QuorumCertificate,
+};
+use crate::metrics::recorder;
+use crate::network::messages::message::{Block, Transaction};
+use crate::network::messages::signing::{sign_as_node, verify_node_signature};
+use libp2p::PeerId;
+use log::{debug, error, info, warn};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How often the engine checks its pacemaker.
+const TICK: Duration = Duration::from_millis(250);
+
+/// Engine runs a chained HotStuff replica. The replicas are the nodes of the network,
+/// or the first `nodes` nodes seen when the node does not know them, sorted by peer id
+/// so that a crashed replica keeps its views and the others time out of them. The committed blocks are appended to the chain of every replica,
+/// blocks never travel with `publish_block`.
+#[derive(Clone)]
+pub struct Engine {
+    block_generation_interval: Duration,
+    params: Params,
+    nodes: usize,
+    rotation: Arc<dyn LeaderRotation>,
+    context: Context,
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    // every peer seen connected, and the replicas once settled
+    members: BTreeSet<PeerId>,
+    replicas: Vec<PeerId>,
+    view: u64,
+    view_start: Duration,
+    // the replica moves to the next view when nothing happened by then
+    view_deadline: Duration,
+    view_timeout: Duration,
+    // the nodes proposed from the last committed one on, by block hash
+    nodes: BTreeMap<Vec<u8>, Node>,
+    // hash of the block the chain started from, certified by the genesis certificate
+    genesis: Vec<u8>,
+    high_qc: QuorumCertificate,
+    voted_view: u64,
+    locked_view: u64,
+    locked: Vec<u8>,
+    // last committed block
+    committed: Option<Block>,
+    proposed_view: Option<u64>,
+    // votes collected as the leader of the next view, by view and block hash
+    votes: BTreeMap<(u64, Vec<u8>), BTreeMap<PeerId, Vec<u8>>>,
+    new_views: BTreeMap<u64, BTreeMap<PeerId, QuorumCertificate>>,
+    // transactions not committed yet, by hash so blocks do not depend on arrival order
+    pending: BTreeMap<Vec<u8>, Transaction>,
+}
+
+impl EngineTrait for Engine {
+    fn run<'a>(&'a self) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> {
+        Box::pin(async move {
+            println!("Engine is running HotStuff");
+            loop {
+                self.context.clock.sleep(TICK).await;
+                self.tick();
+            }
+        })
+    }
+
+    // the chain tells the committed transactions apart, they are not kept twice
+    fn add_transaction(&self, transaction: Transaction) {
+        if self.context.chain.contains(&transaction) {
+            return;
+        }
+        let hash = transaction.hash();
+        let mut state = self.state.lock().unwrap();
+        state.pending.entry(hash).or_insert(transaction);
+    }
+
+    /// HotStuff blocks travel inside proposals, a block relayed on its own is not
+    /// certified by anything.
+    fn on_block(&self, _block: &Block, from: PeerId) -> BlockVerdict {
+        debug!("Ignoring block relayed by {from} outside of a proposal");
+        BlockVerdict::Reject
+    }
+
+    fn handle_message(&self, from: PeerId, bytes: Vec<u8>) {
+        let message = match serde_json::from_slice(&bytes) {
+            Ok(message) => message,
+            Err(e) => {
+                warn!("Failed to decode HotStuff message from {from}: {e}");
+                return;
+            }
+        };
+
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let replicas = state.replicas.clone();
+        if replicas.is_empty() {
+            return;
+        }
+        match message {
+            HotStuffMessage::Proposal { node } => {
+                self.handle_proposal(state, &replicas, from, node)
+            }
+            HotStuffMessage::Vote {
+                view,
+                hash,
+                signature,
+            } => self.handle_vote(state, &replicas, from, view, hash, signature),
+            HotStuffMessage::NewView { view, high_qc } => {
+                self.handle_new_view(state, &replicas, from, view, high_qc)
+            }
+            HotStuffMessage::GetNode { hash } => {
+                if let Some(node) = state.nodes.get(&hash) {
+                    self.send(from, &HotStuffMessage::Node { node: node.clone() });
+                }
+            }
+            HotStuffMessage::Node { node } => {
+                if let Err(e) = self.add_node(state, &replicas, from, node) {
+                    warn!("Dropping fetched node from {from}: {e}");
+                }
+            }
+        }
+    }
+
+    fn mempool_size(&self) -> usize {
+        self.state.lock().unwrap().pending.len()
+    }
+
+    fn state(&self) -> Value {
+        let state = self.state.lock().unwrap();
+        let leader = (!state.replicas.is_empty()).then(|| {
+            self.rotation
+                .leader(state.view, &state.replicas)
+                .to_string()
+        });
+        json!({
+            "view": state.view,
+            "leader": leader,
+            "voted_view": state.voted_view,
+            "locked_view": state.locked_view,
+            "high_qc_view": state.high_qc.view,
+            "committed_height": state
+                .committed
+                .as_ref()
+                .and_then(|block| block.header.as_ref())
+                .map_or(0, |header| header.index),
+            "pending_transactions": state.pending.len(),
+        })
+    }
+}
+
+impl Engine {
+    pub fn new_engine(
+        interval: Duration,
+        params: Params,
+        nodes: usize,
+        context: Context,
+    ) -> Box<dyn EngineTrait> {
+        // a node resuming a persisted chain starts from its head, certified by the genesis certificate
+        let head = context.chain.head().unwrap_or_else(|e| {
+            error!("Failed to read the chain head: {:?}", e);
+            None
+        });
+        let genesis = head.as_ref().map(Block::hash).unwrap_or_default();
+        let view_timeout = Duration::from_secs(params.view_timeout_secs);
+        let now = context.clock.now();
+
+        Box::new(Self {
+            block_generation_interval: interval,
+            params,
+            nodes,
+            rotation: Arc::from(params.leader_rotation.leader_rotation()),
+            state: Arc::new(Mutex::new(State {
+                members: BTreeSet::new(),
+                replicas: Vec::new(),
+                view: 1,
+                view_start: now,
+                view_deadline: now + interval + view_timeout,
+                view_timeout,
+                nodes: head
+                    .iter()
+                    .map(|block| {
+                        let root = Node {
+                            view: 0,
+                            block: block.clone(),
+                            justify: QuorumCertificate::genesis(genesis.clone()),
+                        };
+                        (genesis.clone(), root)
+                    })
+                    .collect(),
+                high_qc: QuorumCertificate::genesis(genesis.clone()),
+                genesis,
+                voted_view: 0,
+                locked_view: 0,
+                locked: Vec::new(),
+                committed: head,
+                proposed_view: None,
+                votes: BTreeMap::new(),
+                new_views: BTreeMap::new(),
+                pending: BTreeMap::new(),
+            })),
+            context,
+        })
+    }
+
+    // proposes as the leader of the view, and moves to the next view when the current one stalls
+    fn tick(&self) {
+        let now = self.context.clock.now();
+        let me = self.context.network.local_peer_id();
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        state.members.extend(self.context.network.connected_peers());
+
+        if state.replicas.is_empty() {
+            // the replicas are the nodes of the network, or the peers seen when the
+            // node does not know them
+            let mut replicas = self.context.network.nodes();
+            if replicas.is_empty() {
+                if state.members.len() + 1 < self.nodes {
+                    return;
+                }
+                replicas = state.members.iter().copied().collect();
+                replicas.push(me);
+            }
+            replicas.sort();
+            info!("Replica set of {} nodes", replicas.len());
+            state.replicas = replicas;
+        }
+        let replicas = state.replicas.clone();
+
+        // an idle network is not a stalled one
+        if !self.has_work(state) {
+            state.view_deadline = now + self.block_generation_interval + state.view_timeout;
+            return;
+        }
+
+        if now >= state.view_deadline {
+            let view = state.view + 1;
+            info!("View {} timed out, moving to view {}", state.view, view);
+            state.view_timeout *= 2;
+            self.enter_view(state, view);
+            let leader = self.rotation.leader(view, &replicas);
+            let high_qc = state.high_qc.clone();
+            if leader == me {
+                self.handle_new_view(state, &replicas, me, view, high_qc);
+            } else {
+                self.send(leader, &HotStuffMessage::NewView { view, high_qc });
+            }
+            return;
+        }
+
+        let justified = state.high_qc.view + 1 == state.view
+            || state
+                .new_views
+                .get(&state.view)
+                .is_some_and(|new_views| new_views.len() >= self.params.quorum(replicas.len()));
+        // a block of transactions waits for the block interval, while the empty blocks carrying
+        // the ones above the last commit to their commit follow right away
+        let waited = now - state.view_start >= self.block_generation_interval;
+        if self.rotation.leader(state.view, &replicas) == me
+            && state.proposed_view != Some(state.view)
+            && justified
+            && (waited || self.pipelined(state))
+        {
+            self.propose(state, &replicas, waited);
+        }
+    }
+
+    // there are transactions to order, or blocks of transactions to carry to their commit
+    fn has_work(&self, state: &State) -> bool {
+        !state.pending.is_empty() || self.pipelined(state)
+    }
+
+    // the replicas commit a block once they see a proposal carrying the certificate of the
+    // second block after it, the leader forming that certificate commits it first
+    fn pipelined(&self, state: &State) -> bool {
+        let committed = state.committed.as_ref().map_or(0, height);
+        self.ancestors(state, &state.high_qc.hash)
+            .enumerate()
+            .take_while(|(index, node)| *index < 3 || node.height() > committed)
+            .any(|(_, node)| node.view > 0 && !node.block.transactions.is_empty())
+    }
+
+    // the blocks from the one with the hash down to the last committed one, the highest first
+    fn uncommitted<'a>(&self, state: &'a State, hash: &[u8]) -> impl Iterator<Item = &'a Node> {
+        let committed = state.committed.as_ref().map_or(0, height);
+        self.ancestors(state, hash)
+            .take_while(move |node| node.height() > committed)
+    }
+
+    // the block with the hash and the known blocks before it, the highest first
+    fn ancestors<'a>(&self, state: &'a State, hash: &[u8]) -> impl Iterator<Item = &'a Node> {
+        let mut next = state.nodes.get(hash);
+        std::iter::from_fn(move || {
+            let node = next?;
+            next = state.nodes.get(parent_hash(&node.block));
+            Some(node)
+        })
+    }
+
+    fn propose(&self, state: &mut State, replicas: &[PeerId], with_transactions: bool) {
+        // the block of the highest certificate is known unless the chain is empty
+        let parent = match state.nodes.get(&state.high_qc.hash) {
+            Some(node) => Some(node.block.clone()),
+            None if state.high_qc.hash.is_empty() => None,
+            None => return,
+        };
+        let included: BTreeSet<Vec<u8>> = self
+            .uncommitted(state, &state.high_qc.hash)
+            .flat_map(|node| node.block.transactions.iter().map(Transaction::hash))
+            .collect();
+        let transactions: Vec<Transaction> = state
+            .pending
+            .iter()
+            .filter(|(hash, _)| with_transactions && !included.contains(*hash))
+            .map(|(_, transaction)| transaction.clone())
+            .collect();
+
+        let mut block = Block::new_block(
+            parent.as_ref(),
+            self.context.network.local_peer_id(),
+            self.context.clock.timestamp(),
+            transactions,
+        );
+        block.sign(&self.context.keypair);
+        let node = Node {
+            view: state.view,
+            block,
+            justify: state.high_qc.clone(),
+        };
+        info!(
+            "Proposing block {} at height {} in view {}",
+            hex::encode(node.hash()),
+            node.height(),
+            node.view
+        );
+        state.proposed_view = Some(state.view);
+        recorder::block_included(&node.block);
+        self.broadcast(&HotStuffMessage::Proposal { node: node.clone() });
+        let me = self.context.network.local_peer_id();
+        self.handle_proposal(state, replicas, me, node);
+    }
+
+    // checks the node, follows the chain it certifies and votes for it if it is safe
+    fn handle_proposal(&self, state: &mut State, replicas: &[PeerId], from: PeerId, node: Node) {
+        let view = node.view;
+        let justify_view = node.justify.view;
+        let hash = node.hash();
+        if let Err(e) = self.add_node(state, replicas, from, node) {
+            warn!("Rejecting proposal from {from}: {e}");
+            return;
+        }
+        if view < state.view {
+            return;
+        }
+        // a replica only skips to a later view on the certificate of the view before it,
+        // a leader cannot move the replicas past the views of the others on its own
+        if view > state.view && justify_view + 1 != view {
+            debug!(
+                "Not following proposal of view {view} from {from} while in view {}",
+                state.view
+            );
+            return;
+        }
+        // the view ends with its proposal, whether the replica votes for it or not
+        self.enter_view(state, view + 1);
+
+        let node = &state.nodes[&hash];
+        // a replica votes once per view, for a node extending its lock unless a newer certificate justifies it
+        let safe = self.extends(state, &hash, &state.locked, state.locked_view)
+            || node.justify.view > state.locked_view;
+        if view <= state.voted_view || !safe {
+            debug!("Not voting for block {} of view {view}", hex::encode(&hash));
+            return;
+        }
+        state.voted_view = view;
+
+        let signature = sign_as_node(&self.context.keypair, &vote_digest(view, &hash));
+        let leader = self.rotation.leader(view + 1, replicas);
+        let me = self.context.network.local_peer_id();
+        if leader == me {
+            self.handle_vote(state, replicas, me, view, hash, signature);
+        } else {
+            self.send(
+                leader,
+                &HotStuffMessage::Vote {
+                    view,
+                    hash,
+                    signature,
+                },
+            );
+        }
+    }
+
+    // checks the node and adds it to the tree, then follows the chain its certificate extends
+    fn add_node(
+        &self,
+        state: &mut State,
+        replicas: &[PeerId],
+        from: PeerId,
+        node: Node,
+    ) -> Result<(), String> {
+        let hash = node.hash();
+        if state.nodes.contains_key(&hash) {
+            return Ok(());
+        }
+        let leader = self.rotation.leader(node.view, replicas);
+        let proposer = node
+            .block
+            .header
+            .as_ref()
+            .and_then(|header| PeerId::from_bytes(&header.proposer).ok());
+        if proposer != Some(leader) || !node.block.verify_signature() {
+            return Err(format!(
+                "block is not signed by the leader of view {}",
+                node.view
+            ));
+        }
+        if node.view <= node.justify.view {
+            return Err("node does not extend an earlier view".into());
+        }
+        if !node
+            .justify
+            .verify(replicas, self.params.quorum(replicas.len()), &state.genesis)
+        {
+            return Err("certificate is not signed by a quorum".into());
+        }
+        if parent_hash(&node.block) != node.justify.hash.as_slice() {
+            return Err("block does not extend the certified block".into());
+        }
+        let parent = state
+            .nodes
+            .get(&node.justify.hash)
+            .map(|parent| &parent.block);
+        if parent.is_none() && !node.justify.hash.is_empty() {
+            debug!(
+                "Fetching the missing node {} from {from}",
+                hex::encode(&node.justify.hash)
+            );
+            self.send(
+                from,
+                &HotStuffMessage::GetNode {
+                    hash: node.justify.hash.clone(),
+                },
+            );
+            return Err("certified block is unknown".into());
+        }
+        node.block.verify(parent).map_err(|e| e.to_string())?;
+
+        let justify = node.justify.clone();
+        state.nodes.insert(hash, node);
+        self.update(state, justify);
+        Ok(())
+    }
+
+    // the certificate a node carries moves the highest certificate, the lock and the commit
+    fn update(&self, state: &mut State, qc: QuorumCertificate) {
+        if qc.view > state.high_qc.view {
+            state.high_qc = qc.clone();
+        }
+        let Some(b2) = state.nodes.get(&qc.hash) else {
+            return;
+        };
+        let Some(b1) = state.nodes.get(&b2.justify.hash) else {
+            return;
+        };
+        if b1.view > state.locked_view {
+            state.locked_view = b1.view;
+            state.locked = b1.hash();
+        }
+        let Some(b0) = state.nodes.get(&b1.justify.hash) else {
+            return;
+        };
+        // three blocks certified in consecutive views commit the first one
+        if b0.view > 0 && b1.view == b0.view + 1 && b2.view == b1.view + 1 {
+            let hash = b0.hash();
+            self.commit(state, &hash);
+        }
+    }
+
+    fn commit(&self, state: &mut State, hash: &[u8]) {
+        let mut blocks: Vec<Block> = self
+            .uncommitted(state, hash)
+            .map(|node| node.block.clone())
+            .collect();
+        blocks.reverse();
+        for block in blocks {
+            if let Err(e) = block.verify_parent(state.committed.as_ref()) {
+                error!("Committed block does not extend the chain: {e}");
+                return;
+            }
+            info!(
+                "Committed block {} at height {}",
+                hex::encode(block.hash()),
+                height(&block)
+            );
+            for transaction in &block.transactions {
+                state.pending.remove(&transaction.hash());
+            }
+            self.context.chain.append(&block);
+            state.committed = Some(block);
+            state.view_timeout = Duration::from_secs(self.params.view_timeout_secs);
+        }
+        // the nodes below the committed one are final, no proposal can extend them again
+        let committed = state.committed.as_ref().map_or(0, height);
+        state.nodes.retain(|_, node| node.height() >= committed);
+    }
+
+    // the leader of the next view turns a quorum of votes into a certificate and moves to its view
+    fn handle_vote(
+        &self,
+        state: &mut State,
+        replicas: &[PeerId],
+        from: PeerId,
+        view: u64,
+        hash: Vec<u8>,
+        signature: Vec<u8>,
+    ) {
+        let me = self.context.network.local_peer_id();
+        if self.rotation.leader(view + 1, replicas) != me || view < state.high_qc.view {
+            return;
+        }
+        if !replicas.contains(&from) {
+            debug!("Ignoring vote from {from}, which is not a replica");
+            return;
+        }
+        if !verify_node_signature(&from, &vote_digest(view, &hash), &signature) {
+            warn!("Dropping vote from {from} with an invalid signature");
+            return;
+        }
+        let votes = state.votes.entry((view, hash.clone())).or_default();
+        votes.insert(from, signature);
+        if votes.len() < self.params.quorum(replicas.len()) || state.high_qc.view >= view {
+            return;
+        }
+
+        let qc = QuorumCertificate {
+            view,
+            hash,
+            votes: votes
+                .iter()
+                .map(|(voter, signature)| (voter.to_bytes(), signature.clone()))
+                .collect(),
+        };
+        debug!("Certified block {} of view {view}", hex::encode(&qc.hash));
+        self.update(state, qc);
+        state.votes.retain(|(voted, _), _| *voted > view);
+        if view + 1 > state.view {
+            self.enter_view(state, view + 1);
+        }
+    }
+
+    // the leader of a view that timed out takes over the highest certificate of a quorum
+    fn handle_new_view(
+        &self,
+        state: &mut State,
+        replicas: &[PeerId],
+        from: PeerId,
+        view: u64,
+        high_qc: QuorumCertificate,
+    ) {
+        let me = self.context.network.local_peer_id();
+        if self.rotation.leader(view, replicas) != me || view < state.view {
+            return;
+        }
+        if !replicas.contains(&from) {
+            debug!("Ignoring new view from {from}, which is not a replica");
+            return;
+        }
+        if !high_qc.verify(replicas, self.params.quorum(replicas.len()), &state.genesis) {
+            warn!("Dropping new view from {from} with an invalid certificate");
+            return;
+        }
+        let new_views = state.new_views.entry(view).or_default();
+        new_views.insert(from, high_qc.clone());
+        let quorum = new_views.len() >= self.params.quorum(replicas.len());
+        self.update(state, high_qc);
+        if quorum && view > state.view {
+            info!("Leading view {view} after a view change");
+            self.enter_view(state, view);
+        }
+    }
+
+    fn enter_view(&self, state: &mut State, view: u64) {
+        let now = self.context.clock.now();
+        state.view = view;
+        state.view_start = now;
+        state.view_deadline = now + self.block_generation_interval + state.view_timeout;
+        state.new_views = state.new_views.split_off(&view);
+    }
+
+    // the block with the hash descends from the locked one
+    fn extends(&self, state: &State, hash: &[u8], locked: &[u8], locked_view: u64) -> bool {
+        if locked_view == 0 {
+            return true;
+        }
+        let mut next = state.nodes.get(hash);
+        while let Some(node) = next {
+            if node.hash() == locked {
+                return true;
+            }
+            if node.view <= locked_view {
+                return false;
+            }
+            next = state.nodes.get(parent_hash(&node.block));
+        }
+        false
+    }
+
+    fn broadcast(&self, message: &HotStuffMessage) {
+        let bytes = serde_json::to_vec(message).expect("Failed to serialize HotStuff message");
+        self.context.network.broadcast(bytes);
+    }
+
+    fn send(&self, peer: PeerId, message: &HotStuffMessage) {
+        let bytes = serde_json::to_vec(message).expect("Failed to serialize HotStuff message");
+        self.context.network.send_to(peer, bytes);
+    }
+}
+
+fn height(block: &Block) -> u64 {
+    block
+        .header
+        .as_ref()
+        .map_or(0, |header| header.index as u64)
+}
+
+fn parent_hash(block: &Block) -> &[u8] {
+    block
+        .header
+        .as_ref()
+        .map_or(&[], |header| header.parent_hash.as_slice())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::byzantine::adversary::{self, new_adversary, Strategy};
+    use crate::consensus::chain::Chain;
+    use crate::consensus::hotstuff::hotstuff::{Hashed, Rotation};
+    use crate::simulation::rng;
+    use crate::testing::{assert_agree, simulate};
+    use std::cell::Cell;
+
+    fn new_hotstuff(context: Context) -> Box<dyn EngineTrait> {
+        Engine::new_engine(Duration::from_secs(5), Params::default(), 4, context)
+    }
+
+    // a commit needs four honest leaders in a row, which the round robin never gives
+    // with a silent replica among four, and short timeouts so its views pass quickly
+    fn new_hashed_hotstuff(context: Context) -> Box<dyn EngineTrait> {
+        let params = Params {
+            leader_rotation: Rotation::Hashed,
+            view_timeout_secs: 2,
+            ..Params::default()
+        };
+        Engine::new_engine(Duration::from_secs(5), params, 4, context)
+    }
+
+    #[test]
+    fn the_replicas_agree_on_the_committed_blocks() {
+        let _rng = rng::exclusive();
+        let chains = simulate(4, 7, 40, |_, context| new_hotstuff(context));
+        assert_agree(&chains, 10);
+    }
+
+    // the leader of the first view never speaks, the correct replicas time out of its
+    // views and carry on without it
+    #[test]
+    fn a_silent_leader_is_skipped_by_the_pacemaker() {
+        let _rng = rng::exclusive();
+        let silent = Cell::new(None);
+        let chains = simulate(4, 7, 240, |node, context| {
+            let mut replicas = context.network.nodes();
+            replicas.sort();
+            if Hashed.leader(1, &replicas) == context.network.local_peer_id() {
+                silent.set(Some(node));
+                new_adversary(
+                    Strategy::Silent,
+                    &adversary::Params::default(),
+                    context,
+                    new_hashed_hotstuff,
+                )
+            } else {
+                new_hashed_hotstuff(context)
+            }
+        });
+        let silent = silent.get().unwrap();
+        let correct: Vec<Chain> = chains
+            .into_iter()
+            .enumerate()
+            .filter(|(node, _)| *node != silent)
+            .map(|(_, chain)| chain)
+            .collect();
+        assert_agree(&correct, 5);
+    }
+}
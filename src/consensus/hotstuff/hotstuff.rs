@@ -0,0 +1,215 @@
+/*
+HotStuff is a leader based BFT protocol whose views each cost a linear number of
+messages: the leader broadcasts a proposal, and the replicas send their votes back
+to the leader of the next view only, which aggregates a quorum of them into a quorum
+certificate and carries it in its own proposal. Chained HotStuff pipelines the
+phases: every proposal extends the block certified by the highest certificate, so
+the certificate of one view is the second phase of the view before it and the third
+of the one before that. A replica locks on the block two certified steps behind a
+proposal, and a block is committed once it heads a chain of three blocks certified
+in consecutive views. A pacemaker moves replicas that see no progress to the next
+view, handing their highest certificate to its leader. Of n replicas tolerating f
+faulty ones a quorum is n - f, 2f + 1 when n = 3f + 1, so that any two quorums share
+a correct replica.
+
+Here the nodes of the tree are blocks, each extending the block certified by the
+certificate it carries, and committing a block appends it and the blocks before it
+to the chain. Votes are signed with the identity of the node so that any replica
+can check a certificate relayed by the leader.
+*/
+
+use crate::network::messages::message::Block;
+use crate::network::messages::signing::verify_node_signature;
+use crate::CunnerError;
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+
+/// Tuning parameters for the algorithm, set in the `hotstuff` section of the engine
+/// configuration.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Params {
+    /// Number of faulty replicas tolerated, the network needs at least 3f + 1 nodes.
+    pub f: usize,
+    /// Seconds a replica waits past the block interval for the next proposal before
+    /// it moves to the next view, doubled by every view that times out.
+    pub view_timeout_secs: u64,
+    /// How the leader of every view is picked among the replicas.
+    pub leader_rotation: Rotation,
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Self {
+            f: 1,
+            view_timeout_secs: 10,
+            leader_rotation: Rotation::RoundRobin,
+        }
+    }
+}
+
+impl Params {
+    pub fn validate(&self) -> Result<(), CunnerError> {
+        let invalid = |message: &str| Err(CunnerError::Config(format!("hotstuff: {message}")));
+        if self.view_timeout_secs == 0 {
+            return invalid("view_timeout_secs must be at least 1");
+        }
+        Ok(())
+    }
+
+    /// Checks that a network of `nodes` replicas tolerates f faulty ones.
+    pub fn validate_nodes(&self, nodes: usize) -> Result<(), CunnerError> {
+        if nodes < 3 * self.f + 1 {
+            return Err(CunnerError::Config(format!(
+                "hotstuff: f = {} needs at least {} nodes, the network has {}",
+                self.f,
+                3 * self.f + 1,
+                nodes
+            )));
+        }
+        Ok(())
+    }
+
+    /// Number of matching votes of the `replicas` that make a certificate.
+    pub fn quorum(&self, replicas: usize) -> usize {
+        replicas - self.f
+    }
+}
+
+/// LeaderRotation picks the leader of every view, every replica must pick the same
+/// one from the same sorted replicas.
+pub trait LeaderRotation: Send + Sync {
+    fn leader(&self, view: u64, replicas: &[PeerId]) -> PeerId;
+}
+
+/// The replicas lead one view each, in turn.
+pub struct RoundRobin;
+
+impl LeaderRotation for RoundRobin {
+    fn leader(&self, view: u64, replicas: &[PeerId]) -> PeerId {
+        replicas[(view % replicas.len() as u64) as usize]
+    }
+}
+
+/// The leader of a view is drawn from the hash of the view number, so a faulty
+/// leader does not know in advance which views are its own.
+pub struct Hashed;
+
+impl LeaderRotation for Hashed {
+    fn leader(&self, view: u64, replicas: &[PeerId]) -> PeerId {
+        let digest = Sha256::digest(view.to_le_bytes());
+        let draw = u64::from_le_bytes(digest[..8].try_into().expect("8 bytes"));
+        replicas[(draw % replicas.len() as u64) as usize]
+    }
+}
+
+/// Rotation names the leader rotations an experiment can pick.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rotation {
+    RoundRobin,
+    Hashed,
+}
+
+impl Rotation {
+    pub fn leader_rotation(self) -> Box<dyn LeaderRotation> {
+        match self {
+            Rotation::RoundRobin => Box::new(RoundRobin),
+            Rotation::Hashed => Box::new(Hashed),
+        }
+    }
+}
+
+/// Messages exchanged by the HotStuff replicas, sent as json over the network.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum HotStuffMessage {
+    /// the leader of the view extends the block of the highest certificate
+    Proposal { node: Node },
+    /// a replica votes for the block of the view, sent to the leader of the next view only
+    Vote {
+        view: u64,
+        hash: Vec<u8>,
+        signature: Vec<u8>,
+    },
+    /// a replica that timed out hands its highest certificate to the leader of the view
+    NewView {
+        view: u64,
+        high_qc: QuorumCertificate,
+    },
+    /// asks a replica for the node of the block with the hash
+    GetNode { hash: Vec<u8> },
+    /// answers with the node
+    Node { node: Node },
+}
+
+/// Node is a block proposed in a view, with the certificate of the block it extends.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Node {
+    pub view: u64,
+    pub block: Block,
+    pub justify: QuorumCertificate,
+}
+
+impl Node {
+    pub fn hash(&self) -> Vec<u8> {
+        self.block.hash()
+    }
+
+    pub fn height(&self) -> u64 {
+        self.block
+            .header
+            .as_ref()
+            .map_or(0, |header| header.index as u64)
+    }
+}
+
+/// QuorumCertificate holds the signed votes of a quorum of replicas for the block with
+/// the hash in the view, the certificate of view 0 is the start of the chain.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuorumCertificate {
+    pub view: u64,
+    pub hash: Vec<u8>,
+    /// peer id and signature of every voter
+    pub votes: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl QuorumCertificate {
+    /// Returns the certificate of the block the chain starts from, empty if none.
+    pub fn genesis(hash: Vec<u8>) -> Self {
+        Self {
+            view: 0,
+            hash,
+            votes: Vec::new(),
+        }
+    }
+
+    /// The certificate is the genesis one, or holds valid votes of a quorum of
+    /// distinct replicas.
+    pub fn verify(&self, replicas: &[PeerId], quorum: usize, genesis: &[u8]) -> bool {
+        if self.view == 0 {
+            return self.hash == genesis;
+        }
+        let digest = vote_digest(self.view, &self.hash);
+        let voters: BTreeSet<PeerId> = self
+            .votes
+            .iter()
+            .filter_map(|(voter, signature)| {
+                let voter = PeerId::from_bytes(voter).ok()?;
+                (replicas.contains(&voter) && verify_node_signature(&voter, &digest, signature))
+                    .then_some(voter)
+            })
+            .collect();
+        voters.len() >= quorum
+    }
+}
+
+/// Returns what a replica signs to vote for the block with the hash in the view.
+pub fn vote_digest(view: u64, hash: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(b"hotstuff vote");
+    hasher.update(view.to_le_bytes());
+    hasher.update(hash);
+    hasher.finalize().to_vec()
+}
@@ -0,0 +1,781 @@
+use crate::consensus::engine::{BlockVerdict, Context, Engine as EngineTrait};
+use crate::consensus::pbft::pbft::{
+    prepare_digest, primary, NewView, Params, PbftMessage, Slot, ViewChange, Votes,
+};
+use crate::metrics::recorder;
+use crate::network::messages::message::{Block, Transaction};
+use crate::network::messages::signing::{sign_as_node, verify_node_signature};
+use libp2p::PeerId;
+use log::{debug, error, info, warn};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How often the engine checks whether it should propose a block or change view.
+const TICK: Duration = Duration::from_millis(250);
+
+/// Engine runs a PBFT replica. The replicas are the nodes of the network, or the first
+/// `nodes` nodes seen when the node does not know them, sorted by peer id so that every
+/// replica counts quorums and picks primaries among the same ones whoever it is
+/// connected to. The primary of a view is the replica at the view number modulo their
+/// count.
+#[derive(Clone)]
+pub struct Engine {
+    block_generation_interval: Duration,
+    params: Params,
+    nodes: usize,
+    context: Context,
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    // every peer seen connected, and the replicas once settled
+    members: BTreeSet<PeerId>,
+    replicas: Vec<PeerId>,
+    view: u64,
+    // a view change to `view` is in progress, pre-prepares wait for its new view
+    changing_view: bool,
+    // sequence number of the last executed block, which is the height of the chain
+    executed: u64,
+    stable_seq: u64,
+    stable_digest: Vec<u8>,
+    slots: BTreeMap<u64, Slot>,
+    checkpoints: Votes<u64, Vec<u8>>,
+    view_changes: Votes<u64, ViewChange>,
+    // transactions waiting for a block, by hash so proposals do not depend on arrival order
+    pending: BTreeMap<Vec<u8>, Transaction>,
+    executed_transactions: BTreeSet<Vec<u8>>,
+    last_proposal: Option<Duration>,
+    // since when the replica waits for progress and how long it waits before changing view
+    waiting_since: Option<Duration>,
+    view_timeout: Duration,
+}
+
+impl EngineTrait for Engine {
+    fn run<'a>(&'a self) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> {
+        Box::pin(async move {
+            println!("Engine is running PBFT");
+            loop {
+                self.context.clock.sleep(TICK).await;
+                self.tick();
+            }
+        })
+    }
+
+    // transactions are the requests of PBFT, they wait in the pool until a block holding them is executed
+    fn add_transaction(&self, transaction: Transaction) {
+        let hash = transaction.hash();
+        let mut state = self.state.lock().unwrap();
+        if !state.executed_transactions.contains(&hash) {
+            state.pending.entry(hash).or_insert(transaction);
+        }
+    }
+
+    /// Blocks are appended by the replicas once committed, blocks relayed by peers
+    /// outside of the protocol are never accepted.
+    fn on_block(&self, _block: &Block, from: PeerId) -> BlockVerdict {
+        debug!("Ignoring block relayed by {from} outside of PBFT");
+        BlockVerdict::Reject
+    }
+
+    fn handle_message(&self, from: PeerId, bytes: Vec<u8>) {
+        let message = match serde_json::from_slice(&bytes) {
+            Ok(message) => message,
+            Err(e) => {
+                warn!("Failed to decode PBFT message from {from}: {e}");
+                return;
+            }
+        };
+
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        if !state.replicas.contains(&from) {
+            debug!("Ignoring PBFT message from {from}, which is not a replica");
+            return;
+        }
+        match message {
+            PbftMessage::PrePrepare { view, seq, block } => {
+                self.handle_pre_prepare(state, from, view, seq, block)
+            }
+            PbftMessage::Prepare {
+                view,
+                seq,
+                digest,
+                signature,
+            } => {
+                if !verify_node_signature(&from, &prepare_digest(view, seq, &digest), &signature) {
+                    warn!("Dropping prepare from {from} with an invalid signature");
+                    return;
+                }
+                if self.in_window(state, seq) {
+                    state
+                        .slots
+                        .entry(seq)
+                        .or_default()
+                        .add_prepare(view, digest, from, signature);
+                    self.check_prepared(state, seq);
+                }
+            }
+            PbftMessage::Commit { view, seq, digest } => {
+                if self.in_window(state, seq) {
+                    state
+                        .slots
+                        .entry(seq)
+                        .or_default()
+                        .add_commit(view, digest, from);
+                    self.execute_committed(state);
+                }
+            }
+            PbftMessage::Checkpoint { seq, digest } => {
+                if seq > state.stable_seq {
+                    state.checkpoints.add(seq, from, digest);
+                    self.check_stable(state, seq);
+                }
+            }
+            PbftMessage::ViewChange { view_change } => {
+                self.handle_view_change(state, from, view_change)
+            }
+            PbftMessage::NewView { view, view_changes } => {
+                self.handle_new_view(state, from, view, view_changes)
+            }
+            PbftMessage::FetchBlocks { from_seq, to_seq } => {
+                self.handle_fetch_blocks(from, from_seq, to_seq)
+            }
+            PbftMessage::Blocks { blocks } => self.handle_blocks(state, from, blocks),
+        }
+    }
+
+    fn mempool_size(&self) -> usize {
+        self.state.lock().unwrap().pending.len()
+    }
+
+    fn state(&self) -> Value {
+        let state = self.state.lock().unwrap();
+        let primary =
+            (!state.replicas.is_empty()).then(|| primary(state.view, &state.replicas).to_string());
+        json!({
+            "view": state.view,
+            "primary": primary,
+            "changing_view": state.changing_view,
+            "executed": state.executed,
+            "stable_seq": state.stable_seq,
+            "pending_transactions": state.pending.len(),
+        })
+    }
+}
+
+impl Engine {
+    pub fn new_engine(
+        interval: Duration,
+        params: Params,
+        nodes: usize,
+        context: Context,
+    ) -> Box<dyn EngineTrait> {
+        // a node resuming a persisted chain continues after its head
+        let executed = context.chain.len().unwrap_or_else(|e| {
+            error!("Failed to read the chain head: {:?}", e);
+            0
+        });
+
+        Box::new(Self {
+            block_generation_interval: interval,
+            params,
+            nodes,
+            context,
+            state: Arc::new(Mutex::new(State {
+                members: BTreeSet::new(),
+                replicas: Vec::new(),
+                view: 0,
+                changing_view: false,
+                executed,
+                stable_seq: executed,
+                stable_digest: Vec::new(),
+                slots: BTreeMap::new(),
+                checkpoints: Votes::default(),
+                view_changes: Votes::default(),
+                pending: BTreeMap::new(),
+                executed_transactions: BTreeSet::new(),
+                last_proposal: None,
+                waiting_since: None,
+                view_timeout: Duration::from_secs(params.view_timeout_secs),
+            })),
+        })
+    }
+
+    // proposes a block when this replica is the primary, and votes for the next view when no progress is made
+    fn tick(&self) {
+        let now = self.context.clock.now();
+        let me = self.context.network.local_peer_id();
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        state.members.extend(self.context.network.connected_peers());
+
+        if state.replicas.is_empty() {
+            // the replicas are the nodes of the network, or the peers seen when the
+            // node does not know them
+            let mut replicas = self.context.network.nodes();
+            if replicas.is_empty() {
+                if state.members.len() + 1 < self.nodes {
+                    return;
+                }
+                replicas = state.members.iter().copied().collect();
+                replicas.push(me);
+            }
+            replicas.sort();
+            info!("Replica set of {} nodes", replicas.len());
+            state.replicas = replicas;
+        }
+
+        let in_flight = state
+            .slots
+            .range(state.executed + 1..)
+            .any(|(_, slot)| slot.pre_prepare.is_some());
+        let interval_elapsed = state
+            .last_proposal
+            .is_none_or(|last| now - last >= self.block_generation_interval);
+        if !state.changing_view
+            && primary(state.view, &state.replicas) == me
+            && !in_flight
+            && state.executed >= state.stable_seq
+            && !state.pending.is_empty()
+            && interval_elapsed
+        {
+            self.propose(state, now);
+            return;
+        }
+
+        // a replica waits for progress while it knows of work that is not executed
+        if !(state.changing_view || in_flight || !state.pending.is_empty()) {
+            state.waiting_since = None;
+            return;
+        }
+        let since = *state.waiting_since.get_or_insert(now);
+        let patience = if state.changing_view {
+            state.view_timeout
+        } else {
+            self.block_generation_interval + state.view_timeout
+        };
+        if now - since >= patience {
+            let view = state.view + 1;
+            warn!(
+                "No progress in view {} for {:?}, voting for view {}",
+                state.view,
+                now - since,
+                view
+            );
+            state.view_timeout *= 2;
+            self.start_view_change(state, view);
+        }
+    }
+
+    fn propose(&self, state: &mut State, now: Duration) {
+        let transactions: Vec<Transaction> = state.pending.values().cloned().collect();
+        let block = match self
+            .context
+            .chain
+            .next_block(transactions, self.context.clock.timestamp())
+        {
+            Ok(block) => block,
+            Err(e) => {
+                error!("Failed to read the chain head: {:?}", e);
+                return;
+            }
+        };
+        let view = state.view;
+        let seq = state.executed + 1;
+        state.last_proposal = Some(now);
+
+        info!(
+            "Proposing block {} at sequence {} in view {}",
+            hex::encode(block.hash()),
+            seq,
+            view
+        );
+        // the block travels in the pre-prepare rather than through publish_block, so its inclusion is recorded here
+        recorder::block_included(&block);
+        let digest = block.hash();
+        state.slots.entry(seq).or_default().pre_prepare = Some((view, block.clone()));
+        self.broadcast(&PbftMessage::PrePrepare { view, seq, block });
+        self.prepare(state, view, seq, digest);
+    }
+
+    // signs and sends the prepare of this replica, the primary prepares its own pre-prepares
+    fn prepare(&self, state: &mut State, view: u64, seq: u64, digest: Vec<u8>) {
+        let me = self.context.network.local_peer_id();
+        let signature = sign_as_node(&self.context.keypair, &prepare_digest(view, seq, &digest));
+        state.slots.entry(seq).or_default().add_prepare(
+            view,
+            digest.clone(),
+            me,
+            signature.clone(),
+        );
+        self.broadcast(&PbftMessage::Prepare {
+            view,
+            seq,
+            digest,
+            signature,
+        });
+        self.check_prepared(state, seq);
+    }
+
+    fn handle_pre_prepare(
+        &self,
+        state: &mut State,
+        from: PeerId,
+        view: u64,
+        seq: u64,
+        block: Block,
+    ) {
+        if view != state.view || state.changing_view {
+            debug!("Ignoring pre-prepare for view {view} from {from}");
+            return;
+        }
+        if from != primary(view, &state.replicas) {
+            warn!("Ignoring pre-prepare from {from}, which is not the primary of view {view}");
+            return;
+        }
+        self.accept_pre_prepare(state, from, view, seq, block);
+    }
+
+    // accepts a pre-prepare of the primary, the ones it sent in a new view included
+    fn accept_pre_prepare(
+        &self,
+        state: &mut State,
+        primary: PeerId,
+        view: u64,
+        seq: u64,
+        block: Block,
+    ) {
+        if !self.in_window(state, seq) {
+            return;
+        }
+        if let Err(e) = block.verify_body() {
+            warn!("Rejecting pre-prepare at sequence {seq} in view {view}: {e}");
+            return;
+        }
+        let digest = block.hash();
+        // a block re-proposed in a new view may already be executed here
+        if seq <= state.executed {
+            match self.context.chain.block(seq) {
+                Ok(Some(executed)) if executed.hash() == digest => {}
+                _ => {
+                    warn!("Rejecting pre-prepare at sequence {seq} in view {view}, it conflicts with the executed block");
+                    return;
+                }
+            }
+        }
+
+        let slot = state.slots.entry(seq).or_default();
+        if let Some((accepted_view, accepted)) = &slot.pre_prepare {
+            if *accepted_view == view {
+                if accepted.hash() != digest {
+                    warn!("Primary {primary} proposed two blocks at sequence {seq} in view {view}");
+                }
+                return;
+            }
+        }
+        slot.pre_prepare = Some((view, block));
+        self.prepare(state, view, seq, digest);
+    }
+
+    // commits a block once prepared in the current view
+    fn check_prepared(&self, state: &mut State, seq: u64) {
+        let me = self.context.network.local_peer_id();
+        let quorum = self.quorum(state);
+        let Some(slot) = state.slots.get_mut(&seq) else {
+            return;
+        };
+        if let Some((view, digest)) = slot.digest() {
+            if slot.is_prepared(quorum)
+                && view == state.view
+                && !state.changing_view
+                && slot.committed_in != Some(view)
+            {
+                slot.committed_in = Some(view);
+                slot.add_commit(view, digest.clone(), me);
+                self.broadcast(&PbftMessage::Commit { view, seq, digest });
+            }
+        }
+        self.execute_committed(state);
+    }
+
+    // executes the committed blocks in sequence
+    fn execute_committed(&self, state: &mut State) {
+        let quorum = self.quorum(state);
+        while let Some(slot) = state.slots.get(&(state.executed + 1)) {
+            if !slot.is_committed(quorum) {
+                break;
+            }
+            let (_, block) = slot
+                .pre_prepare
+                .clone()
+                .expect("a committed slot has a pre-prepare");
+            if !self.execute(state, block) {
+                break;
+            }
+        }
+    }
+
+    fn execute(&self, state: &mut State, block: Block) -> bool {
+        let seq = state.executed + 1;
+        match self.context.chain.head() {
+            Ok(head) => {
+                if let Err(e) = block.verify_parent(head.as_ref()) {
+                    error!("Cannot execute block at sequence {seq}: {e}");
+                    return false;
+                }
+            }
+            Err(e) => {
+                error!("Failed to read the chain head: {:?}", e);
+                return false;
+            }
+        }
+
+        let digest = block.hash();
+        info!(
+            "Executing block {} at sequence {}",
+            hex::encode(&digest),
+            seq
+        );
+        self.context.chain.append(&block);
+        state.executed = seq;
+        for transaction in &block.transactions {
+            let hash = transaction.hash();
+            state.pending.remove(&hash);
+            state.executed_transactions.insert(hash);
+        }
+        state.waiting_since = None;
+        state.view_timeout = Duration::from_secs(self.params.view_timeout_secs);
+
+        if seq.is_multiple_of(self.params.checkpoint_interval) && seq > state.stable_seq {
+            let me = self.context.network.local_peer_id();
+            state.checkpoints.add(seq, me, digest.clone());
+            self.broadcast(&PbftMessage::Checkpoint { seq, digest });
+            self.check_stable(state, seq);
+        }
+        true
+    }
+
+    // a checkpoint is stable once a quorum executed the same block at its sequence number
+    fn check_stable(&self, state: &mut State, seq: u64) {
+        let quorum = self.quorum(state);
+        let Some(votes) = state.checkpoints.get(seq) else {
+            return;
+        };
+        let mut counts: BTreeMap<&Vec<u8>, Vec<PeerId>> = BTreeMap::new();
+        for (peer, digest) in votes {
+            counts.entry(digest).or_default().push(*peer);
+        }
+        let Some((digest, voters)) = counts
+            .into_iter()
+            .find(|(_, voters)| voters.len() >= quorum)
+        else {
+            return;
+        };
+        let digest = digest.clone();
+
+        debug!("Checkpoint at sequence {seq} is stable");
+        self.adopt_stable(state, seq, digest, &voters);
+    }
+
+    // discards the log up to the stable checkpoint, and catches up to it from one of its voters if behind
+    fn adopt_stable(&self, state: &mut State, seq: u64, digest: Vec<u8>, voters: &[PeerId]) {
+        if seq <= state.stable_seq {
+            return;
+        }
+        state.stable_seq = seq;
+        state.stable_digest = digest;
+        state.slots = state.slots.split_off(&(seq + 1));
+        state.checkpoints.discard_up_to(seq);
+
+        let me = self.context.network.local_peer_id();
+        if state.executed < seq {
+            if let Some(peer) = voters.iter().find(|peer| **peer != me) {
+                info!(
+                    "Fetching blocks {} to {} from {}",
+                    state.executed + 1,
+                    seq,
+                    peer
+                );
+                self.send(
+                    *peer,
+                    &PbftMessage::FetchBlocks {
+                        from_seq: state.executed + 1,
+                        to_seq: seq,
+                    },
+                );
+            }
+        }
+    }
+
+    fn handle_fetch_blocks(&self, from: PeerId, from_seq: u64, to_seq: u64) {
+        let mut blocks = Vec::new();
+        for seq in from_seq..=to_seq {
+            match self.context.chain.block(seq) {
+                Ok(Some(block)) => blocks.push(block),
+                Ok(None) => break,
+                Err(e) => {
+                    error!("Failed to read block {seq}: {:?}", e);
+                    break;
+                }
+            }
+        }
+        self.send(from, &PbftMessage::Blocks { blocks });
+    }
+
+    // blocks fetched from a peer are executed only if they lead to the stable checkpoint
+    fn handle_blocks(&self, state: &mut State, from: PeerId, blocks: Vec<Block>) {
+        let missing: Vec<Block> = blocks
+            .into_iter()
+            .filter(|block| {
+                block
+                    .header
+                    .as_ref()
+                    .is_some_and(|header| u64::from(header.index) > state.executed)
+            })
+            .take((state.stable_seq.saturating_sub(state.executed)) as usize)
+            .collect();
+        let leads_to_checkpoint = missing.len() as u64 == state.stable_seq - state.executed
+            && missing.last().map(Block::hash) == Some(state.stable_digest.clone());
+        if missing.is_empty() || !leads_to_checkpoint {
+            warn!("Ignoring blocks from {from} that do not lead to the stable checkpoint");
+            return;
+        }
+        if let Some(block) = missing.iter().find(|block| block.verify_body().is_err()) {
+            warn!(
+                "Ignoring blocks from {from} with invalid block {}",
+                hex::encode(block.hash())
+            );
+            return;
+        }
+
+        for block in missing {
+            if !self.execute(state, block) {
+                return;
+            }
+        }
+        self.execute_committed(state);
+    }
+
+    fn start_view_change(&self, state: &mut State, view: u64) {
+        state.view = view;
+        state.changing_view = true;
+        state.waiting_since = Some(self.context.clock.now());
+
+        let quorum = self.quorum(state);
+        let me = self.context.network.local_peer_id();
+        let mut view_change = ViewChange {
+            view,
+            replica: me.to_bytes(),
+            stable_seq: state.stable_seq,
+            stable_digest: state.stable_digest.clone(),
+            prepared: state
+                .slots
+                .iter()
+                .filter_map(|(seq, slot)| slot.prepared(*seq, quorum))
+                .collect(),
+            signature: Vec::new(),
+        };
+        view_change.signature = sign_as_node(&self.context.keypair, &view_change.digest());
+        state.view_changes.add(view, me, view_change.clone());
+        self.broadcast(&PbftMessage::ViewChange { view_change });
+        self.check_new_view(state);
+    }
+
+    fn handle_view_change(&self, state: &mut State, from: PeerId, view_change: ViewChange) {
+        let view = view_change.view;
+        if view < state.view || (view == state.view && !state.changing_view) {
+            return;
+        }
+        // a view change relayed in a new view is checked again there, so only its sender may send it
+        if view_change.verify(&state.replicas, self.quorum(state)) != Some(from) {
+            warn!("Dropping view change to view {view} from {from} that does not prove what it carries");
+            return;
+        }
+        state.view_changes.add(view, from, view_change);
+
+        // a replica joins a view change f + 1 replicas voted for, at least one of them is correct
+        let joined = state
+            .view_changes
+            .above(state.view)
+            .find(|(_, votes)| votes.len() > self.params.f)
+            .map(|(view, _)| *view);
+        if let Some(view) = joined {
+            info!("Joining the view change to view {view}");
+            self.start_view_change(state, view);
+        } else {
+            self.check_new_view(state);
+        }
+    }
+
+    // the primary of the view starts it once a quorum voted for it
+    fn check_new_view(&self, state: &mut State) {
+        let view = state.view;
+        let me = self.context.network.local_peer_id();
+        if !state.changing_view || primary(view, &state.replicas) != me {
+            return;
+        }
+        let quorum = self.quorum(state);
+        let Some(votes) = state.view_changes.get(view) else {
+            return;
+        };
+        if votes.len() < quorum {
+            return;
+        }
+        let view_changes: Vec<ViewChange> = votes.values().cloned().collect();
+
+        info!("Starting view {view} as its primary");
+        self.broadcast(&PbftMessage::NewView {
+            view,
+            view_changes: view_changes.clone(),
+        });
+        self.enter_view(state, me, view, &view_changes);
+    }
+
+    fn handle_new_view(
+        &self,
+        state: &mut State,
+        from: PeerId,
+        view: u64,
+        view_changes: Vec<ViewChange>,
+    ) {
+        if view < state.view || (view == state.view && !state.changing_view) {
+            return;
+        }
+        if from != primary(view, &state.replicas) {
+            warn!("Ignoring new view {view} from {from}, which is not its primary");
+            return;
+        }
+        // the new view must hold signed view changes of a quorum for it
+        let quorum = self.quorum(state);
+        let replicas: BTreeSet<PeerId> = view_changes
+            .iter()
+            .filter(|view_change| view_change.view == view)
+            .filter_map(|view_change| view_change.verify(&state.replicas, quorum))
+            .collect();
+        if replicas.len() < quorum || replicas.len() != view_changes.len() {
+            warn!("Ignoring new view {view} from {from} without the view changes of a quorum");
+            return;
+        }
+        info!("Entering view {view}");
+        self.enter_view(state, from, view, &view_changes);
+    }
+
+    // every replica derives the blocks of the view from its view changes, so a primary cannot choose them
+    fn enter_view(
+        &self,
+        state: &mut State,
+        primary: PeerId,
+        view: u64,
+        view_changes: &[ViewChange],
+    ) {
+        let view_changes: Vec<(PeerId, &ViewChange)> = view_changes
+            .iter()
+            .filter_map(|view_change| {
+                Some((PeerId::from_bytes(&view_change.replica).ok()?, view_change))
+            })
+            .collect();
+        let new_view = NewView::choose(&view_changes, self.params.f);
+        info!(
+            "View {} re-proposes {} blocks",
+            view,
+            new_view.pre_prepares.len()
+        );
+        if let Some((seq, digest, voters)) = new_view.stable {
+            self.adopt_stable(state, seq, digest, &voters);
+        }
+
+        state.view = view;
+        state.changing_view = false;
+        state.waiting_since = None;
+        state.view_changes.discard_up_to(view);
+        // pre-prepares of the previous views that were not re-proposed are dropped
+        for (_, slot) in state.slots.range_mut(state.executed + 1..) {
+            if slot
+                .pre_prepare
+                .as_ref()
+                .is_some_and(|(accepted_view, _)| *accepted_view < view)
+            {
+                slot.pre_prepare = None;
+            }
+        }
+        for prepared in new_view.pre_prepares {
+            self.accept_pre_prepare(state, primary, view, prepared.seq, prepared.block);
+        }
+    }
+
+    // the low and high water marks of the log
+    fn in_window(&self, state: &State, seq: u64) -> bool {
+        seq > state.stable_seq && seq <= state.stable_seq + 2 * self.params.checkpoint_interval
+    }
+
+    // the number of matching votes of the replicas that make a certificate
+    fn quorum(&self, state: &State) -> usize {
+        self.params.quorum(state.replicas.len())
+    }
+
+    fn broadcast(&self, message: &PbftMessage) {
+        let bytes = serde_json::to_vec(message).expect("Failed to serialize PBFT message");
+        self.context.network.broadcast(bytes);
+    }
+
+    fn send(&self, peer: PeerId, message: &PbftMessage) {
+        let bytes = serde_json::to_vec(message).expect("Failed to serialize PBFT message");
+        self.context.network.send_to(peer, bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::byzantine::adversary::{self, new_adversary, Strategy};
+    use crate::consensus::chain::Chain;
+    use crate::simulation::rng;
+    use crate::testing::{assert_agree, simulate};
+    use std::cell::Cell;
+
+    fn new_pbft(context: Context) -> Box<dyn EngineTrait> {
+        Engine::new_engine(Duration::from_secs(5), Params::default(), 4, context)
+    }
+
+    #[test]
+    fn the_replicas_agree_on_the_executed_blocks() {
+        let _rng = rng::exclusive();
+        let chains = simulate(4, 7, 120, |_, context| new_pbft(context));
+        assert_agree(&chains, 10);
+    }
+
+    // the primary of the first view never speaks, the correct replicas change view
+    // and carry on without it
+    #[test]
+    fn a_silent_primary_is_replaced_by_a_view_change() {
+        let _rng = rng::exclusive();
+        let silent = Cell::new(None);
+        let chains = simulate(4, 7, 120, |node, context| {
+            let mut replicas = context.network.nodes();
+            replicas.sort();
+            if primary(0, &replicas) == context.network.local_peer_id() {
+                silent.set(Some(node));
+                new_adversary(
+                    Strategy::Silent,
+                    &adversary::Params::default(),
+                    context,
+                    new_pbft,
+                )
+            } else {
+                new_pbft(context)
+            }
+        });
+        let silent = silent.get().unwrap();
+        let correct: Vec<Chain> = chains
+            .into_iter()
+            .enumerate()
+            .filter(|(node, _)| *node != silent)
+            .map(|(_, chain)| chain)
+            .collect();
+        assert_agree(&correct, 5);
+    }
+}
@@ -0,0 +1,504 @@
+/*
+Practical Byzantine Fault Tolerance orders requests with three phases led by the
+primary of the current view. The primary assigns the next sequence number to a
+request in a pre-prepare, the replicas agree on it with prepares, and once a
+replica saw a quorum accept it, the pre-prepare and its prepares, it sends a commit.
+A request is executed once a quorum committed it. Of n replicas tolerating f faulty
+ones a quorum is n - f, 2f + 1 when n = 3f + 1, so that any two quorums share a
+correct replica. Replicas that see no progress vote to move to the next view
+and its primary, and every few sequence numbers a checkpoint lets them discard the
+log below it.
+
+Here the requests are blocks of transactions and executing one appends it to the
+chain, so sequence numbers are block heights. The primary keeps a single block in
+flight, every block extends the one executed before it.
+*/
+
+use crate::network::messages::message::Block;
+use crate::network::messages::signing::verify_node_signature;
+use crate::CunnerError;
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Tuning parameters for the algorithm, set in the `pbft` section of the engine
+/// configuration.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Params {
+    /// Number of faulty replicas tolerated, the network needs at least 3f + 1 nodes.
+    pub f: usize,
+    /// Seconds a replica waits past the block interval for a pending request to be
+    /// executed before it votes for a view change, doubled by every failed view change.
+    pub view_timeout_secs: u64,
+    /// Number of sequence numbers between two checkpoints.
+    pub checkpoint_interval: u64,
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Self {
+            f: 1,
+            view_timeout_secs: 10,
+            checkpoint_interval: 10,
+        }
+    }
+}
+
+impl Params {
+    pub fn validate(&self) -> Result<(), CunnerError> {
+        let invalid = |message: &str| Err(CunnerError::Config(format!("pbft: {message}")));
+        if self.view_timeout_secs == 0 {
+            return invalid("view_timeout_secs must be at least 1");
+        }
+        if self.checkpoint_interval == 0 {
+            return invalid("checkpoint_interval must be at least 1");
+        }
+        Ok(())
+    }
+
+    /// Checks that a network of `nodes` replicas tolerates f faulty ones.
+    pub fn validate_nodes(&self, nodes: usize) -> Result<(), CunnerError> {
+        if nodes < 3 * self.f + 1 {
+            return Err(CunnerError::Config(format!(
+                "pbft: f = {} needs at least {} nodes, the network has {}",
+                self.f,
+                3 * self.f + 1,
+                nodes
+            )));
+        }
+        Ok(())
+    }
+
+    /// Number of matching votes of the `replicas` that make a certificate.
+    pub fn quorum(&self, replicas: usize) -> usize {
+        replicas - self.f
+    }
+}
+
+/// Messages exchanged by the PBFT replicas, sent as json over the network. Prepares
+/// and view changes are signed by their replica so that they can be relayed: a view
+/// change proves the blocks it carries were prepared, and a new view proves the view
+/// changes it starts from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum PbftMessage {
+    /// the primary assigns the sequence number to the block
+    PrePrepare { view: u64, seq: u64, block: Block },
+    /// a replica accepted the pre-prepare with this digest
+    Prepare {
+        view: u64,
+        seq: u64,
+        digest: Vec<u8>,
+        signature: Vec<u8>,
+    },
+    /// a replica saw the block prepared by a quorum
+    Commit {
+        view: u64,
+        seq: u64,
+        digest: Vec<u8>,
+    },
+    /// a replica executed the block at this sequence number
+    Checkpoint { seq: u64, digest: Vec<u8> },
+    /// a replica votes to move to the view of the view change
+    ViewChange { view_change: ViewChange },
+    /// the primary of the view starts it with the view changes of a quorum, every
+    /// replica derives the blocks to re-propose from them
+    NewView {
+        view: u64,
+        view_changes: Vec<ViewChange>,
+    },
+    /// asks a replica for its blocks between the sequence numbers
+    FetchBlocks { from_seq: u64, to_seq: u64 },
+    /// answers a fetch with consecutive blocks
+    Blocks { blocks: Vec<Block> },
+}
+
+/// ViewChange is the state a replica carries into the next view: its stable
+/// checkpoint and the blocks it saw prepared above it, signed by the replica.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ViewChange {
+    pub view: u64,
+    pub replica: Vec<u8>,
+    pub stable_seq: u64,
+    pub stable_digest: Vec<u8>,
+    pub prepared: Vec<Prepared>,
+    pub signature: Vec<u8>,
+}
+
+impl ViewChange {
+    /// Returns what the replica signs, the prepares of the blocks carry their own
+    /// signatures.
+    pub fn digest(&self) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        hasher.update(b"pbft view change");
+        hasher.update(self.view.to_le_bytes());
+        hasher.update(&self.replica);
+        hasher.update(self.stable_seq.to_le_bytes());
+        hasher.update(&self.stable_digest);
+        for prepared in &self.prepared {
+            hasher.update(prepared.view.to_le_bytes());
+            hasher.update(prepared.seq.to_le_bytes());
+            hasher.update(prepared.block.hash());
+        }
+        hasher.finalize().to_vec()
+    }
+
+    /// Returns the replica that signed the view change, if it is one of the
+    /// `replicas` and every block it carries was prepared by a quorum in an earlier view.
+    pub fn verify(&self, replicas: &[PeerId], quorum: usize) -> Option<PeerId> {
+        let replica = PeerId::from_bytes(&self.replica).ok()?;
+        let proven = replicas.contains(&replica)
+            && verify_node_signature(&replica, &self.digest(), &self.signature)
+            && self
+                .prepared
+                .iter()
+                .all(|prepared| prepared.view < self.view && prepared.is_proven(replicas, quorum));
+        proven.then_some(replica)
+    }
+}
+
+/// Prepared is a block that was prepared at a sequence number in a view, with the
+/// prepares of the quorum that prepared it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Prepared {
+    pub view: u64,
+    pub seq: u64,
+    pub block: Block,
+    pub prepares: Vec<PrepareSignature>,
+}
+
+impl Prepared {
+    /// A quorum of the replicas signed a prepare for the block.
+    pub fn is_proven(&self, replicas: &[PeerId], quorum: usize) -> bool {
+        let digest = prepare_digest(self.view, self.seq, &self.block.hash());
+        let signers: BTreeSet<PeerId> = self
+            .prepares
+            .iter()
+            .filter_map(|prepare| {
+                let replica = PeerId::from_bytes(&prepare.replica).ok()?;
+                (replicas.contains(&replica)
+                    && verify_node_signature(&replica, &digest, &prepare.signature))
+                .then_some(replica)
+            })
+            .collect();
+        signers.len() >= quorum
+    }
+}
+
+/// PrepareSignature is the signature of a replica over a prepare.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PrepareSignature {
+    pub replica: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+/// Returns what a replica signs to prepare the block with the digest at the
+/// sequence number in the view.
+pub fn prepare_digest(view: u64, seq: u64, digest: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(b"pbft prepare");
+    hasher.update(view.to_le_bytes());
+    hasher.update(seq.to_le_bytes());
+    hasher.update(digest);
+    hasher.finalize().to_vec()
+}
+
+/// NewView is what a view starts from, derived from the view changes of a quorum.
+#[derive(Debug)]
+pub struct NewView {
+    /// the highest stable checkpoint f + 1 view changes vouch for and their replicas
+    pub stable: Option<(u64, Vec<u8>, Vec<PeerId>)>,
+    /// the blocks re-proposed in the view
+    pub pre_prepares: Vec<Prepared>,
+}
+
+impl NewView {
+    /// Chooses the blocks of the new view from verified view changes: every block
+    /// prepared above the checkpoint, in the highest view it was prepared in, up to
+    /// the first sequence number none of them prepared a block at. No replica of the
+    /// quorum prepared a block there, so neither it nor the next ones executed anywhere.
+    pub fn choose(view_changes: &[(PeerId, &ViewChange)], f: usize) -> Self {
+        let mut stables: BTreeMap<(u64, Vec<u8>), Vec<PeerId>> = BTreeMap::new();
+        for (replica, view_change) in view_changes {
+            stables
+                .entry((view_change.stable_seq, view_change.stable_digest.clone()))
+                .or_default()
+                .push(*replica);
+        }
+        let stable = stables
+            .into_iter()
+            .rev()
+            .find(|(_, voters)| voters.len() > f)
+            .map(|((seq, digest), voters)| (seq, digest, voters));
+        // without a checkpoint f + 1 vouch for, the blocks up to the lowest one are
+        // executed by a quorum as a correct replica has a checkpoint at or above it
+        let low = match &stable {
+            Some((seq, _, _)) => *seq,
+            None => view_changes
+                .iter()
+                .map(|(_, view_change)| view_change.stable_seq)
+                .min()
+                .unwrap_or_default(),
+        };
+
+        let mut chosen: BTreeMap<u64, &Prepared> = BTreeMap::new();
+        for (_, view_change) in view_changes {
+            for prepared in &view_change.prepared {
+                if chosen
+                    .get(&prepared.seq)
+                    .is_none_or(|other| prepared.view > other.view)
+                {
+                    chosen.insert(prepared.seq, prepared);
+                }
+            }
+        }
+        let pre_prepares = chosen
+            .into_values()
+            .filter(|prepared| prepared.seq > low)
+            .enumerate()
+            .take_while(|(i, prepared)| prepared.seq == low + 1 + *i as u64)
+            .map(|(_, prepared)| prepared.clone())
+            .collect();
+        Self {
+            stable,
+            pre_prepares,
+        }
+    }
+}
+
+/// Returns the primary of the view among the sorted replicas.
+pub fn primary(view: u64, replicas: &[PeerId]) -> PeerId {
+    replicas[(view % replicas.len() as u64) as usize]
+}
+
+/// Slot is what a replica saw for a sequence number.
+#[derive(Debug, Default)]
+pub struct Slot {
+    /// view the block was pre-prepared in
+    pub pre_prepare: Option<(u64, Block)>,
+    prepares: BTreeMap<(u64, Vec<u8>), BTreeMap<PeerId, Vec<u8>>>,
+    commits: BTreeMap<(u64, Vec<u8>), BTreeSet<PeerId>>,
+    /// view the replica sent its commit in
+    pub committed_in: Option<u64>,
+}
+
+impl Slot {
+    /// Records the prepare of the replica with its signature, checked by the caller.
+    pub fn add_prepare(&mut self, view: u64, digest: Vec<u8>, from: PeerId, signature: Vec<u8>) {
+        self.prepares
+            .entry((view, digest))
+            .or_default()
+            .entry(from)
+            .or_insert(signature);
+    }
+
+    pub fn add_commit(&mut self, view: u64, digest: Vec<u8>, from: PeerId) {
+        self.commits.entry((view, digest)).or_default().insert(from);
+    }
+
+    /// Returns the view and digest of the pre-prepare.
+    pub fn digest(&self) -> Option<(u64, Vec<u8>)> {
+        self.pre_prepare
+            .as_ref()
+            .map(|(view, block)| (*view, block.hash()))
+    }
+
+    /// The block was pre-prepared and a quorum prepared it in the same view, the
+    /// primary included as it prepares its own pre-prepare.
+    pub fn is_prepared(&self, quorum: usize) -> bool {
+        self.digest().is_some_and(|key| {
+            self.prepares
+                .get(&key)
+                .is_some_and(|prepares| prepares.len() >= quorum)
+        })
+    }
+
+    /// The block is prepared and a quorum committed it in the same view.
+    pub fn is_committed(&self, quorum: usize) -> bool {
+        self.is_prepared(quorum)
+            && self.digest().is_some_and(|key| {
+                self.commits
+                    .get(&key)
+                    .is_some_and(|commits| commits.len() >= quorum)
+            })
+    }
+
+    /// Returns the block with the prepares that prove it, if it is prepared.
+    pub fn prepared(&self, seq: u64, quorum: usize) -> Option<Prepared> {
+        if !self.is_prepared(quorum) {
+            return None;
+        }
+        let (view, block) = self.pre_prepare.as_ref()?;
+        let prepares = self.prepares.get(&(*view, block.hash()))?;
+        Some(Prepared {
+            view: *view,
+            seq,
+            block: block.clone(),
+            prepares: prepares
+                .iter()
+                .map(|(replica, signature)| PrepareSignature {
+                    replica: replica.to_bytes(),
+                    signature: signature.clone(),
+                })
+                .collect(),
+        })
+    }
+}
+
+/// Votes collects the replicas voting for each value of a key, a checkpoint digest
+/// for a sequence number or a view change for a view.
+#[derive(Debug)]
+pub struct Votes<K, V> {
+    votes: BTreeMap<K, BTreeMap<PeerId, V>>,
+}
+
+impl<K: Ord, V> Default for Votes<K, V> {
+    fn default() -> Self {
+        Self {
+            votes: BTreeMap::new(),
+        }
+    }
+}
+
+impl<K: Ord + Copy, V> Votes<K, V> {
+    /// Records the vote of the replica, a replica only votes once for a key.
+    pub fn add(&mut self, key: K, from: PeerId, vote: V) {
+        self.votes
+            .entry(key)
+            .or_default()
+            .entry(from)
+            .or_insert(vote);
+    }
+
+    pub fn get(&self, key: K) -> Option<&BTreeMap<PeerId, V>> {
+        self.votes.get(&key)
+    }
+
+    /// Returns the keys above `key` and their votes.
+    pub fn above(&self, key: K) -> impl Iterator<Item = (&K, &BTreeMap<PeerId, V>)> {
+        self.votes
+            .range(key..)
+            .filter(move |(other, _)| **other != key)
+    }
+
+    /// Drops the votes for the keys up to `key`.
+    pub fn discard_up_to(&mut self, key: K) {
+        self.votes = self.votes.split_off(&key);
+        self.votes.remove(&key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::messages::signing::sign_as_node;
+    use crate::simulation::rng;
+    use libp2p::identity::Keypair;
+
+    struct Replicas {
+        keys: Vec<Keypair>,
+        ids: Vec<PeerId>,
+    }
+
+    impl Replicas {
+        fn new(count: usize) -> Self {
+            let keys: Vec<Keypair> = (0..count).map(|_| Keypair::generate_ed25519()).collect();
+            let ids = keys.iter().map(|key| key.public().to_peer_id()).collect();
+            Self { keys, ids }
+        }
+
+        fn prepared(&self, signers: &[usize], view: u64, seq: u64, block: &Block) -> Prepared {
+            let digest = prepare_digest(view, seq, &block.hash());
+            Prepared {
+                view,
+                seq,
+                block: block.clone(),
+                prepares: signers
+                    .iter()
+                    .map(|signer| PrepareSignature {
+                        replica: self.ids[*signer].to_bytes(),
+                        signature: sign_as_node(&self.keys[*signer], &digest),
+                    })
+                    .collect(),
+            }
+        }
+
+        fn view_change(&self, replica: usize, view: u64, prepared: Vec<Prepared>) -> ViewChange {
+            let mut view_change = ViewChange {
+                view,
+                replica: self.ids[replica].to_bytes(),
+                stable_seq: 0,
+                stable_digest: Vec::new(),
+                prepared,
+                signature: Vec::new(),
+            };
+            view_change.signature = sign_as_node(&self.keys[replica], &view_change.digest());
+            view_change
+        }
+    }
+
+    fn block(timestamp: u64) -> Block {
+        Block::new_block(None, PeerId::random(), timestamp, Vec::new())
+    }
+
+    #[test]
+    fn a_view_change_proves_its_prepared_blocks() {
+        let _rng = rng::exclusive();
+        let replicas = Replicas::new(4);
+        let block = block(1_000);
+
+        let proven = replicas.prepared(&[0, 1, 2], 0, 1, &block);
+        let view_change = replicas.view_change(3, 1, vec![proven.clone()]);
+        assert_eq!(view_change.verify(&replicas.ids, 3), Some(replicas.ids[3]));
+
+        // a single byzantine replica cannot claim a block prepared on its own word
+        let forged = replicas.prepared(&[3, 3, 3], 0, 1, &block);
+        let view_change = replicas.view_change(3, 1, vec![forged]);
+        assert_eq!(view_change.verify(&replicas.ids, 3), None);
+
+        // nor claim a block prepared in the view it votes for
+        let future = replicas.prepared(&[0, 1, 2], 5, 1, &block);
+        let view_change = replicas.view_change(3, 5, vec![future]);
+        assert_eq!(view_change.verify(&replicas.ids, 3), None);
+
+        let mut tampered = replicas.view_change(3, 1, vec![proven]);
+        tampered.stable_seq = 10;
+        assert_eq!(tampered.verify(&replicas.ids, 3), None);
+    }
+
+    #[test]
+    fn a_new_view_re_proposes_the_block_prepared_in_the_highest_view() {
+        let _rng = rng::exclusive();
+        let replicas = Replicas::new(4);
+        let (old, new, next) = (block(1_000), block(2_000), block(3_000));
+        let view_changes = [
+            replicas.view_change(0, 3, vec![replicas.prepared(&[0, 1, 2], 0, 1, &old)]),
+            replicas.view_change(
+                1,
+                3,
+                vec![
+                    replicas.prepared(&[1, 2, 3], 2, 1, &new),
+                    replicas.prepared(&[1, 2, 3], 2, 2, &next),
+                ],
+            ),
+            // nothing was prepared at 3, so no block at 4 executed anywhere
+            replicas.view_change(2, 3, vec![replicas.prepared(&[0, 1, 2], 2, 4, &old)]),
+        ];
+        let view_changes: Vec<(PeerId, &ViewChange)> = view_changes
+            .iter()
+            .enumerate()
+            .map(|(replica, view_change)| (replicas.ids[replica], view_change))
+            .collect();
+
+        let new_view = NewView::choose(&view_changes, 1);
+        let chosen: Vec<(u64, Vec<u8>)> = new_view
+            .pre_prepares
+            .iter()
+            .map(|prepared| (prepared.seq, prepared.block.hash()))
+            .collect();
+        assert_eq!(chosen, vec![(1, new.hash()), (2, next.hash())]);
+        let (seq, _, voters) = new_view.stable.unwrap();
+        assert_eq!((seq, voters.len()), (0, 3));
+    }
+}
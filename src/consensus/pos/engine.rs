@@ -0,0 +1,580 @@
+use crate::consensus::engine::{BlockVerdict, Context, Engine as EngineTrait};
+use crate::consensus::pos::pos::{Params, PosMessage, Validators};
+use crate::network::messages::message::{Block, Transaction};
+use libp2p::PeerId;
+use log::{debug, error, info, warn};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How often the engine checks whether a new slot started.
+const TICK: Duration = Duration::from_millis(100);
+
+/// Engine runs a PoS validator. Slots last a block interval, the proposer of a slot
+/// emits a signed block of the pending transactions with `publish_block`, and the
+/// blocks attested by a supermajority of the stake are appended to the chain.
+#[derive(Clone)]
+pub struct Engine {
+    slot_duration: Duration,
+    params: Params,
+    // size of the validator set
+    nodes: usize,
+    context: Context,
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    // every peer seen connected, the validators are the first `nodes` nodes seen
+    // when the node does not know the peers of the network
+    members: BTreeSet<PeerId>,
+    validators: Option<Validators>,
+    // blocks above the final ones, from every fork, by hash
+    blocks: BTreeMap<Vec<u8>, Block>,
+    // last block the node proposed or attested, or the last final block if it is higher
+    head: Option<Block>,
+    attested_height: u64,
+    final_head: Option<Block>,
+    // heights of the final blocks, by hash
+    final_heights: BTreeMap<Vec<u8>, u64>,
+    // slot and attesters of each block
+    attestations: BTreeMap<Vec<u8>, (u64, BTreeSet<PeerId>)>,
+    // block each validator attested at each height above the final ones, a validator
+    // attests a single block per height
+    votes: BTreeMap<(PeerId, u64), Vec<u8>>,
+    last_slot: Option<u64>,
+    // transactions not final yet, by hash so blocks do not depend on arrival order
+    pending: BTreeMap<Vec<u8>, Transaction>,
+    finalized: BTreeSet<Vec<u8>>,
+}
+
+impl EngineTrait for Engine {
+    fn run<'a>(&'a self) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> {
+        Box::pin(async move {
+            println!("Engine is running Proof of Stake");
+            loop {
+                self.context.clock.sleep(TICK).await;
+                self.tick();
+            }
+        })
+    }
+
+    fn add_transaction(&self, transaction: Transaction) {
+        let hash = transaction.hash();
+        let mut state = self.state.lock().unwrap();
+        if !state.finalized.contains(&hash) {
+            state.pending.entry(hash).or_insert(transaction);
+        }
+    }
+
+    /// Blocks signed by the proposer of their slot are held until a supermajority of
+    /// the stake attests them, then the engine appends them to the chain.
+    fn on_block(&self, block: &Block, from: PeerId) -> BlockVerdict {
+        let mut state = self.state.lock().unwrap();
+        match self.add_block(&mut state, block.clone(), from) {
+            Ok(()) => BlockVerdict::Hold,
+            Err(e) => {
+                warn!("Rejecting block from {from}: {e}");
+                BlockVerdict::Reject
+            }
+        }
+    }
+
+    fn handle_message(&self, from: PeerId, bytes: Vec<u8>) {
+        let message = match serde_json::from_slice(&bytes) {
+            Ok(message) => message,
+            Err(e) => {
+                warn!("Failed to decode pos message from {from}: {e}");
+                return;
+            }
+        };
+
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        match message {
+            PosMessage::Attest { slot, height, hash } => {
+                let Some(validators) = &state.validators else {
+                    return;
+                };
+                if validators.stake(&from) == 0 {
+                    debug!("Ignoring attestation from {from} without stake");
+                    return;
+                }
+                if height <= final_height(state) {
+                    return;
+                }
+                let attested = state
+                    .votes
+                    .entry((from, height))
+                    .or_insert_with(|| hash.clone());
+                if *attested != hash {
+                    warn!("Ignoring a second attestation from {from} at height {height}");
+                    return;
+                }
+                state
+                    .attestations
+                    .entry(hash)
+                    .or_insert_with(|| (slot, BTreeSet::new()))
+                    .1
+                    .insert(from);
+                self.check_finality(state);
+            }
+            PosMessage::GetBlock { hash } => {
+                if let Some(block) = self.find_block(state, &hash) {
+                    self.send(from, &PosMessage::Block { block });
+                }
+            }
+            PosMessage::Block { block } => {
+                if let Err(e) = self.add_block(state, block, from) {
+                    warn!("Dropping fetched block from {from}: {e}");
+                }
+            }
+        }
+    }
+
+    fn mempool_size(&self) -> usize {
+        self.state.lock().unwrap().pending.len()
+    }
+
+    fn state(&self) -> Value {
+        let state = self.state.lock().unwrap();
+        let height = |block: &Option<Block>| {
+            block
+                .as_ref()
+                .and_then(|block| block.header.as_ref())
+                .map_or(0, |header| header.index)
+        };
+        json!({
+            "validators_settled": state.validators.is_some(),
+            "last_slot": state.last_slot,
+            "head_height": height(&state.head),
+            "attested_height": state.attested_height,
+            "final_height": height(&state.final_head),
+            "pending_transactions": state.pending.len(),
+        })
+    }
+}
+
+impl Engine {
+    pub fn new_engine(
+        interval: Duration,
+        params: Params,
+        nodes: usize,
+        context: Context,
+    ) -> Box<dyn EngineTrait> {
+        // a node resuming a persisted chain builds on its head
+        let mut final_heights = BTreeMap::new();
+        let mut finalized = BTreeSet::new();
+        let height = context.chain.len().unwrap_or_else(|e| {
+            error!("Failed to read the chain head: {:?}", e);
+            0
+        });
+        for index in 1..=height {
+            match context.chain.block(index) {
+                Ok(Some(block)) => {
+                    finalized.extend(block.transactions.iter().map(Transaction::hash));
+                    final_heights.insert(block.hash(), index);
+                }
+                _ => {
+                    error!("Failed to read block {index} of the chain");
+                    break;
+                }
+            }
+        }
+        let head = context.chain.head().unwrap_or_else(|e| {
+            error!("Failed to read the chain head: {:?}", e);
+            None
+        });
+
+        Box::new(Self {
+            slot_duration: interval,
+            params,
+            nodes,
+            context,
+            state: Arc::new(Mutex::new(State {
+                members: BTreeSet::new(),
+                validators: None,
+                blocks: BTreeMap::new(),
+                head: head.clone(),
+                attested_height: height,
+                final_head: head,
+                final_heights,
+                attestations: BTreeMap::new(),
+                votes: BTreeMap::new(),
+                last_slot: None,
+                pending: BTreeMap::new(),
+                finalized,
+            })),
+        })
+    }
+
+    // settles the validator set once enough nodes are seen, and proposes in the slots of this node
+    fn tick(&self) {
+        let me = self.context.network.local_peer_id();
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        state.members.extend(self.context.network.connected_peers());
+
+        if state.validators.is_none() {
+            // the stakes go to the nodes by index, or to the peers sorted by peer id
+            // when the node does not know the indexes
+            let mut peers = self.context.network.nodes();
+            if peers.is_empty() {
+                if state.members.len() + 1 < self.nodes {
+                    return;
+                }
+                if !self.params.stakes.is_empty() {
+                    warn!(
+                        "network.peers is empty, the stakes go to the validators sorted by peer id"
+                    );
+                }
+                peers = state.members.iter().copied().collect();
+                peers.push(me);
+                peers.sort();
+            }
+            let validators = Validators::new(&peers, &self.params);
+            info!(
+                "Validator set of {} nodes staking {}, this node stakes {}",
+                peers.len(),
+                validators.total(),
+                validators.stake(&me)
+            );
+            state.validators = Some(validators);
+        }
+
+        let slot = self.current_slot();
+        if state.last_slot.is_some_and(|last| last >= slot) {
+            return;
+        }
+        state.last_slot = Some(slot);
+        let proposer = state
+            .validators
+            .as_ref()
+            .and_then(|validators| validators.proposer(slot));
+        if proposer == Some(me) {
+            self.propose(state, slot);
+        }
+    }
+
+    fn propose(&self, state: &mut State, slot: u64) {
+        let included: BTreeSet<Vec<u8>> = self
+            .unfinal_chain(state)
+            .iter()
+            .flat_map(|block| block.transactions.iter().map(Transaction::hash))
+            .collect();
+        let transactions: Vec<Transaction> = state
+            .pending
+            .iter()
+            .filter(|(hash, _)| !included.contains(*hash))
+            .map(|(_, transaction)| transaction.clone())
+            .collect();
+        if transactions.is_empty() {
+            debug!("No transactions to propose in slot {slot}");
+            return;
+        }
+
+        let mut block = Block::new_block(
+            state.head.as_ref(),
+            self.context.network.local_peer_id(),
+            self.context.clock.timestamp(),
+            transactions,
+        );
+        block.sign(&self.context.keypair);
+        info!(
+            "Proposing block {} at height {} in slot {}",
+            hex::encode(block.hash()),
+            height(&block),
+            slot
+        );
+        self.context.network.publish_block(block.clone());
+        state.blocks.insert(block.hash(), block);
+        self.attest_next(state);
+        self.check_finality(state);
+    }
+
+    fn add_block(&self, state: &mut State, block: Block, from: PeerId) -> Result<(), String> {
+        if !block.verify_signature() {
+            return Err("block is not signed by its proposer".into());
+        }
+        block.verify_body().map_err(|e| e.to_string())?;
+        let hash = block.hash();
+        let final_height = final_height(state);
+        if height(&block) <= final_height || state.blocks.contains_key(&hash) {
+            return Ok(());
+        }
+        let Some(validators) = &state.validators else {
+            debug!("Ignoring block from {from} before the validator set is known");
+            return Ok(());
+        };
+        let slot = self.slot(&block);
+        if slot > self.current_slot() + 1 {
+            return Err(format!("block is from the future slot {slot}"));
+        }
+        let proposer = block
+            .header
+            .as_ref()
+            .and_then(|header| PeerId::from_bytes(&header.proposer).ok());
+        if proposer.is_none() || proposer != validators.proposer(slot) {
+            return Err(format!("block is not from the proposer of slot {slot}"));
+        }
+
+        let parent = parent_hash(&block).to_vec();
+        state.blocks.insert(hash, block);
+        if is_missing(state, &parent) {
+            debug!(
+                "Fetching the missing parent {} from {from}",
+                hex::encode(&parent)
+            );
+            self.send(from, &PosMessage::GetBlock { hash: parent });
+        }
+        self.attest_next(state);
+        self.check_finality(state);
+        Ok(())
+    }
+
+    // attests the earliest recent block extending the head, as long as there is one
+    fn attest_next(&self, state: &mut State) {
+        let me = self.context.network.local_peer_id();
+        let current_slot = self.current_slot();
+        loop {
+            let head_slot = state.head.as_ref().map(|head| self.slot(head));
+            let next = state
+                .blocks
+                .values()
+                .filter(|block| {
+                    let slot = self.slot(block);
+                    height(block) > state.attested_height
+                        && slot + 1 >= current_slot
+                        && head_slot.is_none_or(|head_slot| slot > head_slot)
+                        && block.verify_parent(state.head.as_ref()).is_ok()
+                })
+                .min_by_key(|block| self.slot(block))
+                .cloned();
+            let Some(block) = next else {
+                return;
+            };
+
+            let slot = self.slot(&block);
+            let hash = block.hash();
+            state.attested_height = height(&block);
+            state.head = Some(block);
+            let staked = state
+                .validators
+                .as_ref()
+                .is_some_and(|validators| validators.stake(&me) > 0);
+            if staked {
+                debug!("Attesting block {} of slot {slot}", hex::encode(&hash));
+                let height = state.attested_height;
+                state.votes.insert((me, height), hash.clone());
+                state
+                    .attestations
+                    .entry(hash.clone())
+                    .or_insert_with(|| (slot, BTreeSet::new()))
+                    .1
+                    .insert(me);
+                self.broadcast(&PosMessage::Attest { slot, height, hash });
+            }
+        }
+    }
+
+    // appends the highest block attested by a supermajority to the chain, with the blocks before it
+    fn check_finality(&self, state: &mut State) {
+        let Some(validators) = &state.validators else {
+            return;
+        };
+        let final_height = final_height(state);
+        let mut attested: Vec<(Vec<u8>, u64, PeerId)> = state
+            .attestations
+            .iter()
+            .filter_map(|(hash, (_, attesters))| {
+                let block = state.blocks.get(hash)?;
+                let height = height(block);
+                // an attestation only counts at the height it was cast for
+                let attesters: BTreeSet<PeerId> = attesters
+                    .iter()
+                    .filter(|peer| state.votes.get(&(**peer, height)) == Some(hash))
+                    .copied()
+                    .collect();
+                let attester = *attesters.iter().next()?;
+                (height > final_height
+                    && validators.is_supermajority(&attesters, self.params.finality_threshold))
+                .then_some((hash.clone(), height, attester))
+            })
+            .collect();
+        attested.sort_by_key(|(_, height, _)| std::cmp::Reverse(*height));
+
+        let final_hash = state
+            .final_head
+            .as_ref()
+            .map(Block::hash)
+            .unwrap_or_default();
+        for (hash, _, attester) in attested {
+            // walks down to the last final block
+            let mut path = Vec::new();
+            let mut block = &state.blocks[&hash];
+            loop {
+                path.push(block.clone());
+                let parent = parent_hash(block);
+                if parent == final_hash.as_slice() {
+                    break;
+                }
+                match state.blocks.get(parent) {
+                    Some(parent) => block = parent,
+                    None => {
+                        path.clear();
+                        if is_missing(state, parent) {
+                            self.send(
+                                attester,
+                                &PosMessage::GetBlock {
+                                    hash: parent.to_vec(),
+                                },
+                            );
+                        }
+                        break;
+                    }
+                }
+            }
+            if !path.is_empty() {
+                path.reverse();
+                self.finalize(state, path);
+                return;
+            }
+        }
+    }
+
+    fn finalize(&self, state: &mut State, blocks: Vec<Block>) {
+        for block in blocks {
+            if let Err(e) = block.verify_parent(state.final_head.as_ref()) {
+                error!("Attested block does not extend the final blocks: {e}");
+                return;
+            }
+            info!(
+                "Block {} at height {} is final",
+                hex::encode(block.hash()),
+                height(&block)
+            );
+            for transaction in &block.transactions {
+                let hash = transaction.hash();
+                state.pending.remove(&hash);
+                state.finalized.insert(hash);
+            }
+            self.context.chain.append(&block);
+            state.final_heights.insert(block.hash(), height(&block));
+            state.final_head = Some(block);
+        }
+
+        let Some(final_head) = state.final_head.clone() else {
+            return;
+        };
+        let final_height = height(&final_head);
+        let final_slot = self.slot(&final_head);
+        state.blocks.retain(|_, block| height(block) > final_height);
+        state.attestations.retain(|_, (slot, _)| *slot > final_slot);
+        state.votes.retain(|(_, height), _| *height > final_height);
+        // a head on a fork that lost, or below the final blocks, moves to the last final block
+        if !self.extends(state, state.head.as_ref(), &final_head) {
+            state.head = Some(final_head);
+            state.attested_height = state.attested_height.max(final_height);
+        }
+        self.attest_next(state);
+    }
+
+    // the block is the ancestor or itself the final head
+    fn extends(&self, state: &State, block: Option<&Block>, ancestor: &Block) -> bool {
+        let ancestor_hash = ancestor.hash();
+        let mut block = block;
+        while let Some(current) = block {
+            if height(current) <= height(ancestor) {
+                return current.hash() == ancestor_hash;
+            }
+            block = state.blocks.get(parent_hash(current));
+        }
+        false
+    }
+
+    // blocks from the head down to the last final block, the head first
+    fn unfinal_chain<'a>(&self, state: &'a State) -> Vec<&'a Block> {
+        let final_height = final_height(state);
+        let mut chain = Vec::new();
+        let mut block = state.head.as_ref();
+        while let Some(current) = block {
+            if height(current) <= final_height {
+                break;
+            }
+            chain.push(current);
+            block = state.blocks.get(parent_hash(current));
+        }
+        chain
+    }
+
+    fn find_block(&self, state: &State, hash: &[u8]) -> Option<Block> {
+        if let Some(block) = state.blocks.get(hash) {
+            return Some(block.clone());
+        }
+        let height = *state.final_heights.get(hash)?;
+        self.context.chain.block(height).ok().flatten()
+    }
+
+    fn current_slot(&self) -> u64 {
+        self.context.clock.timestamp() / self.slot_duration.as_millis() as u64
+    }
+
+    fn slot(&self, block: &Block) -> u64 {
+        block.header.as_ref().map_or(0, |header| {
+            header.timestamp / self.slot_duration.as_millis() as u64
+        })
+    }
+
+    fn broadcast(&self, message: &PosMessage) {
+        let bytes = serde_json::to_vec(message).expect("Failed to serialize pos message");
+        self.context.network.broadcast(bytes);
+    }
+
+    fn send(&self, peer: PeerId, message: &PosMessage) {
+        let bytes = serde_json::to_vec(message).expect("Failed to serialize pos message");
+        self.context.network.send_to(peer, bytes);
+    }
+}
+
+fn height(block: &Block) -> u64 {
+    block
+        .header
+        .as_ref()
+        .map_or(0, |header| header.index as u64)
+}
+
+fn parent_hash(block: &Block) -> &[u8] {
+    block
+        .header
+        .as_ref()
+        .map_or(&[], |header| header.parent_hash.as_slice())
+}
+
+fn final_height(state: &State) -> u64 {
+    state.final_head.as_ref().map_or(0, height)
+}
+
+// the parent is neither held nor final, it has to be fetched
+fn is_missing(state: &State, parent: &[u8]) -> bool {
+    !parent.is_empty()
+        && !state.blocks.contains_key(parent)
+        && !state.final_heights.contains_key(parent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::rng;
+    use crate::testing::{assert_agree, simulate};
+
+    #[test]
+    fn the_nodes_agree_on_the_finalized_blocks() {
+        let _rng = rng::exclusive();
+        let chains = simulate(4, 7, 120, |_, context| {
+            Engine::new_engine(Duration::from_secs(5), Params::default(), 4, context)
+        });
+        assert_agree(&chains, 10);
+    }
+}
@@ -0,0 +1,130 @@
+/*
+Proof of Stake shares the proposals and the votes out among the validators in
+proportion to their stake. Time is divided into slots, and the proposer of a slot
+is drawn among the validators weighted by their stake, from the slot number alone
+so every node draws the same one. The proposer signs a block extending the last
+block it accepted, the validators attest the block if it extends theirs, and a
+block is final once the validators attesting it hold a supermajority of the stake,
+along with every block before it.
+
+Here a slot lasts a block interval and a validator attests a single block per
+height, so two conflicting blocks never both gather more than half of the stake.
+*/
+
+use crate::network::messages::message::Block;
+use crate::CunnerError;
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+
+/// Tuning parameters for the algorithm, set in the `pos` section of the engine
+/// configuration.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Params {
+    /// Stake of each validator by node index, the order of `network.peers`. Every
+    /// validator stakes 1 if the table is empty, and those past its end stake 0:
+    /// they follow the chain without proposing or attesting.
+    pub stakes: Vec<u64>,
+    /// Fraction of the total stake that must attest a block for it to be final.
+    pub finality_threshold: f64,
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Self {
+            stakes: Vec::new(),
+            finality_threshold: 2.0 / 3.0,
+        }
+    }
+}
+
+impl Params {
+    pub fn validate(&self) -> Result<(), CunnerError> {
+        let invalid = |message: &str| Err(CunnerError::Config(format!("pos: {message}")));
+        if !self.stakes.is_empty() && self.stakes.iter().sum::<u64>() == 0 {
+            return invalid("stakes must hold some stake");
+        }
+        if self.finality_threshold <= 0.5 || self.finality_threshold > 1.0 {
+            return invalid("finality_threshold must be above 0.5 and at most 1");
+        }
+        Ok(())
+    }
+}
+
+/// Messages exchanged by the PoS validators, sent as json over the network. Blocks
+/// travel with `publish_block`, and gossipsub signs every message with the key of
+/// its sender so attestations are not signed themselves.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum PosMessage {
+    /// a validator attests the block of the slot at the height
+    Attest {
+        slot: u64,
+        height: u64,
+        hash: Vec<u8>,
+    },
+    /// asks a node for the block with the hash, the parent of a block it cannot connect
+    GetBlock { hash: Vec<u8> },
+    /// answers with the block
+    Block { block: Block },
+}
+
+/// Validators is the stake table of a run.
+#[derive(Debug)]
+pub struct Validators {
+    validators: Vec<(PeerId, u64)>,
+    total: u64,
+}
+
+impl Validators {
+    /// Gives the stakes of the table to the peers, in their order.
+    pub fn new(peers: &[PeerId], params: &Params) -> Self {
+        let validators: Vec<(PeerId, u64)> = peers
+            .iter()
+            .enumerate()
+            .map(|(index, peer)| {
+                let stake = if params.stakes.is_empty() {
+                    1
+                } else {
+                    params.stakes.get(index).copied().unwrap_or(0)
+                };
+                (*peer, stake)
+            })
+            .collect();
+        let total = validators.iter().map(|(_, stake)| stake).sum();
+        Self { validators, total }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    pub fn stake(&self, peer: &PeerId) -> u64 {
+        self.validators
+            .iter()
+            .find(|(validator, _)| validator == peer)
+            .map_or(0, |(_, stake)| *stake)
+    }
+
+    /// Returns the proposer of the slot, drawn from the hash of the slot number with
+    /// a chance proportional to its stake.
+    pub fn proposer(&self, slot: u64) -> Option<PeerId> {
+        if self.total == 0 {
+            return None;
+        }
+        let digest = Sha256::digest(slot.to_le_bytes());
+        let draw = u64::from_le_bytes(digest[..8].try_into().expect("8 bytes")) % self.total;
+        let mut cumulative = 0;
+        self.validators.iter().find_map(|(validator, stake)| {
+            cumulative += stake;
+            (draw < cumulative).then_some(*validator)
+        })
+    }
+
+    /// The attesters hold at least `threshold` of the total stake.
+    pub fn is_supermajority(&self, attesters: &BTreeSet<PeerId>, threshold: f64) -> bool {
+        let stake: u64 = attesters.iter().map(|peer| self.stake(peer)).sum();
+        self.total > 0 && stake as f64 >= threshold * self.total as f64
+    }
+}
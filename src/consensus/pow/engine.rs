@@ -0,0 +1,313 @@
+use crate::consensus::engine::{BlockVerdict, Context, Engine as EngineTrait};
+use crate::consensus::pow::pow::{meets_difficulty, BlockTree, Insert, Params, PowError};
+use crate::metrics::recorder;
+use crate::network::messages::message::{Block, Transaction};
+use libp2p::PeerId;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How often the engine mines a batch of hashes, its hash rate is spread over the ticks.
+const TICK: Duration = Duration::from_millis(100);
+
+/// Messages exchanged by the PoW engines, sent as json over the network. Mined
+/// blocks travel with `publish_block`, these only fetch the blocks a node misses.
+#[derive(Serialize, Deserialize)]
+enum PowMessage {
+    /// asks a peer for the block with the hash, the parent of a block it cannot connect
+    GetBlock { hash: Vec<u8> },
+    /// answers with the block
+    Block { block: Block },
+}
+
+/// Engine mines blocks of the pending transactions on the tip of the heaviest chain
+/// and appends the blocks of that chain to the node's chain once they are buried
+/// under enough confirmations.
+#[derive(Clone)]
+pub struct Engine {
+    params: Params,
+    context: Context,
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    tree: BlockTree,
+    // block being mined, its nonce is the last one tried
+    candidate: Option<Block>,
+    // transactions arrived since the candidate was built
+    refresh: bool,
+    // transactions not final yet, by hash so blocks do not depend on arrival order
+    pending: BTreeMap<Vec<u8>, Transaction>,
+    finalized: BTreeSet<Vec<u8>>,
+}
+
+impl EngineTrait for Engine {
+    fn run<'a>(&'a self) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> {
+        Box::pin(async move {
+            println!("Engine is mining");
+            loop {
+                self.context.clock.sleep(TICK).await;
+                self.mine();
+            }
+        })
+    }
+
+    fn add_transaction(&self, transaction: Transaction) {
+        let hash = transaction.hash();
+        let mut state = self.state.lock().unwrap();
+        if state.finalized.contains(&hash) || state.pending.contains_key(&hash) {
+            return;
+        }
+        state.pending.insert(hash, transaction);
+        state.refresh = true;
+    }
+
+    /// Blocks are held in the block tree and appended to the chain by the engine
+    /// once final, a block whose parent is unknown is fetched from its miner.
+    fn on_block(&self, block: &Block, from: PeerId) -> BlockVerdict {
+        let mut state = self.state.lock().unwrap();
+        match self.add_block(&mut state, block.clone(), from) {
+            Ok(()) => BlockVerdict::Hold,
+            Err(e) => {
+                warn!("Rejecting block from {from}: {e}");
+                BlockVerdict::Reject
+            }
+        }
+    }
+
+    fn handle_message(&self, from: PeerId, bytes: Vec<u8>) {
+        let message = match serde_json::from_slice(&bytes) {
+            Ok(message) => message,
+            Err(e) => {
+                warn!("Failed to decode pow message from {from}: {e}");
+                return;
+            }
+        };
+
+        let mut state = self.state.lock().unwrap();
+        match message {
+            PowMessage::GetBlock { hash } => {
+                if let Some(block) = state.tree.get(&hash) {
+                    let block = block.clone();
+                    self.send(from, &PowMessage::Block { block });
+                }
+            }
+            PowMessage::Block { block } => {
+                if let Err(e) = self.add_block(&mut state, block, from) {
+                    warn!("Dropping fetched block from {from}: {e}");
+                }
+            }
+        }
+    }
+
+    fn mempool_size(&self) -> usize {
+        self.state.lock().unwrap().pending.len()
+    }
+
+    fn state(&self) -> Value {
+        let state = self.state.lock().unwrap();
+        json!({
+            "tip_height": state.tree.tip_height(),
+            "mining": state.candidate.is_some(),
+            "pending_transactions": state.pending.len(),
+        })
+    }
+}
+
+impl Engine {
+    pub fn new_engine(
+        interval: Duration,
+        params: Params,
+        context: Context,
+    ) -> Box<dyn EngineTrait> {
+        // a node resuming a persisted chain mines on its head
+        let mut chain = Vec::new();
+        let height = context.chain.len().unwrap_or_else(|e| {
+            error!("Failed to read the chain head: {:?}", e);
+            0
+        });
+        for index in 1..=height {
+            match context.chain.block(index) {
+                Ok(Some(block)) => chain.push(block),
+                _ => {
+                    error!("Failed to read block {index} of the chain");
+                    break;
+                }
+            }
+        }
+        let finalized = chain
+            .iter()
+            .flat_map(|block| block.transactions.iter().map(Transaction::hash))
+            .collect();
+
+        Box::new(Self {
+            params,
+            context,
+            state: Arc::new(Mutex::new(State {
+                tree: BlockTree::new(params, params.block_time(interval), chain),
+                candidate: None,
+                refresh: false,
+                pending: BTreeMap::new(),
+                finalized,
+            })),
+        })
+    }
+
+    // tries the next batch of nonces on the candidate, rebuilt when the tip moved or transactions arrived
+    fn mine(&self) {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let tip = state.tree.tip().map(Block::hash).unwrap_or_default();
+        let outdated = state.candidate.as_ref().is_none_or(|candidate| {
+            candidate
+                .header
+                .as_ref()
+                .is_none_or(|header| header.parent_hash != tip)
+        });
+        if outdated || state.refresh {
+            state.candidate = Some(self.candidate(state));
+            state.refresh = false;
+        }
+        let Some(candidate) = state.candidate.as_mut() else {
+            return;
+        };
+        let difficulty = candidate
+            .header
+            .as_ref()
+            .map_or(0, |header| header.difficulty);
+
+        let batch = (self.params.hashes_per_second * TICK.as_millis() as u64 / 1000).max(1);
+        let mut hashes = 0;
+        let mut found = false;
+        while hashes < batch && !found {
+            if let Some(header) = candidate.header.as_mut() {
+                header.nonce = header.nonce.wrapping_add(1);
+            }
+            hashes += 1;
+            found = meets_difficulty(&candidate.hash(), difficulty);
+        }
+        recorder::hashes_computed(hashes);
+        if !found {
+            return;
+        }
+
+        let block = state.candidate.take().expect("mined a candidate");
+        info!(
+            "Mined block {} at height {} with difficulty {}",
+            hex::encode(block.hash()),
+            state.tree.tip_height() + 1,
+            difficulty
+        );
+        recorder::block_mined(&block);
+        match state.tree.add(block.clone()) {
+            Ok(_) => self.context.network.publish_block(block),
+            Err(e) => error!("Mined an invalid block: {e}"),
+        }
+        self.finalize(state);
+    }
+
+    // a block of the pending transactions the tip's chain does not hold yet
+    fn candidate(&self, state: &State) -> Block {
+        let included: BTreeSet<Vec<u8>> = state
+            .tree
+            .unfinal_blocks()
+            .iter()
+            .flat_map(|block| block.transactions.iter().map(Transaction::hash))
+            .collect();
+        let transactions = state
+            .pending
+            .iter()
+            .filter(|(hash, _)| !included.contains(*hash))
+            .map(|(_, transaction)| transaction.clone())
+            .collect();
+
+        let parent = state.tree.tip();
+        // a block is never older than its parent, even if the clocks of the miners differ
+        let timestamp = parent
+            .and_then(|parent| parent.header.as_ref())
+            .map_or(0, |header| header.timestamp)
+            .max(self.context.clock.timestamp());
+        let mut block = Block::new_block(
+            parent,
+            self.context.network.local_peer_id(),
+            timestamp,
+            transactions,
+        );
+        let difficulty = state
+            .tree
+            .next_difficulty(parent.map(Block::hash).as_deref());
+        if let Some(header) = block.header.as_mut() {
+            header.difficulty = difficulty;
+        }
+        block
+    }
+
+    fn add_block(&self, state: &mut State, block: Block, from: PeerId) -> Result<(), PowError> {
+        let parent = block
+            .header
+            .as_ref()
+            .map(|header| header.parent_hash.clone())
+            .unwrap_or_default();
+        match state.tree.add(block)? {
+            Insert::Known => {}
+            Insert::Orphan => {
+                debug!(
+                    "Fetching the missing parent {} from {from}",
+                    hex::encode(&parent)
+                );
+                self.send(from, &PowMessage::GetBlock { hash: parent });
+            }
+            Insert::Connected { tip_changed } => {
+                if tip_changed {
+                    debug!("Tip moved to height {}", state.tree.tip_height());
+                    self.finalize(state);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // appends the blocks buried under enough confirmations to the chain
+    fn finalize(&self, state: &mut State) {
+        for block in state.tree.finalize() {
+            for transaction in &block.transactions {
+                let hash = transaction.hash();
+                state.pending.remove(&hash);
+                state.finalized.insert(hash);
+            }
+            self.context.chain.append(&block);
+        }
+    }
+
+    fn send(&self, peer: PeerId, message: &PowMessage) {
+        let bytes = serde_json::to_vec(message).expect("Failed to serialize pow message");
+        self.context.network.send_to(peer, bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::rng;
+    use crate::testing::{assert_agree, simulate};
+
+    #[test]
+    fn the_nodes_agree_on_the_confirmed_blocks() {
+        let _rng = rng::exclusive();
+        // a low difficulty mined slowly, so the run hashes little
+        let params = Params {
+            initial_difficulty: 10,
+            hashes_per_second: 100,
+            ..Params::default()
+        };
+        let chains = simulate(4, 7, 120, |_, context| {
+            Engine::new_engine(Duration::from_secs(10), params, context)
+        });
+        assert_agree(&chains, 5);
+    }
+}
@@ -0,0 +1,361 @@
+/*
+Proof of Work lets any node extend the chain by finding a nonce that makes the hash
+of its block header start with enough zero bits. Every node mines on the tip of the
+heaviest chain it knows, the one with the most expected work behind it, so two
+blocks mined at about the same time fork the chain until one side is extended and
+the other side is left stale. The difficulty is adjusted every few blocks so that
+blocks keep coming at the target block time whatever the hash rate of the network.
+
+Here a block is final once enough blocks are mined on top of it, which is when it is
+appended to the chain, and the target block time leaves room for them within the
+block interval of the engine, so that a block is final about one block interval after
+it is mined. The chain never forks below its final blocks.
+*/
+
+use crate::network::messages::message::Block;
+use crate::network::messages::messages::BlockError;
+use crate::CunnerError;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use thiserror::Error;
+
+/// Highest difficulty, the work of a block must fit in the cumulative work of a chain.
+pub const MAX_DIFFICULTY: u32 = 64;
+/// Largest change of the difficulty at a retarget, in bits.
+const MAX_ADJUSTMENT: i64 = 2;
+/// Largest number of blocks waiting for their parent.
+const MAX_ORPHANS: usize = 256;
+
+/// Tuning parameters for the algorithm, set in the `pow` section of the engine
+/// configuration.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Params {
+    /// Difficulty of the blocks before the first retarget, in leading zero bits.
+    pub initial_difficulty: u32,
+    /// Hashes a node computes per second, the CPU it spends mining.
+    pub hashes_per_second: u64,
+    /// Number of blocks between two difficulty adjustments, each one compares the
+    /// time the last window took with the target block time.
+    pub retarget_interval: u64,
+    /// Number of blocks mined on top of a block before it is final.
+    pub confirmations: u64,
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Self {
+            initial_difficulty: 16,
+            hashes_per_second: 4_000,
+            retarget_interval: 10,
+            confirmations: 3,
+        }
+    }
+}
+
+impl Params {
+    pub fn validate(&self) -> Result<(), CunnerError> {
+        let invalid = |message: &str| Err(CunnerError::Config(format!("pow: {message}")));
+        if self.initial_difficulty > MAX_DIFFICULTY {
+            return invalid("initial_difficulty must be at most 64");
+        }
+        if self.hashes_per_second == 0 {
+            return invalid("hashes_per_second must be at least 1");
+        }
+        if self.retarget_interval == 0 {
+            return invalid("retarget_interval must be at least 1");
+        }
+        if self.confirmations == 0 {
+            return invalid("confirmations must be at least 1");
+        }
+        Ok(())
+    }
+
+    /// Returns the target block time, the block interval shared by the block and its
+    /// confirmations.
+    pub fn block_time(&self, interval: Duration) -> Duration {
+        let blocks = u32::try_from(self.confirmations.saturating_add(1)).unwrap_or(u32::MAX);
+        interval / blocks
+    }
+}
+
+/// PowError is the reason a mined block does not verify.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum PowError {
+    #[error(transparent)]
+    Block(#[from] BlockError),
+    #[error("difficulty {found} is not the expected difficulty {expected}")]
+    Difficulty { expected: u32, found: u32 },
+    #[error("hash does not meet the difficulty")]
+    Work,
+}
+
+/// Returns the number of leading zero bits of the hash.
+pub fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+pub fn meets_difficulty(hash: &[u8], difficulty: u32) -> bool {
+    leading_zero_bits(hash) >= difficulty
+}
+
+/// Returns the expected number of hashes to mine a block of the difficulty.
+fn work(difficulty: u32) -> u128 {
+    1 << difficulty
+}
+
+fn difficulty(block: &Block) -> u32 {
+    block.header.as_ref().map_or(0, |header| header.difficulty)
+}
+
+fn timestamp(block: &Block) -> u64 {
+    block.header.as_ref().map_or(0, |header| header.timestamp)
+}
+
+fn parent_hash(block: &Block) -> &[u8] {
+    block
+        .header
+        .as_ref()
+        .map_or(&[], |header| header.parent_hash.as_slice())
+}
+
+struct Node {
+    block: Block,
+    height: u64,
+    // work of the chain ending with the block
+    work: u128,
+}
+
+/// Insert is what adding a block did to the tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Insert {
+    Known,
+    /// the parent is unknown, the block waits for it
+    Orphan,
+    /// the block and the orphans waiting for it are in the tree
+    Connected {
+        tip_changed: bool,
+    },
+}
+
+/// BlockTree holds every block a node knows, its forks included, and follows the
+/// heaviest of the chains that extend the final blocks.
+pub struct BlockTree {
+    params: Params,
+    block_time: Duration,
+    nodes: BTreeMap<Vec<u8>, Node>,
+    // blocks waiting for their parent, by parent hash
+    orphans: BTreeMap<Vec<u8>, Vec<Block>>,
+    orphan_count: usize,
+    tip: Option<Vec<u8>>,
+    // last final block, every block from the first one up to it is on the chain
+    final_block: Option<Vec<u8>>,
+    final_height: u64,
+}
+
+impl BlockTree {
+    /// Returns a tree whose final blocks are `chain`, the blocks of the chain of a
+    /// node, mined with `block_time` as the target block time.
+    pub fn new(params: Params, block_time: Duration, chain: Vec<Block>) -> Self {
+        let mut tree = Self {
+            params,
+            block_time,
+            nodes: BTreeMap::new(),
+            orphans: BTreeMap::new(),
+            orphan_count: 0,
+            tip: None,
+            final_block: None,
+            final_height: 0,
+        };
+        for block in chain {
+            let hash = block.hash();
+            let parent_work = tree.tip.as_ref().map_or(0, |tip| tree.nodes[tip].work);
+            tree.final_height += 1;
+            tree.nodes.insert(
+                hash.clone(),
+                Node {
+                    work: parent_work + work(difficulty(&block)),
+                    height: tree.final_height,
+                    block,
+                },
+            );
+            tree.tip = Some(hash.clone());
+            tree.final_block = Some(hash);
+        }
+        tree
+    }
+
+    pub fn get(&self, hash: &[u8]) -> Option<&Block> {
+        self.nodes.get(hash).map(|node| &node.block)
+    }
+
+    pub fn tip(&self) -> Option<&Block> {
+        self.tip.as_ref().map(|tip| &self.nodes[tip].block)
+    }
+
+    pub fn tip_height(&self) -> u64 {
+        self.tip.as_ref().map_or(0, |tip| self.nodes[tip].height)
+    }
+
+    /// Returns the difficulty of a block mined on `parent`, None for the first block.
+    /// Every retarget interval, the difficulty moves by the number of bits that bring
+    /// the time the last interval took back to the target block time.
+    pub fn next_difficulty(&self, parent: Option<&[u8]>) -> u32 {
+        let Some(parent) = parent.and_then(|hash| self.nodes.get(hash)) else {
+            return self.params.initial_difficulty;
+        };
+        let current = difficulty(&parent.block);
+        let interval = self.params.retarget_interval;
+        if parent.height <= interval || !parent.height.is_multiple_of(interval) {
+            return current;
+        }
+        let Some(first) = self.ancestor(&parent.block, interval) else {
+            return current;
+        };
+
+        let expected = (interval as u128 * self.block_time.as_millis()) as f64;
+        let actual = timestamp(&parent.block).saturating_sub(timestamp(first)) as f64;
+        let adjustment = if actual == 0.0 {
+            MAX_ADJUSTMENT
+        } else {
+            ((expected / actual).log2().round() as i64).clamp(-MAX_ADJUSTMENT, MAX_ADJUSTMENT)
+        };
+        (current as i64 + adjustment).clamp(0, MAX_DIFFICULTY as i64) as u32
+    }
+
+    /// Adds a block received from a peer or mined by the node, the tip moves to it if
+    /// it makes the heaviest chain.
+    pub fn add(&mut self, block: Block) -> Result<Insert, PowError> {
+        let hash = block.hash();
+        if self.nodes.contains_key(&hash) {
+            return Ok(Insert::Known);
+        }
+        let parent = parent_hash(&block).to_vec();
+        if !parent.is_empty() && !self.nodes.contains_key(&parent) {
+            if self.orphan_count < MAX_ORPHANS {
+                self.orphans.entry(parent).or_default().push(block);
+                self.orphan_count += 1;
+            }
+            return Ok(Insert::Orphan);
+        }
+
+        let previous_tip = self.tip.clone();
+        self.connect(block)?;
+        // blocks that were waiting for this one follow it, the invalid ones are dropped
+        let mut connected = vec![hash];
+        while let Some(hash) = connected.pop() {
+            for orphan in self.orphans.remove(&hash).unwrap_or_default() {
+                self.orphan_count -= 1;
+                let orphan_hash = orphan.hash();
+                if self.connect(orphan).is_ok() {
+                    connected.push(orphan_hash);
+                }
+            }
+        }
+        Ok(Insert::Connected {
+            tip_changed: self.tip != previous_tip,
+        })
+    }
+
+    // verifies the block against its parent in the tree and moves the tip to it if it is heavier
+    fn connect(&mut self, block: Block) -> Result<(), PowError> {
+        let parent_hash = parent_hash(&block).to_vec();
+        let parent = self.nodes.get(&parent_hash);
+        block.verify(parent.map(|node| &node.block))?;
+
+        let expected = self.next_difficulty(parent.map(|_| parent_hash.as_slice()));
+        let found = difficulty(&block);
+        if found != expected {
+            return Err(PowError::Difficulty { expected, found });
+        }
+        let hash = block.hash();
+        if !meets_difficulty(&hash, found) {
+            return Err(PowError::Work);
+        }
+
+        let node = Node {
+            height: parent.map_or(0, |node| node.height) + 1,
+            work: parent.map_or(0, |node| node.work) + work(found),
+            block,
+        };
+        let tip_work = self.tip.as_ref().map_or(0, |tip| self.nodes[tip].work);
+        // ties keep the chain seen first
+        let heavier = node.work > tip_work && self.extends_final(&node);
+        if node.work > tip_work && !heavier {
+            warn!(
+                "Ignoring a heavier chain at height {} that forks below the final blocks",
+                node.height
+            );
+        }
+        self.nodes.insert(hash.clone(), node);
+        if heavier {
+            self.tip = Some(hash);
+        }
+        Ok(())
+    }
+
+    // the chain ending with the node holds the last final block
+    fn extends_final(&self, node: &Node) -> bool {
+        if node.height <= self.final_height {
+            return false;
+        }
+        let Some(final_block) = &self.final_block else {
+            return true;
+        };
+        let depth = node.height - self.final_height;
+        self.ancestor(&node.block, depth)
+            .is_some_and(|ancestor| ancestor.hash() == *final_block)
+    }
+
+    // returns the block `depth` blocks below `block`
+    fn ancestor<'a>(&'a self, block: &'a Block, depth: u64) -> Option<&'a Block> {
+        let mut block = block;
+        for _ in 0..depth {
+            block = &self.nodes.get(parent_hash(block))?.block;
+        }
+        Some(block)
+    }
+
+    /// Returns the blocks of the tip's chain above the final blocks, the lowest first.
+    pub fn unfinal_blocks(&self) -> Vec<&Block> {
+        let mut blocks = Vec::new();
+        let mut hash = self.tip.clone();
+        while let Some(node) = hash.as_ref().and_then(|hash| self.nodes.get(hash)) {
+            if node.height <= self.final_height {
+                break;
+            }
+            blocks.push(&node.block);
+            hash = Some(parent_hash(&node.block).to_vec());
+        }
+        blocks.reverse();
+        blocks
+    }
+
+    /// Makes final the blocks of the tip's chain buried under enough confirmations
+    /// and returns them, the lowest first.
+    pub fn finalize(&mut self) -> Vec<Block> {
+        let confirmations = self.params.confirmations as usize;
+        let unfinal = self.unfinal_blocks();
+        if unfinal.len() <= confirmations {
+            return Vec::new();
+        }
+        let finalized: Vec<Block> = unfinal[..unfinal.len() - confirmations]
+            .iter()
+            .map(|block| (*block).clone())
+            .collect();
+        if let Some(last) = finalized.last() {
+            self.final_block = Some(last.hash());
+            self.final_height += finalized.len() as u64;
+        }
+        finalized
+    }
+}
@@ -0,0 +1,690 @@
+use crate::consensus::engine::{BlockVerdict, Context, Engine as EngineTrait};
+use crate::consensus::raft::raft::{Entry, HardState, Log, Params, RaftMessage, Role};
+use crate::metrics::recorder;
+use crate::network::messages::message::{Block, Transaction};
+use crate::simulation::rng::with_rng;
+use crate::storage::store::Store;
+use libp2p::PeerId;
+use log::{debug, error, info, warn};
+use rand::Rng;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How often the engine checks its election and heartbeat timers.
+const TICK: Duration = Duration::from_millis(100);
+/// Largest number of entries the leader sends in a single append.
+const MAX_ENTRIES: usize = 64;
+
+/// Engine runs a Raft node. The leader batches the pending transactions into a
+/// block every block interval, appends it to its log and emits it with
+/// `publish_block` once committed. The term, the vote and the log are kept in the
+/// store of the chain.
+#[derive(Clone)]
+pub struct Engine {
+    block_generation_interval: Duration,
+    params: Params,
+    // size of the cluster majorities are counted over
+    nodes: usize,
+    context: Context,
+    store: Arc<dyn Store>,
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    role: Role,
+    term: u64,
+    voted_for: Option<PeerId>,
+    leader: Option<PeerId>,
+    log: Log,
+    commit_index: u64,
+    // last entry appended to the chain
+    applied: u64,
+    votes: BTreeSet<PeerId>,
+    // next entry the leader sends to each follower and the last one it knows the follower holds
+    next_index: BTreeMap<PeerId, u64>,
+    match_index: BTreeMap<PeerId, u64>,
+    // every peer seen connected, a node that left still counts towards the majority
+    members: BTreeSet<PeerId>,
+    election_deadline: Duration,
+    last_heartbeat: Duration,
+    last_block: Duration,
+    // transactions waiting for a block, by hash so blocks do not depend on arrival order
+    pending: BTreeMap<Vec<u8>, Transaction>,
+    logged: BTreeSet<Vec<u8>>,
+}
+
+impl EngineTrait for Engine {
+    fn run<'a>(&'a self) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> {
+        Box::pin(async move {
+            println!("Engine is running Raft");
+            loop {
+                self.context.clock.sleep(TICK).await;
+                self.tick();
+            }
+        })
+    }
+
+    // followers hand the transactions over to the leader, which batches them into its next block
+    fn add_transaction(&self, transaction: Transaction) {
+        let hash = transaction.hash();
+        let mut state = self.state.lock().unwrap();
+        if state.logged.contains(&hash) || state.pending.contains_key(&hash) {
+            return;
+        }
+        state.pending.insert(hash, transaction.clone());
+        if state.role != Role::Leader {
+            if let Some(leader) = state.leader {
+                self.send(leader, &RaftMessage::Forward { transaction });
+            }
+        }
+    }
+
+    /// A block emitted by the leader is committed, a node holding the same entry
+    /// next in its log applies it right away. The others apply it once they learn
+    /// it is committed.
+    fn on_block(&self, block: &Block, from: PeerId) -> BlockVerdict {
+        let mut state = self.state.lock().unwrap();
+        let index = state.applied + 1;
+        match state.log.get(index) {
+            Some(entry) if entry.block.hash() == block.hash() => {
+                state.applied = index;
+                state.commit_index = state.commit_index.max(index);
+                BlockVerdict::Accept
+            }
+            _ => {
+                debug!("Block from {from} is not the next entry of the log");
+                BlockVerdict::Reject
+            }
+        }
+    }
+
+    fn handle_message(&self, from: PeerId, bytes: Vec<u8>) {
+        let message = match serde_json::from_slice(&bytes) {
+            Ok(message) => message,
+            Err(e) => {
+                warn!("Failed to decode raft message from {from}: {e}");
+                return;
+            }
+        };
+
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        match message {
+            RaftMessage::RequestVote {
+                term,
+                last_log_index,
+                last_log_term,
+            } => self.handle_request_vote(state, from, term, last_log_index, last_log_term),
+            RaftMessage::Vote { term, granted } => {
+                if term > state.term {
+                    self.step_down(state, term);
+                } else if state.role == Role::Candidate && term == state.term && granted {
+                    state.votes.insert(from);
+                    self.check_votes(state);
+                }
+            }
+            RaftMessage::AppendEntries {
+                term,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            } => self.handle_append_entries(
+                state,
+                from,
+                term,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            ),
+            RaftMessage::AppendResponse {
+                term,
+                success,
+                last_log_index,
+            } => self.handle_append_response(state, from, term, success, last_log_index),
+            RaftMessage::Forward { transaction } => {
+                if !transaction.verify_signature() {
+                    warn!("Dropping forwarded transaction from {from} with an invalid signature");
+                    return;
+                }
+                let hash = transaction.hash();
+                if !state.logged.contains(&hash) {
+                    state.pending.entry(hash).or_insert(transaction);
+                }
+            }
+        }
+    }
+
+    fn mempool_size(&self) -> usize {
+        self.state.lock().unwrap().pending.len()
+    }
+
+    fn state(&self) -> Value {
+        let state = self.state.lock().unwrap();
+        json!({
+            "role": format!("{:?}", state.role).to_lowercase(),
+            "term": state.term,
+            "leader": state.leader.map(|leader| leader.to_string()),
+            "last_log_index": state.log.last_index(),
+            "commit_index": state.commit_index,
+            "applied": state.applied,
+            "pending_transactions": state.pending.len(),
+        })
+    }
+}
+
+impl Engine {
+    pub fn new_engine(
+        interval: Duration,
+        params: Params,
+        nodes: usize,
+        context: Context,
+    ) -> Box<dyn EngineTrait> {
+        // a node resuming a persisted chain resumes its term, its vote and its log,
+        // the entries up to the head of the chain are committed
+        let store = context.chain.store();
+        let hard_state = HardState::load(&*store).unwrap_or_else(|e| {
+            error!("Failed to read the raft state: {:?}", e);
+            HardState::default()
+        });
+        let mut log = Log::open(store.clone()).unwrap_or_else(|e| {
+            error!("Failed to read the raft log: {:?}", e);
+            Log::new(store.clone())
+        });
+        let height = context.chain.len().unwrap_or_else(|e| {
+            error!("Failed to read the chain head: {:?}", e);
+            0
+        });
+        // a chain written without the log, its blocks enter the log as entries of no term
+        if log.last_index() < height {
+            warn!(
+                "Raft log ends at {} below the chain head at {}, logging the blocks of the chain",
+                log.last_index(),
+                height
+            );
+            for index in log.last_index() + 1..=height {
+                let appended = match context.chain.block(index) {
+                    Ok(Some(block)) => log.append(Entry { term: 0, block }),
+                    _ => Err(io::Error::other(format!("block {index} is missing"))),
+                };
+                if let Err(e) = appended {
+                    error!("Failed to log block {index} of the chain: {:?}", e);
+                    break;
+                }
+            }
+        }
+        let logged = log
+            .entries_from(1, usize::MAX)
+            .iter()
+            .flat_map(|entry| entry.block.transactions.iter().map(Transaction::hash))
+            .collect();
+        let committed = height.min(log.last_index());
+
+        let engine = Self {
+            block_generation_interval: interval,
+            params,
+            nodes,
+            context,
+            store,
+            state: Arc::new(Mutex::new(State {
+                role: Role::Follower,
+                term: hard_state.term,
+                voted_for: hard_state.voted_for(),
+                leader: None,
+                log,
+                commit_index: committed,
+                applied: committed,
+                votes: BTreeSet::new(),
+                next_index: BTreeMap::new(),
+                match_index: BTreeMap::new(),
+                members: BTreeSet::new(),
+                election_deadline: Duration::ZERO,
+                last_heartbeat: Duration::ZERO,
+                last_block: Duration::ZERO,
+                pending: BTreeMap::new(),
+                logged,
+            })),
+        };
+        engine.state.lock().unwrap().election_deadline =
+            engine.context.clock.now() + engine.election_timeout();
+        Box::new(engine)
+    }
+
+    // stands for election when the leader is silent, and replicates the log when leading
+    fn tick(&self) {
+        let now = self.context.clock.now();
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        state.members.extend(self.context.network.connected_peers());
+
+        if state.role != Role::Leader {
+            if now >= state.election_deadline {
+                self.start_election(state);
+            }
+            return;
+        }
+
+        if now - state.last_block >= self.block_generation_interval && !state.pending.is_empty() {
+            state.last_block = now;
+            self.propose(state);
+            // a cluster of one node commits on its own
+            self.advance_commit(state);
+        } else if now - state.last_heartbeat < self.params.heartbeat_interval() {
+            return;
+        }
+        state.last_heartbeat = now;
+        self.replicate(state);
+    }
+
+    fn start_election(&self, state: &mut State) {
+        let me = self.context.network.local_peer_id();
+        state.term += 1;
+        state.role = Role::Candidate;
+        state.voted_for = Some(me);
+        state.leader = None;
+        state.votes = BTreeSet::from([me]);
+        state.election_deadline = self.context.clock.now() + self.election_timeout();
+        self.save_hard_state(state);
+
+        info!("Standing for election in term {}", state.term);
+        self.broadcast(&RaftMessage::RequestVote {
+            term: state.term,
+            last_log_index: state.log.last_index(),
+            last_log_term: state.log.last_term(),
+        });
+        self.check_votes(state);
+    }
+
+    fn handle_request_vote(
+        &self,
+        state: &mut State,
+        from: PeerId,
+        term: u64,
+        last_log_index: u64,
+        last_log_term: u64,
+    ) {
+        if term > state.term {
+            self.step_down(state, term);
+        }
+        // a node votes once per term, for a candidate whose log holds every committed entry
+        let granted = term == state.term
+            && state.voted_for.is_none_or(|voted| voted == from)
+            && state.log.is_up_to_date(last_log_index, last_log_term);
+        if granted && state.voted_for.is_none() {
+            state.voted_for = Some(from);
+            self.save_hard_state(state);
+        }
+        if granted {
+            state.election_deadline = self.context.clock.now() + self.election_timeout();
+        }
+        self.send(
+            from,
+            &RaftMessage::Vote {
+                term: state.term,
+                granted,
+            },
+        );
+    }
+
+    fn check_votes(&self, state: &mut State) {
+        if state.role != Role::Candidate || state.votes.len() < self.quorum(state) {
+            return;
+        }
+
+        info!(
+            "Elected leader of term {} with {} votes",
+            state.term,
+            state.votes.len()
+        );
+        state.role = Role::Leader;
+        state.leader = Some(self.context.network.local_peer_id());
+        let next = state.log.last_index() + 1;
+        state.next_index = state.members.iter().map(|peer| (*peer, next)).collect();
+        state.match_index = state.members.iter().map(|peer| (*peer, 0)).collect();
+        state.last_heartbeat = self.context.clock.now();
+        self.replicate(state);
+    }
+
+    // a node that sees a higher term follows it
+    fn step_down(&self, state: &mut State, term: u64) {
+        if term > state.term {
+            state.term = term;
+            state.voted_for = None;
+            state.leader = None;
+            self.save_hard_state(state);
+        }
+        if state.role != Role::Follower {
+            debug!("Stepping down to follower in term {term}");
+            state.role = Role::Follower;
+        }
+        state.election_deadline = self.context.clock.now() + self.election_timeout();
+    }
+
+    fn propose(&self, state: &mut State) {
+        let transactions: Vec<Transaction> = state.pending.values().cloned().collect();
+        let parent = state
+            .log
+            .get(state.log.last_index())
+            .map(|entry| &entry.block);
+        let block = Block::new_block(
+            parent,
+            self.context.network.local_peer_id(),
+            self.context.clock.timestamp(),
+            transactions,
+        );
+
+        info!(
+            "Appending block {} at index {} in term {}",
+            hex::encode(block.hash()),
+            state.log.last_index() + 1,
+            state.term
+        );
+        // the block is emitted once committed, its inclusion is recorded when it enters the log
+        recorder::block_included(&block);
+        let term = state.term;
+        self.append_entry(state, Entry { term, block });
+    }
+
+    fn replicate(&self, state: &mut State) {
+        let followers: Vec<PeerId> = state.members.iter().copied().collect();
+        for follower in followers {
+            self.send_append(state, follower);
+        }
+    }
+
+    // sends the follower the entries from the next one it needs, a heartbeat if it has them all
+    fn send_append(&self, state: &mut State, follower: PeerId) {
+        let next = *state
+            .next_index
+            .entry(follower)
+            .or_insert(state.log.last_index() + 1);
+        let prev_log_index = next - 1;
+        self.send(
+            follower,
+            &RaftMessage::AppendEntries {
+                term: state.term,
+                prev_log_index,
+                prev_log_term: state.log.term(prev_log_index).unwrap_or(0),
+                entries: state.log.entries_from(next, MAX_ENTRIES),
+                leader_commit: state.commit_index,
+            },
+        );
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn handle_append_entries(
+        &self,
+        state: &mut State,
+        from: PeerId,
+        term: u64,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<Entry>,
+        leader_commit: u64,
+    ) {
+        if term < state.term {
+            self.send(
+                from,
+                &RaftMessage::AppendResponse {
+                    term: state.term,
+                    success: false,
+                    last_log_index: state.log.last_index(),
+                },
+            );
+            return;
+        }
+        self.step_down(state, term);
+        if state.leader != Some(from) {
+            info!("Following {from} in term {term}");
+            state.leader = Some(from);
+            // the transactions not in the log yet are handed over to the new leader
+            for transaction in state.pending.values() {
+                self.send(
+                    from,
+                    &RaftMessage::Forward {
+                        transaction: transaction.clone(),
+                    },
+                );
+            }
+        }
+
+        // the follower misses the previous entry or holds another one, the leader retries from further back
+        if state.log.term(prev_log_index) != Some(prev_log_term) {
+            self.send(
+                from,
+                &RaftMessage::AppendResponse {
+                    term,
+                    success: false,
+                    last_log_index: state.log.last_index().min(prev_log_index.saturating_sub(1)),
+                },
+            );
+            return;
+        }
+
+        let last_new = prev_log_index + entries.len() as u64;
+        for (index, entry) in (prev_log_index + 1..).zip(entries) {
+            match state.log.term(index) {
+                Some(existing) if existing == entry.term => continue,
+                Some(_) => {
+                    if index <= state.commit_index {
+                        error!("Leader {from} conflicts with the committed entry {index}");
+                        return;
+                    }
+                    // entries conflicting with the leader were never committed, their transactions wait again
+                    let removed = match state.log.truncate(index) {
+                        Ok(removed) => removed,
+                        Err(e) => {
+                            error!("Failed to truncate the log at {index}: {:?}", e);
+                            return;
+                        }
+                    };
+                    for removed in removed {
+                        for transaction in removed.block.transactions {
+                            let hash = transaction.hash();
+                            state.logged.remove(&hash);
+                            state.pending.insert(hash, transaction);
+                        }
+                    }
+                    if !self.append_entry(state, entry) {
+                        return;
+                    }
+                }
+                // the leader hears nothing back and sends the entries again
+                None => {
+                    if !self.append_entry(state, entry) {
+                        return;
+                    }
+                }
+            }
+        }
+
+        state.commit_index = state.commit_index.max(leader_commit.min(last_new));
+        self.apply(state);
+        self.send(
+            from,
+            &RaftMessage::AppendResponse {
+                term,
+                success: true,
+                last_log_index: last_new,
+            },
+        );
+    }
+
+    fn handle_append_response(
+        &self,
+        state: &mut State,
+        from: PeerId,
+        term: u64,
+        success: bool,
+        last_log_index: u64,
+    ) {
+        if term > state.term {
+            self.step_down(state, term);
+            return;
+        }
+        if state.role != Role::Leader || term != state.term {
+            return;
+        }
+
+        if success {
+            let matched = state.match_index.entry(from).or_default();
+            *matched = (*matched).max(last_log_index);
+            let next = *matched + 1;
+            state.next_index.insert(from, next);
+            self.advance_commit(state);
+        } else {
+            // retry from the end of the follower's log, at least one entry further back
+            let next = state.next_index.get(&from).copied().unwrap_or(1);
+            state
+                .next_index
+                .insert(from, (last_log_index + 1).min(next - 1).max(1));
+            self.send_append(state, from);
+        }
+    }
+
+    // commits the last entry of the current term a majority holds, and every entry before it
+    fn advance_commit(&self, state: &mut State) {
+        let quorum = self.quorum(state);
+        let mut index = state.log.last_index();
+        while index > state.commit_index {
+            if state.log.term(index) == Some(state.term) {
+                let holders = 1 + state
+                    .match_index
+                    .values()
+                    .filter(|matched| **matched >= index)
+                    .count();
+                if holders >= quorum {
+                    state.commit_index = index;
+                    break;
+                }
+            }
+            index -= 1;
+        }
+        self.apply(state);
+    }
+
+    // appends the committed entries to the chain, the leader emits them to the network
+    fn apply(&self, state: &mut State) {
+        while state.applied < state.commit_index {
+            let Some(entry) = state.log.get(state.applied + 1) else {
+                break;
+            };
+            let block = entry.block.clone();
+            state.applied += 1;
+            debug!("Applying entry {}", state.applied);
+            self.context.chain.append(&block);
+            if state.role == Role::Leader {
+                self.context.network.publish_block(block);
+            }
+        }
+    }
+
+    // an entry is in the log once written to the store, false if it could not be
+    fn append_entry(&self, state: &mut State, entry: Entry) -> bool {
+        let hashes: Vec<Vec<u8>> = entry
+            .block
+            .transactions
+            .iter()
+            .map(Transaction::hash)
+            .collect();
+        if let Err(e) = state.log.append(entry) {
+            error!(
+                "Failed to write entry {} of the log: {:?}",
+                state.log.last_index() + 1,
+                e
+            );
+            return false;
+        }
+        for hash in hashes {
+            state.pending.remove(&hash);
+            state.logged.insert(hash);
+        }
+        true
+    }
+
+    // the term and the vote are written before the node answers in the term
+    fn save_hard_state(&self, state: &State) {
+        let hard_state = HardState {
+            term: state.term,
+            voted_for: state.voted_for.map(|peer| peer.to_bytes()),
+        };
+        if let Err(e) = hard_state.save(&*self.store) {
+            error!("Failed to write the raft state: {:?}", e);
+        }
+    }
+
+    // a majority of the cluster, or of every node seen if more joined
+    fn quorum(&self, state: &State) -> usize {
+        self.nodes.max(state.members.len() + 1) / 2 + 1
+    }
+
+    fn election_timeout(&self) -> Duration {
+        let timeout = with_rng(|rng| {
+            rng.gen_range(self.params.election_timeout_min_ms..=self.params.election_timeout_max_ms)
+        });
+        Duration::from_millis(timeout)
+    }
+
+    fn broadcast(&self, message: &RaftMessage) {
+        let bytes = serde_json::to_vec(message).expect("Failed to serialize raft message");
+        self.context.network.broadcast(bytes);
+    }
+
+    fn send(&self, peer: PeerId, message: &RaftMessage) {
+        let bytes = serde_json::to_vec(message).expect("Failed to serialize raft message");
+        self.context.network.send_to(peer, bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::rng;
+    use crate::testing::{assert_agree, simulate};
+    use std::cell::RefCell;
+
+    fn new_raft(context: Context) -> Box<dyn EngineTrait> {
+        Engine::new_engine(Duration::from_secs(5), Params::default(), 3, context)
+    }
+
+    #[test]
+    fn the_nodes_agree_on_the_committed_blocks() {
+        let _rng = rng::exclusive();
+        let chains = simulate(3, 7, 120, |_, context| new_raft(context));
+        assert_agree(&chains, 10);
+    }
+
+    #[test]
+    fn a_restarted_node_resumes_its_term_and_its_log() {
+        let _rng = rng::exclusive();
+        let first = RefCell::new(None);
+        let chains = simulate(3, 7, 60, |node, context| {
+            let engine = new_raft(context.clone());
+            if node == 0 {
+                *first.borrow_mut() = Some((engine.clone(), context));
+            }
+            engine
+        });
+        assert_agree(&chains, 5);
+
+        let (engine, context) = first.into_inner().unwrap();
+        let store = context.chain.store();
+        let before = engine.state();
+        let resumed = new_raft(context).state();
+        assert_eq!(resumed["term"], before["term"]);
+        assert_eq!(resumed["last_log_index"], before["last_log_index"]);
+        assert_eq!(resumed["applied"], before["applied"]);
+
+        // the entries keep the terms they were appended in
+        let log = Log::open(store.clone()).unwrap();
+        let term = before["term"].as_u64().unwrap();
+        assert!(log.last_term() > 0 && log.last_term() <= term);
+        assert_eq!(HardState::load(&*store).unwrap().term, term);
+    }
+}
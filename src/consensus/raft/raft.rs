@@ -0,0 +1,230 @@
+/*
+Raft elects a leader for every term: a follower that hears nothing from a leader for
+a randomized election timeout becomes a candidate of the next term and asks the
+others for their vote, and a candidate with the votes of a majority leads the term.
+The leader appends entries to its log and replicates them to the followers, which
+only accept entries that extend what they already hold. An entry of the current
+term is committed once a majority of the nodes stored it, and every entry before it
+with it.
+
+Here the entries are blocks of transactions, each extending the block of the entry
+before it, and applying a committed entry appends its block to the chain, so log
+indexes are block heights. Raft tolerates crashed nodes but not byzantine ones.
+
+A node writes its term, its vote and its log entries to the store of its chain
+before it acts on them, so a node restarted on its data directory neither votes
+twice in a term nor forgets entries it acknowledged to a leader.
+*/
+
+use crate::network::messages::message::{Block, Transaction};
+use crate::storage::store::{Batch, Store};
+use crate::CunnerError;
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+// the term and vote of the node, and its log entries by big endian index so they
+// are read back in order
+const HARD_STATE_KEY: &[u8] = b"raft/state";
+const ENTRY_PREFIX: &[u8] = b"raft/entry/";
+
+/// Tuning parameters for the algorithm, set in the `raft` section of the engine
+/// configuration.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Params {
+    /// Milliseconds a follower waits for the leader before standing for election,
+    /// drawn between the two bounds for every election so candidates rarely collide.
+    pub election_timeout_min_ms: u64,
+    pub election_timeout_max_ms: u64,
+    /// Milliseconds between two heartbeats of the leader.
+    pub heartbeat_interval_ms: u64,
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Self {
+            election_timeout_min_ms: 1500,
+            election_timeout_max_ms: 3000,
+            heartbeat_interval_ms: 500,
+        }
+    }
+}
+
+impl Params {
+    pub fn validate(&self) -> Result<(), CunnerError> {
+        let invalid = |message: &str| Err(CunnerError::Config(format!("raft: {message}")));
+        if self.heartbeat_interval_ms == 0 {
+            return invalid("heartbeat_interval_ms must be at least 1");
+        }
+        if self.election_timeout_min_ms <= self.heartbeat_interval_ms {
+            return invalid("election_timeout_min_ms must be above heartbeat_interval_ms");
+        }
+        if self.election_timeout_max_ms < self.election_timeout_min_ms {
+            return invalid("election_timeout_max_ms must be at least election_timeout_min_ms");
+        }
+        Ok(())
+    }
+
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_millis(self.heartbeat_interval_ms)
+    }
+}
+
+/// Messages exchanged by the Raft nodes, sent as json over the network.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RaftMessage {
+    /// a candidate asks for the vote of a node in its term
+    RequestVote {
+        term: u64,
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+    /// answers a vote request
+    Vote { term: u64, granted: bool },
+    /// the leader replicates the entries following `prev_log_index`, a heartbeat if there are none
+    AppendEntries {
+        term: u64,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<Entry>,
+        leader_commit: u64,
+    },
+    /// answers the leader with the last index the follower holds, if it accepted the entries
+    AppendResponse {
+        term: u64,
+        success: bool,
+        last_log_index: u64,
+    },
+    /// a follower hands a transaction over to the leader
+    Forward { transaction: Transaction },
+}
+
+/// Entry is a block appended to the log by the leader of a term.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub term: u64,
+    pub block: Block,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// HardState is what a node remembers across restarts besides its log.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HardState {
+    pub term: u64,
+    pub voted_for: Option<Vec<u8>>,
+}
+
+impl HardState {
+    /// Reads the state persisted in the store, the zero state if there is none.
+    pub fn load(store: &dyn Store) -> io::Result<Self> {
+        match store.get(HARD_STATE_KEY)? {
+            Some(bytes) => serde_json::from_slice(&bytes).map_err(io::Error::other),
+            None => Ok(Self::default()),
+        }
+    }
+
+    pub fn save(&self, store: &dyn Store) -> io::Result<()> {
+        let bytes = serde_json::to_vec(self).map_err(io::Error::other)?;
+        store.put(HARD_STATE_KEY, &bytes)
+    }
+
+    pub fn voted_for(&self) -> Option<PeerId> {
+        self.voted_for
+            .as_ref()
+            .and_then(|peer| PeerId::from_bytes(peer).ok())
+    }
+}
+
+/// Log holds the entries of a node, indexes start at 1. Entries are written to the
+/// store as they are appended and deleted from it as they are truncated.
+pub struct Log {
+    entries: Vec<Entry>,
+    store: Arc<dyn Store>,
+}
+
+impl Log {
+    /// Returns an empty log written to the store.
+    pub fn new(store: Arc<dyn Store>) -> Self {
+        Self {
+            entries: Vec::new(),
+            store,
+        }
+    }
+
+    /// Reads the log persisted in the store.
+    pub fn open(store: Arc<dyn Store>) -> io::Result<Self> {
+        let entries = store
+            .iter_prefix(ENTRY_PREFIX)?
+            .into_iter()
+            .map(|(_, bytes)| serde_json::from_slice(&bytes).map_err(io::Error::other))
+            .collect::<io::Result<Vec<Entry>>>()?;
+        Ok(Self { entries, store })
+    }
+
+    pub fn last_index(&self) -> u64 {
+        self.entries.len() as u64
+    }
+
+    pub fn last_term(&self) -> u64 {
+        self.entries.last().map_or(0, |entry| entry.term)
+    }
+
+    pub fn get(&self, index: u64) -> Option<&Entry> {
+        index
+            .checked_sub(1)
+            .and_then(|position| self.entries.get(position as usize))
+    }
+
+    /// Returns the term of the entry at `index`, 0 before the first entry.
+    pub fn term(&self, index: u64) -> Option<u64> {
+        if index == 0 {
+            return Some(0);
+        }
+        self.get(index).map(|entry| entry.term)
+    }
+
+    /// Returns at most `max` entries from `index` on.
+    pub fn entries_from(&self, index: u64, max: usize) -> Vec<Entry> {
+        let start = (index.max(1) - 1) as usize;
+        self.entries.iter().skip(start).take(max).cloned().collect()
+    }
+
+    pub fn append(&mut self, entry: Entry) -> io::Result<()> {
+        let bytes = serde_json::to_vec(&entry).map_err(io::Error::other)?;
+        self.store.put(&entry_key(self.last_index() + 1), &bytes)?;
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    /// Removes the entries from `index` on and returns them.
+    pub fn truncate(&mut self, index: u64) -> io::Result<Vec<Entry>> {
+        let index = index.max(1);
+        let mut batch = Batch::new();
+        for removed in index..=self.last_index() {
+            batch.delete(&entry_key(removed));
+        }
+        self.store.write_batch(batch)?;
+        Ok(self.entries.split_off((index - 1) as usize))
+    }
+
+    /// A candidate whose log ends at `last_index` in `last_term` is at least as up to
+    /// date as this log, the last term deciding before the length.
+    pub fn is_up_to_date(&self, last_index: u64, last_term: u64) -> bool {
+        (last_term, last_index) >= (self.last_term(), self.last_index())
+    }
+}
+
+fn entry_key(index: u64) -> Vec<u8> {
+    let mut key = ENTRY_PREFIX.to_vec();
+    key.extend_from_slice(&index.to_be_bytes());
+    key
+}
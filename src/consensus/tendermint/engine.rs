@@ -0,0 +1,736 @@
+use crate::consensus::engine::{BlockVerdict, Context, Engine as EngineTrait};
+use crate::consensus::tendermint::tendermint::{
+    proposal_digest, vote_digest, Params, RoundVotes, Step, TendermintMessage, Validators, VoteKind,
+};
+use crate::metrics::recorder;
+use crate::network::messages::message::{Block, Transaction};
+use crate::network::messages::signing::{sign_as_node, verify_node_signature};
+use libp2p::PeerId;
+use log::{debug, error, info, warn};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How often the engine checks its timeouts.
+const TICK: Duration = Duration::from_millis(100);
+
+/// Engine runs a Tendermint validator. The validators are the nodes of the network,
+/// or the first `nodes` nodes seen when the node does not know them, sorted by peer
+/// id. A height starts a block interval after the previous
+/// one was committed, once there are transactions to order, and the committed blocks
+/// are appended to the chain with their precommits, blocks never travel with
+/// `publish_block`.
+#[derive(Clone)]
+pub struct Engine {
+    block_generation_interval: Duration,
+    params: Params,
+    nodes: usize,
+    context: Context,
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    // every peer seen connected, and the validators once settled
+    members: BTreeSet<PeerId>,
+    validators: Option<Arc<Validators>>,
+    // height being decided, one above the head of the chain
+    height: u64,
+    head: Option<Block>,
+    height_start: Duration,
+    round: u64,
+    step: Step,
+    // when the validator sends its messages of the round again if the round is still on
+    resend_at: Duration,
+    // block the validator precommitted last and the round of its polka
+    locked: Option<(u64, Block)>,
+    // last block with a polka the validator saw, proposed again by its next rounds
+    valid: Option<(u64, Block)>,
+    // proposal of every round of the height, with the round of its polka if proposed again
+    proposals: BTreeMap<u64, (Block, Option<u64>)>,
+    prevotes: BTreeMap<u64, RoundVotes>,
+    precommits: BTreeMap<u64, RoundVotes>,
+    timeouts: Vec<(Duration, Timeout)>,
+    // rounds in which the rules that only fire once already fired
+    prevote_timeouts: BTreeSet<u64>,
+    precommit_timeouts: BTreeSet<u64>,
+    polkas: BTreeSet<u64>,
+    // messages of the next height, handled once this one is committed
+    next_height: Vec<(PeerId, TendermintMessage)>,
+    // height last asked to a peer, and when
+    fetching: Option<(u64, Duration)>,
+    // transactions not committed yet, by hash so blocks do not depend on arrival order
+    pending: BTreeMap<Vec<u8>, Transaction>,
+    executed: BTreeSet<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Timeout {
+    Propose(u64),
+    Prevote(u64),
+    Precommit(u64),
+}
+
+impl EngineTrait for Engine {
+    fn run<'a>(&'a self) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> {
+        Box::pin(async move {
+            println!("Engine is running Tendermint");
+            loop {
+                self.context.clock.sleep(TICK).await;
+                self.tick();
+            }
+        })
+    }
+
+    fn add_transaction(&self, transaction: Transaction) {
+        let hash = transaction.hash();
+        let mut state = self.state.lock().unwrap();
+        if !state.executed.contains(&hash) {
+            state.pending.entry(hash).or_insert(transaction);
+        }
+    }
+
+    /// Tendermint blocks travel inside proposals, a block relayed on its own is not
+    /// committed by anything.
+    fn on_block(&self, _block: &Block, from: PeerId) -> BlockVerdict {
+        debug!("Ignoring block relayed by {from} outside of a proposal");
+        BlockVerdict::Reject
+    }
+
+    fn handle_message(&self, from: PeerId, bytes: Vec<u8>) {
+        let message = match serde_json::from_slice(&bytes) {
+            Ok(message) => message,
+            Err(e) => {
+                warn!("Failed to decode Tendermint message from {from}: {e}");
+                return;
+            }
+        };
+
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let Some(validators) = state.validators.clone() else {
+            return;
+        };
+        self.receive(state, &validators, from, message);
+        self.advance(state, &validators);
+    }
+
+    fn mempool_size(&self) -> usize {
+        self.state.lock().unwrap().pending.len()
+    }
+
+    fn state(&self) -> Value {
+        let state = self.state.lock().unwrap();
+        let proposer = state
+            .validators
+            .as_ref()
+            .map(|validators| validators.proposer(state.height, state.round).to_string());
+        json!({
+            "height": state.height,
+            "round": state.round,
+            "step": format!("{:?}", state.step).to_lowercase(),
+            "proposer": proposer,
+            "locked_round": state.locked.as_ref().map(|(round, _)| round),
+            "valid_round": state.valid.as_ref().map(|(round, _)| round),
+            "pending_transactions": state.pending.len(),
+        })
+    }
+}
+
+impl Engine {
+    pub fn new_engine(
+        interval: Duration,
+        params: Params,
+        nodes: usize,
+        context: Context,
+    ) -> Box<dyn EngineTrait> {
+        // a node resuming a persisted chain decides the height above its head
+        let head = context.chain.head().unwrap_or_else(|e| {
+            error!("Failed to read the chain head: {:?}", e);
+            None
+        });
+        let now = context.clock.now();
+
+        Box::new(Self {
+            block_generation_interval: interval,
+            params,
+            nodes,
+            state: Arc::new(Mutex::new(State {
+                members: BTreeSet::new(),
+                validators: None,
+                height: head.as_ref().map_or(0, height) + 1,
+                head,
+                height_start: now,
+                round: 0,
+                step: Step::NewHeight,
+                resend_at: now,
+                locked: None,
+                valid: None,
+                proposals: BTreeMap::new(),
+                prevotes: BTreeMap::new(),
+                precommits: BTreeMap::new(),
+                timeouts: Vec::new(),
+                prevote_timeouts: BTreeSet::new(),
+                precommit_timeouts: BTreeSet::new(),
+                polkas: BTreeSet::new(),
+                next_height: Vec::new(),
+                fetching: None,
+                pending: BTreeMap::new(),
+                executed: BTreeSet::new(),
+            })),
+            context,
+        })
+    }
+
+    // settles the validator set, fires the timeouts due and starts the height once there is work
+    fn tick(&self) {
+        let now = self.context.clock.now();
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        state.members.extend(self.context.network.connected_peers());
+
+        if state.validators.is_none() {
+            // the validators are the nodes of the network, or the peers seen when the
+            // node does not know them
+            let mut peers: BTreeSet<PeerId> = self.context.network.nodes().into_iter().collect();
+            if peers.is_empty() {
+                if state.members.len() + 1 < self.nodes {
+                    return;
+                }
+                peers = state.members.clone();
+                peers.insert(self.context.network.local_peer_id());
+            }
+            info!("Validator set of {} nodes", peers.len());
+            state.validators = Some(Arc::new(Validators::new(&peers)));
+        }
+        let Some(validators) = state.validators.clone() else {
+            return;
+        };
+
+        let (due, timeouts): (Vec<_>, Vec<_>) = std::mem::take(&mut state.timeouts)
+            .into_iter()
+            .partition(|(deadline, _)| *deadline <= now);
+        state.timeouts = timeouts;
+        for (_, timeout) in due {
+            self.on_timeout(state, &validators, timeout);
+        }
+
+        // other validators already started the height when their messages are in
+        let started_elsewhere = !state.proposals.is_empty()
+            || !state.prevotes.is_empty()
+            || !state.precommits.is_empty();
+        let waited = now - state.height_start >= self.block_generation_interval;
+        if state.step == Step::NewHeight
+            && (started_elsewhere || waited && !state.pending.is_empty())
+        {
+            self.start_round(state, &validators, 0);
+        }
+        if state.step != Step::NewHeight && now >= state.resend_at {
+            self.resend(state, &validators);
+        }
+        self.advance(state, &validators);
+    }
+
+    // sends the proposal and votes of the round again, the network may have lost them
+    // and the other validators may never move on without them
+    fn resend(&self, state: &mut State, validators: &Validators) {
+        let (height, round) = (state.height, state.round);
+        state.resend_at = self.context.clock.now() + self.params.timeout_propose(round);
+        debug!("Sending the messages of round {round} of height {height} again");
+        let me = self.context.network.local_peer_id();
+        if validators.proposer(height, round) == me {
+            if let Some((block, valid_round)) = state.proposals.get(&round) {
+                let signature = sign_as_node(
+                    &self.context.keypair,
+                    &proposal_digest(height, round, *valid_round, &block.hash()),
+                );
+                self.broadcast(&TendermintMessage::Proposal {
+                    height,
+                    round,
+                    valid_round: *valid_round,
+                    block: block.clone(),
+                    signature,
+                });
+            }
+        }
+        if let Some((hash, signature)) =
+            state.prevotes.get(&round).and_then(|votes| votes.vote(&me))
+        {
+            self.broadcast(&TendermintMessage::Prevote {
+                height,
+                round,
+                hash: hash.clone(),
+                signature: signature.clone(),
+            });
+        }
+        if let Some((hash, signature)) = state
+            .precommits
+            .get(&round)
+            .and_then(|votes| votes.vote(&me))
+        {
+            self.broadcast(&TendermintMessage::Precommit {
+                height,
+                round,
+                hash: hash.clone(),
+                signature: signature.clone(),
+            });
+        }
+    }
+
+    fn receive(
+        &self,
+        state: &mut State,
+        validators: &Validators,
+        from: PeerId,
+        message: TendermintMessage,
+    ) {
+        let message_height = match &message {
+            TendermintMessage::Proposal { height, .. }
+            | TendermintMessage::Prevote { height, .. }
+            | TendermintMessage::Precommit { height, .. } => *height,
+            TendermintMessage::GetCommit { height } => {
+                return self.handle_get_commit(from, *height);
+            }
+            TendermintMessage::Commit { block } => {
+                return self.handle_commit(state, validators, from, block.clone());
+            }
+        };
+        if message_height < state.height {
+            return;
+        }
+        if message_height > state.height {
+            self.fetch(state, from);
+            if message_height == state.height + 1 {
+                state.next_height.push((from, message));
+            }
+            return;
+        }
+
+        match message {
+            TendermintMessage::Proposal {
+                height,
+                round,
+                valid_round,
+                block,
+                signature,
+            } => {
+                let proposer = validators.proposer(height, round);
+                let digest = proposal_digest(height, round, valid_round, &block.hash());
+                if !verify_node_signature(&proposer, &digest, &signature) {
+                    warn!("Dropping proposal relayed by {from} not signed by the proposer of round {round}");
+                    return;
+                }
+                state.proposals.entry(round).or_insert((block, valid_round));
+            }
+            TendermintMessage::Prevote {
+                height,
+                round,
+                hash,
+                signature,
+            } => {
+                let digest = vote_digest(VoteKind::Prevote, height, round, hash.as_deref());
+                if self.verify_vote(validators, from, &digest, &signature) {
+                    let votes = state.prevotes.entry(round).or_default();
+                    votes.add(from, hash, signature);
+                }
+            }
+            TendermintMessage::Precommit {
+                height,
+                round,
+                hash,
+                signature,
+            } => {
+                let digest = vote_digest(VoteKind::Precommit, height, round, hash.as_deref());
+                if self.verify_vote(validators, from, &digest, &signature) {
+                    let votes = state.precommits.entry(round).or_default();
+                    votes.add(from, hash, signature);
+                }
+            }
+            TendermintMessage::GetCommit { .. } | TendermintMessage::Commit { .. } => {}
+        }
+    }
+
+    fn verify_vote(
+        &self,
+        validators: &Validators,
+        from: PeerId,
+        digest: &[u8],
+        signature: &[u8],
+    ) -> bool {
+        if !validators.contains(&from) {
+            debug!("Ignoring vote from {from}, which is not a validator");
+            return false;
+        }
+        if !verify_node_signature(&from, digest, signature) {
+            warn!("Dropping vote from {from} with an invalid signature");
+            return false;
+        }
+        true
+    }
+
+    // applies the rules of the algorithm until none applies
+    fn advance(&self, state: &mut State, validators: &Validators) {
+        while self.apply_rule(state, validators) {}
+    }
+
+    // applies the first rule whose conditions hold, returns false if none does
+    fn apply_rule(&self, state: &mut State, validators: &Validators) -> bool {
+        if state.step == Step::NewHeight {
+            return false;
+        }
+        let round = state.round;
+        let quorum = validators.quorum();
+
+        // a block precommitted by more than two thirds of the validators in any round is committed
+        let decided = state.proposals.iter().find_map(|(proposed, (block, _))| {
+            let hash = block.hash();
+            let votes = state.precommits.get(proposed)?;
+            (votes.count(Some(&hash)) >= quorum && self.is_valid(state, block))
+                .then(|| (block.clone(), votes.commit(*proposed, &hash)))
+        });
+        if let Some((mut block, commit)) = decided {
+            block.commit = Some(commit);
+            self.decide(state, block);
+            return true;
+        }
+
+        // more than a third of the validators in a later round means the validator fell behind
+        let later: BTreeSet<u64> = state
+            .prevotes
+            .keys()
+            .chain(state.precommits.keys())
+            .copied()
+            .filter(|later| *later > round)
+            .collect();
+        for later in later {
+            if self.voters(state, later) >= validators.one_third() {
+                debug!("Skipping to round {later} of height {}", state.height);
+                self.start_round(state, validators, later);
+                return true;
+            }
+        }
+
+        let proposal = state.proposals.get(&round).cloned();
+        let prevotes = state.prevotes.get(&round);
+        let prevoters = prevotes.map_or(0, RoundVotes::voters);
+        let nil_prevotes = prevotes.map_or(0, |votes| votes.count(None));
+        let precommitters = state.precommits.get(&round).map_or(0, RoundVotes::voters);
+
+        if state.step == Step::Propose {
+            if let Some((block, valid_round)) = &proposal {
+                let hash = block.hash();
+                // a block proposed again needs the polka of its earlier round
+                let justified = valid_round.is_none_or(|valid_round| {
+                    valid_round < round && self.has_polka(state, quorum, valid_round, &hash)
+                });
+                let lock_allows = state.locked.as_ref().is_none_or(|(locked_round, locked)| {
+                    locked.hash() == hash
+                        || valid_round.is_some_and(|valid_round| *locked_round <= valid_round)
+                });
+                if justified {
+                    let vote = (self.is_valid(state, block) && lock_allows).then_some(hash);
+                    self.prevote(state, vote);
+                    return true;
+                }
+            }
+        }
+
+        if state.step == Step::Prevote
+            && prevoters >= quorum
+            && state.prevote_timeouts.insert(round)
+        {
+            self.schedule(state, Timeout::Prevote(round));
+            return true;
+        }
+
+        if state.step >= Step::Prevote {
+            if let Some((block, _)) = &proposal {
+                let hash = block.hash();
+                if self.has_polka(state, quorum, round, &hash)
+                    && self.is_valid(state, block)
+                    && state.polkas.insert(round)
+                {
+                    if state.step == Step::Prevote {
+                        state.locked = Some((round, block.clone()));
+                        self.precommit(state, Some(hash));
+                    }
+                    state.valid = Some((round, block.clone()));
+                    return true;
+                }
+            }
+        }
+
+        if state.step == Step::Prevote && nil_prevotes >= quorum {
+            self.precommit(state, None);
+            return true;
+        }
+
+        if precommitters >= quorum && state.precommit_timeouts.insert(round) {
+            self.schedule(state, Timeout::Precommit(round));
+            return true;
+        }
+        false
+    }
+
+    // more than two thirds of the validators prevoted for the block with the hash in the round
+    fn has_polka(&self, state: &State, quorum: usize, round: u64, hash: &[u8]) -> bool {
+        state
+            .prevotes
+            .get(&round)
+            .is_some_and(|votes| votes.count(Some(hash)) >= quorum)
+    }
+
+    // validators that voted in the round
+    fn voters(&self, state: &State, round: u64) -> usize {
+        let mut voters: BTreeSet<PeerId> = BTreeSet::new();
+        for votes in [state.prevotes.get(&round), state.precommits.get(&round)]
+            .into_iter()
+            .flatten()
+        {
+            voters.extend(votes.validators());
+        }
+        voters.len()
+    }
+
+    fn start_round(&self, state: &mut State, validators: &Validators, round: u64) {
+        state.round = round;
+        state.step = Step::Propose;
+        state.resend_at = self.context.clock.now() + self.params.timeout_propose(round);
+        let me = self.context.network.local_peer_id();
+        if validators.proposer(state.height, round) != me {
+            self.schedule(state, Timeout::Propose(round));
+            return;
+        }
+
+        let (block, valid_round) = match &state.valid {
+            Some((valid_round, block)) => (block.clone(), Some(*valid_round)),
+            None => {
+                let transactions = state.pending.values().cloned().collect();
+                let mut block = Block::new_block(
+                    state.head.as_ref(),
+                    me,
+                    self.context.clock.timestamp(),
+                    transactions,
+                );
+                block.sign(&self.context.keypair);
+                recorder::block_included(&block);
+                (block, None)
+            }
+        };
+        info!(
+            "Proposing block {} at height {} in round {round}",
+            hex::encode(block.hash()),
+            state.height
+        );
+        let signature = sign_as_node(
+            &self.context.keypair,
+            &proposal_digest(state.height, round, valid_round, &block.hash()),
+        );
+        self.broadcast(&TendermintMessage::Proposal {
+            height: state.height,
+            round,
+            valid_round,
+            block: block.clone(),
+            signature,
+        });
+        state.proposals.insert(round, (block, valid_round));
+    }
+
+    fn prevote(&self, state: &mut State, hash: Option<Vec<u8>>) {
+        state.step = Step::Prevote;
+        let (height, round) = (state.height, state.round);
+        let signature = sign_as_node(
+            &self.context.keypair,
+            &vote_digest(VoteKind::Prevote, height, round, hash.as_deref()),
+        );
+        self.broadcast(&TendermintMessage::Prevote {
+            height,
+            round,
+            hash: hash.clone(),
+            signature: signature.clone(),
+        });
+        let me = self.context.network.local_peer_id();
+        state
+            .prevotes
+            .entry(round)
+            .or_default()
+            .add(me, hash, signature);
+    }
+
+    fn precommit(&self, state: &mut State, hash: Option<Vec<u8>>) {
+        state.step = Step::Precommit;
+        let (height, round) = (state.height, state.round);
+        let signature = sign_as_node(
+            &self.context.keypair,
+            &vote_digest(VoteKind::Precommit, height, round, hash.as_deref()),
+        );
+        self.broadcast(&TendermintMessage::Precommit {
+            height,
+            round,
+            hash: hash.clone(),
+            signature: signature.clone(),
+        });
+        let me = self.context.network.local_peer_id();
+        state
+            .precommits
+            .entry(round)
+            .or_default()
+            .add(me, hash, signature);
+    }
+
+    fn schedule(&self, state: &mut State, timeout: Timeout) {
+        let duration = match timeout {
+            Timeout::Propose(round) => self.params.timeout_propose(round),
+            Timeout::Prevote(round) => self.params.timeout_prevote(round),
+            Timeout::Precommit(round) => self.params.timeout_precommit(round),
+        };
+        state
+            .timeouts
+            .push((self.context.clock.now() + duration, timeout));
+    }
+
+    fn on_timeout(&self, state: &mut State, validators: &Validators, timeout: Timeout) {
+        match timeout {
+            Timeout::Propose(round) if round == state.round && state.step == Step::Propose => {
+                info!("No proposal in round {round} of height {}", state.height);
+                self.prevote(state, None);
+            }
+            Timeout::Prevote(round) if round == state.round && state.step == Step::Prevote => {
+                self.precommit(state, None);
+            }
+            Timeout::Precommit(round) if round == state.round => {
+                info!("Round {round} of height {} timed out", state.height);
+                self.start_round(state, validators, round + 1);
+            }
+            _ => {}
+        }
+    }
+
+    // the block extends the head and is signed by the validator that created it
+    fn is_valid(&self, state: &State, block: &Block) -> bool {
+        block.verify(state.head.as_ref()).is_ok() && block.verify_signature()
+    }
+
+    fn decide(&self, state: &mut State, block: Block) {
+        if let Err(e) = block.verify_parent(state.head.as_ref()) {
+            error!("Committed block does not extend the chain: {e}");
+            return;
+        }
+        info!(
+            "Committed block {} at height {} in round {}",
+            hex::encode(block.hash()),
+            state.height,
+            block.commit.as_ref().map_or(0, |commit| commit.round)
+        );
+        for transaction in &block.transactions {
+            let hash = transaction.hash();
+            state.pending.remove(&hash);
+            state.executed.insert(hash);
+        }
+        self.context.chain.append(&block);
+
+        state.height += 1;
+        state.head = Some(block);
+        state.height_start = self.context.clock.now();
+        state.round = 0;
+        state.step = Step::NewHeight;
+        state.locked = None;
+        state.valid = None;
+        state.proposals.clear();
+        state.prevotes.clear();
+        state.precommits.clear();
+        state.timeouts.clear();
+        state.prevote_timeouts.clear();
+        state.precommit_timeouts.clear();
+        state.polkas.clear();
+
+        let Some(validators) = state.validators.clone() else {
+            return;
+        };
+        for (from, message) in std::mem::take(&mut state.next_height) {
+            self.receive(state, &validators, from, message);
+        }
+    }
+
+    // asks a peer ahead of this node for the commit of the height being decided
+    fn fetch(&self, state: &mut State, from: PeerId) {
+        let now = self.context.clock.now();
+        let recent = state.fetching.is_some_and(|(height, asked)| {
+            height == state.height && now - asked < self.params.timeout_propose(0)
+        });
+        if recent {
+            return;
+        }
+        debug!("Fetching the commit of height {} from {from}", state.height);
+        state.fetching = Some((state.height, now));
+        self.send(
+            from,
+            &TendermintMessage::GetCommit {
+                height: state.height,
+            },
+        );
+    }
+
+    fn handle_get_commit(&self, from: PeerId, height: u64) {
+        match self.context.chain.block(height) {
+            Ok(Some(block)) if block.commit.is_some() => {
+                self.send(from, &TendermintMessage::Commit { block });
+            }
+            Ok(_) => {}
+            Err(e) => error!("Failed to read block {height}: {:?}", e),
+        }
+    }
+
+    // a block fetched with its commit is committed if the commit holds
+    fn handle_commit(
+        &self,
+        state: &mut State,
+        validators: &Validators,
+        from: PeerId,
+        block: Block,
+    ) {
+        if height(&block) != state.height {
+            return;
+        }
+        if !validators.verify_commit(&block, state.height) || !self.is_valid(state, &block) {
+            warn!("Ignoring block from {from} without a valid commit");
+            return;
+        }
+        self.decide(state, block);
+    }
+
+    fn broadcast(&self, message: &TendermintMessage) {
+        let bytes = serde_json::to_vec(message).expect("Failed to serialize Tendermint message");
+        self.context.network.broadcast(bytes);
+    }
+
+    fn send(&self, peer: PeerId, message: &TendermintMessage) {
+        let bytes = serde_json::to_vec(message).expect("Failed to serialize Tendermint message");
+        self.context.network.send_to(peer, bytes);
+    }
+}
+
+fn height(block: &Block) -> u64 {
+    block
+        .header
+        .as_ref()
+        .map_or(0, |header| header.index as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::rng;
+    use crate::testing::{assert_agree, simulate};
+
+    #[test]
+    fn the_validators_agree_on_the_committed_blocks() {
+        let _rng = rng::exclusive();
+        let chains = simulate(4, 7, 60, |_, context| {
+            Engine::new_engine(Duration::from_secs(5), Params::default(), 4, context)
+        });
+        assert_agree(&chains, 5);
+    }
+}
@@ -0,0 +1,266 @@
+/*
+Tendermint decides one block per height in rounds of three steps. The proposer of
+the round proposes a block, the validators prevote for it if it is valid and they
+are not locked on another block, and once a validator saw prevotes of more than two
+thirds of the validators for the block, a polka, it locks on it and precommits it.
+The block is committed once more than two thirds of the validators precommitted it
+in the same round. A round that makes no progress times out into the next one, the
+timeouts growing with the round, and a locked validator only prevotes another block
+proposed with a polka newer than its lock, so two blocks are never committed at the
+same height.
+
+Here the proposer of a round is picked in turn among the validators, every validator
+votes with the same power, and the precommits that committed a block are stored
+with it on the chain so any node can check the commit.
+*/
+
+use crate::network::messages::message::{Block, Commit, CommitSignature};
+use crate::network::messages::signing::verify_node_signature;
+use crate::CunnerError;
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+
+/// Tuning parameters for the algorithm, set in the `tendermint` section of the
+/// engine configuration.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Params {
+    /// Milliseconds a validator waits for the proposal of the first round.
+    pub timeout_propose_ms: u64,
+    /// Milliseconds a validator waits for a polka once it saw prevotes of more than two
+    /// thirds of the validators, for the first round.
+    pub timeout_prevote_ms: u64,
+    /// Milliseconds a validator waits for a commit once it saw precommits of more than
+    /// two thirds of the validators, for the first round.
+    pub timeout_precommit_ms: u64,
+    /// Milliseconds added to every timeout by each round after the first.
+    pub timeout_delta_ms: u64,
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Self {
+            timeout_propose_ms: 3_000,
+            timeout_prevote_ms: 1_000,
+            timeout_precommit_ms: 1_000,
+            timeout_delta_ms: 500,
+        }
+    }
+}
+
+impl Params {
+    pub fn validate(&self) -> Result<(), CunnerError> {
+        let invalid = |message: &str| Err(CunnerError::Config(format!("tendermint: {message}")));
+        if self.timeout_propose_ms == 0 {
+            return invalid("timeout_propose_ms must be at least 1");
+        }
+        if self.timeout_prevote_ms == 0 {
+            return invalid("timeout_prevote_ms must be at least 1");
+        }
+        if self.timeout_precommit_ms == 0 {
+            return invalid("timeout_precommit_ms must be at least 1");
+        }
+        Ok(())
+    }
+
+    pub fn timeout_propose(&self, round: u64) -> Duration {
+        self.timeout(self.timeout_propose_ms, round)
+    }
+
+    pub fn timeout_prevote(&self, round: u64) -> Duration {
+        self.timeout(self.timeout_prevote_ms, round)
+    }
+
+    pub fn timeout_precommit(&self, round: u64) -> Duration {
+        self.timeout(self.timeout_precommit_ms, round)
+    }
+
+    fn timeout(&self, base_ms: u64, round: u64) -> Duration {
+        Duration::from_millis(base_ms + round * self.timeout_delta_ms)
+    }
+}
+
+/// Messages exchanged by the Tendermint validators, sent as json over the network.
+/// Proposals and votes are signed with the identity of their validator, since their
+/// signatures end up in the commits stored on the chain.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum TendermintMessage {
+    /// the proposer of the round proposes a block, with the round of the polka it saw
+    /// for it if it proposes a block of an earlier round again
+    Proposal {
+        height: u64,
+        round: u64,
+        valid_round: Option<u64>,
+        block: Block,
+        signature: Vec<u8>,
+    },
+    /// a validator prevotes for the block with the hash, or for none
+    Prevote {
+        height: u64,
+        round: u64,
+        hash: Option<Vec<u8>>,
+        signature: Vec<u8>,
+    },
+    /// a validator precommits the block with the hash, or none
+    Precommit {
+        height: u64,
+        round: u64,
+        hash: Option<Vec<u8>>,
+        signature: Vec<u8>,
+    },
+    /// asks a node for the committed block of the height
+    GetCommit { height: u64 },
+    /// answers with the block and the precommits that committed it
+    Commit { block: Block },
+}
+
+/// Step of a validator within a round.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Step {
+    /// waiting for the first round of the height to start
+    NewHeight,
+    Propose,
+    Prevote,
+    Precommit,
+}
+
+/// VoteKind tells prevotes and precommits apart in what a validator signs.
+#[derive(Debug, Clone, Copy)]
+pub enum VoteKind {
+    Prevote,
+    Precommit,
+}
+
+/// Validators is the validator set of a run, every validator votes with the same power.
+#[derive(Debug)]
+pub struct Validators {
+    validators: Vec<PeerId>,
+}
+
+impl Validators {
+    pub fn new(peers: &BTreeSet<PeerId>) -> Self {
+        Self {
+            validators: peers.iter().copied().collect(),
+        }
+    }
+
+    pub fn contains(&self, peer: &PeerId) -> bool {
+        self.validators.contains(peer)
+    }
+
+    /// Returns the proposer of the round, the validators take turns across rounds and
+    /// heights.
+    pub fn proposer(&self, height: u64, round: u64) -> PeerId {
+        let turn = (height + round) % self.validators.len() as u64;
+        self.validators[turn as usize]
+    }
+
+    /// Number of validators that make more than two thirds of them.
+    pub fn quorum(&self) -> usize {
+        self.validators.len() * 2 / 3 + 1
+    }
+
+    /// Number of validators that make more than a third of them, at least one of them
+    /// honest.
+    pub fn one_third(&self) -> usize {
+        self.validators.len() / 3 + 1
+    }
+
+    /// The block holds precommits of more than two thirds of the validators for it at
+    /// the height.
+    pub fn verify_commit(&self, block: &Block, height: u64) -> bool {
+        let Some(commit) = &block.commit else {
+            return false;
+        };
+        let hash = block.hash();
+        let digest = vote_digest(VoteKind::Precommit, height, commit.round, Some(&hash));
+        let voters: BTreeSet<PeerId> = commit
+            .signatures
+            .iter()
+            .filter_map(|signature| {
+                let validator = PeerId::from_bytes(&signature.validator).ok()?;
+                (self.contains(&validator)
+                    && verify_node_signature(&validator, &digest, &signature.signature))
+                .then_some(validator)
+            })
+            .collect();
+        voters.len() >= self.quorum()
+    }
+}
+
+/// RoundVotes holds the first vote of every validator of a round and step.
+#[derive(Debug, Default)]
+pub struct RoundVotes {
+    votes: BTreeMap<PeerId, (Option<Vec<u8>>, Vec<u8>)>,
+}
+
+impl RoundVotes {
+    pub fn add(&mut self, from: PeerId, hash: Option<Vec<u8>>, signature: Vec<u8>) {
+        self.votes.entry(from).or_insert((hash, signature));
+    }
+
+    pub fn validators(&self) -> impl Iterator<Item = &PeerId> {
+        self.votes.keys()
+    }
+
+    /// Returns the hash the validator voted for and its signature.
+    pub fn vote(&self, validator: &PeerId) -> Option<&(Option<Vec<u8>>, Vec<u8>)> {
+        self.votes.get(validator)
+    }
+
+    /// Number of validators that voted, for anything.
+    pub fn voters(&self) -> usize {
+        self.votes.len()
+    }
+
+    /// Number of validators that voted for the hash, or for none.
+    pub fn count(&self, hash: Option<&[u8]>) -> usize {
+        self.votes
+            .values()
+            .filter(|(voted, _)| voted.as_deref() == hash)
+            .count()
+    }
+
+    /// Returns the votes for the block with the hash, as the commit of the round.
+    pub fn commit(&self, round: u64, hash: &[u8]) -> Commit {
+        Commit {
+            round,
+            signatures: self
+                .votes
+                .iter()
+                .filter(|(_, (voted, _))| voted.as_deref() == Some(hash))
+                .map(|(validator, (_, signature))| CommitSignature {
+                    validator: validator.to_bytes(),
+                    signature: signature.clone(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Returns what a validator signs to vote for the block with the hash, or none.
+pub fn vote_digest(kind: VoteKind, height: u64, round: u64, hash: Option<&[u8]>) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(match kind {
+        VoteKind::Prevote => b"tendermint prevote".as_slice(),
+        VoteKind::Precommit => b"tendermint precommit".as_slice(),
+    });
+    hasher.update(height.to_le_bytes());
+    hasher.update(round.to_le_bytes());
+    hasher.update(hash.unwrap_or_default());
+    hasher.finalize().to_vec()
+}
+
+/// Returns what the proposer of a round signs to propose the block with the hash.
+pub fn proposal_digest(height: u64, round: u64, valid_round: Option<u64>, hash: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(b"tendermint proposal");
+    hasher.update(height.to_le_bytes());
+    hasher.update(round.to_le_bytes());
+    hasher.update(valid_round.unwrap_or(u64::MAX).to_le_bytes());
+    hasher.update(hash);
+    hasher.finalize().to_vec()
+}
@@ -1,38 +1,135 @@
+mod byzantine {
+    pub mod adversary;
+    pub mod transport;
+}
+
+mod cluster {
+    pub mod launcher;
+}
+
+mod config {
+    pub mod experiment;
+}
+
 mod consensus {
     pub mod example {
         pub mod engine;
     }
+    pub mod chain;
     pub mod engine;
     pub mod avalanche {
+        #[allow(clippy::module_inception)]
+        pub mod avalanche;
+        pub mod engine;
+    }
+    pub mod pbft {
+        pub mod engine;
+        #[allow(clippy::module_inception)]
+        pub mod pbft;
+    }
+    pub mod raft {
+        pub mod engine;
+        #[allow(clippy::module_inception)]
+        pub mod raft;
+    }
+    pub mod pow {
+        pub mod engine;
+        #[allow(clippy::module_inception)]
+        pub mod pow;
+    }
+    pub mod pos {
+        pub mod engine;
+        #[allow(clippy::module_inception)]
+        pub mod pos;
+    }
+    pub mod hotstuff {
+        pub mod engine;
+        #[allow(clippy::module_inception)]
+        pub mod hotstuff;
+    }
+    pub mod tendermint {
         pub mod engine;
+        #[allow(clippy::module_inception)]
+        pub mod tendermint;
     }
 }
 
+mod metrics {
+    pub mod invariants;
+    pub mod prometheus;
+    pub mod recorder;
+    pub mod report;
+}
+
 mod network {
+    pub mod faults;
     pub mod peer;
+    pub mod rpc;
+    pub mod transport;
     pub mod messages {
+        #[allow(clippy::module_inception)]
         pub mod message; // generated by protobuf
+        #[allow(clippy::module_inception)]
         pub mod messages;
         pub mod protobuf;
+        pub mod signing;
     }
 }
 
+mod simulation {
+    pub mod clock;
+    pub mod replay;
+    pub mod rng;
+    pub mod scheduler;
+    pub mod simulator;
+    pub mod trace;
+    pub mod transport;
+}
+
 mod storage {
+    pub mod chain_store;
+    pub mod log_store;
     pub mod store;
 }
 
+mod workload {
+    pub mod generator;
+}
+
+#[cfg(test)]
+mod testing;
+
+use byzantine::adversary::{new_adversary, Strategy};
 use clap::{Parser, Subcommand, ValueEnum};
-use consensus::engine::Engine;
-use log::{debug, error, info};
-use network::peer::run_peer;
+use cluster::launcher::{run_cluster, ClusterConfig, Topology};
+use config::experiment::PeerConfig;
+use consensus::chain::Chain;
+use consensus::engine::{Context, Engine};
+use libp2p::PeerId;
+use log::{debug, info, warn};
+use metrics::invariants::{check, History, InvariantReport};
+use metrics::recorder;
+use network::messages::signing::{load_or_generate_identity, SigningKey};
+use network::peer::{run_peer, SwarmTransport};
+use network::transport::Network;
+use serde::{Deserialize, Serialize};
+use simulation::clock::Clock;
+use simulation::replay::Replay;
+use simulation::simulator::{Simulation, SimulationConfig};
+use simulation::trace::{read_trace, Recording, TraceHeader, TraceWriter};
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
+use storage::chain_store::ChainStore;
+use storage::log_store::LogStore;
 use thiserror::Error;
+use tracing::info_span;
 use tracing_subscriber::EnvFilter;
+use workload::generator::Generator;
 
 #[derive(Parser)]
 #[command(about = "Pluggable blockchain consensus simulation framework", long_about = None)]
-
 struct Cli {
     #[command(subcommand)]
     command: Commands,
@@ -45,24 +142,227 @@ enum Commands {
     Node {
         #[arg(long, help = "TCP address to bind to")]
         tcp: Option<u16>,
-        // #[arg(long, help = "Private key for the node for creating the transaction")]
-        // private_key: Option<secp256k1::SecretKey>,
+        #[arg(
+            long,
+            help = "Port the JSON-RPC API of the node is served on, on localhost, no API if not set"
+        )]
+        rpc_port: Option<u16>,
+        #[arg(
+            long,
+            help = "Port the Prometheus metrics of the node are served on at /metrics, on localhost, none if not set"
+        )]
+        metrics_port: Option<u16>,
+        #[arg(
+            long,
+            help = "Hex encoded secp256k1 key the node signs its transactions with, a new one if neither it nor --key-file is set"
+        )]
+        private_key: Option<String>,
+        #[arg(
+            long,
+            conflicts_with = "private_key",
+            help = "File holding the hex encoded secp256k1 key the node signs its transactions with"
+        )]
+        key_file: Option<PathBuf>,
+        #[arg(
+            long,
+            help = "File holding the hex encoded ed25519 key of the node identity, generated if it does not exist, a new identity if not set"
+        )]
+        identity_file: Option<PathBuf>,
+        #[arg(
+            long,
+            num_args = 1..,
+            help = "Peer ids of the nodes of the network, in the order the faults of the experiment name them by index"
+        )]
+        peers: Vec<String>,
         #[arg(long, help = "Consensus engine to use")]
         engine: Option<DefinedEngines>,
+        #[arg(
+            long,
+            help = "Number of nodes in the network, engines count their majorities over it [default: 4]"
+        )]
+        nodes: Option<usize>,
+        #[arg(
+            long,
+            help = "Directory the chain is persisted in, a node restarted with it resumes its chain"
+        )]
+        data_dir: Option<PathBuf>,
+        #[arg(
+            long,
+            help = "File the metrics report is written to on shutdown, CSV if it ends with .csv, JSON otherwise"
+        )]
+        report: Option<PathBuf>,
+        #[arg(
+            long,
+            help = "File the committed chain and transaction times of the node are written to on shutdown, for the invariant checker"
+        )]
+        history: Option<PathBuf>,
+        #[arg(
+            long,
+            help = "File the transactions submitted to the node and the messages it receives are recorded to, for cunner replay"
+        )]
+        record: Option<PathBuf>,
+        #[arg(
+            long,
+            num_args = 1..,
+            help = "Multiaddr of a peer to dial on startup, such as /ip4/127.0.0.1/tcp/4001, added to the ones of the experiment"
+        )]
+        bootstrap: Vec<String>,
+        #[arg(
+            long,
+            help = "Do not discover the peers on the local network with mDNS"
+        )]
+        no_mdns: bool,
+        #[arg(
+            long,
+            help = "Discover the peers of the bootstrap peers through a Kademlia DHT"
+        )]
+        kademlia: bool,
+        #[arg(
+            long,
+            help = "Generator spacing the transactions submitted to the nodes [default: constant]"
+        )]
+        workload: Option<Generator>,
+        #[arg(
+            long,
+            help = "Transactions per second submitted to the whole network, one per node every transaction interval if not set"
+        )]
+        tps: Option<f64>,
+        #[arg(
+            long,
+            help = "Run the node as a byzantine one with the strategy, whatever the experiment sets"
+        )]
+        byzantine: Option<Strategy>,
+        #[arg(long, help = "JSON experiment file, the flags override it")]
+        config: Option<PathBuf>,
+    },
+    /// Start a local network of cunner nodes, one process per node
+    Cluster {
+        #[arg(long, help = "Number of nodes [default: 4]")]
+        nodes: Option<u16>,
+        #[arg(long, help = "Consensus engine to use")]
+        engine: Option<DefinedEngines>,
+        #[arg(
+            long,
+            default_value_t = 4001,
+            help = "TCP port of the first node, the others get the next ports"
+        )]
+        base_port: u16,
+        #[arg(
+            long,
+            help = "Port of the JSON-RPC API of the first node, the others get the next ports, no API if not set"
+        )]
+        rpc_base_port: Option<u16>,
+        #[arg(
+            long,
+            help = "Port of the Prometheus metrics of the first node, the others get the next ports, none if not set"
+        )]
+        metrics_base_port: Option<u16>,
+        #[arg(long, help = "Seconds to run the cluster for, until Ctrl-C if not set")]
+        duration: Option<u64>,
+        #[arg(
+            long,
+            help = "JSON experiment file passed to every node, the flags override it"
+        )]
+        config: Option<PathBuf>,
+        #[arg(
+            long,
+            default_value = "cluster",
+            help = "Directory the log and metrics report of every node are written to"
+        )]
+        log_dir: PathBuf,
+        #[arg(
+            long,
+            help = "Record the inputs of every node to node-<i>.trace in the log directory, for cunner replay"
+        )]
+        record: bool,
+        #[arg(
+            long,
+            help = "How the nodes are connected, every node only dials its neighbours without mDNS unless it is full [default: full]"
+        )]
+        topology: Option<Topology>,
+        #[arg(
+            long,
+            help = "Generator spacing the transactions submitted to the nodes [default: constant]"
+        )]
+        workload: Option<Generator>,
+        #[arg(
+            long,
+            help = "Transactions per second submitted to the whole network, one per node every transaction interval if not set"
+        )]
+        tps: Option<f64>,
+        #[arg(
+            long,
+            help = "Strategy of the byzantine nodes, the ones listed in the experiment [default: node 0]"
+        )]
+        byzantine: Option<Strategy>,
+    },
+    /// Simulate a network of cunner nodes in a single process, in virtual time
+    Simulate {
+        #[arg(long, help = "Number of simulated nodes [default: 4]")]
+        nodes: Option<usize>,
+        #[arg(long, help = "Consensus engine to use")]
+        engine: Option<DefinedEngines>,
+        #[arg(
+            long,
+            help = "Seed driving every random choice of the run [default: 0]"
+        )]
+        seed: Option<u64>,
+        #[arg(long, help = "Virtual duration of the run in seconds [default: 120]")]
+        duration: Option<u64>,
+        #[arg(long, help = "Latency between nodes in milliseconds [default: 50]")]
+        latency: Option<u64>,
+        #[arg(
+            long,
+            help = "File the metrics report is written to, CSV if it ends with .csv, JSON otherwise"
+        )]
+        report: Option<PathBuf>,
+        #[arg(
+            long,
+            help = "File the report of the invariant checker is written to, as JSON"
+        )]
+        invariants: Option<PathBuf>,
+        #[arg(
+            long,
+            help = "Directory the inputs of every node are recorded to as node-<i>.trace, for cunner replay"
+        )]
+        record: Option<PathBuf>,
+        #[arg(
+            long,
+            help = "Generator spacing the transactions submitted to the nodes [default: constant]"
+        )]
+        workload: Option<Generator>,
+        #[arg(
+            long,
+            help = "Transactions per second submitted to the whole network, one per node every transaction interval if not set"
+        )]
+        tps: Option<f64>,
+        #[arg(
+            long,
+            help = "Strategy of the byzantine nodes, the ones listed in the experiment [default: node 0]"
+        )]
+        byzantine: Option<Strategy>,
+        #[arg(long, help = "JSON experiment file, the flags override it")]
+        config: Option<PathBuf>,
+    },
+    /// Replay the trace of a node into a fresh engine and check it commits the same blocks
+    Replay {
+        #[arg(help = "Trace recorded with --record")]
+        trace: PathBuf,
     },
 }
 
-#[derive(Clone, ValueEnum, Debug)]
+#[derive(Clone, ValueEnum, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 enum DefinedEngines {
     Example,
-    Avalanche, // TODO: engine not implemented
-               // add more of your own!
-}
-
-#[derive(Debug)]
-pub struct PeerConfig {
-    tcp_listen_address: Option<u16>,
-    // private_key: Option<secp256k1::SecretKey>,
+    Avalanche,
+    Pbft,
+    Raft,
+    Pow,
+    Pos,
+    Hotstuff,
+    Tendermint,
+    // add more of your own!
 }
 
 #[derive(Error, Debug)]
@@ -85,49 +385,228 @@ fn main() -> Result<(), CunnerError> {
     match cli.command {
         Commands::Node {
             tcp,
-            // private_key,
+            rpc_port,
+            metrics_port,
+            private_key,
+            key_file,
+            identity_file,
+            peers,
             engine,
+            nodes,
+            data_dir,
+            report,
+            history,
+            record,
+            bootstrap,
+            no_mdns,
+            kademlia,
+            workload,
+            tps,
+            byzantine,
+            config,
         } => {
             info!("Starting peer with TCP: {:?}, Engine: {:?}", tcp, engine);
-            start_peer(tcp, engine)?;
+            let mut configuration = PeerConfig::load(config.as_deref())?;
+            configuration.tcp_listen_address = tcp.or(configuration.tcp_listen_address);
+            configuration.rpc_port = rpc_port.or(configuration.rpc_port);
+            configuration.metrics_port = metrics_port.or(configuration.metrics_port);
+            configuration.engine.name = engine.or(configuration.engine.name);
+            configuration.network.nodes = nodes.unwrap_or(configuration.network.nodes);
+            configuration.network.bootstrap.extend(bootstrap);
+            if !peers.is_empty() {
+                configuration.network.peers = peers;
+            }
+            configuration.network.mdns &= !no_mdns;
+            configuration.network.kademlia |= kademlia;
+            configuration.workload.generator = workload.unwrap_or(configuration.workload.generator);
+            configuration.workload.tps = tps.or(configuration.workload.tps);
+            if let Some(private_key) = private_key {
+                configuration.private_key = SigningKey::from_hex(&private_key)?;
+            } else if let Some(key_file) = key_file {
+                configuration.private_key = SigningKey::from_file(&key_file)?;
+            }
+            if let Some(identity_file) = identity_file {
+                configuration.keypair = load_or_generate_identity(&identity_file)?;
+            }
+            configuration.validate()?;
+            start_peer(configuration, data_dir, report, history, record, byzantine)?;
         }
+        Commands::Cluster {
+            nodes,
+            engine,
+            base_port,
+            rpc_base_port,
+            metrics_base_port,
+            duration,
+            config,
+            log_dir,
+            record,
+            topology,
+            workload,
+            tps,
+            byzantine,
+        } => {
+            let mut configuration = PeerConfig::load(config.as_deref())?;
+            if let Some(nodes) = nodes {
+                configuration.network.nodes = nodes.into();
+            }
+            configuration.engine.name = engine.or(configuration.engine.name);
+            configuration.duration_secs = duration.or(configuration.duration_secs);
+            configuration.network.topology = topology.unwrap_or(configuration.network.topology);
+            configuration.workload.generator = workload.unwrap_or(configuration.workload.generator);
+            configuration.workload.tps = tps.or(configuration.workload.tps);
+            configuration.byzantine.strategy = byzantine.or(configuration.byzantine.strategy);
+            configuration.validate()?;
+            start_cluster(
+                configuration,
+                config,
+                base_port,
+                rpc_base_port,
+                metrics_base_port,
+                log_dir,
+                record,
+            )?;
+        }
+        Commands::Simulate {
+            nodes,
+            engine,
+            seed,
+            duration,
+            latency,
+            report,
+            invariants,
+            record,
+            workload,
+            tps,
+            byzantine,
+            config,
+        } => {
+            let mut configuration = PeerConfig::load(config.as_deref())?;
+            configuration.network.nodes = nodes.unwrap_or(configuration.network.nodes);
+            configuration.engine.name = engine.or(configuration.engine.name);
+            configuration.seed = seed.unwrap_or(configuration.seed);
+            configuration.duration_secs = duration.or(configuration.duration_secs);
+            configuration.network.latency_ms = latency.unwrap_or(configuration.network.latency_ms);
+            configuration.workload.generator = workload.unwrap_or(configuration.workload.generator);
+            configuration.workload.tps = tps.or(configuration.workload.tps);
+            configuration.byzantine.strategy = byzantine.or(configuration.byzantine.strategy);
+            configuration.validate()?;
+            simulate(configuration, report, invariants, record)?;
+        }
+        Commands::Replay { trace } => replay(&trace)?,
     }
 
     Ok(())
 }
 
-// initializes the consensus engine based on the provided option, sets up the peer configuration, and starts the network operations.
-fn start_peer(
-    tcp: Option<u16>,
-    // private_key: Option<secp256k1::SecretKey>,
-    engine: Option<DefinedEngines>,
-) -> Result<(), CunnerError> {
-    let engine_instance: Arc<Mutex<Option<Box<dyn Engine>>>> = Arc::new(Mutex::new(None));
-
-    // let private_key = private_key.ok_or("missing private key for consensus node")?;
+// name of the engine on the command line
+fn engine_name(engine: &DefinedEngines) -> String {
+    engine
+        .to_possible_value()
+        .expect("engines are never skipped")
+        .get_name()
+        .to_string()
+}
 
+// builds the consensus engine selected by the configuration
+fn new_engine(
+    engine: &DefinedEngines,
+    configuration: &PeerConfig,
+    context: Context,
+) -> Box<dyn Engine> {
+    let interval = configuration.block_interval();
     match engine {
-        Some(DefinedEngines::Example) => {
+        DefinedEngines::Example => {
             debug!("Initializing Example engine");
-            let mut engine_guard = engine_instance
-                .lock()
-                .map_err(|e| CunnerError::Engine(format!("Failed to lock engine: {}", e)))?;
-            *engine_guard = Some(consensus::example::engine::Engine::new_engine(
-                Duration::from_secs(15),
-            ));
+            consensus::example::engine::Engine::new_engine(interval, context)
         }
-        None => {
-            return Err(CunnerError::Config(
-                "Engine cannot be empty if running a consensus node".into(),
-            ))
+        DefinedEngines::Avalanche => {
+            debug!("Initializing Avalanche engine");
+            consensus::avalanche::engine::Engine::new_engine(
+                interval,
+                configuration.engine.avalanche,
+                context,
+            )
+        }
+        DefinedEngines::Pbft => {
+            debug!("Initializing PBFT engine");
+            consensus::pbft::engine::Engine::new_engine(
+                interval,
+                configuration.engine.pbft,
+                configuration.network.nodes,
+                context,
+            )
+        }
+        DefinedEngines::Raft => {
+            debug!("Initializing Raft engine");
+            consensus::raft::engine::Engine::new_engine(
+                interval,
+                configuration.engine.raft,
+                configuration.network.nodes,
+                context,
+            )
+        }
+        DefinedEngines::Pow => {
+            debug!("Initializing PoW engine");
+            consensus::pow::engine::Engine::new_engine(interval, configuration.engine.pow, context)
+        }
+        DefinedEngines::Pos => {
+            debug!("Initializing PoS engine");
+            consensus::pos::engine::Engine::new_engine(
+                interval,
+                configuration.engine.pos.clone(),
+                configuration.network.nodes,
+                context,
+            )
+        }
+        DefinedEngines::Hotstuff => {
+            debug!("Initializing HotStuff engine");
+            consensus::hotstuff::engine::Engine::new_engine(
+                interval,
+                configuration.engine.hotstuff,
+                configuration.network.nodes,
+                context,
+            )
+        }
+        DefinedEngines::Tendermint => {
+            debug!("Initializing Tendermint engine");
+            consensus::tendermint::engine::Engine::new_engine(
+                interval,
+                configuration.engine.tendermint,
+                configuration.network.nodes,
+                context,
+            )
         }
-        _ => return Err(CunnerError::Config("Invalid engine option".into())),
     }
+}
 
-    let peer_configuration = PeerConfig {
-        tcp_listen_address: Some(tcp.unwrap_or(0)),
-        // private_key: Some(private_key),
-    };
+// builds the engine of a node, wrapped in an adversary if the node is byzantine
+fn new_node_engine(
+    engine: &DefinedEngines,
+    configuration: &PeerConfig,
+    strategy: Option<Strategy>,
+    context: Context,
+) -> Box<dyn Engine> {
+    match strategy {
+        Some(strategy) => new_adversary(strategy, &configuration.byzantine, context, |context| {
+            new_engine(engine, configuration, context)
+        }),
+        None => new_engine(engine, configuration, context),
+    }
+}
+
+// initializes the consensus engine based on the provided option, sets up the peer configuration, and starts the network operations.
+fn start_peer(
+    mut configuration: PeerConfig,
+    data_dir: Option<PathBuf>,
+    report: Option<PathBuf>,
+    history: Option<PathBuf>,
+    record: Option<PathBuf>,
+    byzantine: Option<Strategy>,
+) -> Result<(), CunnerError> {
+    let engine = configuration.engine.name.clone().ok_or_else(|| {
+        CunnerError::Config("Engine cannot be empty if running a consensus node".into())
+    })?;
 
     tracing_subscriber::fmt()
         .with_env_filter(EnvFilter::from_default_env())
@@ -136,14 +615,319 @@ fn start_peer(
             CunnerError::Config(format!("Failed to initialize tracing subscriber: {}", e))
         })?;
 
-    info!("Starting peer with configuration: {:?}", peer_configuration);
+    let peer_id = configuration.keypair.public().to_peer_id();
+    info!("Local peer id: {peer_id}");
+    let chain = match data_dir {
+        Some(data_dir) => open_chain(peer_id, &data_dir)?,
+        None => Chain::in_memory(peer_id),
+    };
+
+    let context = Context {
+        network: Network::new(SwarmTransport::new(peer_id, configuration.peer_ids()?)),
+        clock: Clock::system(),
+        chain: chain.clone(),
+        keypair: configuration.keypair.clone(),
+    };
+    recorder::init(context.clock.clone());
+    // the experiment names its byzantine nodes by their index in the peers of the network
+    let index = configuration
+        .peer_ids()?
+        .iter()
+        .position(|peer| *peer == peer_id);
+    if byzantine.is_none() && configuration.byzantine.strategy.is_some() && index.is_none() {
+        warn!("The experiment has byzantine nodes but the node is not in network.peers, it stays honest");
+    }
+    let strategy =
+        byzantine.or_else(|| index.and_then(|index| configuration.byzantine.strategy_of(index)));
+    let engine_instance: Arc<Mutex<Option<Box<dyn Engine>>>> = Arc::new(Mutex::new(Some(
+        new_node_engine(&engine, &configuration, strategy, context.clone()),
+    )));
+
+    let trace = match record {
+        Some(path) => {
+            // a node not in network.peers is traced as the only node it knows
+            let (node, peers) = match index {
+                Some(index) => (index, configuration.peer_ids()?),
+                None => (0, vec![peer_id]),
+            };
+            let header = TraceHeader {
+                experiment: configuration.to_json()?,
+                node,
+                keypair: configuration.keypair.clone(),
+                peers,
+                strategy,
+                seed: None,
+            };
+            info!("Recording the inputs of the node to {}", path.display());
+            Some(TraceWriter::create(&path, &header, context.clock.clone())?)
+        }
+        None => None,
+    };
+
+    configuration.tcp_listen_address = Some(configuration.tcp_listen_address.unwrap_or(0));
+    info!("Starting peer with configuration: {:?}", configuration);
 
     tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
         .map_err(CunnerError::Io)?
-        .block_on(run_peer(peer_configuration, engine_instance))
+        .block_on(run_peer(configuration, context, engine_instance, trace))
         .map_err(|e| CunnerError::Network(e.to_string()))?;
 
+    // a live node only sees its own chain, so its report covers a network of one node
+    if let Some(path) = report {
+        write_report(&path, &engine, 1)?;
+    }
+    if let Some(path) = history {
+        History::new(peer_id, strategy.is_some(), &chain)?.write(&path)?;
+        info!("Wrote history to {}", path.display());
+    }
+
     Ok(())
 }
+
+// opens the chain persisted in the data directory, creating it on the first run
+fn open_chain(peer_id: PeerId, data_dir: &Path) -> Result<Chain, CunnerError> {
+    fs::create_dir_all(data_dir)?;
+    let store = LogStore::open(data_dir.join("chain.log"))?;
+    let chain = Chain::new(peer_id, ChainStore::new(Arc::new(store)));
+    info!(
+        "Resuming chain from {} at height {}",
+        data_dir.display(),
+        chain.len()?
+    );
+    Ok(chain)
+}
+
+// writes the metrics recorded during the run
+fn write_report(path: &Path, engine: &DefinedEngines, nodes: usize) -> Result<(), CunnerError> {
+    let report = recorder::report(&engine_name(engine), nodes)
+        .ok_or_else(|| CunnerError::Config("Metrics were not recorded".into()))?;
+    report.write(path)?;
+    info!("Wrote metrics report to {}", path.display());
+    Ok(())
+}
+
+// prints the outcome of the invariant checker, the report file holds every violation
+fn print_violations(report: &InvariantReport) {
+    const SHOWN: usize = 20;
+    println!("{}", report.summary());
+    for violation in report.violations.iter().take(SHOWN) {
+        println!("  {violation}");
+    }
+    if report.violations.len() > SHOWN {
+        println!("  and {} more", report.violations.len() - SHOWN);
+    }
+}
+
+// spawns and supervises a node process per node of the cluster
+fn start_cluster(
+    configuration: PeerConfig,
+    experiment: Option<PathBuf>,
+    base_port: u16,
+    rpc_base_port: Option<u16>,
+    metrics_base_port: Option<u16>,
+    log_dir: PathBuf,
+    record: bool,
+) -> Result<(), CunnerError> {
+    let engine =
+        configuration.engine.name.as_ref().ok_or_else(|| {
+            CunnerError::Config("Engine cannot be empty if running a cluster".into())
+        })?;
+    let nodes = u16::try_from(configuration.network.nodes)
+        .map_err(|_| CunnerError::Config("Too many nodes for a cluster".into()))?;
+
+    // the launcher only reports progress, so it logs at info level unless told otherwise
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .try_init()
+        .map_err(|e| {
+            CunnerError::Config(format!("Failed to initialize tracing subscriber: {}", e))
+        })?;
+
+    let cluster_configuration = ClusterConfig {
+        nodes,
+        engine: engine_name(engine),
+        base_port,
+        rpc_base_port,
+        metrics_base_port,
+        duration: configuration.duration(),
+        experiment,
+        log_dir,
+        record,
+        topology: configuration.network.topology,
+        workload: configuration.workload.generator,
+        tps: configuration.workload.tps,
+        byzantine: configuration
+            .byzantine
+            .strategy
+            .map(|strategy| (strategy, configuration.byzantine.nodes.clone())),
+        liveness_bound: configuration.invariants.liveness_bound(),
+    };
+    info!(
+        "Starting cluster with configuration: {:?}",
+        cluster_configuration
+    );
+
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(CunnerError::Io)?
+        .block_on(run_cluster(cluster_configuration))
+}
+
+// runs every node in this process over a simulated network, the same seed gives the same chains and logs
+fn simulate(
+    configuration: PeerConfig,
+    report: Option<PathBuf>,
+    invariants: Option<PathBuf>,
+    record: Option<PathBuf>,
+) -> Result<(), CunnerError> {
+    let engine = configuration.engine.name.clone().ok_or_else(|| {
+        CunnerError::Config("Engine cannot be empty if running a simulation".into())
+    })?;
+
+    let simulation_configuration = SimulationConfig {
+        nodes: configuration.network.nodes,
+        seed: configuration.seed,
+        duration: configuration.duration().unwrap_or(Duration::from_secs(120)),
+        latency: Duration::from_millis(configuration.network.latency_ms),
+        workload: configuration.workload.clone(),
+        faults: configuration.network.faults.clone(),
+        record: record
+            .map(|dir| {
+                Ok::<_, CunnerError>(Recording {
+                    dir,
+                    experiment: configuration.to_json()?,
+                    strategies: (0..configuration.network.nodes)
+                        .map(|node| configuration.byzantine.strategy_of(node))
+                        .collect(),
+                })
+            })
+            .transpose()?,
+    };
+    let simulation = Simulation::new(simulation_configuration.clone());
+
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_timer(simulation.clock())
+        .try_init()
+        .map_err(|e| {
+            CunnerError::Config(format!("Failed to initialize tracing subscriber: {}", e))
+        })?;
+
+    info!(
+        "Starting simulation with configuration: {:?}",
+        simulation_configuration
+    );
+
+    let chains = simulation.run(|node, context| {
+        let strategy = configuration.byzantine.strategy_of(node);
+        new_node_engine(&engine, &configuration, strategy, context)
+    })?;
+
+    for (node, (peer_id, chain)) in chains.iter().enumerate() {
+        println!(
+            "node {} {}: {} blocks, digest {}",
+            node,
+            peer_id,
+            chain.len()?,
+            chain.digest()?
+        );
+    }
+
+    if let Some(path) = report {
+        write_report(&path, &engine, simulation_configuration.nodes)?;
+    }
+
+    let histories = chains
+        .iter()
+        .enumerate()
+        .map(|(node, (peer_id, chain))| {
+            let byzantine = configuration.byzantine.strategy_of(node).is_some();
+            History::new(*peer_id, byzantine, chain)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let invariant_report = check(&histories, configuration.invariants.liveness_bound());
+    print_violations(&invariant_report);
+    if let Some(path) = invariants {
+        invariant_report.write(&path)?;
+        info!("Wrote invariant report to {}", path.display());
+    }
+
+    Ok(())
+}
+
+// replays the trace of a node into a fresh engine built as the node built its own
+fn replay(path: &Path) -> Result<(), CunnerError> {
+    let (header, records) = read_trace(path)?;
+    let mut configuration = PeerConfig::from_json(&header.experiment)?;
+    configuration.keypair = header.keypair.clone();
+    let engine =
+        configuration.engine.name.clone().ok_or_else(|| {
+            CunnerError::Config("Engine cannot be empty if replaying a trace".into())
+        })?;
+    let (node, strategy, simulated) = (header.node, header.strategy, header.seed.is_some());
+    let replay = Replay::new(header);
+
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_timer(replay.clock())
+        .try_init()
+        .map_err(|e| {
+            CunnerError::Config(format!("Failed to initialize tracing subscriber: {}", e))
+        })?;
+
+    if !simulated {
+        println!("The trace is of a live node, whose engine ran on the wall clock, its blocks may differ from the recording");
+    }
+    info!(
+        "Replaying {} records of node {} from {}",
+        records.len(),
+        node,
+        path.display()
+    );
+    let report = {
+        let _span = info_span!("node", id = node).entered();
+        replay.run(records, |context| {
+            new_node_engine(&engine, &configuration, strategy, context)
+        })?
+    };
+
+    println!(
+        "node {} {}: {} inputs over {:.3}s, {} blocks, digest {}",
+        node,
+        report.peer_id,
+        report.inputs,
+        report.duration.as_secs_f64(),
+        report.chain.len()?,
+        report.chain.digest()?
+    );
+    let Some(recorded) = &report.recorded else {
+        println!("The trace ends before the blocks of the node, there is nothing to check");
+        return Ok(());
+    };
+    match report.divergence()? {
+        None => {
+            println!(
+                "Replay committed the same {} blocks as the recording",
+                recorded.len()
+            );
+            Ok(())
+        }
+        Some(height) => {
+            let replayed = report
+                .chain
+                .block(height)?
+                .map(|block| hex::encode(block.hash()));
+            let recorded = recorded.get(height as usize - 1).map(hex::encode);
+            Err(CunnerError::Engine(format!(
+                "Replay diverged from the recording at height {height}: recorded {}, replayed {}",
+                recorded.as_deref().unwrap_or("no block"),
+                replayed.as_deref().unwrap_or("no block")
+            )))
+        }
+    }
+}
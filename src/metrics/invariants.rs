@@ -0,0 +1,498 @@
+/*
+The invariant checker tells whether an engine was correct over a run, from what
+every node committed. It checks the chains of the honest nodes, the byzantine ones
+are free to commit anything:
+
+- agreement: no two honest nodes committed different blocks at the same height
+- validity: every transaction committed was submitted to some node
+- no duplicate inclusion: no honest node committed a transaction twice
+- liveness: every transaction submitted was final on every honest node within the
+  liveness bound, the transactions submitted too close to the end of the run are
+  not held to it
+
+A simulation hands the chains of its nodes over directly. The nodes of a cluster
+each write their history when they stop and the launcher checks them together,
+which is why histories name transactions and blocks by their hex encoded hashes.
+*/
+
+use crate::consensus::chain::Chain;
+use crate::metrics::recorder;
+use crate::CunnerError;
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+/// Settings of the checker, set in the `invariants` section of the experiment.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InvariantConfig {
+    /// seconds a submitted transaction has to become final on every honest node
+    pub liveness_bound_secs: u64,
+}
+
+impl Default for InvariantConfig {
+    fn default() -> Self {
+        Self {
+            liveness_bound_secs: 60,
+        }
+    }
+}
+
+impl InvariantConfig {
+    pub fn validate(&self) -> Result<(), CunnerError> {
+        if self.liveness_bound_secs == 0 {
+            return Err(CunnerError::Config(
+                "invariants: liveness_bound_secs must be at least 1".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn liveness_bound(&self) -> Duration {
+        Duration::from_secs(self.liveness_bound_secs)
+    }
+}
+
+/// History is what a node committed over a run. Times are milliseconds on the clock
+/// blocks are stamped with.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct History {
+    pub node: String,
+    pub byzantine: bool,
+    /// transactions submitted to the node, with when they were
+    pub submitted: BTreeMap<String, u64>,
+    /// transactions of the chain, block after block from height 1
+    pub blocks: Vec<CommittedBlock>,
+    /// when each transaction became final on the node, the ones committed before the
+    /// run by a resumed chain have no time
+    pub finalized: BTreeMap<String, u64>,
+    /// when the history was taken
+    pub end_ms: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CommittedBlock {
+    pub hash: String,
+    pub transactions: Vec<String>,
+}
+
+impl History {
+    /// Returns the history of the node with the chain, with the times recorded so far.
+    pub fn new(node: PeerId, byzantine: bool, chain: &Chain) -> io::Result<Self> {
+        let timeline = recorder::timeline(node).unwrap_or_default();
+        let hex_keys = |times: BTreeMap<Vec<u8>, u64>| {
+            times
+                .into_iter()
+                .map(|(hash, time)| (hex::encode(hash), time))
+                .collect()
+        };
+        let mut blocks = Vec::new();
+        for height in 1..=chain.len()? {
+            let Some(block) = chain.block(height)? else {
+                break;
+            };
+            blocks.push(CommittedBlock {
+                hash: hex::encode(block.hash()),
+                transactions: block
+                    .transactions
+                    .iter()
+                    .map(|transaction| hex::encode(transaction.hash()))
+                    .collect(),
+            });
+        }
+        Ok(Self {
+            node: node.to_string(),
+            byzantine,
+            submitted: hex_keys(timeline.submitted),
+            blocks,
+            finalized: hex_keys(timeline.finalized),
+            end_ms: timeline.end_ms,
+        })
+    }
+
+    pub fn read(path: &Path) -> Result<Self, CunnerError> {
+        let content = fs::read_to_string(path)?;
+        serde_json::from_str(&content)
+            .map_err(|e| CunnerError::Config(format!("Invalid history {}: {}", path.display(), e)))
+    }
+
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, serde_json::to_string(self)? + "\n")
+    }
+}
+
+/// Violation is a breach of an invariant, naming the nodes and heights involved.
+#[derive(Debug, Serialize)]
+#[serde(tag = "invariant", rename_all = "snake_case")]
+pub enum Violation {
+    /// honest nodes committed different blocks at the height, the nodes by block hash
+    Agreement {
+        height: u64,
+        blocks: BTreeMap<String, Vec<String>>,
+    },
+    /// the node committed a transaction no node submitted
+    Validity {
+        node: String,
+        height: u64,
+        transaction: String,
+    },
+    /// the node committed the transaction at several heights, or twice in a block
+    Duplicate {
+        node: String,
+        transaction: String,
+        heights: Vec<u64>,
+    },
+    /// the nodes did not make the transaction final within the liveness bound
+    Liveness {
+        transaction: String,
+        submitted_ms: u64,
+        nodes: Vec<String>,
+    },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::Agreement { height, blocks } => {
+                write!(f, "agreement: at height {height}")?;
+                for (block, nodes) in blocks {
+                    write!(f, ", block {block} on {}", nodes.join(" "))?;
+                }
+                Ok(())
+            }
+            Violation::Validity {
+                node,
+                height,
+                transaction,
+            } => write!(
+                f,
+                "validity: {node} committed transaction {transaction} at height {height}, it was never submitted"
+            ),
+            Violation::Duplicate {
+                node,
+                transaction,
+                heights,
+            } => write!(
+                f,
+                "duplicate: {node} committed transaction {transaction} at heights {heights:?}"
+            ),
+            Violation::Liveness {
+                transaction,
+                submitted_ms,
+                nodes,
+            } => write!(
+                f,
+                "liveness: transaction {transaction} submitted at {submitted_ms} ms is not final in time on {}",
+                nodes.join(" ")
+            ),
+        }
+    }
+}
+
+/// InvariantReport is the outcome of a check.
+#[derive(Debug, Serialize)]
+pub struct InvariantReport {
+    pub nodes: usize,
+    pub honest_nodes: usize,
+    /// highest height an honest node committed
+    pub height: u64,
+    pub transactions_submitted: usize,
+    pub liveness_bound_secs: f64,
+    pub violations: Vec<Violation>,
+}
+
+impl InvariantReport {
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)? + "\n")
+    }
+
+    /// Returns one line telling how many invariants were violated, by invariant.
+    pub fn summary(&self) -> String {
+        if self.violations.is_empty() {
+            return format!(
+                "Invariants hold on {} honest nodes up to height {}",
+                self.honest_nodes, self.height
+            );
+        }
+        let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+        for violation in &self.violations {
+            let invariant = match violation {
+                Violation::Agreement { .. } => "agreement",
+                Violation::Validity { .. } => "validity",
+                Violation::Duplicate { .. } => "duplicate",
+                Violation::Liveness { .. } => "liveness",
+            };
+            *counts.entry(invariant).or_default() += 1;
+        }
+        let counts: Vec<String> = counts
+            .iter()
+            .map(|(invariant, count)| format!("{count} {invariant}"))
+            .collect();
+        format!(
+            "{} invariant violations on {} honest nodes: {}",
+            self.violations.len(),
+            self.honest_nodes,
+            counts.join(", ")
+        )
+    }
+}
+
+/// Checks the invariants over the histories of every node of a run.
+pub fn check(histories: &[History], liveness_bound: Duration) -> InvariantReport {
+    let honest: Vec<&History> = histories
+        .iter()
+        .filter(|history| !history.byzantine)
+        .collect();
+    let mut violations = Vec::new();
+
+    // the first submission of every transaction, to any node
+    let mut submitted: BTreeMap<&str, u64> = BTreeMap::new();
+    for (transaction, time) in histories.iter().flat_map(|history| &history.submitted) {
+        let first = submitted.entry(transaction).or_insert(*time);
+        *first = (*first).min(*time);
+    }
+
+    let height = honest
+        .iter()
+        .map(|history| history.blocks.len())
+        .max()
+        .unwrap_or(0);
+    for index in 0..height {
+        let mut blocks: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for history in &honest {
+            if let Some(block) = history.blocks.get(index) {
+                blocks
+                    .entry(block.hash.clone())
+                    .or_default()
+                    .push(history.node.clone());
+            }
+        }
+        if blocks.len() > 1 {
+            violations.push(Violation::Agreement {
+                height: index as u64 + 1,
+                blocks,
+            });
+        }
+    }
+
+    for history in &honest {
+        let mut heights: BTreeMap<&str, Vec<u64>> = BTreeMap::new();
+        for (index, block) in history.blocks.iter().enumerate() {
+            let height = index as u64 + 1;
+            for transaction in &block.transactions {
+                if !submitted.contains_key(transaction.as_str()) {
+                    violations.push(Violation::Validity {
+                        node: history.node.clone(),
+                        height,
+                        transaction: transaction.clone(),
+                    });
+                }
+                heights.entry(transaction).or_default().push(height);
+            }
+        }
+        for (transaction, heights) in heights {
+            if heights.len() > 1 {
+                violations.push(Violation::Duplicate {
+                    node: history.node.clone(),
+                    transaction: transaction.to_string(),
+                    heights,
+                });
+            }
+        }
+    }
+
+    let bound = liveness_bound.as_millis() as u64;
+    for (transaction, submitted_ms) in &submitted {
+        let deadline = submitted_ms + bound;
+        let late: BTreeSet<&str> = honest
+            .iter()
+            .filter(|history| history.end_ms >= deadline)
+            .filter(|history| {
+                history
+                    .finalized
+                    .get(*transaction)
+                    .is_none_or(|finalized| *finalized > deadline)
+            })
+            .map(|history| history.node.as_str())
+            .collect();
+        if !late.is_empty() {
+            violations.push(Violation::Liveness {
+                transaction: transaction.to_string(),
+                submitted_ms: *submitted_ms,
+                nodes: late.into_iter().map(String::from).collect(),
+            });
+        }
+    }
+
+    InvariantReport {
+        nodes: histories.len(),
+        honest_nodes: honest.len(),
+        height: height as u64,
+        transactions_submitted: submitted.len(),
+        liveness_bound_secs: liveness_bound.as_secs_f64(),
+        violations,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOUND: Duration = Duration::from_secs(60);
+
+    // a history of honest node `node` committing the blocks, made of (hash, transactions)
+    fn history(node: &str, blocks: &[(&str, &[&str])]) -> History {
+        let blocks: Vec<CommittedBlock> = blocks
+            .iter()
+            .map(|(hash, transactions)| CommittedBlock {
+                hash: hash.to_string(),
+                transactions: transactions.iter().map(|t| t.to_string()).collect(),
+            })
+            .collect();
+        // every committed transaction is final a second into the run
+        let finalized = blocks
+            .iter()
+            .flat_map(|block| &block.transactions)
+            .map(|transaction| (transaction.clone(), 1_000))
+            .collect();
+        History {
+            node: node.into(),
+            byzantine: false,
+            submitted: BTreeMap::new(),
+            blocks,
+            finalized,
+            end_ms: 120_000,
+        }
+    }
+
+    fn submit(history: &mut History, transaction: &str, at_ms: u64) {
+        history.submitted.insert(transaction.into(), at_ms);
+    }
+
+    #[test]
+    fn agreeing_histories_hold() {
+        let mut a = history("a", &[("b1", &["t1"]), ("b2", &["t2"])]);
+        // b stopped before the deadline of t2, a shorter chain agrees with a longer one
+        let mut b = history("b", &[("b1", &["t1"])]);
+        b.end_ms = 50_000;
+        submit(&mut a, "t1", 0);
+        submit(&mut a, "t2", 0);
+
+        let report = check(&[a, b], BOUND);
+        assert!(report.violations.is_empty(), "{:?}", report.violations);
+        assert_eq!(report.height, 2);
+        assert_eq!(report.honest_nodes, 2);
+        assert_eq!(report.transactions_submitted, 2);
+    }
+
+    #[test]
+    fn different_blocks_at_a_height_break_agreement() {
+        let mut a = history("a", &[("b1", &["t1"]), ("b2", &[])]);
+        let b = history("b", &[("b1", &["t1"]), ("fork", &[])]);
+        submit(&mut a, "t1", 0);
+
+        let report = check(&[a, b], BOUND);
+        assert_eq!(report.violations.len(), 1);
+        let Violation::Agreement { height, blocks } = &report.violations[0] else {
+            panic!("expected an agreement violation: {:?}", report.violations);
+        };
+        assert_eq!(*height, 2);
+        assert_eq!(blocks["b2"], ["a"]);
+        assert_eq!(blocks["fork"], ["b"]);
+    }
+
+    #[test]
+    fn byzantine_nodes_are_left_out() {
+        let mut a = history("a", &[("b1", &["t1"])]);
+        let mut byzantine = history("z", &[("forged", &["t1", "t1", "unknown"])]);
+        byzantine.byzantine = true;
+        byzantine.finalized.clear();
+        submit(&mut a, "t1", 0);
+
+        let report = check(&[a, byzantine], BOUND);
+        assert!(report.violations.is_empty(), "{:?}", report.violations);
+        assert_eq!(report.nodes, 2);
+        assert_eq!(report.honest_nodes, 1);
+    }
+
+    #[test]
+    fn unsubmitted_transactions_break_validity() {
+        let mut a = history("a", &[("b1", &["t1"]), ("b2", &["forged"])]);
+        submit(&mut a, "t1", 0);
+
+        let report = check(&[a], BOUND);
+        assert_eq!(report.violations.len(), 1);
+        let Violation::Validity {
+            node,
+            height,
+            transaction,
+        } = &report.violations[0]
+        else {
+            panic!("expected a validity violation: {:?}", report.violations);
+        };
+        assert_eq!((node.as_str(), *height), ("a", 2));
+        assert_eq!(transaction, "forged");
+    }
+
+    #[test]
+    fn a_transaction_committed_twice_is_a_duplicate() {
+        let mut a = history("a", &[("b1", &["t1", "t1"]), ("b2", &["t1"])]);
+        submit(&mut a, "t1", 0);
+
+        let report = check(&[a], BOUND);
+        assert_eq!(report.violations.len(), 1);
+        let Violation::Duplicate {
+            node,
+            transaction,
+            heights,
+        } = &report.violations[0]
+        else {
+            panic!("expected a duplicate violation: {:?}", report.violations);
+        };
+        assert_eq!((node.as_str(), transaction.as_str()), ("a", "t1"));
+        assert_eq!(heights, &[1, 1, 2]);
+    }
+
+    #[test]
+    fn late_or_missing_transactions_break_liveness() {
+        let mut a = history("a", &[("b1", &["t1", "late"])]);
+        let mut b = history("b", &[("b1", &["t1", "late"])]);
+        submit(&mut a, "t1", 0);
+        submit(&mut a, "late", 0);
+        submit(&mut b, "missing", 10_000);
+        // only reaches b after the bound
+        b.finalized.insert("late".into(), 61_000);
+        // submitted too close to the end of the run to be checked
+        submit(&mut b, "recent", 100_000);
+
+        let report = check(&[a, b], BOUND);
+        let late: Vec<(&str, u64, &[String])> = report
+            .violations
+            .iter()
+            .map(|violation| match violation {
+                Violation::Liveness {
+                    transaction,
+                    submitted_ms,
+                    nodes,
+                } => (transaction.as_str(), *submitted_ms, nodes.as_slice()),
+                violation => panic!("expected liveness violations: {violation:?}"),
+            })
+            .collect();
+        assert_eq!(
+            late,
+            [
+                ("late", 0, &["b".to_string()][..]),
+                ("missing", 10_000, &["a".to_string(), "b".to_string()][..]),
+            ]
+        );
+        assert_eq!(
+            report.summary(),
+            "2 invariant violations on 2 honest nodes: 2 liveness"
+        );
+    }
+}
@@ -0,0 +1,363 @@
+/*
+A live node exports what it does as Prometheus metrics on `/metrics`, so the nodes of
+a local cluster can be scraped and graphed over a run, where the metrics report only
+tells about a run once it is over: the messages the node sent and received over
+gossipsub by payload, their bytes, the messages it failed to decode, the blocks its
+engine produced and the ones it received from peers by verdict, the restarts of its
+engine, its mempool and chain height, and how long transactions took from their
+submission to each stage of the recorder. A live node only knows when its own
+transactions were submitted, so the latencies it exports are the ones of those.
+*/
+
+use crate::consensus::chain::Chain;
+use crate::consensus::engine::{BlockVerdict, Engine};
+use crate::network::messages::message::message::Payload;
+use crate::CunnerError;
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use log::{error, info};
+use once_cell::sync::Lazy;
+use prometheus_client::encoding::text::encode;
+use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::registry::Registry;
+use std::convert::Infallible;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+static METRICS: Lazy<Metrics> = Lazy::new(Metrics::new);
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct PayloadLabels {
+    payload: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct VerdictLabels {
+    verdict: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct StageLabels {
+    stage: &'static str,
+}
+
+/// Stage is a step of a transaction the recorder timestamps after its submission.
+#[derive(Debug, Clone, Copy)]
+pub enum Stage {
+    /// a node received it from a peer
+    Receive,
+    /// a block first included it
+    Include,
+    /// it was final on a node
+    Finalize,
+}
+
+struct Metrics {
+    registry: Registry,
+    messages_sent: Family<PayloadLabels, Counter>,
+    messages_received: Family<PayloadLabels, Counter>,
+    bytes_sent: Counter,
+    bytes_received: Counter,
+    decode_failures: Counter,
+    blocks_produced: Counter,
+    blocks_received: Family<VerdictLabels, Counter>,
+    engine_restarts: Counter,
+    mempool_transactions: Gauge,
+    chain_height: Gauge,
+    transaction_latency: Family<StageLabels, Histogram, fn() -> Histogram>,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let mut metrics = Self {
+            registry: Registry::with_prefix("cunner"),
+            messages_sent: Family::default(),
+            messages_received: Family::default(),
+            bytes_sent: Counter::default(),
+            bytes_received: Counter::default(),
+            decode_failures: Counter::default(),
+            blocks_produced: Counter::default(),
+            blocks_received: Family::default(),
+            engine_restarts: Counter::default(),
+            mempool_transactions: Gauge::default(),
+            chain_height: Gauge::default(),
+            transaction_latency: Family::new_with_constructor(latency_histogram),
+        };
+        metrics.registry.register(
+            "messages_sent",
+            "Messages published on gossipsub, by payload",
+            metrics.messages_sent.clone(),
+        );
+        metrics.registry.register(
+            "messages_received",
+            "Messages received on gossipsub, by payload",
+            metrics.messages_received.clone(),
+        );
+        metrics.registry.register(
+            "bytes_sent",
+            "Bytes of the messages published on gossipsub",
+            metrics.bytes_sent.clone(),
+        );
+        metrics.registry.register(
+            "bytes_received",
+            "Bytes of the messages received on gossipsub",
+            metrics.bytes_received.clone(),
+        );
+        metrics.registry.register(
+            "decode_failures",
+            "Messages received that failed to decode",
+            metrics.decode_failures.clone(),
+        );
+        metrics.registry.register(
+            "blocks_produced",
+            "Blocks produced by the engine of the node",
+            metrics.blocks_produced.clone(),
+        );
+        metrics.registry.register(
+            "blocks_received",
+            "Blocks received from peers, by verdict of the engine",
+            metrics.blocks_received.clone(),
+        );
+        metrics.registry.register(
+            "engine_restarts",
+            "Restarts of the engine after its run timed out",
+            metrics.engine_restarts.clone(),
+        );
+        metrics.registry.register(
+            "mempool_transactions",
+            "Transactions the engine holds that are not committed yet",
+            metrics.mempool_transactions.clone(),
+        );
+        metrics.registry.register(
+            "chain_height",
+            "Height of the chain of the node",
+            metrics.chain_height.clone(),
+        );
+        metrics.registry.register(
+            "transaction_latency_seconds",
+            "Seconds from the submission of a transaction to each stage, by stage",
+            metrics.transaction_latency.clone(),
+        );
+        metrics
+    }
+}
+
+// from 5 ms to about 3 minutes, finality of slow engines included
+fn latency_histogram() -> Histogram {
+    Histogram::new(exponential_buckets(0.005, 2.0, 16))
+}
+
+fn payload_label(payload: Option<&Payload>) -> PayloadLabels {
+    let payload = match payload {
+        Some(Payload::Transaction(_)) => "transaction",
+        Some(Payload::Block(_)) => "block",
+        Some(Payload::ConsensusMessage(_)) => "consensus_message",
+        None => "empty",
+    };
+    PayloadLabels { payload }
+}
+
+pub fn message_sent(payload: Option<&Payload>, bytes: usize) {
+    METRICS
+        .messages_sent
+        .get_or_create(&payload_label(payload))
+        .inc();
+    METRICS.bytes_sent.inc_by(bytes as u64);
+}
+
+pub fn message_received(payload: Option<&Payload>, bytes: usize) {
+    METRICS
+        .messages_received
+        .get_or_create(&payload_label(payload))
+        .inc();
+    METRICS.bytes_received.inc_by(bytes as u64);
+}
+
+/// Records a message received that is not a valid protobuf message, its bytes count
+/// as received.
+pub fn decode_failed(bytes: usize) {
+    METRICS.decode_failures.inc();
+    METRICS.bytes_received.inc_by(bytes as u64);
+}
+
+pub fn block_produced() {
+    METRICS.blocks_produced.inc();
+}
+
+pub fn block_received(verdict: BlockVerdict) {
+    let verdict = match verdict {
+        BlockVerdict::Accept => "accept",
+        BlockVerdict::Reject => "reject",
+        BlockVerdict::Hold => "hold",
+    };
+    METRICS
+        .blocks_received
+        .get_or_create(&VerdictLabels { verdict })
+        .inc();
+}
+
+pub fn engine_restarted() {
+    METRICS.engine_restarts.inc();
+}
+
+/// Records that a transaction reached `stage` `latency` after it was submitted.
+pub fn transaction_stage(stage: Stage, latency: Duration) {
+    let stage = match stage {
+        Stage::Receive => "receive",
+        Stage::Include => "include",
+        Stage::Finalize => "finalize",
+    };
+    METRICS
+        .transaction_latency
+        .get_or_create(&StageLabels { stage })
+        .observe(latency.as_secs_f64());
+}
+
+/// MetricsNode is what the metrics endpoint samples of a running node when scraped.
+#[derive(Clone)]
+pub struct MetricsNode {
+    pub chain: Chain,
+    pub engine: Arc<Mutex<Option<Box<dyn Engine>>>>,
+}
+
+impl MetricsNode {
+    // the metrics in the text format, with the gauges sampled now
+    fn encode(&self) -> Result<String, CunnerError> {
+        let engine = self.engine.lock().unwrap().as_ref().cloned();
+        if let Some(engine) = engine {
+            METRICS
+                .mempool_transactions
+                .set(engine.mempool_size() as i64);
+        }
+        METRICS.chain_height.set(self.chain.len()? as i64);
+        let mut text = String::new();
+        encode(&mut text, &METRICS.registry)
+            .map_err(|e| io::Error::other(format!("Failed to encode metrics: {e}")))?;
+        Ok(text)
+    }
+}
+
+/// Binds the metrics endpoint to `port` on localhost and serves it in the background.
+pub fn spawn_metrics(port: u16, node: MetricsNode) -> Result<(), CunnerError> {
+    let address = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+    let builder = Server::try_bind(&address).map_err(|e| {
+        CunnerError::Network(format!(
+            "Failed to bind the metrics endpoint to {address}: {e}"
+        ))
+    })?;
+    let node = Arc::new(node);
+    let service = make_service_fn(move |_| {
+        let node = node.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                handle_request(node.clone(), request)
+            }))
+        }
+    });
+    let server = builder.serve(service);
+    info!("Serving metrics on http://{address}/metrics");
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            error!("Metrics endpoint stopped: {e}");
+        }
+    });
+    Ok(())
+}
+
+async fn handle_request(
+    node: Arc<MetricsNode>,
+    request: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let mut response = Response::new(Body::empty());
+    if request.uri().path() != "/metrics" {
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(response);
+    }
+    if request.method() != Method::GET {
+        *response.status_mut() = StatusCode::METHOD_NOT_ALLOWED;
+        return Ok(response);
+    }
+    match node.encode() {
+        Ok(text) => {
+            *response.body_mut() = Body::from(text);
+            response.headers_mut().insert(
+                CONTENT_TYPE,
+                "application/openmetrics-text; version=1.0.0; charset=utf-8"
+                    .parse()
+                    .unwrap(),
+            );
+        }
+        Err(e) => {
+            error!("{e}");
+            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        }
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::messages::message::Block;
+    use crate::simulation::rng;
+    use futures::executor::block_on;
+    use libp2p::PeerId;
+
+    fn get(node: &Arc<MetricsNode>, method: Method, path: &str) -> Response<Body> {
+        let request = Request::builder()
+            .method(method)
+            .uri(path)
+            .body(Body::empty())
+            .unwrap();
+        block_on(handle_request(node.clone(), request)).unwrap()
+    }
+
+    #[test]
+    fn metrics_are_served_in_the_text_format() {
+        let _rng = rng::exclusive();
+        let peer = PeerId::random();
+        let chain = Chain::in_memory(peer);
+        let first = Block::new_block(None, peer, 1_000, Vec::new());
+        chain.append(&first);
+        chain.append(&Block::new_block(Some(&first), peer, 2_000, Vec::new()));
+        let node = Arc::new(MetricsNode {
+            chain,
+            engine: Arc::new(Mutex::new(None)),
+        });
+
+        message_sent(Some(&Payload::Block(first)), 100);
+        block_received(BlockVerdict::Hold);
+        transaction_stage(Stage::Finalize, Duration::from_millis(300));
+
+        let response = get(&node, Method::GET, "/metrics");
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers()[CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .starts_with("application/openmetrics-text"));
+        let body = block_on(hyper::body::to_bytes(response.into_body())).unwrap();
+        let text = String::from_utf8(body.to_vec()).unwrap();
+        for line in [
+            "cunner_chain_height 2",
+            "cunner_messages_sent_total{payload=\"block\"}",
+            "cunner_blocks_received_total{verdict=\"hold\"}",
+            "cunner_transaction_latency_seconds_bucket{le=\"0.32\",stage=\"finalize\"}",
+        ] {
+            assert!(text.contains(line), "{line} is missing from\n{text}");
+        }
+        assert!(text.ends_with("# EOF\n"));
+
+        assert_eq!(get(&node, Method::GET, "/").status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            get(&node, Method::POST, "/metrics").status(),
+            StatusCode::METHOD_NOT_ALLOWED
+        );
+    }
+}
@@ -0,0 +1,336 @@
+/*
+Metrics follow the Hyperledger Blockchain Performance Metrics whitepaper. Every
+transaction is timestamped when it is submitted, when a node receives it, when a
+block first includes it and when it becomes final on a node, which is when a block
+holding it is appended to the chain of that node. Timestamps are taken on the clock
+of the run, so a simulation records virtual time and the same seed gives the same
+report.
+
+Proof-of-work engines also record the blocks they mine. A mined block no node made
+final is stale, it lost a fork, and the stale blocks whose parent is final are the
+uncles of the chain: they forked off it a single block deep.
+*/
+
+use crate::metrics::prometheus::{self, Stage};
+use crate::metrics::report::{Distribution, Report};
+use crate::network::messages::message::{Block, Transaction};
+use crate::simulation::clock::Clock;
+use libp2p::PeerId;
+use once_cell::sync::Lazy;
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Fraction of the nodes a transaction must be final on to count as final, the
+/// whitepaper leaves this network threshold to the deployment. Two thirds of the
+/// nodes is what byzantine fault tolerant engines wait for.
+pub const NETWORK_THRESHOLD: f64 = 2.0 / 3.0;
+
+static RECORDER: Lazy<Mutex<Option<Recorder>>> = Lazy::new(|| Mutex::new(None));
+
+struct Recorder {
+    clock: Clock,
+    transactions: BTreeMap<Vec<u8>, TransactionTimes>,
+    // times each node appended a block to its chain
+    blocks: BTreeMap<PeerId, Vec<Duration>>,
+    // transactions each node dropped for an invalid signature
+    invalid: BTreeMap<PeerId, usize>,
+    // blocks each node proposed
+    proposed: BTreeMap<PeerId, usize>,
+    // engine messages sent, a broadcast counts once per peer it reaches
+    consensus_messages: usize,
+    consensus_bytes: usize,
+    // parent hash and height of every mined block, by hash
+    mined: BTreeMap<Vec<u8>, (Vec<u8>, u32)>,
+    // blocks a block proposer included transactions in, and the ones appended to the
+    // chain of any node
+    included_blocks: BTreeSet<Vec<u8>>,
+    finalized_blocks: BTreeSet<Vec<u8>>,
+    hashes: u64,
+}
+
+#[derive(Default)]
+struct TransactionTimes {
+    submitted: Option<Duration>,
+    // first receipt on each node
+    received: BTreeMap<PeerId, Duration>,
+    included: Option<Duration>,
+    // first time it was final on each node
+    finalized: BTreeMap<PeerId, Duration>,
+}
+
+/// Starts recording with timestamps taken on `clock`, dropping what was recorded before.
+pub fn init(clock: Clock) {
+    *RECORDER.lock().unwrap() = Some(Recorder {
+        clock,
+        transactions: BTreeMap::new(),
+        blocks: BTreeMap::new(),
+        invalid: BTreeMap::new(),
+        proposed: BTreeMap::new(),
+        consensus_messages: 0,
+        consensus_bytes: 0,
+        mined: BTreeMap::new(),
+        included_blocks: BTreeSet::new(),
+        finalized_blocks: BTreeSet::new(),
+        hashes: 0,
+    });
+}
+
+pub fn transaction_submitted(transaction: &Transaction) {
+    record(|recorder, now| {
+        recorder
+            .transactions
+            .entry(transaction.hash())
+            .or_default()
+            .submitted
+            .get_or_insert(now);
+    });
+}
+
+pub fn transaction_received(transaction: &Transaction, node: PeerId) {
+    record(|recorder, now| {
+        let times = recorder.transactions.entry(transaction.hash()).or_default();
+        if let Entry::Vacant(received) = times.received.entry(node) {
+            received.insert(now);
+            stage_reached(times.submitted, Stage::Receive, now);
+        }
+    });
+}
+
+/// Returns whether `node` received the transaction or it was submitted, which on a
+/// live node means submitted to the node itself.
+pub fn transaction_seen(hash: &[u8], node: PeerId) -> bool {
+    RECORDER
+        .lock()
+        .unwrap()
+        .as_ref()
+        .and_then(|recorder| recorder.transactions.get(hash))
+        .is_some_and(|times| times.submitted.is_some() || times.received.contains_key(&node))
+}
+
+pub fn transaction_invalid(node: PeerId) {
+    record(|recorder, _| {
+        *recorder.invalid.entry(node).or_default() += 1;
+    });
+}
+
+/// Records an engine message of `bytes` bytes sent to `recipients` peers.
+pub fn consensus_message_sent(recipients: usize, bytes: usize) {
+    record(|recorder, _| {
+        recorder.consensus_messages += recipients;
+        recorder.consensus_bytes += recipients * bytes;
+    });
+}
+
+/// Records a block found by a proof-of-work miner.
+pub fn block_mined(block: &Block) {
+    record(|recorder, _| {
+        if let Some(header) = &block.header {
+            recorder
+                .mined
+                .insert(block.hash(), (header.parent_hash.clone(), header.index));
+        }
+    });
+}
+
+/// Records hashes computed by a proof-of-work miner.
+pub fn hashes_computed(hashes: u64) {
+    record(|recorder, _| recorder.hashes += hashes);
+}
+
+pub fn block_included(block: &Block) {
+    record(|recorder, now| {
+        // engines may record the inclusion again when they emit the block
+        if recorder.included_blocks.insert(block.hash()) {
+            prometheus::block_produced();
+            let proposer = block
+                .header
+                .as_ref()
+                .and_then(|header| PeerId::from_bytes(&header.proposer).ok());
+            if let Some(proposer) = proposer {
+                *recorder.proposed.entry(proposer).or_default() += 1;
+            }
+        }
+        for transaction in &block.transactions {
+            let times = recorder.transactions.entry(transaction.hash()).or_default();
+            if times.included.is_none() {
+                times.included = Some(now);
+                stage_reached(times.submitted, Stage::Include, now);
+            }
+        }
+    });
+}
+
+pub fn block_finalized(block: &Block, node: PeerId) {
+    record(|recorder, now| {
+        recorder.blocks.entry(node).or_default().push(now);
+        recorder.finalized_blocks.insert(block.hash());
+        for transaction in &block.transactions {
+            let times = recorder.transactions.entry(transaction.hash()).or_default();
+            if let Entry::Vacant(finalized) = times.finalized.entry(node) {
+                finalized.insert(now);
+                stage_reached(times.submitted, Stage::Finalize, now);
+            }
+        }
+    });
+}
+
+/// Computes the report of what was recorded so far over a network of `nodes` nodes.
+/// Returns None if recording was never started.
+pub fn report(engine: &str, nodes: usize) -> Option<Report> {
+    let recorder = RECORDER.lock().unwrap();
+    let recorder = recorder.as_ref()?;
+    let duration = recorder.clock.now();
+    let threshold = ((nodes as f64 * NETWORK_THRESHOLD).ceil() as usize).max(1);
+
+    let mut transaction_latency = Vec::new();
+    let mut propagation_latency = Vec::new();
+    let mut finality_time = Vec::new();
+    for times in recorder.transactions.values() {
+        let Some(submitted) = times.submitted else {
+            continue;
+        };
+        propagation_latency.extend(
+            times
+                .received
+                .values()
+                .map(|received| received.saturating_sub(submitted)),
+        );
+
+        // confirmed once the threshold-th node has it final
+        let mut finalized: Vec<Duration> = times.finalized.values().copied().collect();
+        finalized.sort();
+        if let Some(confirmed) = finalized.get(threshold - 1) {
+            transaction_latency.push(confirmed.saturating_sub(submitted));
+            if let Some(included) = times.included {
+                finality_time.push(confirmed.saturating_sub(included));
+            }
+        }
+    }
+
+    let block_time: Vec<Duration> = recorder
+        .blocks
+        .values()
+        .flat_map(|times| times.windows(2).map(|pair| pair[1] - pair[0]))
+        .collect();
+
+    let submitted = recorder
+        .transactions
+        .values()
+        .filter(|times| times.submitted.is_some())
+        .count();
+    let finalized = transaction_latency.len();
+
+    // blocks above the highest final one may still win their fork
+    let decided_height = recorder
+        .mined
+        .iter()
+        .filter(|(hash, _)| recorder.finalized_blocks.contains(*hash))
+        .map(|(_, (_, height))| *height)
+        .max()
+        .unwrap_or(0);
+    let decided: Vec<(&Vec<u8>, &Vec<u8>)> = recorder
+        .mined
+        .iter()
+        .filter(|(_, (_, height))| *height <= decided_height)
+        .map(|(hash, (parent, _))| (hash, parent))
+        .collect();
+    let stale: Vec<&Vec<u8>> = decided
+        .iter()
+        .filter(|(hash, _)| !recorder.finalized_blocks.contains(*hash))
+        .map(|(_, parent)| *parent)
+        .collect();
+    let uncles = stale
+        .iter()
+        .filter(|parent| parent.is_empty() || recorder.finalized_blocks.contains(**parent))
+        .count();
+    let rate = |count: usize| {
+        if decided.is_empty() {
+            0.0
+        } else {
+            count as f64 / decided.len() as f64
+        }
+    };
+
+    Some(Report {
+        engine: engine.to_string(),
+        nodes,
+        duration_secs: duration.as_secs_f64(),
+        transactions_submitted: submitted,
+        transactions_finalized: finalized,
+        transactions_invalid: recorder.invalid.values().sum(),
+        blocks_proposed: recorder
+            .proposed
+            .iter()
+            .map(|(node, blocks)| (node.to_string(), *blocks))
+            .collect(),
+        consensus_messages: recorder.consensus_messages,
+        consensus_bytes: recorder.consensus_bytes,
+        blocks_mined: recorder.mined.len(),
+        orphan_rate: rate(stale.len()),
+        uncle_rate: rate(uncles),
+        hashes: recorder.hashes,
+        throughput_tps: if duration.is_zero() {
+            0.0
+        } else {
+            finalized as f64 / duration.as_secs_f64()
+        },
+        transaction_latency: Distribution::new(transaction_latency),
+        propagation_latency: Distribution::new(propagation_latency),
+        block_time: Distribution::new(block_time),
+        finality_time: Distribution::new(finality_time),
+    })
+}
+
+/// Timeline is when the transactions submitted to a node were submitted, and when
+/// every transaction became final on it, by transaction hash. Times are milliseconds
+/// on the clock blocks are stamped with, so the timelines of live nodes compare.
+#[derive(Debug, Default)]
+pub struct Timeline {
+    pub submitted: BTreeMap<Vec<u8>, u64>,
+    pub finalized: BTreeMap<Vec<u8>, u64>,
+    /// when the timeline was taken
+    pub end_ms: u64,
+}
+
+/// Returns the timeline of `node` recorded so far, None if recording was never started.
+pub fn timeline(node: PeerId) -> Option<Timeline> {
+    let recorder = RECORDER.lock().unwrap();
+    let recorder = recorder.as_ref()?;
+    let now = recorder.clock.now();
+    let start_ms = recorder
+        .clock
+        .timestamp()
+        .saturating_sub(now.as_millis() as u64);
+    let ms = |time: &Duration| start_ms + time.as_millis() as u64;
+
+    let mut timeline = Timeline {
+        end_ms: ms(&now),
+        ..Timeline::default()
+    };
+    for (hash, times) in &recorder.transactions {
+        if let Some(submitted) = &times.submitted {
+            timeline.submitted.insert(hash.clone(), ms(submitted));
+        }
+        if let Some(finalized) = times.finalized.get(&node) {
+            timeline.finalized.insert(hash.clone(), ms(finalized));
+        }
+    }
+    Some(timeline)
+}
+
+// exports how long a transaction took to reach the stage, if it was submitted here
+fn stage_reached(submitted: Option<Duration>, stage: Stage, now: Duration) {
+    if let Some(submitted) = submitted {
+        prometheus::transaction_stage(stage, now.saturating_sub(submitted));
+    }
+}
+
+// runs `f` with the current time if recording was started
+fn record(f: impl FnOnce(&mut Recorder, Duration)) {
+    if let Some(recorder) = RECORDER.lock().unwrap().as_mut() {
+        let now = recorder.clock.now();
+        f(recorder, now);
+    }
+}
@@ -0,0 +1,130 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+/// Report holds the Hyperledger performance metrics of a run, durations are in seconds.
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub engine: String,
+    pub nodes: usize,
+    pub duration_secs: f64,
+    pub transactions_submitted: usize,
+    /// transactions final on the network threshold of nodes
+    pub transactions_finalized: usize,
+    /// receipts dropped for an invalid signature, over every node
+    pub transactions_invalid: usize,
+    /// blocks each node proposed, by peer id, how the block production load is spread
+    pub blocks_proposed: BTreeMap<String, usize>,
+    /// engine messages sent, a broadcast counts once per peer it reaches
+    pub consensus_messages: usize,
+    /// bytes of the engine messages sent
+    pub consensus_bytes: usize,
+    /// blocks found by proof-of-work miners
+    pub blocks_mined: usize,
+    /// share of the mined blocks up to the highest final one that no node made final
+    pub orphan_rate: f64,
+    /// share of the same blocks that are stale with a final parent, forks one block deep
+    pub uncle_rate: f64,
+    /// hashes computed by proof-of-work miners
+    pub hashes: u64,
+    /// finalized transactions per second over the run
+    pub throughput_tps: f64,
+    /// from submission to being final on the network threshold of nodes
+    pub transaction_latency: Distribution,
+    /// from submission to receipt, for every node that received the transaction, how
+    /// long gossip takes to spread it rather than the read latency of the whitepaper
+    pub propagation_latency: Distribution,
+    /// between two consecutive blocks appended to the chain of a node
+    pub block_time: Distribution,
+    /// from inclusion in a block to being final on the network threshold of nodes
+    pub finality_time: Distribution,
+}
+
+/// Distribution summarizes a set of durations, in seconds.
+#[derive(Debug, Default, Serialize)]
+pub struct Distribution {
+    pub samples: usize,
+    pub mean: f64,
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+impl Distribution {
+    pub fn new(mut durations: Vec<Duration>) -> Self {
+        if durations.is_empty() {
+            return Self::default();
+        }
+        durations.sort();
+
+        // nearest-rank percentile
+        let percentile = |p: f64| {
+            let rank = ((p / 100.0) * durations.len() as f64).ceil() as usize;
+            durations[rank.clamp(1, durations.len()) - 1].as_secs_f64()
+        };
+        let total: Duration = durations.iter().sum();
+
+        Self {
+            samples: durations.len(),
+            mean: total.as_secs_f64() / durations.len() as f64,
+            p50: percentile(50.0),
+            p90: percentile(90.0),
+            p99: percentile(99.0),
+            max: durations[durations.len() - 1].as_secs_f64(),
+        }
+    }
+}
+
+impl Report {
+    /// Writes the report to `path`, as CSV if the file name ends with `.csv` and as JSON otherwise.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let content = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("csv") => self.to_csv(),
+            _ => serde_json::to_string_pretty(self)? + "\n",
+        };
+        fs::write(path, content)
+    }
+
+    // one metric,value row per metric, distributions are flattened as <name>_<statistic>
+    fn to_csv(&self) -> String {
+        let mut csv = String::from("metric,value\n");
+        let mut row = |metric: &str, value: &dyn std::fmt::Display| {
+            writeln!(csv, "{},{}", metric, value).unwrap();
+        };
+        row("engine", &self.engine);
+        row("nodes", &self.nodes);
+        row("duration_secs", &self.duration_secs);
+        row("transactions_submitted", &self.transactions_submitted);
+        row("transactions_finalized", &self.transactions_finalized);
+        row("transactions_invalid", &self.transactions_invalid);
+        for (node, blocks) in &self.blocks_proposed {
+            row(&format!("blocks_proposed_{node}"), blocks);
+        }
+        row("consensus_messages", &self.consensus_messages);
+        row("consensus_bytes", &self.consensus_bytes);
+        row("blocks_mined", &self.blocks_mined);
+        row("orphan_rate", &self.orphan_rate);
+        row("uncle_rate", &self.uncle_rate);
+        row("hashes", &self.hashes);
+        row("throughput_tps", &self.throughput_tps);
+        for (name, distribution) in [
+            ("transaction_latency", &self.transaction_latency),
+            ("propagation_latency", &self.propagation_latency),
+            ("block_time", &self.block_time),
+            ("finality_time", &self.finality_time),
+        ] {
+            row(&format!("{name}_samples"), &distribution.samples);
+            row(&format!("{name}_mean"), &distribution.mean);
+            row(&format!("{name}_p50"), &distribution.p50);
+            row(&format!("{name}_p90"), &distribution.p90);
+            row(&format!("{name}_p99"), &distribution.p99);
+            row(&format!("{name}_max"), &distribution.max);
+        }
+        csv
+    }
+}
@@ -0,0 +1,404 @@
+/*
+Faults are injected between the network and the engine of a node: a message a node
+receives from a peer is dropped with the loss probability of their link, or handed
+to the engine after a delay drawn from the latency distribution of the link plus a
+uniform jitter. Scheduled partitions split the nodes into groups that drop each
+other's messages until the partition heals. A message is judged by the node that
+published it rather than the peer that relayed it, so a partition holds whatever
+route a message takes through the gossip mesh.
+
+Links and partitions name nodes by their index: the order of the simulated nodes,
+or on a live network the order of the peer ids listed in `network.peers`. Every draw
+goes through the generator of the run, so a seeded simulation injects the same
+faults every time.
+*/
+
+use crate::simulation::rng::with_shared_rng;
+use crate::CunnerError;
+use libp2p::PeerId;
+use rand::Rng;
+use rand_distr::{Distribution, Exp, Normal, Pareto};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Faults injected into the network, set in the `faults` section of the network
+/// configuration. Nothing is injected by default.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FaultConfig {
+    /// delay added to every message, on top of the latency of the network
+    pub latency: Option<Latency>,
+    /// upper bound of a uniform delay added on top of the latency
+    pub jitter_ms: u64,
+    /// probability that a message is dropped
+    pub loss: f64,
+    /// links whose faults differ from the ones above
+    pub links: Vec<LinkFaults>,
+    pub partitions: Vec<Partition>,
+}
+
+/// Latency is the distribution the delay of a message is drawn from, in milliseconds.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(tag = "distribution", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Latency {
+    Constant {
+        ms: f64,
+    },
+    Uniform {
+        min_ms: f64,
+        max_ms: f64,
+    },
+    Normal {
+        mean_ms: f64,
+        std_dev_ms: f64,
+    },
+    Exponential {
+        mean_ms: f64,
+    },
+    /// heavy tailed, most messages take about `scale_ms` and a few take much longer
+    Pareto {
+        scale_ms: f64,
+        shape: f64,
+    },
+}
+
+/// LinkFaults replaces the faults of the messages between two nodes, in both
+/// directions, the ones it does not set are kept.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LinkFaults {
+    pub between: (usize, usize),
+    pub latency: Option<Latency>,
+    pub jitter_ms: Option<u64>,
+    pub loss: Option<f64>,
+}
+
+/// Partition splits the nodes into groups that do not hear each other from
+/// `at_secs` after the start for `duration_secs`, or until the end of the run. Nodes
+/// of no group still hear every node.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Partition {
+    pub at_secs: u64,
+    pub duration_secs: Option<u64>,
+    pub groups: Vec<Vec<usize>>,
+}
+
+impl FaultConfig {
+    /// Checks the faults of a network of `nodes` nodes.
+    pub fn validate(&self, nodes: usize) -> Result<(), CunnerError> {
+        let invalid = |message: &str| Err(CunnerError::Config(format!("faults: {message}")));
+        let loss = |loss: f64| (0.0..=1.0).contains(&loss);
+        if !loss(self.loss) {
+            return invalid("loss must be between 0 and 1");
+        }
+        if let Some(latency) = &self.latency {
+            latency.validate()?;
+        }
+        for link in &self.links {
+            let (a, b) = link.between;
+            if a >= nodes || b >= nodes || a == b {
+                return invalid("links must be between two distinct nodes of the network");
+            }
+            if link.loss.is_some_and(|link_loss| !loss(link_loss)) {
+                return invalid("the loss of a link must be between 0 and 1");
+            }
+            if let Some(latency) = &link.latency {
+                latency.validate()?;
+            }
+        }
+        for partition in &self.partitions {
+            if partition.duration_secs == Some(0) {
+                return invalid("the duration_secs of a partition must be at least 1");
+            }
+            if partition.groups.len() < 2 {
+                return invalid("a partition needs at least two groups");
+            }
+            let mut seen = vec![false; nodes];
+            for node in partition.groups.iter().flatten() {
+                match seen.get_mut(*node) {
+                    Some(seen) if !*seen => *seen = true,
+                    Some(_) => return invalid("a node can only be in one group of a partition"),
+                    None => return invalid("partitions must only name nodes of the network"),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The faults name nodes by index, which a live node needs peer ids for.
+    pub fn names_nodes(&self) -> bool {
+        !self.links.is_empty() || !self.partitions.is_empty()
+    }
+}
+
+impl Latency {
+    fn validate(&self) -> Result<(), CunnerError> {
+        let valid = match *self {
+            Latency::Constant { ms } => ms >= 0.0,
+            Latency::Uniform { min_ms, max_ms } => min_ms >= 0.0 && min_ms <= max_ms,
+            Latency::Normal {
+                mean_ms,
+                std_dev_ms,
+            } => mean_ms >= 0.0 && std_dev_ms >= 0.0,
+            Latency::Exponential { mean_ms } => mean_ms > 0.0,
+            Latency::Pareto { scale_ms, shape } => scale_ms > 0.0 && shape > 0.0,
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err(CunnerError::Config(format!(
+                "faults: invalid latency {self:?}"
+            )))
+        }
+    }
+
+    // draws a delay in milliseconds, normal draws below zero count as none
+    fn sample(&self) -> f64 {
+        with_shared_rng(|rng| match *self {
+            Latency::Constant { ms } => ms,
+            Latency::Uniform { min_ms, max_ms } => rng.gen_range(min_ms..=max_ms),
+            Latency::Normal {
+                mean_ms,
+                std_dev_ms,
+            } => Normal::new(mean_ms, std_dev_ms)
+                .expect("validated normal latency")
+                .sample(rng),
+            Latency::Exponential { mean_ms } => Exp::new(1.0 / mean_ms)
+                .expect("validated exponential latency")
+                .sample(rng),
+            Latency::Pareto { scale_ms, shape } => Pareto::new(scale_ms, shape)
+                .expect("validated pareto latency")
+                .sample(rng),
+        })
+        .max(0.0)
+    }
+}
+
+impl Partition {
+    fn is_active(&self, now: Duration) -> bool {
+        let start = Duration::from_secs(self.at_secs);
+        now >= start
+            && self
+                .duration_secs
+                .is_none_or(|duration| now < start + Duration::from_secs(duration))
+    }
+
+    fn group(&self, node: usize) -> Option<usize> {
+        self.groups.iter().position(|group| group.contains(&node))
+    }
+}
+
+/// Faults decides what happens to every message a node receives.
+#[derive(Debug)]
+pub struct Faults {
+    config: FaultConfig,
+    // index of every node the faults can name
+    nodes: BTreeMap<PeerId, usize>,
+}
+
+impl Faults {
+    /// Returns the faults of a network whose nodes have the peer ids, in index order.
+    pub fn new(config: FaultConfig, nodes: &[PeerId]) -> Self {
+        Self {
+            config,
+            nodes: nodes
+                .iter()
+                .enumerate()
+                .map(|(index, peer_id)| (*peer_id, index))
+                .collect(),
+        }
+    }
+
+    /// Returns how long the message `to` receives from `from` at `now` is held back,
+    /// None if it is dropped.
+    pub fn delay(&self, from: PeerId, to: PeerId, now: Duration) -> Option<Duration> {
+        let nodes = self.nodes.get(&from).zip(self.nodes.get(&to));
+        if let Some((&from, &to)) = nodes {
+            let partitioned = self.config.partitions.iter().any(|partition| {
+                partition.is_active(now)
+                    && matches!(
+                        (partition.group(from), partition.group(to)),
+                        (Some(a), Some(b)) if a != b
+                    )
+            });
+            if partitioned {
+                return None;
+            }
+        }
+
+        let link = nodes.and_then(|(&from, &to)| {
+            self.config
+                .links
+                .iter()
+                .find(|link| link.between == (from, to) || link.between == (to, from))
+        });
+        let loss = link.and_then(|link| link.loss).unwrap_or(self.config.loss);
+        let latency = link.and_then(|link| link.latency).or(self.config.latency);
+        let jitter_ms = link
+            .and_then(|link| link.jitter_ms)
+            .unwrap_or(self.config.jitter_ms);
+
+        // nothing is drawn without faults, so they do not change the other draws of a run
+        if loss > 0.0 && with_shared_rng(|rng| rng.gen_bool(loss)) {
+            return None;
+        }
+        let mut delay_ms = latency.map_or(0.0, |latency| latency.sample());
+        if jitter_ms > 0 {
+            delay_ms += with_shared_rng(|rng| rng.gen_range(0.0..=jitter_ms as f64));
+        }
+        Some(Duration::from_secs_f64(delay_ms / 1000.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::rng;
+
+    fn faults(config: FaultConfig) -> (Faults, Vec<PeerId>) {
+        let nodes: Vec<PeerId> = (0..4).map(|_| PeerId::random()).collect();
+        (Faults::new(config, &nodes), nodes)
+    }
+
+    #[test]
+    fn validate_rejects_faults_outside_the_network() {
+        let link = |between, loss| LinkFaults {
+            between,
+            latency: None,
+            jitter_ms: None,
+            loss,
+        };
+        let partition = |groups| Partition {
+            at_secs: 0,
+            duration_secs: None,
+            groups,
+        };
+        let invalid = [
+            FaultConfig {
+                loss: 1.5,
+                ..FaultConfig::default()
+            },
+            FaultConfig {
+                latency: Some(Latency::Uniform {
+                    min_ms: 20.0,
+                    max_ms: 10.0,
+                }),
+                ..FaultConfig::default()
+            },
+            FaultConfig {
+                links: vec![link((1, 1), None)],
+                ..FaultConfig::default()
+            },
+            FaultConfig {
+                links: vec![link((0, 4), None)],
+                ..FaultConfig::default()
+            },
+            FaultConfig {
+                links: vec![link((0, 1), Some(-0.1))],
+                ..FaultConfig::default()
+            },
+            FaultConfig {
+                partitions: vec![partition(vec![vec![0, 1, 2, 3]])],
+                ..FaultConfig::default()
+            },
+            FaultConfig {
+                partitions: vec![partition(vec![vec![0, 1], vec![1, 2]])],
+                ..FaultConfig::default()
+            },
+            FaultConfig {
+                partitions: vec![partition(vec![vec![0], vec![4]])],
+                ..FaultConfig::default()
+            },
+        ];
+        for config in invalid {
+            assert!(config.validate(4).is_err(), "{config:?} is valid");
+        }
+        let valid = FaultConfig {
+            loss: 0.1,
+            links: vec![link((0, 3), Some(1.0))],
+            partitions: vec![partition(vec![vec![0, 1], vec![2]])],
+            ..FaultConfig::default()
+        };
+        assert!(valid.validate(4).is_ok());
+    }
+
+    #[test]
+    fn a_partition_drops_messages_between_its_groups_while_active() {
+        let (faults, nodes) = faults(FaultConfig {
+            partitions: vec![Partition {
+                at_secs: 10,
+                duration_secs: Some(20),
+                groups: vec![vec![0, 1], vec![2]],
+            }],
+            ..FaultConfig::default()
+        });
+        let delay = |from: usize, to: usize, secs| {
+            faults.delay(nodes[from], nodes[to], Duration::from_secs(secs))
+        };
+        assert_eq!(delay(0, 2, 5), Some(Duration::ZERO));
+        assert_eq!(delay(0, 2, 10), None);
+        assert_eq!(delay(2, 1, 29), None);
+        assert_eq!(delay(0, 1, 15), Some(Duration::ZERO));
+        // a node of no group hears every node
+        assert_eq!(delay(3, 2, 15), Some(Duration::ZERO));
+        assert_eq!(delay(0, 2, 30), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn messages_are_dropped_at_the_loss_rate_of_their_link() {
+        let _rng = rng::exclusive();
+        rng::seed(3);
+        let (faults, nodes) = faults(FaultConfig {
+            loss: 0.2,
+            links: vec![LinkFaults {
+                between: (2, 0),
+                latency: None,
+                jitter_ms: None,
+                loss: Some(0.0),
+            }],
+            ..FaultConfig::default()
+        });
+        let dropped = |from: usize, to: usize| {
+            (0..10_000)
+                .filter(|_| {
+                    faults
+                        .delay(nodes[from], nodes[to], Duration::ZERO)
+                        .is_none()
+                })
+                .count()
+        };
+        let lossy = dropped(0, 1);
+        assert!((1_800..2_200).contains(&lossy), "{lossy} of 10000 dropped");
+        assert_eq!(dropped(0, 2), 0);
+    }
+
+    #[test]
+    fn delays_are_the_latency_plus_a_bounded_jitter() {
+        let _rng = rng::exclusive();
+        rng::seed(3);
+        let (faults, nodes) = faults(FaultConfig {
+            latency: Some(Latency::Constant { ms: 100.0 }),
+            jitter_ms: 20,
+            links: vec![LinkFaults {
+                between: (0, 1),
+                latency: Some(Latency::Constant { ms: 500.0 }),
+                jitter_ms: Some(0),
+                loss: None,
+            }],
+            ..FaultConfig::default()
+        });
+        for _ in 0..1_000 {
+            let delay = faults.delay(nodes[2], nodes[3], Duration::ZERO).unwrap();
+            assert!(
+                (Duration::from_millis(100)..=Duration::from_millis(120)).contains(&delay),
+                "{delay:?}"
+            );
+        }
+        assert_eq!(
+            faults.delay(nodes[1], nodes[0], Duration::ZERO),
+            Some(Duration::from_millis(500))
+        );
+    }
+}
@@ -2,7 +2,11 @@
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Message {
-    #[prost(oneof = "message::Payload", tags = "5, 6")]
+    /// Peer the message is addressed to, empty when published to every peer. Gossip
+    /// has no unicast, the peers it is not addressed to drop it.
+    #[prost(bytes = "vec", tag = "8")]
+    pub to: ::prost::alloc::vec::Vec<u8>,
+    #[prost(oneof = "message::Payload", tags = "5, 6, 7")]
     pub payload: ::core::option::Option<message::Payload>,
 }
 /// Nested message and enum types in `Message`.
@@ -14,20 +18,41 @@ pub mod message {
         Transaction(super::Transaction),
         #[prost(message, tag = "6")]
         Block(super::Block),
+        #[prost(message, tag = "7")]
+        ConsensusMessage(super::ConsensusMessage),
     }
 }
 /// Header represents a very simple block header used for simulation.
+#[derive(serde::Serialize, serde::Deserialize)]
 #[allow(clippy::derive_partial_eq_without_eq)]
-#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Header {
     /// Index of the block.
     #[prost(uint32, tag = "1")]
     pub index: u32,
-    /// Nonce used to prevent hash collisions.
+    /// Nonce used to prevent hash collisions, proof-of-work engines search it for a
+    /// hash meeting the difficulty.
     #[prost(uint64, tag = "2")]
     pub nonce: u64,
+    /// Hash of the header of the previous block, empty for the first block of a chain.
+    #[prost(bytes = "vec", tag = "3")]
+    pub parent_hash: ::prost::alloc::vec::Vec<u8>,
+    /// Milliseconds on the clock of the proposer when the block was created.
+    #[prost(uint64, tag = "4")]
+    pub timestamp: u64,
+    /// Peer id of the node that created the block.
+    #[prost(bytes = "vec", tag = "5")]
+    pub proposer: ::prost::alloc::vec::Vec<u8>,
+    /// Merkle root over the hashes of the transactions of the block.
+    #[prost(bytes = "vec", tag = "6")]
+    pub tx_root: ::prost::alloc::vec::Vec<u8>,
+    /// Number of leading zero bits the hash of the header must have, 0 for engines
+    /// without proof of work.
+    #[prost(uint32, tag = "7")]
+    pub difficulty: u32,
 }
 /// Block represents a very simple Block used for simulation.
+#[derive(serde::Serialize, serde::Deserialize)]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Block {
@@ -37,13 +62,62 @@ pub struct Block {
     /// List of recorded transactions.
     #[prost(message, repeated, tag = "2")]
     pub transactions: ::prost::alloc::vec::Vec<Transaction>,
+    /// Signature of the proposer over the hash of the header, empty for engines that
+    /// do not sign their blocks.
+    #[prost(bytes = "vec", tag = "3")]
+    pub signature: ::prost::alloc::vec::Vec<u8>,
+    /// Votes of the validators that committed the block, outside the header so they do
+    /// not change its hash, missing for engines without commit votes.
+    #[prost(message, optional, tag = "4")]
+    pub commit: ::core::option::Option<Commit>,
+}
+/// Commit holds the signed votes that committed a block in a round.
+#[derive(serde::Serialize, serde::Deserialize)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Commit {
+    /// Round the block was committed in.
+    #[prost(uint64, tag = "1")]
+    pub round: u64,
+    /// Signature of every validator that voted for the block.
+    #[prost(message, repeated, tag = "2")]
+    pub signatures: ::prost::alloc::vec::Vec<CommitSignature>,
+}
+/// CommitSignature is the vote of a single validator.
+#[derive(serde::Serialize, serde::Deserialize)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct CommitSignature {
+    /// Peer id of the validator.
+    #[prost(bytes = "vec", tag = "1")]
+    pub validator: ::prost::alloc::vec::Vec<u8>,
+    /// Signature of the validator over its vote for the block.
+    #[prost(bytes = "vec", tag = "2")]
+    pub signature: ::prost::alloc::vec::Vec<u8>,
 }
 /// Transaction represents a very simple transaction used for simulation.
 #[derive(serde::Serialize, serde::Deserialize)]
 #[allow(clippy::derive_partial_eq_without_eq)]
-#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Transaction {
     /// Nonce used to prevent hash collisions.
     #[prost(uint64, tag = "1")]
     pub nonce: u64,
+    /// Compressed secp256k1 public key of the node that emitted the transaction.
+    #[prost(bytes = "vec", tag = "2")]
+    pub sender: ::prost::alloc::vec::Vec<u8>,
+    /// Application data carried by the transaction.
+    #[prost(bytes = "vec", tag = "3")]
+    pub payload: ::prost::alloc::vec::Vec<u8>,
+    /// Compact ECDSA signature of the nonce, sender and payload by the sender.
+    #[prost(bytes = "vec", tag = "4")]
+    pub signature: ::prost::alloc::vec::Vec<u8>,
+}
+/// ConsensusMessage carries an engine specific message, opaque to the network.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ConsensusMessage {
+    /// Message as encoded by the sending engine.
+    #[prost(bytes = "vec", tag = "1")]
+    pub data: ::prost::alloc::vec::Vec<u8>,
 }
@@ -1,36 +1,158 @@
 use crate::network::messages::message::{Block, Header, Transaction};
+use crate::network::messages::protobuf::encode_header;
+use crate::simulation::rng::with_rng;
+use libp2p::PeerId;
 use rand::Rng;
 use sha2::{Digest, Sha256};
-use std::sync::Once;
+use std::collections::HashSet;
+use thiserror::Error;
 
-static INIT: Once = Once::new();
-
-fn init() {
-    INIT.call_once(|| {
-        let mut rng = rand::thread_rng();
-        rng.gen::<u64>(); // Seed the RNG
-    });
+/// BlockError is the reason a block does not verify.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum BlockError {
+    #[error("block has no header")]
+    MissingHeader,
+    #[error("transaction root does not match the transactions")]
+    TxRoot,
+    #[error("transaction {0} is not signed by its sender")]
+    Signature(usize),
+    #[error("transaction {0} is a duplicate of an earlier transaction")]
+    Duplicate(usize),
+    #[error("parent hash does not match the parent block")]
+    ParentHash,
+    #[error("index {found} does not follow the parent index {parent}")]
+    Index { parent: u32, found: u32 },
+    #[error("timestamp is older than the parent timestamp")]
+    Timestamp,
 }
 
 impl Block {
-    pub fn new_block(prev_index: u32, transactions: Vec<Transaction>) -> Block {
-        init();
-        let mut rng = rand::thread_rng();
+    /// Returns a block extending `parent` created by `proposer` at `timestamp`,
+    /// the first block of a chain has no parent.
+    pub fn new_block(
+        parent: Option<&Block>,
+        proposer: PeerId,
+        timestamp: u64,
+        transactions: Vec<Transaction>,
+    ) -> Block {
+        let parent_header = parent.and_then(|parent| parent.header.as_ref());
         Block {
             header: Some(Header {
-                index: prev_index + 1,
-                nonce: rng.gen(),
+                index: parent_header.map_or(0, |header| header.index) + 1,
+                nonce: with_rng(|rng| rng.gen()),
+                parent_hash: parent.map(Block::hash).unwrap_or_default(),
+                timestamp,
+                proposer: proposer.to_bytes(),
+                tx_root: tx_root(&transactions),
+                difficulty: 0,
             }),
             transactions,
+            signature: Vec::new(),
+            commit: None,
+        }
+    }
+
+    /// Returns the sha256 hash of the encoded header, the header commits to the
+    /// transactions through their Merkle root.
+    pub fn hash(&self) -> Vec<u8> {
+        let encoded = self.header.as_ref().map(encode_header).unwrap_or_default();
+        Sha256::digest(encoded).to_vec()
+    }
+
+    /// Checks the block on its own: it has a header whose root matches its
+    /// transactions, no transaction appears twice and every transaction is signed
+    /// by its sender.
+    pub fn verify_body(&self) -> Result<(), BlockError> {
+        let header = self.header.as_ref().ok_or(BlockError::MissingHeader)?;
+        if header.tx_root != tx_root(&self.transactions) {
+            return Err(BlockError::TxRoot);
+        }
+        let mut seen = HashSet::new();
+        if let Some(index) = self
+            .transactions
+            .iter()
+            .position(|transaction| !seen.insert(transaction.hash()))
+        {
+            return Err(BlockError::Duplicate(index));
+        }
+        if let Some(index) = self
+            .transactions
+            .iter()
+            .position(|transaction| !transaction.verify_signature())
+        {
+            return Err(BlockError::Signature(index));
         }
+        Ok(())
+    }
+
+    /// Checks that the block extends `parent`, None checks it starts a chain.
+    pub fn verify_parent(&self, parent: Option<&Block>) -> Result<(), BlockError> {
+        let header = self.header.as_ref().ok_or(BlockError::MissingHeader)?;
+        let (parent_hash, parent_index, parent_timestamp) = match parent {
+            Some(parent) => {
+                let parent_header = parent.header.as_ref().ok_or(BlockError::MissingHeader)?;
+                (parent.hash(), parent_header.index, parent_header.timestamp)
+            }
+            None => (Vec::new(), 0, 0),
+        };
+
+        if header.parent_hash != parent_hash {
+            return Err(BlockError::ParentHash);
+        }
+        if header.index != parent_index + 1 {
+            return Err(BlockError::Index {
+                parent: parent_index,
+                found: header.index,
+            });
+        }
+        if header.timestamp < parent_timestamp {
+            return Err(BlockError::Timestamp);
+        }
+        Ok(())
+    }
+
+    /// Checks the block on its own and as the child of `parent`.
+    pub fn verify(&self, parent: Option<&Block>) -> Result<(), BlockError> {
+        self.verify_body()?;
+        self.verify_parent(parent)
     }
 }
 
+/// Returns the Merkle root over the hashes of the transactions. Pairs are hashed
+/// with sha256, the last hash of an odd level moves up a level as it is, and an
+/// empty list has a root of zeros. Pairing the odd hash with itself would give a
+/// list ending in a repeated transaction the root of the list without the repeat.
+pub fn tx_root(transactions: &[Transaction]) -> Vec<u8> {
+    let mut level: Vec<Vec<u8>> = transactions.iter().map(Transaction::hash).collect();
+    if level.is_empty() {
+        return vec![0; 32];
+    }
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => {
+                    let mut hasher = Sha256::new();
+                    hasher.update(left);
+                    hasher.update(right);
+                    hasher.finalize().to_vec()
+                }
+                [odd] => odd.clone(),
+                _ => unreachable!("chunks of two"),
+            })
+            .collect();
+    }
+    level.remove(0)
+}
+
 impl Transaction {
     pub fn new_transaction() -> Transaction {
-        init();
-        let mut rng = rand::thread_rng();
-        Transaction { nonce: rng.gen() }
+        Transaction {
+            nonce: with_rng(|rng| rng.gen()),
+            sender: Vec::new(),
+            payload: Vec::new(),
+            signature: Vec::new(),
+        }
     }
 
     pub fn hash(&self) -> Vec<u8> {
@@ -45,3 +167,108 @@ impl Transaction {
         double_hashed.to_vec()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::messages::signing::SigningKey;
+    use crate::simulation::rng;
+
+    fn signed_transactions(count: usize) -> Vec<Transaction> {
+        let key = SigningKey::generate();
+        (0..count)
+            .map(|_| {
+                let mut transaction = Transaction::new_transaction();
+                key.sign(&mut transaction);
+                transaction
+            })
+            .collect()
+    }
+
+    fn pair(left: &[u8], right: &[u8]) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        hasher.update(left);
+        hasher.update(right);
+        hasher.finalize().to_vec()
+    }
+
+    #[test]
+    fn tx_root_pairs_the_hashes() {
+        let _rng = rng::exclusive();
+        assert_eq!(tx_root(&[]), vec![0; 32]);
+
+        let transactions = signed_transactions(3);
+        let hashes: Vec<Vec<u8>> = transactions.iter().map(Transaction::hash).collect();
+        assert_eq!(tx_root(&transactions[..1]), hashes[0]);
+        // the odd hash of a level moves up unhashed
+        let expected = pair(&pair(&hashes[0], &hashes[1]), &hashes[2]);
+        assert_eq!(tx_root(&transactions), expected);
+
+        let mut repeated = transactions.clone();
+        repeated.push(transactions[2].clone());
+        assert_ne!(tx_root(&repeated), expected);
+
+        let mut reordered = transactions.clone();
+        reordered.swap(0, 1);
+        assert_ne!(tx_root(&reordered), expected);
+    }
+
+    #[test]
+    fn verify_body_checks_the_root_and_the_signatures() {
+        let _rng = rng::exclusive();
+        let block = Block::new_block(None, PeerId::random(), 1_000, signed_transactions(2));
+        assert_eq!(block.verify_body(), Ok(()));
+
+        let mut dropped = block.clone();
+        dropped.transactions.pop();
+        assert_eq!(dropped.verify_body(), Err(BlockError::TxRoot));
+
+        let mut unsigned = signed_transactions(2);
+        unsigned[1].signature.clear();
+        let block = Block::new_block(None, PeerId::random(), 1_000, unsigned);
+        assert_eq!(block.verify_body(), Err(BlockError::Signature(1)));
+
+        let mut duplicated = signed_transactions(2);
+        duplicated.push(duplicated[0].clone());
+        let block = Block::new_block(None, PeerId::random(), 1_000, duplicated);
+        assert_eq!(block.verify_body(), Err(BlockError::Duplicate(2)));
+
+        let mut headless = block;
+        headless.header = None;
+        assert_eq!(headless.verify_body(), Err(BlockError::MissingHeader));
+    }
+
+    #[test]
+    fn verify_parent_rejects_blocks_not_extending_the_parent() {
+        let _rng = rng::exclusive();
+        let proposer = PeerId::random();
+        let genesis = Block::new_block(None, proposer, 1_000, Vec::new());
+        let parent = Block::new_block(Some(&genesis), proposer, 2_000, Vec::new());
+        let child = Block::new_block(Some(&parent), proposer, 3_000, Vec::new());
+        assert_eq!(genesis.verify_parent(None), Ok(()));
+        assert_eq!(child.verify_parent(Some(&parent)), Ok(()));
+
+        assert_eq!(
+            child.verify_parent(Some(&genesis)),
+            Err(BlockError::ParentHash)
+        );
+        assert_eq!(parent.verify_parent(None), Err(BlockError::ParentHash));
+
+        let mut skipped = child.clone();
+        skipped.header.as_mut().unwrap().index = 4;
+        assert_eq!(
+            skipped.verify_parent(Some(&parent)),
+            Err(BlockError::Index {
+                parent: 2,
+                found: 4
+            })
+        );
+
+        let mut older = child;
+        older.header.as_mut().unwrap().timestamp = 1_500;
+        assert_eq!(
+            older.verify_parent(Some(&parent)),
+            Err(BlockError::Timestamp)
+        );
+    }
+}
@@ -1,5 +1,7 @@
 use crate::network::messages::message::message::Payload;
-use crate::network::messages::message::{Block, Header, Message, Transaction};
+use crate::network::messages::message::{
+    Block, Commit, CommitSignature, ConsensusMessage, Header, Message, Transaction,
+};
 use std::io::{self, Error, ErrorKind};
 
 // Encode a Message into a Vec<u8>
@@ -21,16 +23,32 @@ pub fn encode_message(msg: &Message) -> Vec<u8> {
             encode_varint(encoded_block.len() as u64, &mut result);
             result.extend_from_slice(&encoded_block);
         }
+        Some(Payload::ConsensusMessage(consensus_message)) => {
+            // Field number 7, wire type 2 (length-delimited)
+            result.extend_from_slice(&[58]);
+            let encoded_consensus_message = encode_consensus_message(consensus_message);
+            encode_varint(encoded_consensus_message.len() as u64, &mut result);
+            result.extend_from_slice(&encoded_consensus_message);
+        }
         None => {}
     }
 
+    if !msg.to.is_empty() {
+        // Field number 8, wire type 2 (length-delimited)
+        result.extend_from_slice(&[66]);
+        encode_bytes(&msg.to, &mut result);
+    }
+
     result
 }
 
 // Decode a Vec<u8> into a Message
 pub fn decode_message(bytes: &[u8]) -> io::Result<Message> {
     let mut index = 0;
-    let mut msg = Message { payload: None };
+    let mut msg = Message {
+        payload: None,
+        to: Vec::new(),
+    };
 
     while index < bytes.len() {
         let (field_number, wire_type) = decode_key(&mut index, bytes)?;
@@ -49,6 +67,17 @@ pub fn decode_message(bytes: &[u8]) -> io::Result<Message> {
                 msg.payload = Some(Payload::Block(block));
                 index += len;
             }
+            (7, 2) => {
+                // ConsensusMessage
+                let len = decode_varint(&mut index, bytes)? as usize;
+                let consensus_message = decode_consensus_message(slice(bytes, index, len)?)?;
+                msg.payload = Some(Payload::ConsensusMessage(consensus_message));
+                index += len;
+            }
+            (8, 2) => {
+                // to
+                msg.to = decode_bytes(&mut index, bytes)?;
+            }
             _ => return Err(Error::new(ErrorKind::InvalidData, "Unknown field")),
         }
     }
@@ -61,12 +90,30 @@ fn encode_transaction(transaction: &Transaction) -> Vec<u8> {
     // Field number 1, wire type 0 (varint)
     result.extend_from_slice(&[8]);
     encode_varint(transaction.nonce, &mut result);
+
+    // Field number 2, wire type 2 (length-delimited)
+    result.extend_from_slice(&[18]);
+    encode_bytes(&transaction.sender, &mut result);
+
+    // Field number 3, wire type 2 (length-delimited)
+    result.extend_from_slice(&[26]);
+    encode_bytes(&transaction.payload, &mut result);
+
+    // Field number 4, wire type 2 (length-delimited)
+    result.extend_from_slice(&[34]);
+    encode_bytes(&transaction.signature, &mut result);
+
     result
 }
 
 fn decode_transaction(bytes: &[u8]) -> io::Result<Transaction> {
     let mut index = 0;
-    let mut transaction = Transaction { nonce: 0 };
+    let mut transaction = Transaction {
+        nonce: 0,
+        sender: Vec::new(),
+        payload: Vec::new(),
+        signature: Vec::new(),
+    };
 
     while index < bytes.len() {
         let (field_number, wire_type) = decode_key(&mut index, bytes)?;
@@ -75,6 +122,18 @@ fn decode_transaction(bytes: &[u8]) -> io::Result<Transaction> {
                 // nonce
                 transaction.nonce = decode_varint(&mut index, bytes)?;
             }
+            (2, 2) => {
+                // sender
+                transaction.sender = decode_bytes(&mut index, bytes)?;
+            }
+            (3, 2) => {
+                // payload
+                transaction.payload = decode_bytes(&mut index, bytes)?;
+            }
+            (4, 2) => {
+                // signature
+                transaction.signature = decode_bytes(&mut index, bytes)?;
+            }
             _ => {
                 return Err(Error::new(
                     ErrorKind::InvalidData,
@@ -106,6 +165,20 @@ fn encode_block(block: &Block) -> Vec<u8> {
         result.extend_from_slice(&encoded_transaction);
     }
 
+    if !block.signature.is_empty() {
+        // Field number 3, wire type 2 (length-delimited)
+        result.extend_from_slice(&[26]);
+        encode_bytes(&block.signature, &mut result);
+    }
+
+    if let Some(commit) = &block.commit {
+        // Field number 4, wire type 2 (length-delimited)
+        result.extend_from_slice(&[34]);
+        let encoded_commit = encode_commit(commit);
+        encode_varint(encoded_commit.len() as u64, &mut result);
+        result.extend_from_slice(&encoded_commit);
+    }
+
     result
 }
 
@@ -114,6 +187,8 @@ fn decode_block(bytes: &[u8]) -> io::Result<Block> {
     let mut block = Block {
         header: None,
         transactions: Vec::new(),
+        signature: Vec::new(),
+        commit: None,
     };
 
     while index < bytes.len() {
@@ -132,6 +207,16 @@ fn decode_block(bytes: &[u8]) -> io::Result<Block> {
                 block.transactions.push(transaction);
                 index += len;
             }
+            (3, 2) => {
+                // signature
+                block.signature = decode_bytes(&mut index, bytes)?;
+            }
+            (4, 2) => {
+                // commit
+                let len = decode_varint(&mut index, bytes)? as usize;
+                block.commit = Some(decode_commit(slice(bytes, index, len)?)?);
+                index += len;
+            }
             _ => return Err(Error::new(ErrorKind::InvalidData, "Unknown field in Block")),
         }
     }
@@ -139,7 +224,103 @@ fn decode_block(bytes: &[u8]) -> io::Result<Block> {
     Ok(block)
 }
 
-fn encode_header(header: &Header) -> Vec<u8> {
+fn encode_commit(commit: &Commit) -> Vec<u8> {
+    let mut result = Vec::new();
+
+    // Field number 1, wire type 0 (varint)
+    result.extend_from_slice(&[8]);
+    encode_varint(commit.round, &mut result);
+
+    for signature in &commit.signatures {
+        // Field number 2, wire type 2 (length-delimited)
+        result.extend_from_slice(&[18]);
+        let encoded_signature = encode_commit_signature(signature);
+        encode_varint(encoded_signature.len() as u64, &mut result);
+        result.extend_from_slice(&encoded_signature);
+    }
+
+    result
+}
+
+fn decode_commit(bytes: &[u8]) -> io::Result<Commit> {
+    let mut index = 0;
+    let mut commit = Commit {
+        round: 0,
+        signatures: Vec::new(),
+    };
+
+    while index < bytes.len() {
+        let (field_number, wire_type) = decode_key(&mut index, bytes)?;
+        match (field_number, wire_type) {
+            (1, 0) => {
+                // round
+                commit.round = decode_varint(&mut index, bytes)?;
+            }
+            (2, 2) => {
+                // signature
+                let len = decode_varint(&mut index, bytes)? as usize;
+                let signature = decode_commit_signature(slice(bytes, index, len)?)?;
+                commit.signatures.push(signature);
+                index += len;
+            }
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Unknown field in Commit",
+                ))
+            }
+        }
+    }
+
+    Ok(commit)
+}
+
+fn encode_commit_signature(signature: &CommitSignature) -> Vec<u8> {
+    let mut result = Vec::new();
+
+    // Field number 1, wire type 2 (length-delimited)
+    result.extend_from_slice(&[10]);
+    encode_bytes(&signature.validator, &mut result);
+
+    // Field number 2, wire type 2 (length-delimited)
+    result.extend_from_slice(&[18]);
+    encode_bytes(&signature.signature, &mut result);
+
+    result
+}
+
+fn decode_commit_signature(bytes: &[u8]) -> io::Result<CommitSignature> {
+    let mut index = 0;
+    let mut signature = CommitSignature {
+        validator: Vec::new(),
+        signature: Vec::new(),
+    };
+
+    while index < bytes.len() {
+        let (field_number, wire_type) = decode_key(&mut index, bytes)?;
+        match (field_number, wire_type) {
+            (1, 2) => {
+                // validator
+                signature.validator = decode_bytes(&mut index, bytes)?;
+            }
+            (2, 2) => {
+                // signature
+                signature.signature = decode_bytes(&mut index, bytes)?;
+            }
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Unknown field in CommitSignature",
+                ))
+            }
+        }
+    }
+
+    Ok(signature)
+}
+
+/// Encodes a block header, which is what the hash of a block is computed over.
+pub fn encode_header(header: &Header) -> Vec<u8> {
     let mut result = Vec::new();
 
     // Field number 1, wire type 0 (varint)
@@ -150,12 +331,40 @@ fn encode_header(header: &Header) -> Vec<u8> {
     result.extend_from_slice(&[16]);
     encode_varint(header.nonce, &mut result);
 
+    // Field number 3, wire type 2 (length-delimited)
+    result.extend_from_slice(&[26]);
+    encode_bytes(&header.parent_hash, &mut result);
+
+    // Field number 4, wire type 0 (varint)
+    result.extend_from_slice(&[32]);
+    encode_varint(header.timestamp, &mut result);
+
+    // Field number 5, wire type 2 (length-delimited)
+    result.extend_from_slice(&[42]);
+    encode_bytes(&header.proposer, &mut result);
+
+    // Field number 6, wire type 2 (length-delimited)
+    result.extend_from_slice(&[50]);
+    encode_bytes(&header.tx_root, &mut result);
+
+    // Field number 7, wire type 0 (varint)
+    result.extend_from_slice(&[56]);
+    encode_varint(header.difficulty as u64, &mut result);
+
     result
 }
 
 fn decode_header(bytes: &[u8]) -> io::Result<Header> {
     let mut index = 0;
-    let mut header = Header { index: 0, nonce: 0 };
+    let mut header = Header {
+        index: 0,
+        nonce: 0,
+        parent_hash: Vec::new(),
+        timestamp: 0,
+        proposer: Vec::new(),
+        tx_root: Vec::new(),
+        difficulty: 0,
+    };
 
     while index < bytes.len() {
         let (field_number, wire_type) = decode_key(&mut index, bytes)?;
@@ -168,6 +377,26 @@ fn decode_header(bytes: &[u8]) -> io::Result<Header> {
                 // nonce
                 header.nonce = decode_varint(&mut index, bytes)?;
             }
+            (3, 2) => {
+                // parent_hash
+                header.parent_hash = decode_bytes(&mut index, bytes)?;
+            }
+            (4, 0) => {
+                // timestamp
+                header.timestamp = decode_varint(&mut index, bytes)?;
+            }
+            (5, 2) => {
+                // proposer
+                header.proposer = decode_bytes(&mut index, bytes)?;
+            }
+            (6, 2) => {
+                // tx_root
+                header.tx_root = decode_bytes(&mut index, bytes)?;
+            }
+            (7, 0) => {
+                // difficulty
+                header.difficulty = decode_varint(&mut index, bytes)? as u32;
+            }
             _ => {
                 return Err(Error::new(
                     ErrorKind::InvalidData,
@@ -180,6 +409,61 @@ fn decode_header(bytes: &[u8]) -> io::Result<Header> {
     Ok(header)
 }
 
+fn encode_consensus_message(consensus_message: &ConsensusMessage) -> Vec<u8> {
+    let mut result = Vec::new();
+
+    // Field number 1, wire type 2 (length-delimited)
+    result.extend_from_slice(&[10]);
+    encode_bytes(&consensus_message.data, &mut result);
+
+    result
+}
+
+fn decode_consensus_message(bytes: &[u8]) -> io::Result<ConsensusMessage> {
+    let mut index = 0;
+    let mut consensus_message = ConsensusMessage { data: Vec::new() };
+
+    while index < bytes.len() {
+        let (field_number, wire_type) = decode_key(&mut index, bytes)?;
+        match (field_number, wire_type) {
+            (1, 2) => {
+                // data
+                consensus_message.data = decode_bytes(&mut index, bytes)?;
+            }
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Unknown field in ConsensusMessage",
+                ))
+            }
+        }
+    }
+
+    Ok(consensus_message)
+}
+
+fn encode_bytes(value: &[u8], output: &mut Vec<u8>) {
+    encode_varint(value.len() as u64, output);
+    output.extend_from_slice(value);
+}
+
+fn decode_bytes(index: &mut usize, bytes: &[u8]) -> io::Result<Vec<u8>> {
+    let len = decode_varint(index, bytes)? as usize;
+    let value = slice(bytes, *index, len)?.to_vec();
+    *index += len;
+    Ok(value)
+}
+
+// Bounds checked sub-slice of a length-delimited field
+fn slice(bytes: &[u8], index: usize, len: usize) -> io::Result<&[u8]> {
+    bytes.get(index..index.saturating_add(len)).ok_or_else(|| {
+        Error::new(
+            ErrorKind::UnexpectedEof,
+            "Length-delimited field exceeds input",
+        )
+    })
+}
+
 fn encode_varint(value: u64, output: &mut Vec<u8>) {
     let mut value = value;
     while value >= 0b1000_0000 {
@@ -0,0 +1,210 @@
+/*
+Transactions are signed by the node that emits them with a secp256k1 key, and every
+node checks the signature before handing a transaction to its engine. The signature
+covers the nonce, the sender public key and the payload, so none of them can be
+changed by a relaying peer.
+
+Engines that sign their blocks do it with the libp2p identity of the proposer. The
+signature covers the hash of the header, and the peer id the header names as its
+proposer embeds the ed25519 public key it is checked against. A node given an
+identity file keeps its identity, and so its peer id, across restarts.
+*/
+
+use crate::network::messages::message::{Block, Transaction};
+use crate::simulation::rng::with_rng;
+use crate::CunnerError;
+use libp2p::identity::{Keypair, PublicKey as IdentityKey};
+use libp2p::PeerId;
+use once_cell::sync::Lazy;
+use rand::Rng;
+use secp256k1::ecdsa::Signature;
+use secp256k1::{All, Message as SignedDigest, PublicKey, Secp256k1, SecretKey};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+static SECP: Lazy<Secp256k1<All>> = Lazy::new(Secp256k1::new);
+
+/// SigningKey is the secp256k1 key a node signs its transactions with.
+#[derive(Clone)]
+pub struct SigningKey {
+    secret: SecretKey,
+    public: PublicKey,
+}
+
+impl SigningKey {
+    /// Generates a new key, drawn from the generator of the run so simulated keys are seeded.
+    pub fn generate() -> Self {
+        loop {
+            let secret: [u8; 32] = with_rng(|rng| rng.gen());
+            // the odds of drawing an invalid secret are about 2^-128
+            if let Ok(secret) = SecretKey::from_slice(&secret) {
+                return Self::new(secret);
+            }
+        }
+    }
+
+    /// Parses a hex encoded 32 bytes secret key.
+    pub fn from_hex(hex_key: &str) -> Result<Self, CunnerError> {
+        let bytes = hex::decode(hex_key.trim())
+            .map_err(|e| CunnerError::Config(format!("Private key is not hex: {}", e)))?;
+        let secret = SecretKey::from_slice(&bytes)
+            .map_err(|e| CunnerError::Config(format!("Invalid private key: {}", e)))?;
+        Ok(Self::new(secret))
+    }
+
+    /// Reads a key file holding a hex encoded secret key.
+    pub fn from_file(path: &Path) -> Result<Self, CunnerError> {
+        let content = fs::read_to_string(path).map_err(|e| {
+            CunnerError::Config(format!("Failed to read key file {}: {}", path.display(), e))
+        })?;
+        Self::from_hex(&content)
+    }
+
+    fn new(secret: SecretKey) -> Self {
+        Self {
+            public: secret.public_key(&SECP),
+            secret,
+        }
+    }
+
+    /// Returns the compressed public key transactions name as their sender.
+    pub fn public_key(&self) -> Vec<u8> {
+        self.public.serialize().to_vec()
+    }
+
+    /// Sets the sender of the transaction to this key and signs it.
+    pub fn sign(&self, transaction: &mut Transaction) {
+        transaction.sender = self.public_key();
+        let digest = signed_digest(transaction);
+        transaction.signature = SECP
+            .sign_ecdsa(&digest, &self.secret)
+            .serialize_compact()
+            .to_vec();
+    }
+}
+
+// the secret never shows up in logs
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SigningKey")
+            .field("public", &hex::encode(self.public_key()))
+            .finish()
+    }
+}
+
+impl Transaction {
+    /// Checks that the transaction is signed by its sender.
+    pub fn verify_signature(&self) -> bool {
+        let Ok(sender) = PublicKey::from_slice(&self.sender) else {
+            return false;
+        };
+        let Ok(signature) = Signature::from_compact(&self.signature) else {
+            return false;
+        };
+        SECP.verify_ecdsa(&signed_digest(self), &signature, &sender)
+            .is_ok()
+    }
+}
+
+impl Block {
+    /// Signs the hash of the header with the identity of the node, which must be
+    /// the proposer of the block.
+    pub fn sign(&mut self, keypair: &Keypair) {
+        self.signature = sign_as_node(keypair, &self.hash());
+    }
+
+    /// Checks that the block is signed by its proposer.
+    pub fn verify_signature(&self) -> bool {
+        let Some(header) = &self.header else {
+            return false;
+        };
+        PeerId::from_bytes(&header.proposer)
+            .is_ok_and(|proposer| verify_node_signature(&proposer, &self.hash(), &self.signature))
+    }
+}
+
+/// Signs `message` with the identity of the node, for engine messages that are
+/// relayed by other nodes and must still name who signed them.
+pub fn sign_as_node(keypair: &Keypair, message: &[u8]) -> Vec<u8> {
+    keypair.sign(message).expect("ed25519 keys always sign")
+}
+
+/// Checks that `signature` over `message` is by the node with the peer id.
+pub fn verify_node_signature(node: &PeerId, message: &[u8], signature: &[u8]) -> bool {
+    // peer ids of ed25519 keys hold the encoded key itself
+    let multihash = node.as_ref();
+    if multihash.code() != 0 {
+        return false;
+    }
+    IdentityKey::try_decode_protobuf(multihash.digest())
+        .is_ok_and(|key| key.verify(message, signature))
+}
+
+/// Reads the identity of a node from a file holding its hex encoded ed25519 secret
+/// key, or generates a new identity and writes it to the file if there is none.
+pub fn load_or_generate_identity(path: &Path) -> Result<Keypair, CunnerError> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let keypair = Keypair::generate_ed25519();
+            let secret = keypair
+                .clone()
+                .try_into_ed25519()
+                .expect("generated an ed25519 key")
+                .secret();
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            write_secret(path, &hex::encode(secret)).map_err(|e| {
+                CunnerError::Config(format!(
+                    "Failed to write identity file {}: {}",
+                    path.display(),
+                    e
+                ))
+            })?;
+            return Ok(keypair);
+        }
+        Err(e) => {
+            return Err(CunnerError::Config(format!(
+                "Failed to read identity file {}: {}",
+                path.display(),
+                e
+            )))
+        }
+    };
+    let bytes = hex::decode(content.trim())
+        .map_err(|e| CunnerError::Config(format!("Identity key is not hex: {}", e)))?;
+    Keypair::ed25519_from_bytes(bytes)
+        .map_err(|e| CunnerError::Config(format!("Invalid identity key: {}", e)))
+}
+
+// only the owner of the file may read the secret
+#[cfg(unix)]
+fn write_secret(path: &Path, content: &str) -> io::Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(content.as_bytes())
+}
+
+#[cfg(not(unix))]
+fn write_secret(path: &Path, content: &str) -> io::Result<()> {
+    fs::File::create_new(path)?.write_all(content.as_bytes())
+}
+
+// sha256 over the nonce, the sender and the payload, each length prefixed
+fn signed_digest(transaction: &Transaction) -> SignedDigest {
+    let mut hasher = Sha256::new();
+    hasher.update(transaction.nonce.to_le_bytes());
+    for field in [&transaction.sender, &transaction.payload] {
+        hasher.update((field.len() as u64).to_le_bytes());
+        hasher.update(field);
+    }
+    SignedDigest::from_digest(hasher.finalize().into())
+}
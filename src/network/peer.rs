@@ -1,58 +1,183 @@
-use crate::consensus::engine::Engine;
+use crate::config::experiment::PeerConfig;
+use crate::consensus::engine::{BlockVerdict, Context, Engine};
+use crate::metrics::prometheus::{self, spawn_metrics, MetricsNode};
+use crate::metrics::recorder;
+use crate::network::faults::Faults;
 use crate::network::messages::message::message::Payload;
-use crate::network::messages::message::Block;
 use crate::network::messages::message::{Message, Transaction};
 use crate::network::messages::protobuf::{decode_protobuf, encode_protobuf};
+use crate::network::messages::signing::SigningKey;
+use crate::network::rpc::{spawn_rpc, RpcNode};
+use crate::network::transport::Transport;
+use crate::simulation::rng::with_rng;
+use crate::simulation::trace::TraceWriter;
+use crate::workload::generator::Submission;
 use crate::CunnerError;
-use crate::PeerConfig;
-use libp2p::Swarm;
+use libp2p::core::ConnectedPoint;
+use libp2p::identity::Keypair;
+use libp2p::kad::store::MemoryStore;
+use libp2p::swarm::behaviour::toggle::Toggle;
 use libp2p::{
     futures::StreamExt,
-    gossipsub, mdns, noise,
+    gossipsub, identify, kad, mdns, noise,
     swarm::{NetworkBehaviour, SwarmEvent},
     tcp, yamux, PeerId,
 };
+use libp2p::{Multiaddr, StreamProtocol, Swarm};
 use log::{debug, error, info, warn};
 use once_cell::sync::Lazy;
-use rand::{thread_rng, Rng};
-use std::collections::{hash_map::DefaultHasher, HashSet};
+use rand::Rng;
+use std::collections::{hash_map::DefaultHasher, HashMap, HashSet};
 use std::error::Error as StdError;
+use std::future::pending;
 use std::hash::{Hash, Hasher};
+use std::pin::pin;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::sync::mpsc;
-use tokio::{io, select, time::sleep};
+use tokio::time::{interval, sleep, sleep_until, Instant};
+use tokio::{io, select, signal};
 // use web3::signing;
 
+/// Size of the random payload of an emitted transaction.
+const PAYLOAD_SIZE: usize = 32;
+/// Period between two dials of the bootstrap peers a node is not connected to, and
+/// two lookups of new peers in the Kademlia DHT.
+const DISCOVERY_INTERVAL: Duration = Duration::from_secs(5);
+/// Protocol of the Kademlia DHT, so cunner nodes never join another DHT.
+const KADEMLIA_PROTOCOL: StreamProtocol = StreamProtocol::new("/cunner/kad/1.0.0");
+
 static TRANSACTION_COUNTER: AtomicU64 = AtomicU64::new(0);
-static NETWORK_CONTEXT: Lazy<
-    Mutex<Option<(Arc<Mutex<Swarm<PeerBehaviour>>>, Arc<gossipsub::IdentTopic>)>>,
-> = Lazy::new(|| Mutex::new(None));
+static NETWORK_CONTEXT: Lazy<Mutex<Option<NetworkContext>>> = Lazy::new(|| Mutex::new(None));
+
+// what the engines get to see of the swarm owned by run_peer
+struct NetworkContext {
+    // messages published by the engine are queued here and relayed by the swarm loop
+    outbound: mpsc::UnboundedSender<Message>,
+    peers: HashSet<PeerId>,
+}
+
+/// SwarmTransport is the transport of a live node, what the engine publishes is
+/// relayed to the gossipsub topic by run_peer.
+pub struct SwarmTransport {
+    local_peer_id: PeerId,
+    // the peers of the network in index order, from network.peers
+    nodes: Vec<PeerId>,
+}
+
+impl SwarmTransport {
+    pub fn new(local_peer_id: PeerId, nodes: Vec<PeerId>) -> Self {
+        Self {
+            local_peer_id,
+            nodes,
+        }
+    }
+}
+
+impl Transport for SwarmTransport {
+    fn local_peer_id(&self) -> PeerId {
+        self.local_peer_id
+    }
+
+    fn connected_peers(&self) -> Vec<PeerId> {
+        NETWORK_CONTEXT
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|context| context.peers.iter().copied().collect())
+            .unwrap_or_default()
+    }
 
+    fn nodes(&self) -> Vec<PeerId> {
+        self.nodes.clone()
+    }
+
+    // gossipsub has no unicast, the message is published on the topic and
+    // dropped by every peer it is not addressed to
+    fn send_to(&self, peer: PeerId, mut message: Message) {
+        message.to = peer.to_bytes();
+        self.publish(message);
+    }
+
+    // queues the message for the swarm loop
+    fn publish(&self, message: Message) {
+        if let Some(context) = NETWORK_CONTEXT.lock().unwrap().as_ref() {
+            if context.outbound.send(message).is_err() {
+                error!("Network loop has stopped, dropping message");
+            }
+        } else {
+            error!("Network context not initialized");
+        }
+    }
+}
+
+// mDNS can be turned off, and Kademlia on, identify telling the DHT which addresses
+// the peers listen on
 #[derive(NetworkBehaviour)]
 struct PeerBehaviour {
     gossipsub: gossipsub::Behaviour,
-    mdns: mdns::tokio::Behaviour,
+    mdns: Toggle<mdns::tokio::Behaviour>,
+    kademlia: Toggle<kad::Behaviour<MemoryStore>>,
+    identify: Toggle<identify::Behaviour>,
 }
 
 // sets up the libp2p swarm, subscribes to a gossipsub topic, and starts listening for incoming connections
 pub async fn run_peer(
     configuration: PeerConfig,
+    context: Context,
     engine_instance: Arc<Mutex<Option<Box<dyn Engine>>>>,
+    mut trace: Option<TraceWriter>,
 ) -> Result<(), CunnerError> {
     // creating a multi-producer, single-consumer channel for Transaction types.
     // decouples the receipt of transactions from their processing, which can help manage load and ensure that network operations don't block transaction processing or vice versa.
-    let (tx, mut rx) = mpsc::channel(32);
+    // unbounded, as a burst of the workload is sent to it all at once from the loop that drains it
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    // the engine never touches the swarm directly, so publishing from it never waits on network events
+    let (outbound_tx, mut outbound_rx) = mpsc::unbounded_channel();
+    // messages received from peers wait here until the injected faults let them through
+    let (inbound_tx, mut inbound_rx) = mpsc::unbounded_channel::<(PeerId, Payload)>();
 
     // creates a new libp2p swarm with the provided configuration and custom behaviour
-    let swarm = Arc::new(Mutex::new(
-        create_swarm().map_err(|e| CunnerError::Network(e.to_string()))?,
-    ));
-    let topic = Arc::new(gossipsub::IdentTopic::new("cunner"));
+    let mut swarm = create_swarm(
+        configuration.keypair.clone(),
+        configuration.gossipsub_heartbeat(),
+        configuration.network.mdns,
+        configuration.network.kademlia,
+    )
+    .map_err(|e| CunnerError::Network(e.to_string()))?;
+    let topic = gossipsub::IdentTopic::new("cunner");
 
-    // stores the swarm and topic in a lazy-initialized mutex for thread-safe access
-    *NETWORK_CONTEXT.lock().unwrap() = Some((swarm.clone(), topic.clone()));
+    // stores the handle to the swarm loop in a lazy-initialized mutex for thread-safe access
+    *NETWORK_CONTEXT.lock().unwrap() = Some(NetworkContext {
+        outbound: outbound_tx,
+        peers: HashSet::new(),
+    });
+
+    // transactions submitted through the RPC API, handled like the ones the node emits
+    let (submitted_tx, mut submitted_rx) = mpsc::unbounded_channel();
+    if let Some(port) = configuration.rpc_port {
+        spawn_rpc(
+            port,
+            RpcNode {
+                chain: context.chain.clone(),
+                network: context.network.clone(),
+                engine: engine_instance.clone(),
+                key: configuration.private_key.clone(),
+                submitted: submitted_tx,
+            },
+        )?;
+    }
+
+    if let Some(port) = configuration.metrics_port {
+        spawn_metrics(
+            port,
+            MetricsNode {
+                chain: context.chain.clone(),
+                engine: engine_instance.clone(),
+            },
+        )?;
+    }
 
     let listen_address = configuration
         .tcp_listen_address
@@ -60,8 +185,6 @@ pub async fn run_peer(
         .unwrap_or_else(|| "/ip4/0.0.0.0/tcp/0".to_string());
 
     swarm
-        .lock()
-        .unwrap()
         .behaviour_mut()
         .gossipsub
         .subscribe(&topic)
@@ -69,8 +192,6 @@ pub async fn run_peer(
 
     // listen on default address if no port is specified
     swarm
-        .lock()
-        .unwrap()
         .listen_on(
             listen_address
                 .parse()
@@ -78,8 +199,19 @@ pub async fn run_peer(
         )
         .map_err(|e| CunnerError::Network(format!("Failed to listen on address: {}", e)))?;
 
-    // a set to keep track of discovered peers
-    let mut discovered_peers = HashSet::new();
+    // the peers connected to this node, and the ones only reached through them, whose
+    // messages were relayed by gossip
+    let mut connected_peers = HashSet::new();
+    let mut relayed_peers = HashSet::new();
+    let local_peer_id = context.network.local_peer_id();
+    let bootstrap = configuration.bootstrap_addresses()?;
+    // the peer last reached at every bootstrap address
+    let mut bootstrap_peers: HashMap<Multiaddr, PeerId> = HashMap::new();
+    let faults = &configuration.network.faults;
+    if faults.names_nodes() && configuration.network.peers.is_empty() {
+        warn!("Fault links and partitions name nodes by index, they need network.peers to apply");
+    }
+    let faults = Faults::new(faults.clone(), &configuration.peer_ids()?);
     // let mut processed_transactions: HashSet<Transaction> = HashSet::new();
 
     let _engine_run_future = {
@@ -95,6 +227,7 @@ pub async fn run_peer(
                         _ = engine.run() => {},
                         _ = tokio::time::sleep(Duration::from_secs(60)) => {
                             warn!("Engine run timed out, restarting...");
+                            prometheus::engine_restarted();
                         }
                     }
                 } else {
@@ -104,80 +237,260 @@ pub async fn run_peer(
         })
     };
 
+    // the workload of the node, shared with the others by its index in network.peers
+    let index = configuration
+        .peer_ids()?
+        .iter()
+        .position(|peer| *peer == local_peer_id);
+    if index.is_none() && configuration.workload.submission == Submission::SingleClient {
+        warn!("Single client submission needs the node in network.peers, it submits its share of the workload");
+    }
+    let mut workload = configuration
+        .workload
+        .new_workload(index, configuration.network.nodes)?;
+    // arrivals are due at a time since the start rather than after a sleep per loop
+    // iteration, so busy gossip does not starve the workload
+    let start = Instant::now();
+    let mut arrival = workload.as_mut().and_then(|workload| workload.next());
+    let mut discovery = interval(DISCOVERY_INTERVAL);
+    let mut shutdown = pin!(signal::ctrl_c());
+    let duration = configuration.duration();
+    // the node runs until Ctrl-C if the experiment has no duration
+    let mut deadline = pin!(async move {
+        match duration {
+            Some(duration) => sleep(duration).await,
+            None => pending().await,
+        }
+    });
+
     loop {
-        let mut swarm_guard = swarm.lock().unwrap();
         select! {
-            event = swarm_guard.select_next_some() => match event {
-                SwarmEvent::Behaviour(PeerBehaviourEvent::Mdns(mdns::Event::Discovered(list))) => {
-                    drop(swarm_guard);
-                    for (peer_id, _multiaddr) in list {
+            _ = &mut shutdown => {
+                info!("Shutting down");
+                break;
+            },
+            _ = &mut deadline => {
+                info!("Ran for the configured duration, shutting down");
+                break;
+            },
+            event = swarm.select_next_some() => match event {
+                SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. } => {
+                    if let ConnectedPoint::Dialer { address, .. } = endpoint {
+                        if bootstrap.contains(&address) {
+                            bootstrap_peers.insert(address.clone(), peer_id);
+                        }
+                        if let Some(kademlia) = swarm.behaviour_mut().kademlia.as_mut() {
+                            kademlia.add_address(&peer_id, address);
+                        }
+                    }
+                    if connected_peers.insert(peer_id) {
                         info!("Discovered a new peer: {peer_id}");
-                        discovered_peers.insert(peer_id);
-                        swarm.lock().unwrap().behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+                        swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+                        set_connected_peers(&connected_peers, &relayed_peers);
+                    }
+                },
+                SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. } => {
+                    warn!("Peer has gone offline: {peer_id}");
+                    connected_peers.remove(&peer_id);
+                    relayed_peers.remove(&peer_id);
+                    set_connected_peers(&connected_peers, &relayed_peers);
+                },
+                SwarmEvent::Behaviour(PeerBehaviourEvent::Mdns(mdns::Event::Discovered(list))) => {
+                    for (peer_id, multiaddr) in list {
+                        debug!("Found peer {peer_id} at {multiaddr} with mDNS");
+                        if let Some(kademlia) = swarm.behaviour_mut().kademlia.as_mut() {
+                            kademlia.add_address(&peer_id, multiaddr);
+                        }
+                        dial_peer(&mut swarm, peer_id);
                     }
                 },
                 SwarmEvent::Behaviour(PeerBehaviourEvent::Mdns(mdns::Event::Expired(list))) => {
-                    drop(swarm_guard);
                     for (peer_id, _multiaddr) in list {
-                        warn!("Peer has gone offline: {peer_id}");
-                        discovered_peers.remove(&peer_id);
-                        swarm.lock().unwrap().behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
+                        debug!("mDNS record of peer {peer_id} expired");
+                        swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
+                    }
+                },
+                SwarmEvent::Behaviour(PeerBehaviourEvent::Identify(identify::Event::Received { peer_id, info })) => {
+                    if let Some(kademlia) = swarm.behaviour_mut().kademlia.as_mut() {
+                        for address in info.listen_addrs {
+                            kademlia.add_address(&peer_id, address);
+                        }
                     }
                 },
+                SwarmEvent::Behaviour(PeerBehaviourEvent::Kademlia(kad::Event::RoutingUpdated { peer, is_new_peer: true, .. }))
+                    if !connected_peers.contains(&peer) => {
+                    debug!("Found peer {peer} in the Kademlia DHT");
+                    dial_peer(&mut swarm, peer);
+                },
                 SwarmEvent::Behaviour(PeerBehaviourEvent::Gossipsub(gossipsub::Event::Message {
+                    propagation_source,
                     message_id: _,
                     message,
-                    ..
                 })) => {
-                    drop(swarm_guard);
+                    let from = message.source.unwrap_or(propagation_source);
+                    if from != local_peer_id && !connected_peers.contains(&from) && relayed_peers.insert(from) {
+                        info!("Reached peer {from} through {propagation_source}");
+                        set_connected_peers(&connected_peers, &relayed_peers);
+                    }
                     match decode_protobuf(&message.data) {
                         Ok(decoded_message) => {
-                            match decoded_message.payload {
-                                // a transaction is received via gossipsub, sent to the channel
-                                Some(Payload::Transaction(transaction)) => {
-                                    debug!("Received transaction: {:?}", transaction);
-                                    tx.send(transaction.clone()).await.map_err(|e| CunnerError::Network(format!("Failed to send transaction: {}", e)))?;
+                            prometheus::message_received(decoded_message.payload.as_ref(), message.data.len());
+                            if !decoded_message.to.is_empty() && decoded_message.to != local_peer_id.to_bytes() {
+                                continue;
+                            }
+                            let Some(payload) = decoded_message.payload else {
+                                warn!("Received message with empty payload");
+                                continue;
+                            };
+                            match faults.delay(from, local_peer_id, context.clock.now()) {
+                                None => debug!("Fault dropped a message from {from}"),
+                                Some(delay) if delay.is_zero() => {
+                                    let _ = inbound_tx.send((from, payload));
                                 },
-                                // Process the block with the consensus engine
-                                Some(Payload::Block(block)) => {
-                                    info!("Received block: {:?}", block);
+                                Some(delay) => {
+                                    let inbound_tx = inbound_tx.clone();
+                                    tokio::spawn(async move {
+                                        sleep(delay).await;
+                                        let _ = inbound_tx.send((from, payload));
+                                    });
                                 },
-                                None => warn!("Received message with empty payload"),
                             }
                         },
-                        Err(e) => error!("Failed to decode message: {:?}", e),
+                        Err(e) => {
+                            error!("Failed to decode message: {:?}", e);
+                            prometheus::decode_failed(message.data.len());
+                        },
                     }
                 },
                 SwarmEvent::NewListenAddr { address, .. } => {
-                    drop(swarm_guard);
                     info!("Listening on {address}");
                 }
-                _ => {
-                    drop(swarm_guard);
+                _ => {}
+            },
+            _ = sleep_until(start + arrival.as_ref().map_or(Duration::ZERO, |arrival| arrival.at)), if arrival.is_some() => {
+                let due = arrival.take().expect("an arrival is pending");
+                if connected_peers.is_empty() {
+                    warn!("No peers discovered, skipping transaction emission");
+                } else {
+                    for _ in 0..due.count {
+                        let transaction = match &due.payload {
+                            Some(payload) => new_transaction_with_payload(&configuration.private_key, payload.clone()),
+                            None => new_transaction(&configuration.private_key),
+                        };
+                        debug!("Generated new transaction: {:?}", transaction);
+                        submit_transaction(&tx, &mut swarm, &topic, trace.as_mut(), transaction)?;
+                    }
                 }
+                arrival = workload.as_mut().and_then(|workload| workload.next());
             },
-            _ = sleep(Duration::from_secs(5)) => {
-                drop(swarm_guard);
-                if !discovered_peers.is_empty() {
-                    emit_transaction(tx.clone(), swarm.clone(), topic.clone(), &discovered_peers).await;
+            _ = discovery.tick() => {
+                for address in &bootstrap {
+                    let reached = bootstrap_peers.get(address).is_some_and(|peer| connected_peers.contains(peer));
+                    if !reached {
+                        if let Err(e) = swarm.dial(address.clone()) {
+                            warn!("Failed to dial bootstrap peer {address}: {e}");
+                        }
+                    }
+                }
+                if let Some(kademlia) = swarm.behaviour_mut().kademlia.as_mut() {
+                    // fails until the DHT knows of a first peer
+                    if let Err(e) = kademlia.bootstrap() {
+                        debug!("Kademlia lookup not started: {e}");
+                    }
+                }
+            },
+
+            // hands the messages received from peers to the engine
+            Some((from, payload)) = inbound_rx.recv() => {
+                if let Some(trace) = trace.as_mut() {
+                    trace.message(from, &payload)?;
+                }
+                match payload {
+                    // a transaction is received via gossipsub, sent to the channel
+                    Payload::Transaction(transaction) => {
+                        debug!("Received transaction: {:?}", transaction);
+                        if !verify_transaction(&transaction, local_peer_id, from) {
+                            continue;
+                        }
+                        recorder::transaction_received(&transaction, local_peer_id);
+                        tx.send(transaction).map_err(|e| CunnerError::Network(format!("Failed to send transaction: {}", e)))?;
+                    },
+                    payload => {
+                        if let Some(engine) = engine_instance.lock().unwrap().as_ref() {
+                            handle_payload(engine.as_ref(), &context, from, payload);
+                        }
+                    },
                 }
             },
 
+            // hands the transactions submitted through the RPC API to the engine and the peers
+            Some(transaction) = submitted_rx.recv() => {
+                info!("Transaction {} submitted through the RPC API", hex::encode(transaction.hash()));
+                submit_transaction(&tx, &mut swarm, &topic, trace.as_mut(), transaction)?;
+            },
+
+            // relays the messages published by the engine
+            Some(message) = outbound_rx.recv() => {
+                publish_message(&mut swarm, &topic, message);
+            },
+
             // listens for transactions from the channel
             Some(transaction) = rx.recv() => {
-                drop(swarm_guard);
                 let mut engine_guard = engine_instance.lock().unwrap();
                 if let Some(engine) = engine_guard.as_mut() {
-                    engine.add_transaction(transaction.clone());
-                    debug!("Added transaction to engine: {:?}", transaction.clone());
+                    debug!("Adding transaction to engine: {:?}", transaction);
+                    engine.add_transaction(transaction);
                 }
             }
         }
     }
+
+    // the blocks the node committed, which a replay of its trace is checked against
+    if let Some(trace) = trace.as_mut() {
+        trace.chain(&context.chain)?;
+    }
+    Ok(())
+}
+
+/// Hands a payload received from a peer over to the engine of the node running in `context`.
+pub fn handle_payload(engine: &dyn Engine, context: &Context, from: PeerId, payload: Payload) {
+    match payload {
+        Payload::Transaction(transaction) => {
+            debug!("Received transaction: {:?}", transaction);
+            if !verify_transaction(&transaction, context.network.local_peer_id(), from) {
+                return;
+            }
+            recorder::transaction_received(&transaction, context.network.local_peer_id());
+            engine.add_transaction(transaction);
+        }
+        // Process the block with the consensus engine
+        Payload::Block(block) => {
+            info!("Received block from {from}: {:?}", block);
+            let verdict = engine.on_block(&block, from);
+            prometheus::block_received(verdict);
+            match verdict {
+                BlockVerdict::Accept => context.chain.append(&block),
+                BlockVerdict::Reject => warn!("Rejected block from {from}"),
+                BlockVerdict::Hold => debug!("Engine holds block from {from}"),
+            }
+        }
+        Payload::ConsensusMessage(message) => {
+            debug!(
+                "Received consensus message from {from} ({} bytes)",
+                message.data.len()
+            );
+            engine.handle_message(from, message.data);
+        }
+    }
 }
 
-fn create_swarm() -> Result<libp2p::Swarm<PeerBehaviour>, Box<dyn StdError>> {
-    let swarm = libp2p::SwarmBuilder::with_new_identity()
+fn create_swarm(
+    keypair: Keypair,
+    heartbeat: Duration,
+    mdns: bool,
+    kademlia: bool,
+) -> Result<libp2p::Swarm<PeerBehaviour>, Box<dyn StdError>> {
+    let swarm = libp2p::SwarmBuilder::with_existing_identity(keypair)
         .with_tokio()
         .with_tcp(
             tcp::Config::default(),
@@ -185,27 +498,55 @@ fn create_swarm() -> Result<libp2p::Swarm<PeerBehaviour>, Box<dyn StdError>> {
             yamux::Config::default,
         )?
         .with_behaviour(|key| {
+            // the source is part of the id, so identical votes from different peers are not deduplicated,
+            // and so is its sequence number, so a peer can send the same message twice, as a heartbeat
             let message_id_fn = |message: &gossipsub::Message| {
                 let mut s = DefaultHasher::new();
+                message.source.hash(&mut s);
+                message.sequence_number.hash(&mut s);
                 message.data.hash(&mut s);
                 gossipsub::MessageId::from(s.finish().to_string())
             };
 
             let gossipsub_config = gossipsub::ConfigBuilder::default()
-                .heartbeat_interval(Duration::from_secs(10))
+                .heartbeat_interval(heartbeat)
                 .validation_mode(gossipsub::ValidationMode::Strict)
                 .message_id_fn(message_id_fn)
                 .build()
-                .map_err(|msg| io::Error::new(io::ErrorKind::Other, msg))?;
+                .map_err(io::Error::other)?;
 
             let gossipsub = gossipsub::Behaviour::new(
                 gossipsub::MessageAuthenticity::Signed(key.clone()),
                 gossipsub_config,
             )?;
 
-            let mdns =
-                mdns::tokio::Behaviour::new(mdns::Config::default(), key.public().to_peer_id())?;
-            Ok(PeerBehaviour { gossipsub, mdns })
+            let peer_id = key.public().to_peer_id();
+            let mdns = mdns
+                .then(|| mdns::tokio::Behaviour::new(mdns::Config::default(), peer_id))
+                .transpose()?;
+
+            let (kademlia, identify) = if kademlia {
+                let mut config = kad::Config::default();
+                config.set_protocol_names(vec![KADEMLIA_PROTOCOL]);
+                let mut kademlia =
+                    kad::Behaviour::with_config(peer_id, MemoryStore::new(peer_id), config);
+                // nodes know no external address, without it they would only query the DHT
+                kademlia.set_mode(Some(kad::Mode::Server));
+                let identify = identify::Behaviour::new(identify::Config::new(
+                    "/cunner/1.0.0".into(),
+                    key.public(),
+                ));
+                (Some(kademlia), Some(identify))
+            } else {
+                (None, None)
+            };
+
+            Ok(PeerBehaviour {
+                gossipsub,
+                mdns: mdns.into(),
+                kademlia: kademlia.into(),
+                identify: identify.into(),
+            })
         })?
         .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60)))
         .build();
@@ -213,73 +554,121 @@ fn create_swarm() -> Result<libp2p::Swarm<PeerBehaviour>, Box<dyn StdError>> {
     Ok(swarm)
 }
 
-async fn emit_transaction(
-    tx: mpsc::Sender<Transaction>,
-    swarm: Arc<Mutex<libp2p::Swarm<PeerBehaviour>>>,
-    topic: Arc<gossipsub::IdentTopic>,
-    discovered_peers: &HashSet<PeerId>,
-) {
-    if discovered_peers.is_empty() {
-        warn!("No peers discovered, skipping transaction emission");
-        return;
+// hands a transaction submitted to the node to its engine and its peers
+fn submit_transaction(
+    tx: &mpsc::UnboundedSender<Transaction>,
+    swarm: &mut Swarm<PeerBehaviour>,
+    topic: &gossipsub::IdentTopic,
+    trace: Option<&mut TraceWriter>,
+    transaction: Transaction,
+) -> Result<(), CunnerError> {
+    recorder::transaction_submitted(&transaction);
+    if let Some(trace) = trace {
+        trace.transaction(&transaction)?;
     }
-
-    let transaction = new_transaction();
-    debug!("Generated new transaction: {:?}", transaction);
-    tx.send(transaction.clone()).await.unwrap();
+    tx.send(transaction.clone())
+        .map_err(|e| CunnerError::Network(format!("Failed to send transaction: {}", e)))?;
     debug!("Sending transaction: {:?}", transaction);
-    let message = Message {
-        payload: Some(Payload::Transaction(transaction)),
-    };
-    info!("Attempting to publish transaction to network");
-    match encode_protobuf(&message) {
-        Ok(encoded_message) => {
-            if let Err(e) = swarm
-                .lock()
-                .unwrap()
-                .behaviour_mut()
-                .gossipsub
-                .publish(topic.as_ref().clone(), encoded_message)
-            {
-                error!("Failed to publish transaction: {:?}", e);
-            } else {
-                info!("Successfully published transaction to network");
-            }
-        }
-        Err(e) => error!("Failed to encode message: {:?}", e),
+    publish_message(
+        swarm,
+        topic,
+        Message {
+            payload: Some(Payload::Transaction(transaction)),
+            to: Vec::new(),
+        },
+    );
+    Ok(())
+}
+
+// the engines reach the peers connected to the node and the ones behind them alike
+fn set_connected_peers(connected: &HashSet<PeerId>, relayed: &HashSet<PeerId>) {
+    if let Some(context) = NETWORK_CONTEXT.lock().unwrap().as_mut() {
+        context.peers = connected.union(relayed).copied().collect();
     }
 }
 
-pub fn publish_block(block: Block) {
-    let message = Message {
-        payload: Some(Payload::Block(block)),
-    };
-    let encoded_message = encode_protobuf(&message).expect("Failed to encode message");
+// dials a peer found by mDNS or the DHT, unless a connection to it is open or pending
+fn dial_peer(swarm: &mut Swarm<PeerBehaviour>, peer_id: PeerId) {
+    if let Err(e) = swarm.dial(peer_id) {
+        debug!("Failed to dial peer {peer_id}: {e}");
+    }
+}
 
-    if let Some((swarm, topic)) = NETWORK_CONTEXT.lock().unwrap().as_ref() {
-        if let Err(e) = swarm
-            .lock()
-            .unwrap()
-            .behaviour_mut()
-            .gossipsub
-            .publish(topic.as_ref().clone(), encoded_message)
-        {
-            error!("Failed to publish block: {:?}", e);
-        } else {
-            info!("Successfully published block to network");
+fn publish_message(
+    swarm: &mut Swarm<PeerBehaviour>,
+    topic: &gossipsub::IdentTopic,
+    message: Message,
+) {
+    let is_block = matches!(message.payload, Some(Payload::Block(_)));
+    let encoded_message = match encode_protobuf(&message) {
+        Ok(encoded_message) => encoded_message,
+        Err(e) => {
+            error!("Failed to encode message: {:?}", e);
+            return;
         }
+    };
+
+    let bytes = encoded_message.len();
+    if let Err(e) = swarm
+        .behaviour_mut()
+        .gossipsub
+        .publish(topic.clone(), encoded_message)
+    {
+        error!("Failed to publish message: {:?}", e);
+        return;
+    }
+    prometheus::message_sent(message.payload.as_ref(), bytes);
+    if is_block {
+        info!("Successfully published block to network");
     } else {
-        error!("Network context not initialized");
+        debug!("Successfully published message to network");
     }
 }
 
-fn new_transaction() -> Transaction {
-    let mut rng = thread_rng();
-    let nonce = TRANSACTION_COUNTER.fetch_add(1, Ordering::SeqCst);
+/// Returns a new transaction carrying a random payload, signed with `key`.
+pub fn new_transaction(key: &SigningKey) -> Transaction {
+    let nonce = next_nonce();
+    let payload = with_rng(|rng| rng.gen::<[u8; PAYLOAD_SIZE]>().to_vec());
+    signed_transaction(key, nonce, payload)
+}
 
-    Transaction {
-        nonce: nonce + rng.gen::<u64>(), // unique nonce
-    }
+/// Returns a new transaction carrying `payload`, signed with `key`.
+pub fn new_transaction_with_payload(key: &SigningKey, payload: Vec<u8>) -> Transaction {
+    signed_transaction(key, next_nonce(), payload)
+}
+
+// a nonce no other transaction of the process has, so no two transactions of a node
+// share one, the simulated nodes of a run included
+fn next_nonce() -> u64 {
+    TRANSACTION_COUNTER.fetch_add(1, Ordering::SeqCst)
+}
+
+fn signed_transaction(key: &SigningKey, nonce: u64, payload: Vec<u8>) -> Transaction {
+    let mut transaction = Transaction {
+        nonce,
+        sender: Vec::new(),
+        payload,
+        signature: Vec::new(),
+    };
+    key.sign(&mut transaction);
+    transaction
+}
 
-    // sign the transaction with the private key according to the transaction that you have
+/// Numbers the next transactions from 0 again, as in a new process, so that every
+/// simulation of a process draws the same nonces.
+pub fn reset_nonces() {
+    TRANSACTION_COUNTER.store(0, Ordering::SeqCst);
+}
+
+// a transaction that is not signed by its sender never reaches the engine
+fn verify_transaction(transaction: &Transaction, node: PeerId, from: PeerId) -> bool {
+    if transaction.verify_signature() {
+        return true;
+    }
+    warn!(
+        "Dropping transaction from {from} with an invalid signature: {:?}",
+        transaction
+    );
+    recorder::transaction_invalid(node);
+    false
 }
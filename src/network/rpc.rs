@@ -0,0 +1,482 @@
+/*
+The RPC API lets test harnesses and dashboards drive and inspect a live node. It
+speaks JSON-RPC 2.0 over HTTP on localhost only: every request is a POST holding a
+single call, answered with its result or an error. Hashes, keys and payloads are hex
+encoded.
+
+- submit_transaction: `{"payload": hex}` signs a transaction carrying the payload with
+  the key of the node, `{"transaction": {nonce, sender, payload, signature}}` submits
+  one signed elsewhere. The node handles it as one it emitted and returns its hash.
+- get_transaction: `{"hash": hex}` tells whether the transaction is committed, and
+  at which height, pending if the node saw it but did not commit it, unknown otherwise
+- get_block: `{"height": n}` or `{"hash": hex}`, null if the chain has no such block
+- get_head: the height of the chain and its last block
+- get_peers: the peer id of the node and the peers it reaches
+- get_engine_state: what the engine tells of where it stands, its view or round
+*/
+
+use crate::consensus::chain::Chain;
+use crate::consensus::engine::Engine;
+use crate::metrics::recorder;
+use crate::network::messages::message::{Block, Transaction};
+use crate::network::messages::signing::SigningKey;
+use crate::network::peer::new_transaction_with_payload;
+use crate::network::transport::Network;
+use crate::CunnerError;
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use libp2p::PeerId;
+use log::{debug, error, info};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+// error codes of the JSON-RPC 2.0 specification
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+
+/// RpcNode is what the RPC API reaches of a running node. Transactions submitted
+/// through it are handed to run_peer, which gives them to the engine and the peers.
+#[derive(Clone)]
+pub struct RpcNode {
+    pub chain: Chain,
+    pub network: Network,
+    pub engine: Arc<Mutex<Option<Box<dyn Engine>>>>,
+    /// key transactions submitted with a payload only are signed with
+    pub key: SigningKey,
+    pub submitted: mpsc::UnboundedSender<Transaction>,
+}
+
+#[derive(Deserialize)]
+struct RpcRequest {
+    jsonrpc: String,
+    method: String,
+    #[serde(default)]
+    params: Value,
+    #[serde(default)]
+    id: Value,
+}
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn invalid_params(message: impl Into<String>) -> Self {
+        Self::new(INVALID_PARAMS, message)
+    }
+
+    fn internal(error: impl ToString) -> Self {
+        Self::new(INTERNAL_ERROR, error.to_string())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SubmitParams {
+    payload: Option<String>,
+    transaction: Option<SignedTransaction>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SignedTransaction {
+    nonce: u64,
+    sender: String,
+    payload: String,
+    signature: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HashParams {
+    hash: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BlockParams {
+    height: Option<u64>,
+    hash: Option<String>,
+}
+
+/// Binds the RPC API to `port` on localhost and serves it in the background.
+pub fn spawn_rpc(port: u16, node: RpcNode) -> Result<(), CunnerError> {
+    let address = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+    let builder = Server::try_bind(&address).map_err(|e| {
+        CunnerError::Network(format!("Failed to bind the RPC API to {address}: {e}"))
+    })?;
+    let node = Arc::new(node);
+    let service = make_service_fn(move |_| {
+        let node = node.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                handle_request(node.clone(), request)
+            }))
+        }
+    });
+    let server = builder.serve(service);
+    info!("Serving the RPC API on http://{address}");
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            error!("RPC API stopped: {e}");
+        }
+    });
+    Ok(())
+}
+
+async fn handle_request(
+    node: Arc<RpcNode>,
+    request: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    if request.method() != Method::POST {
+        let mut response = Response::new(Body::from("JSON-RPC calls are POST requests\n"));
+        *response.status_mut() = StatusCode::METHOD_NOT_ALLOWED;
+        return Ok(response);
+    }
+    let response = match hyper::body::to_bytes(request.into_body()).await {
+        Ok(body) => node.call(&body),
+        Err(e) => error_response(Value::Null, RpcError::new(PARSE_ERROR, e.to_string())),
+    };
+    let mut response = Response::new(Body::from(response.to_string()));
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, "application/json".parse().unwrap());
+    Ok(response)
+}
+
+fn error_response(id: Value, error: RpcError) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "error": { "code": error.code, "message": error.message },
+        "id": id,
+    })
+}
+
+impl RpcNode {
+    // answers a call, a body that is not a call is answered with an error
+    fn call(&self, body: &[u8]) -> Value {
+        let value: Value = match serde_json::from_slice(body) {
+            Ok(value) => value,
+            Err(e) => {
+                return error_response(Value::Null, RpcError::new(PARSE_ERROR, e.to_string()))
+            }
+        };
+        let id = value.get("id").cloned().unwrap_or(Value::Null);
+        let request = match serde_json::from_value::<RpcRequest>(value) {
+            Ok(request) if request.jsonrpc == "2.0" => request,
+            Ok(_) => {
+                return error_response(id, RpcError::new(INVALID_REQUEST, "jsonrpc must be 2.0"))
+            }
+            Err(e) => return error_response(id, RpcError::new(INVALID_REQUEST, e.to_string())),
+        };
+        debug!("RPC call {}", request.method);
+        let result = match request.method.as_str() {
+            "submit_transaction" => params(request.params).and_then(|params| self.submit(params)),
+            "get_transaction" => params(request.params).and_then(|params| self.transaction(params)),
+            "get_block" => params(request.params).and_then(|params| self.block(params)),
+            "get_head" => self.head(),
+            "get_peers" => Ok(self.peers()),
+            "get_engine_state" => Ok(self.engine_state()),
+            method => Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("Unknown method {method}"),
+            )),
+        };
+        match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": request.id }),
+            Err(error) => error_response(request.id, error),
+        }
+    }
+
+    fn submit(&self, params: SubmitParams) -> Result<Value, RpcError> {
+        let transaction = match (params.payload, params.transaction) {
+            (Some(payload), None) => {
+                new_transaction_with_payload(&self.key, decode_hex("payload", &payload)?)
+            }
+            (None, Some(signed)) => {
+                let transaction = Transaction {
+                    nonce: signed.nonce,
+                    sender: decode_hex("sender", &signed.sender)?,
+                    payload: decode_hex("payload", &signed.payload)?,
+                    signature: decode_hex("signature", &signed.signature)?,
+                };
+                if !transaction.verify_signature() {
+                    return Err(RpcError::invalid_params(
+                        "transaction is not signed by its sender",
+                    ));
+                }
+                transaction
+            }
+            _ => {
+                return Err(RpcError::invalid_params(
+                    "expected either a payload or a transaction",
+                ))
+            }
+        };
+        let hash = hex::encode(transaction.hash());
+        self.submitted
+            .send(transaction)
+            .map_err(|_| RpcError::internal("Node is shutting down"))?;
+        Ok(json!({ "hash": hash }))
+    }
+
+    fn transaction(&self, params: HashParams) -> Result<Value, RpcError> {
+        let hash = decode_hex("hash", &params.hash)?;
+        if let Some(height) = self
+            .chain
+            .transaction_height(&hash)
+            .map_err(RpcError::internal)?
+        {
+            let block = self.chain.block(height).map_err(RpcError::internal)?;
+            return Ok(json!({
+                "hash": params.hash,
+                "status": "committed",
+                "height": height,
+                "block": block.map(|block| hex::encode(block.hash())),
+            }));
+        }
+        let status = if recorder::transaction_seen(&hash, self.network.local_peer_id()) {
+            "pending"
+        } else {
+            "unknown"
+        };
+        Ok(json!({ "hash": params.hash, "status": status }))
+    }
+
+    fn block(&self, params: BlockParams) -> Result<Value, RpcError> {
+        let block = match (params.height, params.hash) {
+            (Some(height), None) => self.chain.block(height),
+            (None, Some(hash)) => self.chain.block_by_hash(&decode_hex("hash", &hash)?),
+            _ => {
+                return Err(RpcError::invalid_params(
+                    "expected either a height or a hash",
+                ))
+            }
+        }
+        .map_err(RpcError::internal)?;
+        Ok(block.as_ref().map_or(Value::Null, block_view))
+    }
+
+    fn head(&self) -> Result<Value, RpcError> {
+        let height = self.chain.len().map_err(RpcError::internal)?;
+        let head = self.chain.head().map_err(RpcError::internal)?;
+        Ok(json!({
+            "height": height,
+            "block": head.as_ref().map_or(Value::Null, block_view),
+        }))
+    }
+
+    fn peers(&self) -> Value {
+        let mut peers = self.network.connected_peers();
+        peers.sort();
+        json!({
+            "peer_id": self.network.local_peer_id().to_string(),
+            "peers": peers.iter().map(PeerId::to_string).collect::<Vec<_>>(),
+        })
+    }
+
+    fn engine_state(&self) -> Value {
+        let engine = self.engine.lock().unwrap().as_ref().cloned();
+        engine.map_or(Value::Null, |engine| engine.state())
+    }
+}
+
+// the params of a call, which are an object
+fn params<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))
+}
+
+fn decode_hex(field: &str, value: &str) -> Result<Vec<u8>, RpcError> {
+    hex::decode(value).map_err(|e| RpcError::invalid_params(format!("{field} is not hex: {e}")))
+}
+
+// the header of the block, its transactions by hash and whether validators committed it
+fn block_view(block: &Block) -> Value {
+    let header = block.header.clone().unwrap_or_default();
+    let proposer = PeerId::from_bytes(&header.proposer)
+        .map(|proposer| proposer.to_string())
+        .unwrap_or_else(|_| hex::encode(&header.proposer));
+    json!({
+        "hash": hex::encode(block.hash()),
+        "height": header.index,
+        "parent_hash": hex::encode(&header.parent_hash),
+        "timestamp": header.timestamp,
+        "proposer": proposer,
+        "tx_root": hex::encode(&header.tx_root),
+        "difficulty": header.difficulty,
+        "transactions": block
+            .transactions
+            .iter()
+            .map(|transaction| hex::encode(transaction.hash()))
+            .collect::<Vec<_>>(),
+        "signature": hex::encode(&block.signature),
+        "commit_signatures": block.commit.as_ref().map(|commit| commit.signatures.len()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::faults::{FaultConfig, Faults};
+    use crate::simulation::rng;
+    use crate::simulation::scheduler::Scheduler;
+    use crate::simulation::transport::SimTransport;
+    use std::time::Duration;
+
+    // a node of three simulated nodes, with a chain of two blocks, and what it submits
+    fn node() -> (RpcNode, mpsc::UnboundedReceiver<Transaction>, Vec<PeerId>) {
+        let peers: Vec<PeerId> = (0..3).map(|_| PeerId::random()).collect();
+        let transport = SimTransport::new(
+            0,
+            Arc::new(peers.clone()),
+            Arc::new(Mutex::new(Scheduler::new())),
+            Duration::ZERO,
+            Arc::new(Faults::new(FaultConfig::default(), &peers)),
+        );
+        let key = SigningKey::generate();
+        let chain = Chain::in_memory(peers[0]);
+        let first = Block::new_block(
+            None,
+            peers[1],
+            1_000,
+            vec![new_transaction_with_payload(&key, b"first".to_vec())],
+        );
+        chain.append(&first);
+        chain.append(&Block::new_block(Some(&first), peers[2], 2_000, Vec::new()));
+        let (submitted, receiver) = mpsc::unbounded_channel();
+        let node = RpcNode {
+            chain,
+            network: Network::new(transport),
+            engine: Arc::new(Mutex::new(None)),
+            key,
+            submitted,
+        };
+        (node, receiver, peers)
+    }
+
+    fn call(node: &RpcNode, method: &str, params: Value) -> Value {
+        let request = json!({ "jsonrpc": "2.0", "method": method, "params": params, "id": 1 });
+        let response = node.call(request.to_string().as_bytes());
+        assert_eq!(response["id"], 1);
+        response
+    }
+
+    #[test]
+    fn submitted_transactions_are_handed_to_the_node() {
+        let _rng = rng::exclusive();
+        let (node, mut receiver, _) = node();
+        let response = call(&node, "submit_transaction", json!({ "payload": "cafe" }));
+        let transaction = receiver.try_recv().unwrap();
+        assert_eq!(transaction.payload, [0xca, 0xfe]);
+        assert!(transaction.verify_signature());
+        assert_eq!(response["result"]["hash"], hex::encode(transaction.hash()));
+
+        let mut forged = json!({
+            "nonce": transaction.nonce,
+            "sender": hex::encode(&transaction.sender),
+            "payload": "beef",
+            "signature": hex::encode(&transaction.signature),
+        });
+        let response = call(
+            &node,
+            "submit_transaction",
+            json!({ "transaction": forged }),
+        );
+        assert_eq!(response["error"]["code"], INVALID_PARAMS);
+        forged["payload"] = json!("cafe");
+        let response = call(
+            &node,
+            "submit_transaction",
+            json!({ "transaction": forged }),
+        );
+        assert_eq!(response["result"]["hash"], hex::encode(transaction.hash()));
+        assert!(receiver.try_recv().is_ok());
+    }
+
+    #[test]
+    fn the_chain_is_read_by_height_and_hash() {
+        let _rng = rng::exclusive();
+        let (node, _, peers) = node();
+        let first = node.chain.block(1).unwrap().unwrap();
+
+        let head = call(&node, "get_head", Value::Null)["result"].clone();
+        assert_eq!(head["height"], 2);
+        assert_eq!(head["block"]["parent_hash"], hex::encode(first.hash()));
+        assert_eq!(head["block"]["proposer"], peers[2].to_string());
+
+        let by_height = call(&node, "get_block", json!({ "height": 1 }));
+        let by_hash = call(
+            &node,
+            "get_block",
+            json!({ "hash": hex::encode(first.hash()) }),
+        );
+        assert_eq!(by_height["result"], by_hash["result"]);
+        assert_eq!(by_height["result"]["hash"], hex::encode(first.hash()));
+        assert_eq!(
+            call(&node, "get_block", json!({ "height": 3 }))["result"],
+            Value::Null
+        );
+        let both = call(&node, "get_block", json!({ "height": 1, "hash": "00" }));
+        assert_eq!(both["error"]["code"], INVALID_PARAMS);
+
+        let committed = hex::encode(first.transactions[0].hash());
+        let transaction = call(&node, "get_transaction", json!({ "hash": committed }));
+        assert_eq!(transaction["result"]["status"], "committed");
+        assert_eq!(transaction["result"]["height"], 1);
+        let unknown = call(&node, "get_transaction", json!({ "hash": "00" }));
+        assert_eq!(unknown["result"]["status"], "unknown");
+    }
+
+    #[test]
+    fn peers_and_engine_state_describe_the_node() {
+        let _rng = rng::exclusive();
+        let (node, _, peers) = node();
+        let result = call(&node, "get_peers", Value::Null)["result"].clone();
+        assert_eq!(result["peer_id"], peers[0].to_string());
+        let mut others = peers[1..].to_vec();
+        others.sort();
+        let others: Vec<String> = others.iter().map(PeerId::to_string).collect();
+        assert_eq!(result["peers"], json!(others));
+        // a node whose engine is not running yet has no state
+        assert_eq!(
+            call(&node, "get_engine_state", Value::Null)["result"],
+            Value::Null
+        );
+    }
+
+    #[test]
+    fn invalid_calls_are_answered_with_errors() {
+        let _rng = rng::exclusive();
+        let (node, _, _) = node();
+        assert_eq!(node.call(b"{")["error"]["code"], PARSE_ERROR);
+        let old = json!({ "jsonrpc": "1.0", "method": "get_head", "id": 7 });
+        let response = node.call(old.to_string().as_bytes());
+        assert_eq!(response["error"]["code"], INVALID_REQUEST);
+        assert_eq!(response["id"], 7);
+        let unknown = call(&node, "get_mempool", Value::Null);
+        assert_eq!(unknown["error"]["code"], METHOD_NOT_FOUND);
+        let not_hex = call(&node, "get_transaction", json!({ "hash": "xyz" }));
+        assert_eq!(not_hex["error"]["code"], INVALID_PARAMS);
+
+        let request = Request::get("/").body(Body::empty()).unwrap();
+        let response = futures::executor::block_on(handle_request(Arc::new(node), request));
+        assert_eq!(response.unwrap().status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+}
@@ -0,0 +1,85 @@
+use crate::metrics::recorder;
+use crate::network::messages::message::message::Payload;
+use crate::network::messages::message::{Block, ConsensusMessage, Message};
+use libp2p::PeerId;
+use std::sync::Arc;
+
+/// Transport is how an engine reaches the other nodes, either through the libp2p
+/// swarm of a node or through the simulated network of `cunner simulate`.
+pub trait Transport: Send + Sync {
+    /// id of the node the engine runs on
+    fn local_peer_id(&self) -> PeerId;
+
+    /// peers this node can currently reach
+    fn connected_peers(&self) -> Vec<PeerId>;
+
+    /// peer ids of the nodes of the network by index, this node included, empty if
+    /// the node does not know them
+    fn nodes(&self) -> Vec<PeerId>;
+
+    /// broadcasts the message to every reachable peer
+    fn publish(&self, message: Message);
+
+    /// sends the message to a single peer
+    fn send_to(&self, peer: PeerId, message: Message);
+}
+
+/// Network is the handle engines use to talk to their peers, it is cheap to clone.
+#[derive(Clone)]
+pub struct Network {
+    transport: Arc<dyn Transport>,
+}
+
+impl Network {
+    pub fn new(transport: impl Transport + 'static) -> Self {
+        Self {
+            transport: Arc::new(transport),
+        }
+    }
+
+    pub fn local_peer_id(&self) -> PeerId {
+        self.transport.local_peer_id()
+    }
+
+    pub fn connected_peers(&self) -> Vec<PeerId> {
+        self.transport.connected_peers()
+    }
+
+    pub fn nodes(&self) -> Vec<PeerId> {
+        self.transport.nodes()
+    }
+
+    /// Returns the transport the network sends through, for wrappers that stand
+    /// between an engine and it.
+    pub fn transport(&self) -> Arc<dyn Transport> {
+        self.transport.clone()
+    }
+
+    pub fn publish_block(&self, block: Block) {
+        recorder::block_included(&block);
+        self.publish(Payload::Block(block));
+    }
+
+    /// Broadcasts an engine specific message to every peer.
+    pub fn broadcast(&self, bytes: Vec<u8>) {
+        recorder::consensus_message_sent(self.connected_peers().len(), bytes.len());
+        self.publish(Payload::ConsensusMessage(ConsensusMessage { data: bytes }));
+    }
+
+    /// Sends an engine specific message to a single peer.
+    pub fn send_to(&self, peer: PeerId, bytes: Vec<u8>) {
+        recorder::consensus_message_sent(1, bytes.len());
+        let message = Message {
+            payload: Some(Payload::ConsensusMessage(ConsensusMessage { data: bytes })),
+            to: peer.to_bytes(),
+        };
+        self.transport.send_to(peer, message);
+    }
+
+    fn publish(&self, payload: Payload) {
+        self.transport.publish(Message {
+            payload: Some(payload),
+            to: Vec::new(),
+        });
+    }
+}
@@ -0,0 +1,89 @@
+use crate::simulation::scheduler::{Event, Scheduler};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::time::FormatTime;
+
+/// Clock is the time source of an engine, engines sleep on it instead of calling
+/// tokio::time::sleep so the same engine runs on a live node and in a simulation.
+#[derive(Clone)]
+pub enum Clock {
+    /// wall clock time of a live node, counted from its start
+    System(Instant),
+    /// virtual time of a simulation, driven by its scheduler
+    Virtual(Arc<Mutex<Scheduler>>),
+}
+
+impl Clock {
+    pub fn system() -> Self {
+        Clock::System(Instant::now())
+    }
+
+    /// Returns the time elapsed since the node or simulation started.
+    pub fn now(&self) -> Duration {
+        match self {
+            Clock::System(start) => start.elapsed(),
+            Clock::Virtual(scheduler) => scheduler.lock().unwrap().now(),
+        }
+    }
+
+    /// Returns the milliseconds blocks are stamped with, since the UNIX epoch on a
+    /// live node so nodes can compare them, and since the start in a simulation.
+    pub fn timestamp(&self) -> u64 {
+        match self {
+            Clock::System(_) => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            Clock::Virtual(_) => self.now().as_millis() as u64,
+        }
+    }
+
+    /// Waits until `duration` has elapsed on this clock.
+    pub fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        match self {
+            Clock::System(_) => Box::pin(tokio::time::sleep(duration)),
+            Clock::Virtual(scheduler) => {
+                let deadline = scheduler.lock().unwrap().now() + duration;
+                Box::pin(VirtualSleep {
+                    scheduler: scheduler.clone(),
+                    deadline,
+                    scheduled: false,
+                })
+            }
+        }
+    }
+}
+
+// log lines of a simulation are stamped with the virtual time, so they do not differ between runs
+impl FormatTime for Clock {
+    fn format_time(&self, w: &mut Writer<'_>) -> std::fmt::Result {
+        write!(w, "{:>10.3}s", self.now().as_secs_f64())
+    }
+}
+
+struct VirtualSleep {
+    scheduler: Arc<Mutex<Scheduler>>,
+    deadline: Duration,
+    scheduled: bool,
+}
+
+impl Future for VirtualSleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = self.get_mut();
+        let mut scheduler = this.scheduler.lock().unwrap();
+        if scheduler.now() >= this.deadline {
+            return Poll::Ready(());
+        }
+        if !this.scheduled {
+            scheduler.schedule(this.deadline, Event::Wake(cx.waker().clone()));
+            this.scheduled = true;
+        }
+        Poll::Pending
+    }
+}
@@ -0,0 +1,212 @@
+/*
+A replay feeds the trace of a node back into a fresh engine, built from the header of
+the trace, on a virtual clock. Transactions are handed to `add_transaction` and
+messages to the hooks a node hands them to, at the time they were recorded, while
+what the engine publishes goes nowhere since the trace holds everything the node
+received.
+
+A simulated node is replayed exactly: its engine draws from a generator seeded as it
+was, and runs only when the trace says the simulator ran it, so it commits the same
+blocks. A live node ran on the wall clock, its engine runs on its own timers between
+its inputs, and the blocks it commits may differ from the recording.
+*/
+
+use crate::consensus::chain::Chain;
+use crate::consensus::engine::{Context, Engine};
+use crate::metrics::recorder;
+use crate::network::faults::{FaultConfig, Faults};
+use crate::network::peer::handle_payload;
+use crate::network::transport::Network;
+use crate::simulation::clock::Clock;
+use crate::simulation::rng;
+use crate::simulation::scheduler::{Event, Scheduler};
+use crate::simulation::trace::{Record, TraceHeader};
+use crate::simulation::transport::SimTransport;
+use crate::CunnerError;
+use futures::task::{waker, ArcWake};
+use libp2p::PeerId;
+use log::{debug, warn};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context as TaskContext, Poll, Waker};
+use std::time::Duration;
+
+type Task<'a> = Pin<Box<dyn Future<Output = ()> + Send + 'a>>;
+
+pub struct Replay {
+    header: TraceHeader,
+    scheduler: Arc<Mutex<Scheduler>>,
+}
+
+/// ReplayReport is what a replay ended with.
+pub struct ReplayReport {
+    pub peer_id: PeerId,
+    /// transactions and messages handed to the engine
+    pub inputs: usize,
+    /// virtual time the replay ended at
+    pub duration: Duration,
+    /// chain the engine committed during the replay
+    pub chain: Chain,
+    /// hashes of the blocks the node committed during the run, None if its trace
+    /// ended before they were recorded
+    pub recorded: Option<Vec<Vec<u8>>>,
+}
+
+impl ReplayReport {
+    /// Returns the first height the replayed chain differs from the recorded one
+    /// at, None if they are the same or nothing was recorded.
+    pub fn divergence(&self) -> io::Result<Option<u64>> {
+        let Some(recorded) = &self.recorded else {
+            return Ok(None);
+        };
+        let replayed: Vec<Vec<u8>> = self
+            .chain
+            .blocks()?
+            .iter()
+            .map(|block| block.hash())
+            .collect();
+        let height = (0..recorded.len().max(replayed.len()))
+            .find(|&index| recorded.get(index) != replayed.get(index))
+            .map(|index| index as u64 + 1);
+        Ok(height)
+    }
+}
+
+// remembers that the engine was woken, for the engine of a live node which runs on its timers
+#[derive(Default)]
+struct Woken(AtomicBool);
+
+impl ArcWake for Woken {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+impl Replay {
+    pub fn new(header: TraceHeader) -> Self {
+        Self {
+            header,
+            scheduler: Arc::new(Mutex::new(Scheduler::new())),
+        }
+    }
+
+    /// Returns the virtual clock of the replay.
+    pub fn clock(&self) -> Clock {
+        Clock::Virtual(self.scheduler.clone())
+    }
+
+    /// Replays the records of the trace into an engine built by `new_engine` from the
+    /// context of the node.
+    pub fn run(
+        &self,
+        records: Vec<Record>,
+        new_engine: impl FnOnce(Context) -> Box<dyn Engine>,
+    ) -> Result<ReplayReport, CunnerError> {
+        let header = &self.header;
+        let peer_id = header.keypair.public().to_peer_id();
+        if header.peers.get(header.node) != Some(&peer_id) {
+            return Err(CunnerError::Config(format!(
+                "Trace of {peer_id} does not list it as node {}",
+                header.node
+            )));
+        }
+        // the trace of a live node holds no poll, the engine runs whenever its timers wake it
+        let simulated = header.seed.is_some();
+        if let Some(seed) = header.seed {
+            rng::seed_node(header.node, seed);
+        }
+        let _node = header.seed.map(|_| rng::enter(header.node));
+        recorder::init(self.clock());
+
+        let peers = Arc::new(header.peers.clone());
+        let transport = SimTransport::new(
+            header.node,
+            peers.clone(),
+            self.scheduler.clone(),
+            Duration::ZERO,
+            Arc::new(Faults::new(FaultConfig::default(), &peers)),
+        );
+        let context = Context {
+            network: Network::new(transport),
+            clock: self.clock(),
+            chain: Chain::in_memory(peer_id),
+            keypair: header.keypair.clone(),
+        };
+        let engine = new_engine(context.clone());
+        let runner = engine.clone();
+        let mut task: Option<Task> = Some(Box::pin(async move { runner.run().await }));
+        let woken = Arc::new(Woken::default());
+        let waker = waker(woken.clone());
+        if !simulated {
+            poll(&mut task, &waker);
+        }
+
+        let mut inputs = 0;
+        let mut recorded = None;
+        for record in records {
+            let at = match &record {
+                Record::Transaction { at, .. }
+                | Record::Message { at, .. }
+                | Record::Poll { at }
+                | Record::Chain { at, .. } => *at,
+            };
+            // the wakeups of the engine and the messages it published until the record,
+            // which go nowhere
+            loop {
+                let event = self.scheduler.lock().unwrap().next_event(at);
+                match event {
+                    Some(Event::Wake(task_waker)) if !simulated => {
+                        task_waker.wake();
+                        if woken.0.swap(false, Ordering::SeqCst) {
+                            poll(&mut task, &waker);
+                        }
+                    }
+                    Some(_) => {}
+                    None => break,
+                }
+            }
+            self.scheduler.lock().unwrap().advance(at);
+
+            match record {
+                Record::Poll { .. } => poll(&mut task, &waker),
+                Record::Transaction { transaction, .. } => {
+                    debug!("Replaying transaction {}", hex::encode(transaction.hash()));
+                    inputs += 1;
+                    recorder::transaction_submitted(&transaction);
+                    engine.add_transaction(transaction);
+                }
+                Record::Message { from, payload, .. } => {
+                    inputs += 1;
+                    handle_payload(engine.as_ref(), &context, from, payload);
+                }
+                Record::Chain { hashes, .. } => recorded = Some(hashes),
+            }
+            if !simulated && woken.0.swap(false, Ordering::SeqCst) {
+                poll(&mut task, &waker);
+            }
+        }
+
+        let duration = self.scheduler.lock().unwrap().now();
+        Ok(ReplayReport {
+            peer_id,
+            inputs,
+            duration,
+            chain: context.chain,
+            recorded,
+        })
+    }
+}
+
+// polls the run of the engine, unless it returned
+fn poll(task: &mut Option<Task>, waker: &Waker) {
+    let Some(run) = task.as_mut() else {
+        return;
+    };
+    if let Poll::Ready(()) = run.as_mut().poll(&mut TaskContext::from_waker(waker)) {
+        warn!("Engine run returned");
+        *task = None;
+    }
+}
@@ -0,0 +1,86 @@
+/*
+Every random choice made by cunner (transaction nonces, block nonces, peer samples)
+goes through this generator, so a simulation seeded with the same value replays
+exactly the same run. Live nodes keep it seeded from entropy.
+
+Every node of a simulation also has a generator of its own, which the code of the
+node draws from while the simulator runs it, so what a node draws only depends on
+what happened to that node. A replay of the node seeds it the same way and draws the
+same values, even though the other nodes are gone. What the network draws, the faults
+it injects, comes from the shared generator whichever node is running.
+*/
+
+use once_cell::sync::Lazy;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+static RNG: Lazy<Mutex<StdRng>> = Lazy::new(|| Mutex::new(StdRng::from_entropy()));
+static NODE_RNGS: Lazy<Mutex<BTreeMap<usize, StdRng>>> = Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+thread_local! {
+    // the node the code running on this thread draws for, None for the shared generator
+    static NODE: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// Entered is a node the draws are made for, until it is dropped.
+#[must_use = "the node is left as soon as the guard is dropped"]
+pub struct Entered {
+    previous: Option<usize>,
+}
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        NODE.set(self.previous);
+    }
+}
+
+/// Returns a guard tests hold while they draw from the shared generator, so that a
+/// test seeding it draws the same values whatever other tests run beside it.
+#[cfg(test)]
+pub fn exclusive() -> std::sync::MutexGuard<'static, ()> {
+    static EXCLUSIVE: Mutex<()> = Mutex::new(());
+    EXCLUSIVE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Reseeds the generator, every draw after this is determined by the seed. The
+/// generators of the nodes are dropped.
+pub fn seed(seed: u64) {
+    *RNG.lock().unwrap() = StdRng::seed_from_u64(seed);
+    NODE_RNGS.lock().unwrap().clear();
+}
+
+/// Gives `node` a generator of its own, seeded with `seed`.
+pub fn seed_node(node: usize, seed: u64) {
+    NODE_RNGS
+        .lock()
+        .unwrap()
+        .insert(node, StdRng::seed_from_u64(seed));
+}
+
+/// Draws from the generator of `node` until the returned guard is dropped, the node
+/// must have been seeded.
+pub fn enter(node: usize) -> Entered {
+    Entered {
+        previous: NODE.replace(Some(node)),
+    }
+}
+
+/// Runs `f` with the generator of the node entered, the shared one if none is.
+pub fn with_rng<T>(f: impl FnOnce(&mut StdRng) -> T) -> T {
+    match NODE.get() {
+        Some(node) => f(NODE_RNGS
+            .lock()
+            .unwrap()
+            .get_mut(&node)
+            .expect("entered nodes are seeded")),
+        None => with_shared_rng(f),
+    }
+}
+
+/// Runs `f` with the shared generator, whichever node is entered.
+pub fn with_shared_rng<T>(f: impl FnOnce(&mut StdRng) -> T) -> T {
+    f(&mut RNG.lock().unwrap())
+}
@@ -0,0 +1,106 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::task::Waker;
+use std::time::Duration;
+
+/// Event is something that happens at a given virtual time of a simulation.
+pub enum Event {
+    /// wakes a task sleeping on the virtual clock
+    Wake(Waker),
+    /// delivers an encoded message published by a node to one of its peers
+    Deliver {
+        from: usize,
+        to: usize,
+        data: Vec<u8>,
+    },
+    /// a node emits a new transaction, as run_peer does every few seconds
+    EmitTransaction { node: usize },
+}
+
+/// Scheduler is the discrete-event queue of a simulation, it owns the virtual
+/// time which only moves forward when the next event is popped.
+pub struct Scheduler {
+    now: Duration,
+    // breaks ties between events scheduled at the same time in insertion order
+    sequence: u64,
+    queue: BinaryHeap<Scheduled>,
+}
+
+struct Scheduled {
+    at: Duration,
+    sequence: u64,
+    event: Event,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self {
+            now: Duration::ZERO,
+            sequence: 0,
+            queue: BinaryHeap::new(),
+        }
+    }
+
+    /// Returns the current virtual time.
+    pub fn now(&self) -> Duration {
+        self.now
+    }
+
+    /// Schedules the event `delay` after the current virtual time.
+    pub fn schedule_in(&mut self, delay: Duration, event: Event) {
+        self.schedule(self.now + delay, event);
+    }
+
+    /// Schedules the event at the virtual time `at`, events in the past happen right away.
+    pub fn schedule(&mut self, at: Duration, event: Event) {
+        self.sequence += 1;
+        self.queue.push(Scheduled {
+            at: at.max(self.now),
+            sequence: self.sequence,
+            event,
+        });
+    }
+
+    /// Moves the virtual time forward to `at` without popping the events due before,
+    /// for a replay whose recording decides when things happen.
+    pub fn advance(&mut self, at: Duration) {
+        self.now = self.now.max(at);
+    }
+
+    /// Pops the next event due no later than `until` and advances the virtual time to it.
+    pub fn next_event(&mut self, until: Duration) -> Option<Event> {
+        if self.queue.peek()?.at > until {
+            return None;
+        }
+        let scheduled = self.queue.pop()?;
+        self.now = scheduled.at;
+        Some(scheduled.event)
+    }
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// BinaryHeap is a max-heap, the ordering is reversed so the earliest event comes out first
+impl Ord for Scheduled {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.at, other.sequence).cmp(&(self.at, self.sequence))
+    }
+}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Self) -> bool {
+        (self.at, self.sequence) == (other.at, other.sequence)
+    }
+}
+
+impl Eq for Scheduled {}
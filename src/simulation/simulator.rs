@@ -0,0 +1,340 @@
+/*
+The simulator runs every node of a network in a single process and a single thread.
+Engines are polled by a small executor and only ever wait on the virtual clock, while
+the scheduler delivers the messages they publish after a simulated latency, and the
+faults injected into the network. Nothing
+depends on the wall clock or on thread scheduling, so a run is fully determined by
+its configuration and seed.
+*/
+
+use crate::consensus::chain::Chain;
+use crate::consensus::engine::{Context, Engine};
+use crate::metrics::recorder;
+use crate::network::faults::{FaultConfig, Faults};
+use crate::network::messages::message::message::Payload;
+use crate::network::messages::message::Message;
+use crate::network::messages::protobuf::decode_protobuf;
+use crate::network::messages::signing::SigningKey;
+use crate::network::peer::{
+    handle_payload, new_transaction, new_transaction_with_payload, reset_nonces,
+};
+use crate::network::transport::{Network, Transport};
+use crate::simulation::clock::Clock;
+use crate::simulation::rng::{self, with_rng};
+use crate::simulation::scheduler::{Event, Scheduler};
+use crate::simulation::trace::{Recording, TraceHeader, TraceWriter};
+use crate::simulation::transport::SimTransport;
+use crate::workload::generator::{Arrival, WorkloadConfig};
+use crate::CunnerError;
+use futures::task::{waker, ArcWake};
+use libp2p::identity::Keypair;
+use libp2p::PeerId;
+use log::{debug, error, info, warn};
+use rand::Rng;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
+use tracing::info_span;
+
+#[derive(Debug, Clone)]
+pub struct SimulationConfig {
+    pub nodes: usize,
+    pub seed: u64,
+    pub duration: Duration,
+    pub latency: Duration,
+    pub workload: WorkloadConfig,
+    pub faults: FaultConfig,
+    /// where the trace of every node is recorded, none if not set
+    pub record: Option<Recording>,
+}
+
+pub struct Simulation {
+    config: SimulationConfig,
+    scheduler: Arc<Mutex<Scheduler>>,
+}
+
+type Task = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+// wakes a task by queueing it for the next polling pass
+struct TaskWaker {
+    task: usize,
+    ready: Arc<Mutex<VecDeque<usize>>>,
+}
+
+impl ArcWake for TaskWaker {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        let mut ready = arc_self.ready.lock().unwrap();
+        if !ready.contains(&arc_self.task) {
+            ready.push_back(arc_self.task);
+        }
+    }
+}
+
+impl Simulation {
+    pub fn new(config: SimulationConfig) -> Self {
+        Self {
+            config,
+            scheduler: Arc::new(Mutex::new(Scheduler::new())),
+        }
+    }
+
+    /// Returns the virtual clock of the simulation.
+    pub fn clock(&self) -> Clock {
+        Clock::Virtual(self.scheduler.clone())
+    }
+
+    /// Runs the simulation with an engine per node built by `new_engine` from the index
+    /// and context of the node, and returns the chain each node accepted.
+    pub fn run(
+        &self,
+        new_engine: impl Fn(usize, Context) -> Box<dyn Engine>,
+    ) -> Result<Vec<(PeerId, Chain)>, CunnerError> {
+        let config = &self.config;
+        rng::seed(config.seed);
+        reset_nonces();
+        recorder::init(self.clock());
+
+        let keypairs: Vec<Keypair> = (0..config.nodes)
+            .map(|_| {
+                let secret: [u8; 32] = with_rng(|rng| rng.gen());
+                Keypair::ed25519_from_bytes(secret).expect("32 bytes are a valid ed25519 secret")
+            })
+            .collect();
+        let peer_ids: Arc<Vec<PeerId>> = Arc::new(
+            keypairs
+                .iter()
+                .map(|keypair| keypair.public().to_peer_id())
+                .collect(),
+        );
+
+        let keys: Vec<SigningKey> = (0..config.nodes).map(|_| SigningKey::generate()).collect();
+        // every node draws from a generator of its own, so a replay of a node draws the same
+        let seeds: Vec<u64> = (0..config.nodes)
+            .map(|_| with_rng(|rng| rng.gen()))
+            .collect();
+        for (node, seed) in seeds.iter().enumerate() {
+            rng::seed_node(node, *seed);
+        }
+        let faults = Arc::new(Faults::new(config.faults.clone(), &peer_ids));
+
+        let transports: Vec<SimTransport> = (0..config.nodes)
+            .map(|node| {
+                SimTransport::new(
+                    node,
+                    peer_ids.clone(),
+                    self.scheduler.clone(),
+                    config.latency,
+                    faults.clone(),
+                )
+            })
+            .collect();
+        let contexts: Vec<Context> = transports
+            .iter()
+            .zip(peer_ids.iter().zip(keypairs))
+            .map(|(transport, (peer_id, keypair))| Context {
+                network: Network::new(transport.clone()),
+                clock: self.clock(),
+                chain: Chain::in_memory(*peer_id),
+                keypair,
+            })
+            .collect();
+        let engines: Vec<Box<dyn Engine>> = contexts
+            .iter()
+            .enumerate()
+            .map(|(node, context)| {
+                let _node = rng::enter(node);
+                new_engine(node, context.clone())
+            })
+            .collect();
+        let mut traces: Vec<Option<TraceWriter>> = (0..config.nodes)
+            .map(|node| {
+                let Some(record) = &config.record else {
+                    return Ok(None);
+                };
+                let header = TraceHeader {
+                    experiment: record.experiment.clone(),
+                    node,
+                    keypair: contexts[node].keypair.clone(),
+                    peers: peer_ids.to_vec(),
+                    strategy: record.strategies[node],
+                    seed: Some(seeds[node]),
+                };
+                TraceWriter::create(&record.path(node), &header, self.clock()).map(Some)
+            })
+            .collect::<Result<_, io::Error>>()?;
+
+        let mut workloads = (0..config.nodes)
+            .map(|node| config.workload.new_workload(Some(node), config.nodes))
+            .collect::<Result<Vec<_>, _>>()?;
+        // the arrival every node submits on its next EmitTransaction event
+        let mut arrivals: Vec<Option<Arrival>> = vec![None; config.nodes];
+        for (node, peer_id) in peer_ids.iter().enumerate() {
+            info!("Simulated node {node} is {peer_id}");
+            let Some(arrival) = workloads[node]
+                .as_mut()
+                .and_then(|workload| workload.next())
+            else {
+                continue;
+            };
+            self.scheduler
+                .lock()
+                .unwrap()
+                .schedule(arrival.at, Event::EmitTransaction { node });
+            arrivals[node] = Some(arrival);
+        }
+
+        let ready = Arc::new(Mutex::new((0..config.nodes).collect::<VecDeque<_>>()));
+        let mut tasks: Vec<Option<Task>> = engines
+            .iter()
+            .map(|engine| {
+                let engine = engine.clone();
+                Some(Box::pin(async move { engine.run().await }) as Task)
+            })
+            .collect();
+
+        loop {
+            // polls the engines until all of them wait on the clock or on messages
+            while let Some(node) = ready.lock().unwrap().pop_front() {
+                let Some(task) = tasks[node].as_mut() else {
+                    continue;
+                };
+                let _span = info_span!("node", id = node).entered();
+                let _node = rng::enter(node);
+                if let Some(trace) = traces[node].as_mut() {
+                    trace.poll()?;
+                }
+                let waker = waker(Arc::new(TaskWaker {
+                    task: node,
+                    ready: ready.clone(),
+                }));
+                if let Poll::Ready(()) = task.as_mut().poll(&mut TaskContext::from_waker(&waker)) {
+                    warn!("Engine run returned");
+                    tasks[node] = None;
+                }
+            }
+
+            let event = self.scheduler.lock().unwrap().next_event(config.duration);
+            let Some(event) = event else {
+                break;
+            };
+
+            match event {
+                Event::Wake(waker) => waker.wake(),
+                Event::Deliver { from, to, data } => {
+                    let _span = info_span!("node", id = to).entered();
+                    let _node = rng::enter(to);
+                    match decode_protobuf(&data) {
+                        Ok(Message {
+                            payload: Some(payload),
+                            ..
+                        }) => {
+                            if let Some(trace) = traces[to].as_mut() {
+                                trace.message(peer_ids[from], &payload)?;
+                            }
+                            handle_payload(
+                                engines[to].as_ref(),
+                                &contexts[to],
+                                peer_ids[from],
+                                payload,
+                            )
+                        }
+                        Ok(_) => warn!("Received message with empty payload"),
+                        Err(e) => error!("Failed to decode message: {:?}", e),
+                    }
+                }
+                Event::EmitTransaction { node } => {
+                    let _span = info_span!("node", id = node).entered();
+                    let Some(arrival) = arrivals[node].take() else {
+                        continue;
+                    };
+                    if config.nodes > 1 {
+                        for _ in 0..arrival.count {
+                            let transaction = match &arrival.payload {
+                                Some(payload) => {
+                                    new_transaction_with_payload(&keys[node], payload.clone())
+                                }
+                                None => new_transaction(&keys[node]),
+                            };
+                            debug!("Generated new transaction: {:?}", transaction);
+                            recorder::transaction_submitted(&transaction);
+                            if let Some(trace) = traces[node].as_mut() {
+                                trace.transaction(&transaction)?;
+                            }
+                            {
+                                let _node = rng::enter(node);
+                                engines[node].add_transaction(transaction.clone());
+                            }
+                            transports[node].publish(Message {
+                                payload: Some(Payload::Transaction(transaction)),
+                                to: Vec::new(),
+                            });
+                        }
+                    }
+                    if let Some(next) = workloads[node]
+                        .as_mut()
+                        .and_then(|workload| workload.next())
+                    {
+                        self.scheduler
+                            .lock()
+                            .unwrap()
+                            .schedule(next.at, Event::EmitTransaction { node });
+                        arrivals[node] = Some(next);
+                    }
+                }
+            }
+        }
+
+        for (trace, context) in traces.iter_mut().zip(&contexts) {
+            if let Some(trace) = trace {
+                trace.chain(&context.chain)?;
+            }
+        }
+
+        Ok(peer_ids
+            .iter()
+            .zip(contexts)
+            .map(|(peer_id, context)| (*peer_id, context.chain))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consensus::example::engine::Engine as ExampleEngine;
+
+    // runs four nodes of the example engine for a minute and returns their chain digests
+    fn digests(seed: u64) -> Vec<String> {
+        let simulation = Simulation::new(SimulationConfig {
+            nodes: 4,
+            seed,
+            duration: Duration::from_secs(60),
+            latency: Duration::from_millis(50),
+            workload: WorkloadConfig::default(),
+            faults: FaultConfig::default(),
+            record: None,
+        });
+        let chains = simulation
+            .run(|_, context| ExampleEngine::new_engine(Duration::from_secs(5), context))
+            .unwrap();
+        chains
+            .iter()
+            .map(|(_, chain)| {
+                assert!(chain.len().unwrap() > 0, "the nodes committed no block");
+                chain.digest().unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn a_seed_determines_the_run() {
+        let _rng = rng::exclusive();
+        let first = digests(7);
+        assert_eq!(digests(7), first);
+        assert_ne!(digests(8), first);
+    }
+}
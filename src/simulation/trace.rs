@@ -0,0 +1,443 @@
+/*
+A trace records the inputs of one node, so `cunner replay` can feed them back into a
+fresh engine and reproduce a run: every transaction submitted to the node and every
+message it received, with the time since the start of the run and the peer it came
+from, in the order the node handled them. A simulated node also records every time
+the simulator ran its engine, so a replay runs it at the same virtual times and in
+the same order with its inputs. When the run ends, the trace records the blocks the
+node committed, which the replay checks its own chain against.
+
+The trace starts with a header holding what the engine of the node was built from:
+the experiment as it ran, the identity and index of the node, the peers it knew, its
+byzantine strategy, and the seed of its generator if it was simulated. It is a binary
+log of entries, each a little-endian u32 length followed by its kind, its time in
+nanoseconds and its fields, messages encoded with protobuf as they are on the wire.
+The identity of the node is in the header, so a trace should be kept as private as
+the node identity file.
+*/
+
+use crate::byzantine::adversary::Strategy;
+use crate::consensus::chain::Chain;
+use crate::network::messages::message::message::Payload;
+use crate::network::messages::message::{Message, Transaction};
+use crate::network::messages::protobuf::{decode_protobuf, encode_protobuf};
+use crate::simulation::clock::Clock;
+use crate::storage::log_store::{read_field, write_field};
+use crate::CunnerError;
+use clap::ValueEnum;
+use libp2p::identity::Keypair;
+use libp2p::PeerId;
+use log::warn;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const MAGIC: &[u8] = b"cunner-trace/1\n";
+
+const HEADER: u8 = 0;
+const TRANSACTION: u8 = 1;
+const MESSAGE: u8 = 2;
+const POLL: u8 = 3;
+const CHAIN: u8 = 4;
+
+/// TraceHeader is what the engine of a traced node is built from.
+pub struct TraceHeader {
+    /// the experiment the node ran, as JSON
+    pub experiment: String,
+    /// index of the node in its network, 0 for a live node not in network.peers
+    pub node: usize,
+    pub keypair: Keypair,
+    /// peer ids of the nodes of the network, in index order
+    pub peers: Vec<PeerId>,
+    pub strategy: Option<Strategy>,
+    /// seed of the generator of a simulated node, None for a live node
+    pub seed: Option<u64>,
+}
+
+/// Record is an entry of a trace after its header, `at` is the time since the start.
+pub enum Record {
+    /// a transaction submitted to the node
+    Transaction {
+        at: Duration,
+        transaction: Transaction,
+    },
+    /// a message the node received from a peer
+    Message {
+        at: Duration,
+        from: PeerId,
+        payload: Payload,
+    },
+    /// the simulator ran the engine of the node
+    Poll { at: Duration },
+    /// hashes of the blocks the node committed by the end of the run, in height order
+    Chain { at: Duration, hashes: Vec<Vec<u8>> },
+}
+
+/// Recording is where a simulation writes the trace of every node, and what it
+/// writes in their headers.
+#[derive(Debug, Clone)]
+pub struct Recording {
+    /// directory the trace of node i is written to as node-i.trace
+    pub dir: PathBuf,
+    pub experiment: String,
+    /// byzantine strategy of every node
+    pub strategies: Vec<Option<Strategy>>,
+}
+
+impl Recording {
+    pub fn path(&self, node: usize) -> PathBuf {
+        self.dir.join(format!("node-{node}.trace"))
+    }
+}
+
+/// TraceWriter records the inputs of a node as they happen, stamped with its clock.
+pub struct TraceWriter {
+    file: BufWriter<File>,
+    clock: Clock,
+}
+
+impl TraceWriter {
+    /// Creates the trace at `path`, replacing any previous one, and writes its header.
+    pub fn create(path: &Path, header: &TraceHeader, clock: Clock) -> io::Result<Self> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let mut writer = Self {
+            file: BufWriter::new(File::create(path)?),
+            clock,
+        };
+        writer.file.write_all(MAGIC)?;
+
+        let mut entry = vec![HEADER];
+        write_field(&mut entry, header.experiment.as_bytes())?;
+        entry.extend_from_slice(&(header.node as u64).to_le_bytes());
+        let keypair = header
+            .keypair
+            .to_protobuf_encoding()
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        write_field(&mut entry, &keypair)?;
+        entry.extend_from_slice(&(header.peers.len() as u32).to_le_bytes());
+        for peer in &header.peers {
+            write_field(&mut entry, &peer.to_bytes())?;
+        }
+        let strategy = header.strategy.map(|strategy| {
+            strategy
+                .to_possible_value()
+                .expect("strategies are never skipped")
+                .get_name()
+                .to_string()
+        });
+        write_field(&mut entry, strategy.unwrap_or_default().as_bytes())?;
+        match header.seed {
+            Some(seed) => {
+                entry.push(1);
+                entry.extend_from_slice(&seed.to_le_bytes());
+            }
+            None => entry.push(0),
+        }
+        writer.write_entry(&entry)?;
+        Ok(writer)
+    }
+
+    pub fn transaction(&mut self, transaction: &Transaction) -> io::Result<()> {
+        let mut entry = self.start_entry(TRANSACTION);
+        let message = Message {
+            payload: Some(Payload::Transaction(transaction.clone())),
+            to: Vec::new(),
+        };
+        write_field(&mut entry, &encode_protobuf(&message)?)?;
+        self.write_entry(&entry)
+    }
+
+    pub fn message(&mut self, from: PeerId, payload: &Payload) -> io::Result<()> {
+        let mut entry = self.start_entry(MESSAGE);
+        write_field(&mut entry, &from.to_bytes())?;
+        let message = Message {
+            payload: Some(payload.clone()),
+            to: Vec::new(),
+        };
+        write_field(&mut entry, &encode_protobuf(&message)?)?;
+        self.write_entry(&entry)
+    }
+
+    pub fn poll(&mut self) -> io::Result<()> {
+        let entry = self.start_entry(POLL);
+        self.write_entry(&entry)
+    }
+
+    /// Records the blocks of the chain and flushes the trace, at the end of the run.
+    pub fn chain(&mut self, chain: &Chain) -> Result<(), CunnerError> {
+        let mut entry = self.start_entry(CHAIN);
+        let blocks = chain.blocks()?;
+        entry.extend_from_slice(&(blocks.len() as u32).to_le_bytes());
+        for block in &blocks {
+            write_field(&mut entry, &block.hash())?;
+        }
+        self.write_entry(&entry)?;
+        Ok(self.file.flush()?)
+    }
+
+    // the kind of the entry and the time it is recorded at
+    fn start_entry(&self, kind: u8) -> Vec<u8> {
+        let mut entry = vec![kind];
+        let at = self.clock.now().as_nanos() as u64;
+        entry.extend_from_slice(&at.to_le_bytes());
+        entry
+    }
+
+    fn write_entry(&mut self, entry: &[u8]) -> io::Result<()> {
+        let len = u32::try_from(entry.len())
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "Trace entry is too large"))?;
+        self.file.write_all(&len.to_le_bytes())?;
+        self.file.write_all(entry)
+    }
+}
+
+/// Reads the trace at `path`. An entry cut short at the end, by a node that did not
+/// shut down cleanly, is dropped.
+pub fn read_trace(path: &Path) -> Result<(TraceHeader, Vec<Record>), CunnerError> {
+    let content = fs::read(path).map_err(|e| {
+        CunnerError::Config(format!("Failed to read trace {}: {}", path.display(), e))
+    })?;
+    let invalid = |message: String| {
+        CunnerError::Config(format!("Invalid trace {}: {}", path.display(), message))
+    };
+    let entries = content
+        .strip_prefix(MAGIC)
+        .ok_or_else(|| invalid("not a cunner trace".into()))?;
+
+    let mut offset = 0;
+    let mut header = None;
+    let mut records = Vec::new();
+    while let Some(len) = entries.get(offset..offset + 4) {
+        let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+        let Some(entry) = entries.get(offset + 4..offset + 4 + len) else {
+            break;
+        };
+        offset += 4 + len;
+        if header.is_none() {
+            header = Some(read_header(entry).map_err(|e| invalid(e.to_string()))?);
+        } else {
+            records.push(read_record(entry).map_err(|e| invalid(e.to_string()))?);
+        }
+    }
+    if offset < entries.len() {
+        warn!(
+            "Dropping {} bytes of partially written entry at the end of {}",
+            entries.len() - offset,
+            path.display()
+        );
+    }
+    let header = header.ok_or_else(|| invalid("no header".into()))?;
+    Ok((header, records))
+}
+
+fn read_header(entry: &[u8]) -> io::Result<TraceHeader> {
+    let invalid = |message: String| Error::new(ErrorKind::InvalidData, message);
+    if entry.first() != Some(&HEADER) {
+        return Err(invalid("the first entry is not a header".into()));
+    }
+    let mut index = 1;
+    let experiment = String::from_utf8(read_field(entry, &mut index)?.to_vec())
+        .map_err(|e| invalid(e.to_string()))?;
+    let node = read_u64(entry, &mut index)? as usize;
+    let keypair = Keypair::from_protobuf_encoding(read_field(entry, &mut index)?)
+        .map_err(|e| invalid(e.to_string()))?;
+    let peers = (0..read_u32(entry, &mut index)?)
+        .map(|_| {
+            PeerId::from_bytes(read_field(entry, &mut index)?).map_err(|e| invalid(e.to_string()))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    let strategy = match read_field(entry, &mut index)? {
+        [] => None,
+        name => {
+            let name = String::from_utf8_lossy(name);
+            Some(Strategy::from_str(&name, false).map_err(invalid)?)
+        }
+    };
+    let seed = match entry.get(index) {
+        Some(1) => {
+            index += 1;
+            Some(read_u64(entry, &mut index)?)
+        }
+        Some(0) => None,
+        _ => return Err(invalid("truncated header".into())),
+    };
+    Ok(TraceHeader {
+        experiment,
+        node,
+        keypair,
+        peers,
+        strategy,
+        seed,
+    })
+}
+
+fn read_record(entry: &[u8]) -> io::Result<Record> {
+    let invalid = |message: String| Error::new(ErrorKind::InvalidData, message);
+    let kind = *entry.first().ok_or_else(|| invalid("empty entry".into()))?;
+    let mut index = 1;
+    let at = Duration::from_nanos(read_u64(entry, &mut index)?);
+    // the payload of a message as it was encoded on the wire
+    let read_payload = |index: &mut usize| -> io::Result<Payload> {
+        decode_protobuf(read_field(entry, index)?)?
+            .payload
+            .ok_or_else(|| invalid("message without a payload".into()))
+    };
+    match kind {
+        TRANSACTION => match read_payload(&mut index)? {
+            Payload::Transaction(transaction) => Ok(Record::Transaction { at, transaction }),
+            _ => Err(invalid("submitted message is not a transaction".into())),
+        },
+        MESSAGE => {
+            let from = PeerId::from_bytes(read_field(entry, &mut index)?)
+                .map_err(|e| invalid(e.to_string()))?;
+            let payload = read_payload(&mut index)?;
+            Ok(Record::Message { at, from, payload })
+        }
+        POLL => Ok(Record::Poll { at }),
+        CHAIN => {
+            let hashes = (0..read_u32(entry, &mut index)?)
+                .map(|_| read_field(entry, &mut index).map(<[u8]>::to_vec))
+                .collect::<io::Result<Vec<_>>>()?;
+            Ok(Record::Chain { at, hashes })
+        }
+        kind => Err(invalid(format!("unknown entry kind {kind}"))),
+    }
+}
+
+fn read_u64(entry: &[u8], index: &mut usize) -> io::Result<u64> {
+    let bytes = entry
+        .get(*index..*index + 8)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Truncated trace entry"))?;
+    *index += 8;
+    Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u32(entry: &[u8], index: &mut usize) -> io::Result<u32> {
+    let bytes = entry
+        .get(*index..*index + 4)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Truncated trace entry"))?;
+    *index += 4;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::messages::message::{Block, ConsensusMessage};
+    use crate::simulation::rng;
+    use crate::simulation::scheduler::Scheduler;
+    use crate::testing::TempFile;
+    use std::sync::{Arc, Mutex};
+
+    // writes a trace of every kind of record, one a second, and returns what it holds
+    fn write_trace(path: &Path) -> (TraceHeader, Transaction, Payload, Chain) {
+        let keypair = Keypair::generate_ed25519();
+        let peer = keypair.public().to_peer_id();
+        let header = TraceHeader {
+            experiment: r#"{"engine":{"name":"pbft"}}"#.into(),
+            node: 1,
+            keypair,
+            peers: vec![PeerId::random(), peer, PeerId::random()],
+            strategy: Some(Strategy::Delay),
+            seed: Some(42),
+        };
+        let scheduler = Arc::new(Mutex::new(Scheduler::new()));
+        let clock = Clock::Virtual(scheduler.clone());
+        let at = |secs| scheduler.lock().unwrap().advance(Duration::from_secs(secs));
+        let mut writer = TraceWriter::create(path, &header, clock).unwrap();
+
+        let transaction = Transaction::new_transaction();
+        let payload = Payload::ConsensusMessage(ConsensusMessage {
+            data: b"prepare".to_vec(),
+        });
+        let chain = Chain::in_memory(peer);
+        chain.append(&Block::new_block(None, peer, 0, vec![transaction.clone()]));
+
+        at(1);
+        writer.transaction(&transaction).unwrap();
+        at(2);
+        writer.message(header.peers[0], &payload).unwrap();
+        at(3);
+        writer.poll().unwrap();
+        at(4);
+        writer.chain(&chain).unwrap();
+        (header, transaction, payload, chain)
+    }
+
+    #[test]
+    fn a_trace_reads_back_as_written() {
+        let _rng = rng::exclusive();
+        let trace = TempFile::new("round-trip.trace");
+        let (written, transaction, payload, chain) = write_trace(trace.path());
+
+        let (header, records) = read_trace(trace.path()).unwrap();
+        assert_eq!(header.experiment, written.experiment);
+        assert_eq!(header.node, 1);
+        assert_eq!(
+            header.keypair.public().to_peer_id(),
+            written.keypair.public().to_peer_id()
+        );
+        assert_eq!(header.peers, written.peers);
+        assert_eq!(header.strategy, Some(Strategy::Delay));
+        assert_eq!(header.seed, Some(42));
+
+        assert_eq!(records.len(), 4);
+        let Record::Transaction {
+            at,
+            transaction: read,
+        } = &records[0]
+        else {
+            panic!("expected a transaction record");
+        };
+        assert_eq!((*at, read), (Duration::from_secs(1), &transaction));
+        let Record::Message {
+            at,
+            from,
+            payload: read,
+        } = &records[1]
+        else {
+            panic!("expected a message record");
+        };
+        assert_eq!((*at, *from), (Duration::from_secs(2), written.peers[0]));
+        assert_eq!(read, &payload);
+        let Record::Poll { at } = &records[2] else {
+            panic!("expected a poll record");
+        };
+        assert_eq!(*at, Duration::from_secs(3));
+        let Record::Chain { at, hashes } = &records[3] else {
+            panic!("expected a chain record");
+        };
+        assert_eq!(*at, Duration::from_secs(4));
+        let blocks: Vec<Vec<u8>> = chain.blocks().unwrap().iter().map(Block::hash).collect();
+        assert_eq!(hashes, &blocks);
+    }
+
+    #[test]
+    fn a_truncated_final_entry_is_dropped() {
+        let _rng = rng::exclusive();
+        let trace = TempFile::new("truncated.trace");
+        write_trace(trace.path());
+        let len = fs::metadata(trace.path()).unwrap().len();
+        File::options()
+            .write(true)
+            .open(trace.path())
+            .unwrap()
+            .set_len(len - 5)
+            .unwrap();
+
+        let (header, records) = read_trace(trace.path()).unwrap();
+        assert_eq!(header.node, 1);
+        assert_eq!(records.len(), 3);
+        assert!(matches!(records[2], Record::Poll { .. }));
+    }
+
+    #[test]
+    fn other_files_are_not_traces() {
+        let trace = TempFile::new("other.trace");
+        fs::write(trace.path(), b"not a trace").unwrap();
+        assert!(read_trace(trace.path()).is_err());
+    }
+}
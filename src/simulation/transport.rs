@@ -0,0 +1,103 @@
+use crate::network::faults::Faults;
+use crate::network::messages::message::Message;
+use crate::network::messages::protobuf::encode_protobuf;
+use crate::network::transport::Transport;
+use crate::simulation::scheduler::{Event, Scheduler};
+use libp2p::PeerId;
+use log::{debug, error};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// SimTransport is the transport of a simulated node: every published message is
+/// encoded as it would be on the wire and scheduled for delivery to every other
+/// node after the configured latency, unless the injected faults drop it or hold it
+/// back longer.
+#[derive(Clone)]
+pub struct SimTransport {
+    node: usize,
+    peer_ids: Arc<Vec<PeerId>>,
+    scheduler: Arc<Mutex<Scheduler>>,
+    latency: Duration,
+    faults: Arc<Faults>,
+}
+
+impl SimTransport {
+    pub fn new(
+        node: usize,
+        peer_ids: Arc<Vec<PeerId>>,
+        scheduler: Arc<Mutex<Scheduler>>,
+        latency: Duration,
+        faults: Arc<Faults>,
+    ) -> Self {
+        Self {
+            node,
+            peer_ids,
+            scheduler,
+            latency,
+            faults,
+        }
+    }
+
+    // schedules the delivery of the message to the node, if the faults let it through
+    fn deliver(&self, scheduler: &mut Scheduler, to: usize, data: Vec<u8>) {
+        let (from, to_peer) = (self.peer_ids[self.node], self.peer_ids[to]);
+        let Some(delay) = self.faults.delay(from, to_peer, scheduler.now()) else {
+            debug!("Fault dropped a message to node {to}");
+            return;
+        };
+        scheduler.schedule_in(
+            self.latency + delay,
+            Event::Deliver {
+                from: self.node,
+                to,
+                data,
+            },
+        );
+    }
+}
+
+impl Transport for SimTransport {
+    fn local_peer_id(&self) -> PeerId {
+        self.peer_ids[self.node]
+    }
+
+    fn connected_peers(&self) -> Vec<PeerId> {
+        self.peer_ids
+            .iter()
+            .enumerate()
+            .filter(|(node, _)| *node != self.node)
+            .map(|(_, peer_id)| *peer_id)
+            .collect()
+    }
+
+    fn nodes(&self) -> Vec<PeerId> {
+        self.peer_ids.to_vec()
+    }
+
+    fn publish(&self, message: Message) {
+        let data = match encode_protobuf(&message) {
+            Ok(data) => data,
+            Err(e) => {
+                error!("Failed to encode message: {:?}", e);
+                return;
+            }
+        };
+
+        let mut scheduler = self.scheduler.lock().unwrap();
+        for to in (0..self.peer_ids.len()).filter(|to| *to != self.node) {
+            self.deliver(&mut scheduler, to, data.clone());
+        }
+    }
+
+    fn send_to(&self, peer: PeerId, message: Message) {
+        let Some(to) = self.peer_ids.iter().position(|peer_id| *peer_id == peer) else {
+            error!("Unknown simulated peer {peer}");
+            return;
+        };
+
+        match encode_protobuf(&message) {
+            Ok(data) => self.deliver(&mut self.scheduler.lock().unwrap(), to, data),
+            Err(e) => error!("Failed to encode message: {:?}", e),
+        }
+    }
+}
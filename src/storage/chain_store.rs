@@ -0,0 +1,191 @@
+use crate::network::messages::message::message::Payload;
+use crate::network::messages::message::{Block, Message};
+use crate::network::messages::protobuf::{decode_protobuf, encode_protobuf};
+use crate::storage::store::{Batch, Store};
+use std::io::{self, Error, ErrorKind};
+use std::sync::{Arc, Mutex};
+
+// blocks are stored by hash, heights map to the hash of the block at that height,
+// transactions to the height of the first block holding them, and the head holds
+// the height and hash of the last block
+const BLOCK_PREFIX: &[u8] = b"block/";
+const HEIGHT_PREFIX: &[u8] = b"height/";
+const TRANSACTION_PREFIX: &[u8] = b"tx/";
+const HEAD_KEY: &[u8] = b"head";
+
+/// ChainStore lays a chain of blocks out on a Store. Heights start at 1, the
+/// head is the block at the highest height.
+pub struct ChainStore {
+    store: Arc<dyn Store>,
+    // appends read the head before writing the next one
+    append_lock: Mutex<()>,
+}
+
+impl ChainStore {
+    pub fn new(store: Arc<dyn Store>) -> Self {
+        Self {
+            store,
+            append_lock: Mutex::new(()),
+        }
+    }
+
+    /// Returns the store the chain is laid out on, engines keep their own state in it
+    /// under keys of their own.
+    pub fn store(&self) -> Arc<dyn Store> {
+        self.store.clone()
+    }
+
+    /// Appends the block on top of the head and returns its height. A block that
+    /// does not extend the head, by its index and parent hash, is refused.
+    pub fn append(&self, block: &Block) -> io::Result<u64> {
+        let _guard = self.append_lock.lock().unwrap();
+        let (height, head_hash) = self
+            .head()?
+            .map_or((1, Vec::new()), |(height, hash)| (height + 1, hash));
+        let hash = block.hash();
+        let header = block
+            .header
+            .as_ref()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Block has no header"))?;
+        if u64::from(header.index) != height || header.parent_hash != head_hash {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Block {} at index {} does not extend the head at height {}",
+                    hex::encode(&hash),
+                    header.index,
+                    height - 1
+                ),
+            ));
+        }
+
+        let mut batch = Batch::new();
+        if !self.store.has(&block_key(&hash))? {
+            batch.put(&block_key(&hash), &encode_block(block)?);
+        }
+        batch.put(&height_key(height), &hash);
+        for transaction in &block.transactions {
+            let key = transaction_key(&transaction.hash());
+            if !self.store.has(&key)? {
+                batch.put(&key, &height.to_be_bytes());
+            }
+        }
+        let mut head = height.to_be_bytes().to_vec();
+        head.extend_from_slice(&hash);
+        batch.put(HEAD_KEY, &head);
+        self.store.write_batch(batch)?;
+
+        Ok(height)
+    }
+
+    /// Returns the height and hash of the head, None for an empty chain.
+    pub fn head(&self) -> io::Result<Option<(u64, Vec<u8>)>> {
+        let Some(head) = self.store.get(HEAD_KEY)? else {
+            return Ok(None);
+        };
+        if head.len() < 8 {
+            return Err(Error::new(ErrorKind::InvalidData, "Corrupted chain head"));
+        }
+        let height = u64::from_be_bytes(head[..8].try_into().unwrap());
+        Ok(Some((height, head[8..].to_vec())))
+    }
+
+    pub fn block_by_hash(&self, hash: &[u8]) -> io::Result<Option<Block>> {
+        self.store
+            .get(&block_key(hash))?
+            .map(|bytes| decode_block(&bytes))
+            .transpose()
+    }
+
+    pub fn block_by_height(&self, height: u64) -> io::Result<Option<Block>> {
+        match self.store.get(&height_key(height))? {
+            Some(hash) => self.block_by_hash(&hash),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the height of the first block holding the transaction, None if no
+    /// block of the chain holds it.
+    pub fn transaction_height(&self, hash: &[u8]) -> io::Result<Option<u64>> {
+        match self.store.get(&transaction_key(hash))? {
+            Some(height) => {
+                let height = height[..].try_into().map_err(|_| {
+                    Error::new(ErrorKind::InvalidData, "Corrupted transaction height")
+                })?;
+                Ok(Some(u64::from_be_bytes(height)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Returns every block of the chain, by increasing height.
+    pub fn blocks(&self) -> io::Result<Vec<Block>> {
+        // heights are big endian, so the keys sort by height
+        self.store
+            .iter_prefix(HEIGHT_PREFIX)?
+            .into_iter()
+            .map(|(_, hash)| {
+                self.block_by_hash(&hash)?
+                    .ok_or_else(|| Error::new(ErrorKind::NotFound, "Missing block in chain"))
+            })
+            .collect()
+    }
+}
+
+fn block_key(hash: &[u8]) -> Vec<u8> {
+    [BLOCK_PREFIX, hash].concat()
+}
+
+fn height_key(height: u64) -> Vec<u8> {
+    [HEIGHT_PREFIX, &height.to_be_bytes()].concat()
+}
+
+fn transaction_key(hash: &[u8]) -> Vec<u8> {
+    [TRANSACTION_PREFIX, hash].concat()
+}
+
+fn encode_block(block: &Block) -> io::Result<Vec<u8>> {
+    encode_protobuf(&Message {
+        payload: Some(Payload::Block(block.clone())),
+        to: Vec::new(),
+    })
+}
+
+fn decode_block(bytes: &[u8]) -> io::Result<Block> {
+    match decode_protobuf(bytes)?.payload {
+        Some(Payload::Block(block)) => Ok(block),
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            "Stored value is not a block",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::rng;
+    use crate::storage::store::MemStore;
+    use libp2p::PeerId;
+
+    #[test]
+    fn append_refuses_blocks_not_extending_the_head() {
+        let _rng = rng::exclusive();
+        let store = ChainStore::new(MemStore::new_mem_store());
+        let proposer = PeerId::random();
+        let first = Block::new_block(None, proposer, 1_000, Vec::new());
+        let second = Block::new_block(Some(&first), proposer, 2_000, Vec::new());
+
+        let error = store.append(&second).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        assert_eq!(store.head().unwrap(), None);
+
+        assert_eq!(store.append(&first).unwrap(), 1);
+        // a sibling of the head, and the head once more
+        let sibling = Block::new_block(None, proposer, 1_500, Vec::new());
+        assert!(store.append(&sibling).is_err());
+        assert!(store.append(&first).is_err());
+        assert_eq!(store.append(&second).unwrap(), 2);
+        assert_eq!(store.head().unwrap(), Some((2, second.hash())));
+    }
+}
@@ -0,0 +1,325 @@
+/*
+The LogStore persists a store as an append-only log. Every batch is written as a
+single entry, a little-endian u32 length followed by its operations, and synced to
+disk before it is applied. Opening the log replays every entry into a sorted index
+of value offsets, so reads are a seek away. A crash in the middle of a write leaves
+a partial entry at the end of the log, it is dropped when the log is reopened, which
+keeps batches atomic. A write that fails is cut off the log right away, and if even
+that fails the store refuses every later write, which could only follow the partial
+entry. The log is never compacted, overwritten and deleted values keep their space.
+*/
+
+use crate::storage::store::{Batch, Operation, Store};
+use bytes::Bytes;
+use log::{error, warn};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+const PUT: u8 = 1;
+const DELETE: u8 = 0;
+
+/// LogStore is a Store persisted to an append-only file.
+pub struct LogStore {
+    log: Mutex<Log>,
+}
+
+struct Log {
+    file: File,
+    // length of the valid part of the file, where the next entry is written
+    len: u64,
+    // offset and length in the file of the current value of each key
+    index: BTreeMap<Vec<u8>, (u64, usize)>,
+    // a failed write left part of an entry at the end of the file
+    poisoned: bool,
+}
+
+impl LogStore {
+    /// Opens the log at `path`, creating it if it does not exist.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+
+        let mut content = Vec::new();
+        file.read_to_end(&mut content)?;
+
+        let mut index = BTreeMap::new();
+        let mut offset = 0;
+        while let Some((entry, operations)) = read_entry(&content, offset)? {
+            for operation in operations {
+                match operation {
+                    (key, Some(value)) => index.insert(key, value),
+                    (key, None) => index.remove(&key),
+                };
+            }
+            offset = entry;
+        }
+
+        if offset < content.len() {
+            warn!(
+                "Dropping {} bytes of partially written entry at the end of {}",
+                content.len() - offset,
+                path.display()
+            );
+            file.set_len(offset as u64)?;
+        }
+
+        Ok(Self {
+            log: Mutex::new(Log {
+                file,
+                len: offset as u64,
+                index,
+                poisoned: false,
+            }),
+        })
+    }
+}
+
+impl Store for LogStore {
+    fn get(&self, key: &[u8]) -> io::Result<Option<Bytes>> {
+        let mut log = self.log.lock().unwrap();
+        let Some(&(offset, len)) = log.index.get(key) else {
+            return Ok(None);
+        };
+        read_value(&mut log.file, offset, len).map(Some)
+    }
+
+    fn iter_prefix(&self, prefix: &[u8]) -> io::Result<Vec<(Vec<u8>, Bytes)>> {
+        let mut log = self.log.lock().unwrap();
+        let locations: Vec<(Vec<u8>, u64, usize)> = log
+            .index
+            .range(prefix.to_vec()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, &(offset, len))| (key.clone(), offset, len))
+            .collect();
+
+        locations
+            .into_iter()
+            .map(|(key, offset, len)| Ok((key, read_value(&mut log.file, offset, len)?)))
+            .collect()
+    }
+
+    fn write_batch(&self, batch: Batch) -> io::Result<()> {
+        let mut log = self.log.lock().unwrap();
+        if log.poisoned {
+            return Err(Error::other(
+                "Log holds a partially written entry, it must be reopened",
+            ));
+        }
+
+        // the entry starts with its length, values are located relative to the start of the entry
+        let mut entry = vec![0; 4];
+        let mut updates = Vec::new();
+        for operation in batch.operations() {
+            match operation {
+                Operation::Put { key, value } => {
+                    entry.push(PUT);
+                    write_field(&mut entry, key)?;
+                    let value_offset = log.len + entry.len() as u64 + 4;
+                    write_field(&mut entry, value)?;
+                    updates.push((key.clone(), Some((value_offset, value.len()))));
+                }
+                Operation::Delete { key } => {
+                    entry.push(DELETE);
+                    write_field(&mut entry, key)?;
+                    updates.push((key.clone(), None));
+                }
+            }
+        }
+        let len = u32::try_from(entry.len() - 4)
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "Batch is too large"))?;
+        entry[..4].copy_from_slice(&len.to_le_bytes());
+
+        if let Err(e) = log
+            .file
+            .write_all(&entry)
+            .and_then(|()| log.file.sync_data())
+        {
+            // the next entry must start where the valid part of the log ends
+            let len = log.len;
+            if let Err(truncate) = log.file.set_len(len) {
+                error!("Failed to cut a partially written entry off the log: {truncate}");
+                log.poisoned = true;
+            }
+            return Err(e);
+        }
+        log.len += entry.len() as u64;
+
+        for (key, location) in updates {
+            match location {
+                Some(location) => log.index.insert(key, location),
+                None => log.index.remove(&key),
+            };
+        }
+        Ok(())
+    }
+}
+
+type Update = (Vec<u8>, Option<(u64, usize)>);
+
+// reads the entry at offset, returns the offset of the next entry and the updates to the index,
+// or None if the entry is missing or was only partially written
+fn read_entry(content: &[u8], offset: usize) -> io::Result<Option<(usize, Vec<Update>)>> {
+    let Some(len) = content.get(offset..offset + 4) else {
+        return Ok(None);
+    };
+    let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+    let start = offset + 4;
+    let Some(entry) = content.get(start..start + len) else {
+        return Ok(None);
+    };
+
+    let mut updates = Vec::new();
+    let mut index = 0;
+    while index < entry.len() {
+        let operation = entry[index];
+        index += 1;
+        let key = read_field(entry, &mut index)?.to_vec();
+        match operation {
+            PUT => {
+                let value_offset = (start + index + 4) as u64;
+                let value = read_field(entry, &mut index)?;
+                updates.push((key, Some((value_offset, value.len()))));
+            }
+            DELETE => updates.push((key, None)),
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Unknown operation {operation} in log entry at {offset}"),
+                ))
+            }
+        }
+    }
+
+    Ok(Some((start + len, updates)))
+}
+
+/// Appends a field to an entry, a little-endian u32 length followed by its bytes.
+pub fn write_field(entry: &mut Vec<u8>, field: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(field.len())
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, "Field is too large"))?;
+    entry.extend_from_slice(&len.to_le_bytes());
+    entry.extend_from_slice(field);
+    Ok(())
+}
+
+/// Reads the field at `index` of an entry and moves `index` past it.
+pub fn read_field<'a>(entry: &'a [u8], index: &mut usize) -> io::Result<&'a [u8]> {
+    let truncated = || Error::new(ErrorKind::InvalidData, "Truncated field in log entry");
+    let len = entry.get(*index..*index + 4).ok_or_else(truncated)?;
+    let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+    let field = entry
+        .get(*index + 4..*index + 4 + len)
+        .ok_or_else(truncated)?;
+    *index += 4 + len;
+    Ok(field)
+}
+
+fn read_value(file: &mut File, offset: u64, len: usize) -> io::Result<Bytes> {
+    let mut value = vec![0; len];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut value)?;
+    Ok(Bytes::from(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempFile;
+    use std::fs;
+
+    fn write(store: &LogStore, puts: &[(&[u8], &[u8])]) {
+        let mut batch = Batch::new();
+        for (key, value) in puts {
+            batch.put(key, value);
+        }
+        store.write_batch(batch).unwrap();
+    }
+
+    #[test]
+    fn reopen_replays_the_log() {
+        let log = TempFile::new("reopen.log");
+        let store = LogStore::open(log.path()).unwrap();
+        write(&store, &[(b"a/1", b"one"), (b"a/2", b"two")]);
+        write(&store, &[(b"a/1", b"uno"), (b"b/1", b"other")]);
+        drop(store);
+
+        let store = LogStore::open(log.path()).unwrap();
+        assert_eq!(store.get(b"a/1").unwrap().as_deref(), Some(&b"uno"[..]));
+        assert_eq!(store.get(b"c").unwrap(), None);
+        let prefix = store.iter_prefix(b"a/").unwrap();
+        let keys: Vec<&[u8]> = prefix.iter().map(|(key, _)| key.as_slice()).collect();
+        assert_eq!(keys, [&b"a/1"[..], &b"a/2"[..]]);
+    }
+
+    #[test]
+    fn reopen_replays_the_deletes() {
+        let log = TempFile::new("delete.log");
+        let store = LogStore::open(log.path()).unwrap();
+        write(&store, &[(b"a", b"one"), (b"b", b"two")]);
+        let mut batch = Batch::new();
+        batch.delete(b"a");
+        batch.put(b"c", b"three");
+        store.write_batch(batch).unwrap();
+        store.delete(b"b").unwrap();
+        store.delete(b"missing").unwrap();
+        drop(store);
+
+        let store = LogStore::open(log.path()).unwrap();
+        assert!(!store.has(b"a").unwrap());
+        assert!(!store.has(b"b").unwrap());
+        assert_eq!(store.get(b"c").unwrap().as_deref(), Some(&b"three"[..]));
+        store.put(b"a", b"again").unwrap();
+        assert_eq!(store.get(b"a").unwrap().as_deref(), Some(&b"again"[..]));
+    }
+
+    #[test]
+    fn reopen_drops_a_truncated_tail_entry() {
+        let log = TempFile::new("truncated.log");
+        let store = LogStore::open(log.path()).unwrap();
+        write(&store, &[(b"a", b"kept")]);
+        let kept = fs::metadata(log.path()).unwrap().len();
+        write(&store, &[(b"b", b"lost"), (b"c", b"lost")]);
+        drop(store);
+
+        // a crash in the middle of the second entry leaves a part of it
+        let file = OpenOptions::new().write(true).open(log.path()).unwrap();
+        file.set_len(fs::metadata(log.path()).unwrap().len() - 3)
+            .unwrap();
+        drop(file);
+
+        let store = LogStore::open(log.path()).unwrap();
+        assert_eq!(store.get(b"a").unwrap().as_deref(), Some(&b"kept"[..]));
+        assert_eq!(store.get(b"b").unwrap(), None);
+        assert_eq!(store.get(b"c").unwrap(), None);
+        assert_eq!(fs::metadata(log.path()).unwrap().len(), kept);
+
+        // the next entry is written where the dropped one started
+        write(&store, &[(b"d", b"after")]);
+        drop(store);
+        let store = LogStore::open(log.path()).unwrap();
+        assert_eq!(store.get(b"d").unwrap().as_deref(), Some(&b"after"[..]));
+        assert_eq!(store.get(b"a").unwrap().as_deref(), Some(&b"kept"[..]));
+    }
+
+    #[test]
+    fn unknown_operation_is_an_error() {
+        let log = TempFile::new("unknown.log");
+        let mut entry = vec![0; 4];
+        entry.push(7);
+        write_field(&mut entry, b"key").unwrap();
+        write_field(&mut entry, b"value").unwrap();
+        let len = (entry.len() - 4) as u32;
+        entry[..4].copy_from_slice(&len.to_le_bytes());
+        fs::write(log.path(), entry).unwrap();
+
+        let error = LogStore::open(log.path()).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -1,54 +1,125 @@
 /*
-While the current implementation doesn't persist blocks,
-the MemStore provides a simple key-value storage mechanism that could be
-used to simulate blockchain state in memory. This is useful for testing and
-simulating consensus algorithms without the overhead of actual disk storage.
+Stores are the key-value layer the chain is persisted on. Keys are kept sorted so
+they can be scanned by prefix, which is how the chain store lays out its blocks.
+The MemStore keeps everything in memory, which is useful for testing and simulating
+consensus algorithms without the overhead of actual disk storage, while the LogStore
+persists every write to an append-only file.
 */
 
 use bytes::Bytes;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::io;
 use std::sync::{Arc, RwLock};
 
+/// Store is a sorted key-value store, implementations must be safe to share between threads.
+pub trait Store: Send + Sync {
+    /// Gets a value by key, None if the key does not exist.
+    fn get(&self, key: &[u8]) -> io::Result<Option<Bytes>>;
+
+    /// Returns the key-value pairs whose key starts with the prefix, in key order.
+    fn iter_prefix(&self, prefix: &[u8]) -> io::Result<Vec<(Vec<u8>, Bytes)>>;
+
+    /// Applies every operation of the batch, or none of them.
+    fn write_batch(&self, batch: Batch) -> io::Result<()>;
+
+    /// Puts a key-value pair into the store.
+    fn put(&self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        let mut batch = Batch::new();
+        batch.put(key, value);
+        self.write_batch(batch)
+    }
+
+    /// Deletes a key, deleting a missing key is not an error.
+    #[allow(dead_code)] // logs truncate their entries in batches
+    fn delete(&self, key: &[u8]) -> io::Result<()> {
+        let mut batch = Batch::new();
+        batch.delete(key);
+        self.write_batch(batch)
+    }
+
+    /// Checks if a key exists in the store.
+    fn has(&self, key: &[u8]) -> io::Result<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+}
+
+/// Operation is a single write of a batch.
+#[derive(Debug, Clone)]
+pub enum Operation {
+    Put { key: Vec<u8>, value: Vec<u8> },
+    Delete { key: Vec<u8> },
+}
+
+/// Batch is a list of writes applied atomically by `Store::write_batch`.
+#[derive(Debug, Clone, Default)]
+pub struct Batch {
+    operations: Vec<Operation>,
+}
+
+impl Batch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) {
+        self.operations.push(Operation::Put {
+            key: key.to_vec(),
+            value: value.to_vec(),
+        });
+    }
+
+    pub fn delete(&mut self, key: &[u8]) {
+        self.operations
+            .push(Operation::Delete { key: key.to_vec() });
+    }
+
+    pub fn operations(&self) -> &[Operation] {
+        &self.operations
+    }
+}
+
 /// MemStore is an in-memory Store implementation for cunner framework.
 /// Using an in-memory store allows for fast read and write operations, which is crucial for simulating high-throughput consensus algorithms.
 pub struct MemStore {
-    lock: RwLock<HashMap<String, Bytes>>, // ensure thread safety and efficient read/write operations
+    lock: RwLock<BTreeMap<Vec<u8>, Bytes>>, // ensure thread safety and efficient read/write operations
 }
 
 impl MemStore {
     /// Returns a new MemoryStore.
     pub fn new_mem_store() -> Arc<Self> {
         Arc::new(Self {
-            lock: RwLock::new(HashMap::new()),
+            lock: RwLock::new(BTreeMap::new()),
         })
     }
+}
 
-    /// Gets a value by key. It returns Option<Bytes> to handle cases where the key might not exist.
-    pub fn get_value_from_key(&self, key: &[u8]) -> Option<Bytes> {
+impl Store for MemStore {
+    fn get(&self, key: &[u8]) -> io::Result<Option<Bytes>> {
         let read_lock = self.lock.read().unwrap();
-        read_lock
-            .get(String::from_utf8_lossy(key).as_ref())
-            .cloned()
-    }
-
-    /// Puts a key-value pair into the store.
-    pub fn put_value_to_key(&self, key: &[u8], val: &[u8]) {
-        let mut write_lock = self.lock.write().unwrap();
-        write_lock.insert(
-            String::from_utf8_lossy(key).to_string(),
-            Bytes::copy_from_slice(val),
-        );
+        Ok(read_lock.get(key).cloned())
     }
 
-    /// Checks if a key exists in the store.
-    pub fn has_key(&self, key: &[u8]) -> bool {
+    fn iter_prefix(&self, prefix: &[u8]) -> io::Result<Vec<(Vec<u8>, Bytes)>> {
         let read_lock = self.lock.read().unwrap();
-        read_lock.contains_key(String::from_utf8_lossy(key).as_ref())
+        Ok(read_lock
+            .range(prefix.to_vec()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect())
     }
 
-    /// Returns the number of elements in the store.
-    pub fn length(&self) -> usize {
-        let read_lock = self.lock.read().unwrap();
-        read_lock.len()
+    fn write_batch(&self, batch: Batch) -> io::Result<()> {
+        let mut write_lock = self.lock.write().unwrap();
+        for operation in batch.operations {
+            match operation {
+                Operation::Put { key, value } => {
+                    write_lock.insert(key, Bytes::from(value));
+                }
+                Operation::Delete { key } => {
+                    write_lock.remove(&key);
+                }
+            }
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,87 @@
+/*
+Fixtures shared by the tests of the modules.
+*/
+
+use crate::consensus::chain::Chain;
+use crate::consensus::engine::{Context, Engine};
+use crate::network::faults::FaultConfig;
+use crate::simulation::simulator::{Simulation, SimulationConfig};
+use crate::workload::generator::WorkloadConfig;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// TempFile is a file of its own for a test in the temporary directory, removed when
+/// the test ends. Tests running at once in the process must use different names.
+pub struct TempFile(PathBuf);
+
+impl TempFile {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("cunner-{}-{}", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Runs `nodes` nodes for `secs` seconds of a simulation seeded with `seed`, every
+/// node running the engine `new_engine` returns for its index, and returns the chain
+/// of every node. The caller holds `rng::exclusive`.
+pub fn simulate(
+    nodes: usize,
+    seed: u64,
+    secs: u64,
+    new_engine: impl Fn(usize, Context) -> Box<dyn Engine>,
+) -> Vec<Chain> {
+    let simulation = Simulation::new(SimulationConfig {
+        nodes,
+        seed,
+        duration: Duration::from_secs(secs),
+        latency: Duration::from_millis(50),
+        workload: WorkloadConfig::default(),
+        faults: FaultConfig::default(),
+        record: None,
+    });
+    simulation
+        .run(new_engine)
+        .unwrap()
+        .into_iter()
+        .map(|(_, chain)| chain)
+        .collect()
+}
+
+/// Asserts that every chain holds `min_height` blocks at least, and that no two
+/// chains hold different blocks at a height.
+pub fn assert_agree(chains: &[Chain], min_height: u64) {
+    let hashes: Vec<Vec<Vec<u8>>> = chains
+        .iter()
+        .map(|chain| {
+            chain
+                .blocks()
+                .unwrap()
+                .iter()
+                .map(|block| block.hash())
+                .collect()
+        })
+        .collect();
+    let longest = hashes.iter().max_by_key(|hashes| hashes.len()).unwrap();
+    for (node, hashes) in hashes.iter().enumerate() {
+        assert!(
+            hashes.len() as u64 >= min_height,
+            "node {node} holds {} blocks, expected {min_height} at least",
+            hashes.len()
+        );
+        if let Some(height) = (0..hashes.len()).find(|&height| hashes[height] != longest[height]) {
+            panic!("node {node} disagrees at height {}", height + 1);
+        }
+    }
+}
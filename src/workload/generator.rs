@@ -0,0 +1,480 @@
+/*
+A workload decides when transactions are submitted to the nodes, to load an engine
+the way the saturation and scalability benchmarks of Gromit do. A generator yields
+the arrivals of a submitter, each arrival being one or more transactions due at a
+time since the start of the run:
+
+- constant: evenly spaced transactions at the target rate
+- poisson: exponentially distributed gaps averaging the target rate
+- bursty: bursts of `burst_size` transactions at once, averaging the target rate
+- ramp: a rate growing linearly from `ramp_start_tps` to the target over
+  `ramp_secs`, then steady
+- trace: the transactions of a trace file, at the times it recorded
+
+The target rate is over the whole network. Every node submits its share of it by
+default, and with single client submission node 0 submits all of it, as a single
+client sending everything to one node would. A trace is split between the nodes the
+same way, its transactions going to every node in turn. Without a target rate every
+node submits a transaction every `transaction_interval_secs`, as nodes always did.
+Every draw goes through the generator of the run, so a seeded simulation submits the
+same transactions at the same times every time.
+*/
+
+use crate::simulation::rng::with_rng;
+use crate::CunnerError;
+use clap::ValueEnum;
+use rand::Rng;
+use rand_distr::{Distribution, Exp};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Shortest gap between two arrivals of a submitter, whatever the target rate.
+const MIN_GAP: Duration = Duration::from_micros(1);
+
+/// Generator is how the arrivals of a workload are spaced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Generator {
+    Constant,
+    Poisson,
+    Bursty,
+    Ramp,
+    Trace,
+}
+
+/// Submission is which nodes the transactions of a workload are submitted to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Submission {
+    /// every node submits its share of the workload
+    PerNode,
+    /// node 0 submits the whole workload
+    SingleClient,
+}
+
+/// Workload of a run, set in the `workload` section of the experiment.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WorkloadConfig {
+    pub generator: Generator,
+    /// transactions per second submitted to the whole network
+    pub tps: Option<f64>,
+    pub submission: Submission,
+    /// period between two transactions submitted by a node when no tps is set
+    pub transaction_interval_secs: u64,
+    /// transactions of a burst of the bursty generator
+    pub burst_size: usize,
+    /// rate over the whole network the ramp starts from, and how long it takes to
+    /// reach the target
+    pub ramp_start_tps: f64,
+    pub ramp_secs: u64,
+    /// file the trace generator replays, a transaction per line: the milliseconds
+    /// since the start it is due at, and optionally a comma and its hex payload
+    pub trace: Option<PathBuf>,
+}
+
+impl Default for WorkloadConfig {
+    fn default() -> Self {
+        Self {
+            generator: Generator::Constant,
+            tps: None,
+            submission: Submission::PerNode,
+            transaction_interval_secs: 5,
+            burst_size: 10,
+            ramp_start_tps: 0.0,
+            ramp_secs: 60,
+            trace: None,
+        }
+    }
+}
+
+impl WorkloadConfig {
+    pub fn validate(&self) -> Result<(), CunnerError> {
+        let invalid = |message: &str| Err(CunnerError::Config(format!("workload: {message}")));
+        if self.transaction_interval_secs == 0 {
+            return invalid("transaction_interval_secs must be at least 1");
+        }
+        if self.tps.is_some_and(|tps| !tps.is_finite() || tps <= 0.0) {
+            return invalid("tps must be above 0");
+        }
+        if self.burst_size == 0 {
+            return invalid("burst_size must be at least 1");
+        }
+        if !self.ramp_start_tps.is_finite() || self.ramp_start_tps < 0.0 {
+            return invalid("ramp_start_tps must not be negative");
+        }
+        if self.ramp_secs == 0 {
+            return invalid("ramp_secs must be at least 1");
+        }
+        if self.generator == Generator::Trace && self.trace.is_none() {
+            return invalid("the trace generator needs a trace file");
+        }
+        Ok(())
+    }
+
+    /// Returns the workload the node at `index` of a network of `nodes` nodes
+    /// submits, None if it submits nothing. A node whose index is unknown submits
+    /// its share of the workload.
+    pub fn new_workload(
+        &self,
+        index: Option<usize>,
+        nodes: usize,
+    ) -> Result<Option<Box<dyn Workload>>, CunnerError> {
+        // the nodes the workload is split between, and the place of this one among them
+        let (submitters, place) = match (self.submission, index) {
+            (Submission::SingleClient, Some(0)) => (1, 0),
+            (Submission::SingleClient, Some(_)) => return Ok(None),
+            (Submission::PerNode, Some(index)) => (nodes, index),
+            (_, None) => (nodes, 0),
+        };
+        // the rate of this node, every node submitted a transaction per interval so far
+        let rate = self
+            .tps
+            .unwrap_or(nodes as f64 / self.transaction_interval_secs as f64)
+            / submitters as f64;
+        let gap = if self.tps.is_none() && submitters == nodes {
+            Duration::from_secs(self.transaction_interval_secs)
+        } else {
+            Duration::from_secs_f64(1.0 / rate).max(MIN_GAP)
+        };
+        let workload: Box<dyn Workload> = match self.generator {
+            Generator::Constant => Box::new(Periodic::new(gap, 1)),
+            Generator::Poisson => Box::new(Poisson { rate, at: 0.0 }),
+            Generator::Bursty => Box::new(Periodic::new(
+                (gap * self.burst_size as u32).max(MIN_GAP),
+                self.burst_size,
+            )),
+            Generator::Ramp => Box::new(Ramp {
+                start: self.ramp_start_tps / submitters as f64,
+                target: rate,
+                duration: self.ramp_secs as f64,
+                at: 0.0,
+            }),
+            Generator::Trace => {
+                let path = self.trace.as_ref().expect("validated trace workload");
+                let arrivals = read_trace(path)?
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(line, _)| line % submitters == place)
+                    .map(|(_, arrival)| arrival)
+                    .collect();
+                Box::new(Trace { arrivals })
+            }
+        };
+        Ok(Some(workload))
+    }
+}
+
+/// Arrival is a submission of a workload: `count` transactions due `at` after the
+/// start, carrying `payload` if the workload sets one, a random one otherwise.
+#[derive(Debug, Clone)]
+pub struct Arrival {
+    pub at: Duration,
+    pub count: usize,
+    pub payload: Option<Vec<u8>>,
+}
+
+/// Workload yields the arrivals of a submitter, in order.
+pub trait Workload: Send {
+    /// Returns the next arrival, None once the workload is over.
+    fn next(&mut self) -> Option<Arrival>;
+}
+
+// `count` transactions every `gap`, from a random offset within the first gap so the
+// nodes do not submit in lockstep, as they would not start at the same time
+struct Periodic {
+    gap: Duration,
+    count: usize,
+    at: Option<Duration>,
+}
+
+impl Periodic {
+    fn new(gap: Duration, count: usize) -> Self {
+        Self {
+            gap,
+            count,
+            at: None,
+        }
+    }
+}
+
+impl Workload for Periodic {
+    fn next(&mut self) -> Option<Arrival> {
+        let gap = self.gap;
+        let at = match self.at {
+            Some(at) => at + gap,
+            // drawn on the first arrival, so a simulation draws it once seeded
+            None => with_rng(|rng| rng.gen_range(Duration::ZERO..gap)),
+        };
+        self.at = Some(at);
+        Some(Arrival {
+            at,
+            count: self.count,
+            payload: None,
+        })
+    }
+}
+
+// a Poisson process of `rate` transactions per second, `at` in seconds
+struct Poisson {
+    rate: f64,
+    at: f64,
+}
+
+impl Workload for Poisson {
+    fn next(&mut self) -> Option<Arrival> {
+        let exp = Exp::new(self.rate).expect("validated workload rate");
+        self.at += with_rng(|rng| exp.sample(rng));
+        Some(Arrival {
+            at: Duration::from_secs_f64(self.at),
+            count: 1,
+            payload: None,
+        })
+    }
+}
+
+// a rate growing linearly from `start` to `target` over `duration` seconds, `at` in
+// seconds
+struct Ramp {
+    start: f64,
+    target: f64,
+    duration: f64,
+    at: f64,
+}
+
+impl Workload for Ramp {
+    // the next arrival is when one more transaction is due at the rate of the ramp
+    fn next(&mut self) -> Option<Arrival> {
+        let slope = (self.target - self.start) / self.duration;
+        let gap = if self.at < self.duration {
+            let rate = self.start + slope * self.at;
+            let left = self.duration - self.at;
+            let due_by_end = rate * left + slope * left * left / 2.0;
+            if due_by_end < 1.0 {
+                left + (1.0 - due_by_end) / self.target
+            } else if slope == 0.0 {
+                1.0 / rate
+            } else {
+                // the first root of slope / 2 * gap^2 + rate * gap = 1
+                (-rate + (rate * rate + 2.0 * slope).sqrt()) / slope
+            }
+        } else {
+            1.0 / self.target
+        };
+        self.at += gap.max(MIN_GAP.as_secs_f64());
+        Some(Arrival {
+            at: Duration::from_secs_f64(self.at),
+            count: 1,
+            payload: None,
+        })
+    }
+}
+
+struct Trace {
+    arrivals: VecDeque<Arrival>,
+}
+
+impl Workload for Trace {
+    fn next(&mut self) -> Option<Arrival> {
+        self.arrivals.pop_front()
+    }
+}
+
+// reads the arrivals of a trace, blank lines and lines starting with # are skipped
+fn read_trace(path: &Path) -> Result<Vec<Arrival>, CunnerError> {
+    let invalid = |line: usize, message: String| {
+        CunnerError::Config(format!(
+            "Invalid trace {} at line {}: {}",
+            path.display(),
+            line + 1,
+            message
+        ))
+    };
+    let content = fs::read_to_string(path).map_err(|e| {
+        CunnerError::Config(format!("Failed to read trace {}: {}", path.display(), e))
+    })?;
+    let mut arrivals = Vec::new();
+    for (line, text) in content.lines().enumerate() {
+        let text = text.trim();
+        if text.is_empty() || text.starts_with('#') {
+            continue;
+        }
+        let (at, payload) = match text.split_once(',') {
+            Some((at, payload)) => (at, Some(payload.trim())),
+            None => (text, None),
+        };
+        let at: u64 = at
+            .trim()
+            .parse()
+            .map_err(|e| invalid(line, format!("time {at}: {e}")))?;
+        let payload = payload
+            .map(|payload| hex::decode(payload).map_err(|e| invalid(line, format!("payload: {e}"))))
+            .transpose()?;
+        arrivals.push(Arrival {
+            at: Duration::from_millis(at),
+            count: 1,
+            payload,
+        });
+    }
+    // a trace need not be sorted, its transactions are submitted in time order
+    arrivals.sort_by_key(|arrival| arrival.at);
+    Ok(arrivals)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::rng;
+    use crate::testing::TempFile;
+
+    fn config(generator: Generator, tps: f64) -> WorkloadConfig {
+        WorkloadConfig {
+            generator,
+            tps: Some(tps),
+            ..WorkloadConfig::default()
+        }
+    }
+
+    // the transactions a workload submits from `from` to `to` seconds after the start
+    fn submitted(workload: &mut dyn Workload, from: u64, to: u64) -> usize {
+        let mut count = 0;
+        while let Some(arrival) = workload.next() {
+            if arrival.at >= Duration::from_secs(to) {
+                break;
+            }
+            if arrival.at >= Duration::from_secs(from) {
+                count += arrival.count;
+            }
+        }
+        count
+    }
+
+    fn workload(config: &WorkloadConfig, index: usize) -> Box<dyn Workload> {
+        config.new_workload(Some(index), 4).unwrap().unwrap()
+    }
+
+    #[test]
+    fn every_node_submits_its_share_of_the_rate() {
+        let _rng = rng::exclusive();
+        rng::seed(5);
+        for generator in [Generator::Constant, Generator::Poisson, Generator::Bursty] {
+            let config = config(generator, 40.0);
+            let total: usize = (0..4)
+                .map(|index| submitted(workload(&config, index).as_mut(), 0, 100))
+                .sum();
+            assert!(
+                (3_800..=4_200).contains(&total),
+                "{generator:?} submitted {total} transactions in 100 seconds"
+            );
+        }
+    }
+
+    #[test]
+    fn bursts_arrive_together() {
+        let _rng = rng::exclusive();
+        let mut config = config(Generator::Bursty, 20.0);
+        config.burst_size = 5;
+        let mut workload = workload(&config, 1);
+        let first = workload.next().unwrap();
+        let second = workload.next().unwrap();
+        assert_eq!((first.count, second.count), (5, 5));
+        assert_eq!(second.at - first.at, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn the_ramp_reaches_the_target_rate() {
+        let mut config = config(Generator::Ramp, 40.0);
+        config.ramp_start_tps = 4.0;
+        config.ramp_secs = 60;
+        // the rate of node 0 grows from 1 to 10 transactions a second over a minute
+        let early = submitted(workload(&config, 0).as_mut(), 0, 10);
+        let late = submitted(workload(&config, 0).as_mut(), 50, 60);
+        let steady = submitted(workload(&config, 0).as_mut(), 60, 70);
+        assert!(
+            (15..=20).contains(&early),
+            "{early} in the first 10 seconds"
+        );
+        assert!((90..=100).contains(&late), "{late} in the last 10 seconds");
+        assert!((99..=101).contains(&steady), "{steady} once steady");
+    }
+
+    #[test]
+    fn a_single_client_submits_the_whole_rate() {
+        let _rng = rng::exclusive();
+        let mut config = config(Generator::Constant, 40.0);
+        config.submission = Submission::SingleClient;
+        assert!(config.new_workload(Some(1), 4).unwrap().is_none());
+        let count = submitted(workload(&config, 0).as_mut(), 0, 10);
+        assert!((399..=401).contains(&count), "{count} in 10 seconds");
+    }
+
+    #[test]
+    fn without_a_rate_nodes_submit_every_interval() {
+        let _rng = rng::exclusive();
+        let mut workload = workload(&WorkloadConfig::default(), 2);
+        let first = workload.next().unwrap();
+        assert!(first.at < Duration::from_secs(5));
+        assert_eq!(
+            workload.next().unwrap().at - first.at,
+            Duration::from_secs(5)
+        );
+    }
+
+    #[test]
+    fn a_trace_is_split_between_the_nodes_in_time_order() {
+        let trace = TempFile::new("workload.csv");
+        fs::write(
+            trace.path(),
+            "# ms,payload\n300\n100,cafe\n\n200, 00ff\n400\n500\n",
+        )
+        .unwrap();
+        let config = WorkloadConfig {
+            generator: Generator::Trace,
+            trace: Some(trace.path().to_path_buf()),
+            ..WorkloadConfig::default()
+        };
+        let arrivals = |index| {
+            let mut workload = config.new_workload(Some(index), 2).unwrap().unwrap();
+            std::iter::from_fn(move || workload.next())
+                .map(|arrival| (arrival.at.as_millis(), arrival.payload))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            arrivals(0),
+            [(100, Some(vec![0xca, 0xfe])), (300, None), (500, None)]
+        );
+        assert_eq!(arrivals(1), [(200, Some(vec![0x00, 0xff])), (400, None)]);
+
+        fs::write(trace.path(), "100,xyz\n").unwrap();
+        assert!(config.new_workload(Some(0), 2).is_err());
+    }
+
+    #[test]
+    fn validate_rejects_an_invalid_workload() {
+        let invalid = [
+            WorkloadConfig {
+                tps: Some(0.0),
+                ..WorkloadConfig::default()
+            },
+            WorkloadConfig {
+                burst_size: 0,
+                ..WorkloadConfig::default()
+            },
+            WorkloadConfig {
+                ramp_start_tps: -1.0,
+                ..WorkloadConfig::default()
+            },
+            WorkloadConfig {
+                generator: Generator::Trace,
+                ..WorkloadConfig::default()
+            },
+        ];
+        for config in invalid {
+            assert!(config.validate().is_err(), "{config:?} is valid");
+        }
+        assert!(WorkloadConfig::default().validate().is_ok());
+    }
+}